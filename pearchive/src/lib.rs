@@ -1,22 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{CStr, CString, OsStr};
 use std::fs;
 use std::fs::File;
 use std::io::{BufWriter, Cursor, Write};
 use std::os::fd::OwnedFd;
+use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use memmap2::MmapOptions;
+use memmap2::{Mmap, MmapOptions};
 use rustix::{
+    event::{PollFd, PollFlags},
     fd::AsFd,
     fs::{FileType, RawDir},
+    io::Errno,
     process::{getegid, geteuid},
     thread::{unshare, UnshareFlags},
 };
 
 mod open;
-use open::{mkdirat, openat, openat_w, opendir, opendirat, opendirat_cwd, openpathat};
+use open::{
+    mkdirat, openat, openat_w, openat_w_beneath, opendir, opendirat, opendirat_cwd, openpathat,
+    openpathat_beneath,
+};
 
 const MAX_DIR_DEPTH: usize = 32;
 const DIRENT_BUF_SIZE: usize = 2048;
@@ -53,6 +60,8 @@ pub enum Error {
     OnPop,
     Write,
     SendFile(i32),
+    // the file had less data than its size said, eg it got truncated under us
+    SendFileEof,
     Flush,
     BadName,
     BadSize,
@@ -66,6 +75,11 @@ pub enum Error {
     StackEmpty,
     BadCStr,
     SizeUnderflow,
+    NameExists,
+    // the file's size doesn't fit in the u32 the format stores
+    FileTooBig(String),
+    // writing the named file into the archive failed with the inner error
+    PackFile(String, Box<Error>),
 }
 
 impl std::fmt::Display for Error {
@@ -84,6 +98,18 @@ pub trait PackFsVisitor {
     fn on_file(&mut self, name: &CStr, size: u64, fd: OwnedFd) -> Result<(), Error>;
     fn on_dir(&mut self, name: &CStr) -> Result<(), Error>;
     fn leave_dir(&mut self) -> Result<(), Error>;
+    /// called for anything that isn't a regular file or dir (symlinks, fifos, sockets, devices),
+    /// these never make it into an archive
+    fn on_skip(&mut self, _name: &CStr, _file_type: FileType) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// an entry left out of an archive because of its type, path is relative to the packed dir
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedEntry {
+    pub path: PathBuf,
+    pub file_type: FileType,
 }
 
 pub trait PackMemVisitor {
@@ -94,11 +120,24 @@ pub trait PackMemVisitor {
 
 pub trait UnpackVisitor {
     fn on_file(&mut self, path: &Path, data: &[u8]) -> bool;
+    /// called for every dir, even with unpack_with_filter since a dir can lead to a kept file
+    fn on_dir(&mut self, _path: &Path) {}
 }
 
 struct PackFsToWriter<W: Write + AsFd> {
     writer: BufWriter<W>,
     depth: usize,
+    // when Some, the archive is kept to at most this many bytes by leaving out files and dirs
+    // that don't fit. used counts bytes written so far and every open dir reserves one byte for
+    // its pop so the archive stays well formed
+    limit: Option<u64>,
+    used: u64,
+    // > 0 while inside a dir that was left out
+    skip_depth: usize,
+    truncated: bool,
+    // path of the dir we're in (skipped or not), only kept up to date when skipped is Some
+    dir_path: PathBuf,
+    skipped: Option<Vec<SkippedEntry>>,
 }
 
 impl<W: Write + AsFd> PackFsToWriter<W> {
@@ -106,17 +145,32 @@ impl<W: Write + AsFd> PackFsToWriter<W> {
         Self {
             depth: 0,
             writer: BufWriter::new(out),
+            limit: None,
+            used: 0,
+            skip_depth: 0,
+            truncated: false,
+            dir_path: PathBuf::new(),
+            skipped: None,
+        }
+    }
+
+    fn with_limit(out: W, limit: u64) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Self::new(out)
         }
     }
 
+    fn fits(&self, n: u64) -> bool {
+        self.limit
+            .is_none_or(|limit| self.used + n + self.depth as u64 <= limit)
+    }
+
     fn into_file(self) -> Result<W, Error> {
         self.writer.into_inner().map_err(|_| Error::Write)
     }
-}
 
-impl<W: Write + AsFd> PackFsVisitor for PackFsToWriter<W> {
-    fn on_file(&mut self, name: &CStr, size: u64, fd: OwnedFd) -> Result<(), Error> {
-        let size_u32: u32 = size.try_into().map_err(|_| Error::Write)?;
+    fn write_file(&mut self, name: &CStr, size: u32, fd: OwnedFd) -> Result<(), Error> {
         self.writer
             .write_all(&[ArchiveFormat1Tag::File as u8])
             .map_err(|_| Error::Write)?;
@@ -124,17 +178,51 @@ impl<W: Write + AsFd> PackFsVisitor for PackFsToWriter<W> {
             .write_all(name.to_bytes_with_nul())
             .map_err(|_| Error::Write)?;
         self.writer
-            .write_all(&size_u32.to_le_bytes())
+            .write_all(&size.to_le_bytes())
             .map_err(|_| Error::Write)?;
         self.writer.flush().map_err(|_| Error::Flush)?;
-        sendfile_all(&fd, self.writer.get_ref(), size)?;
-        Ok(())
+        sendfile_all(&fd, self.writer.get_ref(), size.into())
+    }
+}
+
+impl<W: Write + AsFd> PackFsVisitor for PackFsToWriter<W> {
+    fn on_file(&mut self, name: &CStr, size: u64, fd: OwnedFd) -> Result<(), Error> {
+        let entry_len = 1 + name.to_bytes_with_nul().len() as u64 + 4 + size;
+        if self.skip_depth > 0 {
+            return Ok(());
+        }
+        if !self.fits(entry_len) {
+            self.truncated = true;
+            return Ok(());
+        }
+        self.used += entry_len;
+        let name_lossy = || name.to_string_lossy().into_owned();
+        let size_u32: u32 = size
+            .try_into()
+            .map_err(|_| Error::FileTooBig(name_lossy()))?;
+        self.write_file(name, size_u32, fd)
+            .map_err(|e| Error::PackFile(name_lossy(), Box::new(e)))
     }
 
     fn on_dir(&mut self, name: &CStr) -> Result<(), Error> {
+        if self.skipped.is_some() {
+            self.dir_path.push(OsStr::from_bytes(name.to_bytes()));
+        }
+        if self.skip_depth > 0 {
+            self.skip_depth += 1;
+            return Ok(());
+        }
         if self.depth > MAX_DIR_DEPTH {
             return Err(Error::DirTooDeep);
         }
+        let entry_len = 1 + name.to_bytes_with_nul().len() as u64;
+        // plus one for the pop
+        if !self.fits(entry_len + 1) {
+            self.truncated = true;
+            self.skip_depth = 1;
+            return Ok(());
+        }
+        self.used += entry_len;
         self.depth += 1;
         self.writer
             .write_all(&[ArchiveFormat1Tag::Dir as u8])
@@ -146,6 +234,14 @@ impl<W: Write + AsFd> PackFsVisitor for PackFsToWriter<W> {
     }
 
     fn leave_dir(&mut self) -> Result<(), Error> {
+        if self.skipped.is_some() {
+            self.dir_path.pop();
+        }
+        if self.skip_depth > 0 {
+            self.skip_depth -= 1;
+            return Ok(());
+        }
+        self.used += 1;
         if self.depth == 0 {
             return Err(Error::EmptyStack);
         }
@@ -155,6 +251,16 @@ impl<W: Write + AsFd> PackFsVisitor for PackFsToWriter<W> {
             .map_err(|_| Error::Write)?;
         Ok(())
     }
+
+    fn on_skip(&mut self, name: &CStr, file_type: FileType) -> Result<(), Error> {
+        if let Some(skipped) = &mut self.skipped {
+            skipped.push(SkippedEntry {
+                path: self.dir_path.join(OsStr::from_bytes(name.to_bytes())),
+                file_type: file_type,
+            });
+        }
+        Ok(())
+    }
 }
 
 pub struct PackMemToWriter<W: Write> {
@@ -255,6 +361,38 @@ impl PackMemVisitor for PackMemToVec {
     }
 }
 
+/// in-memory directory tree, keyed by name so that iteration order is sorted
+pub type Tree = BTreeMap<String, Node>;
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    File(Vec<u8>),
+    Dir(Tree),
+}
+
+/// drives the visitor over the tree with dir/pop correctly nested
+pub fn visit_tree<V: PackMemVisitor>(tree: &Tree, v: &mut V) -> Result<(), Error> {
+    for (name, node) in tree.iter() {
+        match node {
+            Node::File(data) => {
+                v.file(name, data)?;
+            }
+            Node::Dir(subtree) => {
+                v.dir(name)?;
+                visit_tree(subtree, v)?;
+                v.pop()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn pack_tree(root: &Tree) -> Result<Vec<u8>, Error> {
+    let mut visitor = PackMemToVec::new();
+    visit_tree(root, &mut visitor)?;
+    visitor.into_vec()
+}
+
 fn unshare_user() -> Result<(), Error> {
     let uid = geteuid();
     let gid = getegid();
@@ -302,6 +440,16 @@ fn read_le_u32(input: &mut &[u8]) -> Result<u32, Error> {
     ))
 }
 
+// names from an archive are a single path component, anything else could walk out of the dir
+// we're unpacking into
+fn check_name(name: &CStr) -> Result<(), Error> {
+    let name = name.to_bytes();
+    if name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(Error::BadName);
+    }
+    Ok(())
+}
+
 fn read_cstr<'a>(input: &mut &'a [u8]) -> Result<&'a CStr, Error> {
     // memchr ...
     if input.is_empty() {
@@ -333,13 +481,31 @@ fn sendfile_all<Fd1: rustix::fd::AsFd, Fd2: rustix::fd::AsFd>(
 ) -> Result<(), Error> {
     let mut len = len;
     while len > 0 {
-        let sent = rustix::fs::sendfile(fd_out, fd_in, None, len as usize)
-            .map_err(|e| Error::SendFile(e.raw_os_error()))?;
+        let sent = match rustix::fs::sendfile(fd_out, fd_in, None, len as usize) {
+            Ok(0) => return Err(Error::SendFileEof),
+            Ok(sent) => sent,
+            Err(e) if e == Errno::INTR => continue,
+            // fd_out is nonblocking and full
+            Err(e) if e == Errno::AGAIN => {
+                wait_writable(fd_out)?;
+                continue;
+            }
+            Err(e) => return Err(Error::SendFile(e.raw_os_error())),
+        };
         len = len.checked_sub(sent as u64).ok_or(Error::SizeUnderflow)?
     }
     Ok(())
 }
 
+fn wait_writable<Fd: rustix::fd::AsFd>(fd: &Fd) -> Result<(), Error> {
+    let mut fds = [PollFd::new(fd, PollFlags::OUT)];
+    match rustix::event::poll(&mut fds, None) {
+        Ok(_) => Ok(()),
+        Err(e) if e == Errno::INTR => Ok(()),
+        Err(e) => Err(Error::SendFile(e.raw_os_error())),
+    }
+}
+
 // would love to know how this looks as an iterator at some point
 fn visit_dirc_rec<V: PackFsVisitor>(curdir: &OwnedFd, v: &mut V) -> Result<(), Error> {
     let mut buf = Vec::with_capacity(DIRENT_BUF_SIZE);
@@ -365,7 +531,7 @@ fn visit_dirc_rec<V: PackFsVisitor>(curdir: &OwnedFd, v: &mut V) -> Result<(), E
                 visit_dirc_rec(&newdirfd, v)?;
                 v.leave_dir().map_err(|_| Error::OnDir)?;
             }
-            _ => {}
+            file_type => v.on_skip(entry.file_name(), file_type)?,
         }
     }
 
@@ -393,10 +559,56 @@ pub fn pack_dir_to_file(dir: &Path, file: File) -> Result<File, Error> {
     pack_dir_to_writer(dir, file)
 }
 
+/// like pack_dir_to_writer but also returns every entry that was left out because it isn't a
+/// regular file or dir
+pub fn pack_dir_to_writer_skipped<W: Write + AsFd>(
+    dir: &Path,
+    writer: W,
+) -> Result<(W, Vec<SkippedEntry>), Error> {
+    let mut visitor = PackFsToWriter::new(writer);
+    visitor.skipped = Some(vec![]);
+    visit_dir(dir, &mut visitor)?;
+    let skipped = visitor.skipped.take().unwrap_or_default();
+    Ok((visitor.into_file()?, skipped))
+}
+
+/// like pack_dir_to_writer but writes at most limit bytes, leaving out whatever files and dirs
+/// don't fit. The archive is still valid. Returns true if anything was left out
+pub fn pack_dir_to_writer_limit<W: Write + AsFd>(
+    dir: &Path,
+    writer: W,
+    limit: u64,
+) -> Result<(W, bool), Error> {
+    let mut visitor = PackFsToWriter::with_limit(writer, limit);
+    visit_dir(dir, &mut visitor)?;
+    let truncated = visitor.truncated;
+    Ok((visitor.into_file()?, truncated))
+}
+
 /// deemed unsafe because we unpack to cwd with no path traversal protection, caller should ensure
 /// we are in a chroot or otherwise protected
 /// even though we use openat2 with RESOLVE_BENEATH, there is no equivalent for mkdirat
 unsafe fn unpack_to_dir(data: &[u8], starting_dir: OwnedFd) -> Result<(), Error> {
+    unpack_to_dir_impl(data, starting_dir, openat_w, openpathat)
+}
+
+/// safe to use outside a chroot: every name must be a single component, files and dirs are
+/// opened with RESOLVE_BENEATH|RESOLVE_NO_SYMLINKS (or O_NOFOLLOW without openat2) and files are
+/// created with O_EXCL, so nothing can land outside dir. dir should be empty, anything already
+/// there with the same name as something in the archive is an error
+pub fn unpack_to_dir_beneath<Fd: AsFd>(data: &[u8], dir: Fd) -> Result<(), Error> {
+    let starting_dir = openpathat_beneath(&dir, c".")?;
+    unpack_to_dir_impl(data, starting_dir, openat_w_beneath, openpathat_beneath)
+}
+
+type OpenAtFn = fn(&OwnedFd, &CStr) -> Result<OwnedFd, Error>;
+
+fn unpack_to_dir_impl(
+    data: &[u8],
+    starting_dir: OwnedFd,
+    open_file: OpenAtFn,
+    open_dir: OpenAtFn,
+) -> Result<(), Error> {
     let mut stack: Vec<OwnedFd> = Vec::with_capacity(32); // always non-empty
     stack.push(starting_dir);
 
@@ -407,11 +619,12 @@ unsafe fn unpack_to_dir(data: &[u8], starting_dir: OwnedFd) -> Result<(), Error>
                 cur = &cur[1..];
                 let parent = stack.last().ok_or(Error::StackEmpty)?;
                 let name = read_cstr(&mut cur)?;
+                check_name(name)?;
                 let len = read_le_u32(&mut cur)? as usize;
                 if len > cur.len() {
                     return Err(Error::ArchiveTruncated);
                 }
-                let mut file: File = openat_w(parent, name)?.into();
+                let mut file: File = open_file(parent, name)?.into();
                 file.write_all(&cur[..len]).map_err(|_| Error::Write)?;
                 cur = &cur[len..];
             }
@@ -419,6 +632,7 @@ unsafe fn unpack_to_dir(data: &[u8], starting_dir: OwnedFd) -> Result<(), Error>
                 cur = &cur[1..];
                 let parent = stack.last().ok_or(Error::StackEmpty)?;
                 let name = read_cstr(&mut cur)?;
+                check_name(name)?;
                 mkdirat(parent, name)?;
                 match cur.first().map(|x| x.try_into()) {
                     Some(Ok(ArchiveFormat1Tag::Pop)) => {
@@ -426,7 +640,7 @@ unsafe fn unpack_to_dir(data: &[u8], starting_dir: OwnedFd) -> Result<(), Error>
                         cur = &cur[1..]; // advance past Pop
                     }
                     Some(Ok(_)) => {
-                        stack.push(openpathat(parent, name)?);
+                        stack.push(open_dir(parent, name)?);
                     }
                     _ => {
                         // handled in outer match next loop
@@ -451,6 +665,33 @@ unsafe fn unpack_to_dir(data: &[u8], starting_dir: OwnedFd) -> Result<(), Error>
 
 // duplicated but w/e
 pub fn unpack_visitor<V: UnpackVisitor>(data: &[u8], v: &mut V) -> Result<(), Error> {
+    unpack_visitor_impl(data, v, |_| true)
+}
+
+/// like unpack_visitor but only visits files under one of the prefixes (relative to the archive
+/// root, a leading / is ignored). Excluded file bodies are skipped over without being sliced
+pub fn unpack_with_filter<P: AsRef<Path>, V: UnpackVisitor>(
+    data: &[u8],
+    prefixes: &[P],
+    v: &mut V,
+) -> Result<(), Error> {
+    let prefixes: Vec<&Path> = prefixes
+        .iter()
+        .map(|x| {
+            let x = x.as_ref();
+            x.strip_prefix("/").unwrap_or(x)
+        })
+        .collect();
+    unpack_visitor_impl(data, v, |path| {
+        prefixes.iter().any(|prefix| path.starts_with(prefix))
+    })
+}
+
+fn unpack_visitor_impl<V: UnpackVisitor, F: Fn(&Path) -> bool>(
+    data: &[u8],
+    v: &mut V,
+    keep: F,
+) -> Result<(), Error> {
     let mut path = PathBuf::new();
     let mut depth = 0;
     let mut cur = data;
@@ -463,9 +704,8 @@ pub fn unpack_visitor<V: UnpackVisitor>(data: &[u8], v: &mut V) -> Result<(), Er
                 if len > cur.len() {
                     return Err(Error::ArchiveTruncated);
                 }
-                let data = &cur[..len];
                 path.push(OsStr::from_bytes(name.to_bytes()));
-                if !v.on_file(&path, data) {
+                if keep(&path) && !v.on_file(&path, &cur[..len]) {
                     return Ok(());
                 }
                 path.pop();
@@ -476,6 +716,7 @@ pub fn unpack_visitor<V: UnpackVisitor>(data: &[u8], v: &mut V) -> Result<(), Er
                 let name = read_cstr(&mut cur)?;
                 path.push(OsStr::from_bytes(name.to_bytes()));
                 depth += 1;
+                v.on_dir(&path);
             }
             Some(Ok(ArchiveFormat1Tag::Pop)) => {
                 cur = &cur[1..];
@@ -529,6 +770,198 @@ pub fn unpack_file_to_hashmap(file: &File) -> Result<HashMap<PathBuf, Vec<u8>>,
     unpack_to_hashmap(mmap.as_ref())
 }
 
+/// what unpacking an archive would create
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ArchiveStats {
+    pub files: usize,
+    pub dirs: usize,
+    /// sum of file sizes
+    pub total_bytes: u64,
+    /// deepest dir nesting, 0 when everything is at the top level
+    pub max_depth: usize,
+}
+
+impl UnpackVisitor for ArchiveStats {
+    fn on_file(&mut self, _path: &Path, data: &[u8]) -> bool {
+        self.files += 1;
+        self.total_bytes += data.len() as u64;
+        true
+    }
+
+    fn on_dir(&mut self, path: &Path) {
+        self.dirs += 1;
+        self.max_depth = self.max_depth.max(path.components().count());
+    }
+}
+
+/// walks the archive without writing anything, eg to check it fits before unpacking it
+pub fn archive_stats(data: &[u8]) -> Result<ArchiveStats, Error> {
+    let mut stats = ArchiveStats::default();
+    unpack_visitor(data, &mut stats)?;
+    Ok(stats)
+}
+
+struct FindTopLevel<'a> {
+    name: &'a Path,
+    found: bool,
+}
+
+impl UnpackVisitor for FindTopLevel<'_> {
+    fn on_file(&mut self, path: &Path, _data: &[u8]) -> bool {
+        self.found |= path == self.name;
+        true
+    }
+
+    fn on_dir(&mut self, path: &Path) {
+        self.found |= path == self.name;
+    }
+}
+
+/// a new archive of base with overlay's tree nested under a new top level dir name. Since every
+/// message carries its own size this is just base + dir name + overlay + pop, but both are walked
+/// first so we don't splice in something malformed. name can't already be at the top of base
+pub fn merge_under(base: &[u8], name: &str, overlay: &[u8]) -> Result<Vec<u8>, Error> {
+    let cname = CString::new(name).map_err(|_| Error::BadName)?;
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(Error::BadName);
+    }
+    check_name(&cname)?;
+
+    let mut find = FindTopLevel {
+        name: Path::new(name),
+        found: false,
+    };
+    unpack_visitor(base, &mut find)?;
+    if find.found {
+        return Err(Error::NameExists);
+    }
+    let overlay_stats = archive_stats(overlay)?;
+    if overlay_stats.max_depth + 1 > MAX_DIR_DEPTH {
+        return Err(Error::DirTooDeep);
+    }
+
+    let mut ret = Vec::with_capacity(base.len() + name.len() + 3 + overlay.len());
+    ret.extend_from_slice(base);
+    ret.push(ArchiveFormat1Tag::Dir as u8);
+    ret.extend_from_slice(cname.as_bytes_with_nul());
+    ret.extend_from_slice(overlay);
+    ret.push(ArchiveFormat1Tag::Pop as u8);
+    Ok(ret)
+}
+
+/// a file's data within a shared mmap of the archive, the mmap lives as long as any slice does
+#[derive(Clone)]
+pub struct MmapSlice {
+    mmap: Arc<Mmap>,
+    offset: usize,
+    len: usize,
+}
+
+impl Deref for MmapSlice {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.mmap[self.offset..self.offset + self.len]
+    }
+}
+
+impl AsRef<[u8]> for MmapSlice {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::fmt::Debug for MmapSlice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MmapSlice")
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+struct UnpackToSharedHashmap {
+    mmap: Arc<Mmap>,
+    map: HashMap<PathBuf, MmapSlice>,
+}
+
+impl UnpackVisitor for UnpackToSharedHashmap {
+    fn on_file(&mut self, path: &Path, data: &[u8]) -> bool {
+        // data is always a subslice of the mmap since that is what we pass to unpack_visitor
+        let offset = data.as_ptr() as usize - self.mmap.as_ptr() as usize;
+        let slice = MmapSlice {
+            mmap: self.mmap.clone(),
+            offset: offset,
+            len: data.len(),
+        };
+        self.map.insert(path.into(), slice);
+        true
+    }
+}
+
+/// like unpack_file_to_hashmap but without copying any file data out of the mmap
+pub fn unpack_file_to_shared_hashmap(file: &File) -> Result<HashMap<PathBuf, MmapSlice>, Error> {
+    let mmap = unsafe { MmapOptions::new().map(file).map_err(|_| Error::Mmap)? };
+    let mut visitor = UnpackToSharedHashmap {
+        mmap: Arc::new(mmap),
+        map: HashMap::new(),
+    };
+    let mmap = visitor.mmap.clone();
+    unpack_visitor(&mmap, &mut visitor)?;
+    Ok(visitor.map)
+}
+
+/// path -> file data over an archive, built with one walk so that looking up a file doesn't walk
+/// the archive again. Paths are relative to the archive root (a leading / is ignored) and the data
+/// is a subslice of the archive, nothing is copied
+pub struct ArchiveIndex<'a> {
+    data: &'a [u8],
+    // (offset, len) into data
+    files: HashMap<PathBuf, (usize, usize)>,
+}
+
+impl<'a> ArchiveIndex<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let mut index = Self {
+            data: data,
+            files: HashMap::new(),
+        };
+        unpack_visitor(data, &mut index)?;
+        Ok(index)
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&'a [u8]> {
+        let path = path.as_ref();
+        let (offset, len) = self.files.get(path.strip_prefix("/").unwrap_or(path))?;
+        Some(&self.data[*offset..*offset + *len])
+    }
+
+    /// only files, a dir isn't in the index
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.get(path).is_some()
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(|x| x.as_path())
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+impl UnpackVisitor for ArchiveIndex<'_> {
+    fn on_file(&mut self, path: &Path, data: &[u8]) -> bool {
+        // data is always a subslice of self.data since that is what we pass to unpack_visitor
+        let offset = data.as_ptr() as usize - self.data.as_ptr() as usize;
+        self.files.insert(path.into(), (offset, data.len()));
+        true
+    }
+}
+
 pub fn unpack_file_to_dir_with_unshare_chroot(file: File, dir: &Path) -> Result<(), Error> {
     let mmap = unsafe { MmapOptions::new().map(&file).map_err(|_| Error::Mmap)? };
     unpack_data_to_dir_with_unshare_chroot(mmap.as_ref(), dir)
@@ -543,6 +976,13 @@ pub fn unpack_data_to_dir_with_unshare_chroot(data: &[u8], dir: &Path) -> Result
     unsafe { unpack_to_dir(data, starting_dir) }
 }
 
+/// unpack_to_dir_beneath by path, for callers that can't (or don't want to) unshare+chroot the
+/// whole process
+pub fn unpack_data_to_dir(data: &[u8], dir: &Path) -> Result<(), Error> {
+    let dir = CString::new(dir.as_os_str().as_bytes()).map_err(|_| Error::BadCStr)?;
+    unpack_to_dir_beneath(data, opendir(&dir)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -550,7 +990,7 @@ mod tests {
     use std::fs;
     use std::path::PathBuf;
     //use std::thread;
-    use std::io::{Seek, SeekFrom};
+    use std::io::{Read, Seek, SeekFrom};
     use std::process::Command;
 
     use rand;
@@ -686,6 +1126,167 @@ mod tests {
         assert_eq!(fs::read(td2.join("adir/another-file")).unwrap(), b"some data");
     }
 
+    #[test]
+    fn unpack_to_dir_no_chroot() {
+        let tree = Tree::from([
+            ("file1".to_string(), Node::File(b"hello world".to_vec())),
+            (
+                "adir".to_string(),
+                Node::Dir(Tree::from([
+                    (
+                        "another-file".to_string(),
+                        Node::File(b"some data".to_vec()),
+                    ),
+                    ("empty".to_string(), Node::Dir(Tree::new())),
+                ])),
+            ),
+        ]);
+        let td = TempDir::new();
+        unpack_data_to_dir(&pack_tree(&tree).unwrap(), td.as_ref()).unwrap();
+        assert_eq!(fs::read(td.join("file1")).unwrap(), b"hello world");
+        assert_eq!(
+            fs::read(td.join("adir/another-file")).unwrap(),
+            b"some data"
+        );
+        assert!(td.join("adir/empty").is_dir());
+
+        // without the chroot the names are all that keep us in the dir
+        let td = TempDir::new();
+        let inner = td.join("inner");
+        fs::create_dir(&inner).unwrap();
+        for name in ["..", "../escape", "a/b", "."] {
+            let mut v = PackMemToVec::new();
+            v.dir(name).unwrap();
+            v.pop().unwrap();
+            assert_eq!(
+                Error::BadName,
+                unpack_data_to_dir(&v.into_vec().unwrap(), &inner).unwrap_err()
+            );
+
+            let mut v = PackMemToVec::new();
+            v.file(name, b"x").unwrap();
+            assert_eq!(
+                Error::BadName,
+                unpack_data_to_dir(&v.into_vec().unwrap(), &inner).unwrap_err()
+            );
+        }
+        assert!(!td.join("escape").exists());
+        assert_eq!(fs::read_dir(&inner).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn unpack_to_dir_beneath_symlinks() {
+        let td = TempDir::new().dir("inner").dir("outside");
+        let inner = td.join("inner");
+        std::os::unix::fs::symlink("../outside", inner.join("link")).unwrap();
+        std::os::unix::fs::symlink("../outside/file", inner.join("file-link")).unwrap();
+        let inner_fd = File::open(&inner).unwrap();
+
+        // file through a dir symlink
+        let mut v = PackMemToVec::new();
+        v.dir("link").unwrap();
+        v.file("x", b"escaped").unwrap();
+        v.pop().unwrap();
+        assert!(unpack_to_dir_beneath(&v.into_vec().unwrap(), &inner_fd).is_err());
+
+        // file onto a symlink
+        let mut v = PackMemToVec::new();
+        v.file("file-link", b"escaped").unwrap();
+        assert!(unpack_to_dir_beneath(&v.into_vec().unwrap(), &inner_fd).is_err());
+
+        assert_eq!(fs::read_dir(td.join("outside")).unwrap().count(), 0);
+
+        // and an archive that stays put is fine next to them
+        let mut v = PackMemToVec::new();
+        v.dir("adir").unwrap();
+        v.file("x", b"data").unwrap();
+        v.pop().unwrap();
+        unpack_to_dir_beneath(&v.into_vec().unwrap(), &inner_fd).unwrap();
+        assert_eq!(fs::read(inner.join("adir/x")).unwrap(), b"data");
+
+        // won't write through an existing file
+        let mut v = PackMemToVec::new();
+        v.file("adir", b"data").unwrap();
+        assert!(unpack_to_dir_beneath(&v.into_vec().unwrap(), &inner_fd).is_err());
+    }
+
+    #[test]
+    fn pack_limit_truncates() {
+        let td1 = TempDir::new()
+            .file("a", &[b'a'; 100])
+            .dir("adir")
+            .file("adir/b", &[b'b'; 1000])
+            .file("adir/c", &[b'c'; 10])
+            .file("z", &[b'z'; 10]);
+
+        let full = pack_dir_to_file(td1.as_ref(), tempfile()).unwrap();
+        let full_len = full.metadata().unwrap().len();
+
+        // plenty of room, same as no limit
+        let (f, truncated) = pack_dir_to_writer_limit(td1.as_ref(), tempfile(), full_len).unwrap();
+        assert!(!truncated);
+        assert_eq!(f.metadata().unwrap().len(), full_len);
+
+        // no room for adir/b
+        let limit = 200;
+        let (mut f, truncated) = pack_dir_to_writer_limit(td1.as_ref(), tempfile(), limit).unwrap();
+        assert!(truncated);
+        assert!(f.metadata().unwrap().len() <= limit);
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let hm = unpack_file_to_hashmap(&f).unwrap();
+        assert!(!hm.contains_key(Path::new("adir/b")));
+        assert_eq!(hm.get(Path::new("a")).unwrap(), &[b'a'; 100]);
+        assert_eq!(hm.get(Path::new("adir/c")).unwrap(), &[b'c'; 10]);
+        assert_eq!(hm.get(Path::new("z")).unwrap(), &[b'z'; 10]);
+
+        // only room for adir itself, its pop still has to make it in
+        let limit = 20;
+        let (mut f, truncated) = pack_dir_to_writer_limit(td1.as_ref(), tempfile(), limit).unwrap();
+        assert!(truncated);
+        assert!(f.metadata().unwrap().len() <= limit);
+        f.seek(SeekFrom::Start(0)).unwrap();
+        assert!(unpack_file_to_hashmap(&f).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pack_reports_skipped() {
+        let td1 = TempDir::new()
+            .file("a", b"aaa")
+            .dir("adir")
+            .file("adir/b", b"bbb");
+        rustix::fs::mknodat(
+            rustix::fs::CWD,
+            td1.join("adir/fifo"),
+            FileType::Fifo,
+            rustix::fs::Mode::from_raw_mode(0o644),
+            0,
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("a", td1.join("link")).unwrap();
+
+        let (mut f, mut skipped) = pack_dir_to_writer_skipped(td1.as_ref(), tempfile()).unwrap();
+        skipped.sort_by(|x, y| x.path.cmp(&y.path));
+        assert_eq!(
+            skipped,
+            [
+                SkippedEntry {
+                    path: "adir/fifo".into(),
+                    file_type: FileType::Fifo
+                },
+                SkippedEntry {
+                    path: "link".into(),
+                    file_type: FileType::Symlink
+                },
+            ]
+        );
+
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let hm = unpack_file_to_hashmap(&f).unwrap();
+        assert_eq!(hm.len(), 2);
+        assert_eq!(hm.get(Path::new("a")).unwrap(), b"aaa");
+        assert_eq!(hm.get(Path::new("adir/b")).unwrap(), b"bbb");
+    }
+
     #[test]
     fn pack_name_max_length_ok() {
         let name255 = String::from_utf8(vec![97u8; 255]).unwrap();
@@ -700,6 +1301,28 @@ mod tests {
         let _ = TempDir::new().file(&name256, b"hello world");
     }
 
+    #[test]
+    fn pack_file_too_big() {
+        let td1 = TempDir::new().file("small", b"hello");
+        // sparse so this doesn't actually take 4G
+        File::create(td1.join("big"))
+            .unwrap()
+            .set_len(u32::MAX as u64 + 1)
+            .unwrap();
+        assert_eq!(
+            pack_dir_to_file(td1.as_ref(), tempfile()).unwrap_err(),
+            Error::FileTooBig("big".into())
+        );
+
+        // a write error says which file too, here from a read only output
+        let td2 = TempDir::new().file("a", b"hello");
+        let out = File::open(td2.join("a")).unwrap();
+        assert_eq!(
+            pack_dir_to_file(td2.as_ref(), out).unwrap_err(),
+            Error::PackFile("a".into(), Box::new(Error::Flush))
+        );
+    }
+
     #[test]
     fn basic_pack_to_mem() {
         let mut v = PackMemToFile::new(tempfile());
@@ -745,4 +1368,318 @@ mod tests {
             buf
         );
     }
+
+    #[test]
+    fn unpack_shared_aliases_mmap() {
+        let mut v = PackMemToFile::new(tempfile());
+        v.file("file1", b"data1").unwrap();
+        v.dir("adir").unwrap();
+        v.file("file2", b"data22").unwrap();
+        v.pop().unwrap();
+        let mut f = v.into_inner().unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+
+        let hm = unpack_file_to_shared_hashmap(&f).unwrap();
+        assert_eq!(hm.len(), 2);
+        let file1 = hm.get(Path::new("file1")).unwrap();
+        let file2 = hm.get(Path::new("adir/file2")).unwrap();
+        assert_eq!(file1.as_ref(), b"data1");
+        assert_eq!(file2.as_ref(), b"data22");
+
+        assert!(Arc::ptr_eq(&file1.mmap, &file2.mmap));
+        let range = file1.mmap.as_ptr_range();
+        for slice in [file1, file2] {
+            let slice_range = slice.as_ptr_range();
+            assert!(range.start <= slice_range.start && slice_range.end <= range.end);
+            assert_eq!(slice.as_ptr(), file1.mmap[slice.offset..].as_ptr());
+        }
+    }
+
+    #[test]
+    fn pack_tree_nested() {
+        let tree = Tree::from([
+            ("b".to_string(), Node::File(b"data-b".to_vec())),
+            (
+                "adir".to_string(),
+                Node::Dir(Tree::from([
+                    ("c".to_string(), Node::File(b"data-c".to_vec())),
+                    (
+                        "bdir".to_string(),
+                        Node::Dir(Tree::from([(
+                            "d".to_string(),
+                            Node::File(b"data-d".to_vec()),
+                        )])),
+                    ),
+                    ("empty".to_string(), Node::Dir(Tree::new())),
+                ])),
+            ),
+            ("a".to_string(), Node::File(vec![])),
+        ]);
+        let buf = pack_tree(&tree).unwrap();
+        let hm = unpack_to_hashmap(&buf).unwrap();
+        assert_eq!(hm.len(), 4);
+        assert_eq!(hm.get(Path::new("a")).unwrap(), b"");
+        assert_eq!(hm.get(Path::new("b")).unwrap(), b"data-b");
+        assert_eq!(hm.get(Path::new("adir/c")).unwrap(), b"data-c");
+        assert_eq!(hm.get(Path::new("adir/bdir/d")).unwrap(), b"data-d");
+
+        // same as driving the visitor by hand in sorted order
+        let mut v = PackMemToVec::new();
+        v.file("a", b"").unwrap();
+        v.dir("adir").unwrap();
+        v.dir("bdir").unwrap();
+        v.file("d", b"data-d").unwrap();
+        v.pop().unwrap();
+        v.file("c", b"data-c").unwrap();
+        v.dir("empty").unwrap();
+        v.pop().unwrap();
+        v.pop().unwrap();
+        v.file("b", b"data-b").unwrap();
+        assert_eq!(v.into_vec().unwrap(), buf);
+    }
+
+    #[test]
+    fn archive_stats_nested() {
+        let tree = Tree::from([
+            ("b".to_string(), Node::File(b"data-b".to_vec())),
+            (
+                "adir".to_string(),
+                Node::Dir(Tree::from([
+                    ("c".to_string(), Node::File(b"data-c".to_vec())),
+                    (
+                        "bdir".to_string(),
+                        Node::Dir(Tree::from([(
+                            "d".to_string(),
+                            Node::File(b"data-dd".to_vec()),
+                        )])),
+                    ),
+                    ("empty".to_string(), Node::Dir(Tree::new())),
+                ])),
+            ),
+            ("a".to_string(), Node::File(vec![])),
+        ]);
+        let buf = pack_tree(&tree).unwrap();
+        assert_eq!(
+            archive_stats(&buf).unwrap(),
+            ArchiveStats {
+                files: 4,
+                dirs: 3,
+                total_bytes: 19,
+                max_depth: 2,
+            }
+        );
+
+        assert_eq!(archive_stats(&[]).unwrap(), ArchiveStats::default());
+        assert_eq!(
+            archive_stats(&buf[..buf.len() - 1]),
+            Err(Error::ArchiveTruncated)
+        );
+    }
+
+    #[test]
+    fn merge_under_nested() {
+        let base = pack_tree(&Tree::from([
+            ("a".to_string(), Node::File(b"data-a".to_vec())),
+            (
+                "adir".to_string(),
+                Node::Dir(Tree::from([(
+                    "b".to_string(),
+                    Node::File(b"data-b".to_vec()),
+                )])),
+            ),
+        ]))
+        .unwrap();
+        let overlay = pack_tree(&Tree::from([
+            ("a".to_string(), Node::File(b"overlay-a".to_vec())),
+            (
+                "bdir".to_string(),
+                Node::Dir(Tree::from([
+                    ("c".to_string(), Node::File(b"data-c".to_vec())),
+                    ("empty".to_string(), Node::Dir(Tree::new())),
+                ])),
+            ),
+        ]))
+        .unwrap();
+
+        let merged = merge_under(&base, "run", &overlay).unwrap();
+        assert_eq!(
+            archive_stats(&merged).unwrap(),
+            ArchiveStats {
+                files: 4,
+                dirs: 4,
+                total_bytes: 27,
+                max_depth: 3,
+            }
+        );
+
+        let td = TempDir::new().dir("out");
+        let out = File::open(td.join("out")).unwrap();
+        unpack_to_dir_beneath(&merged, &out).unwrap();
+        let read = |p: &str| fs::read(td.join("out").join(p)).unwrap();
+        assert_eq!(read("a"), b"data-a");
+        assert_eq!(read("adir/b"), b"data-b");
+        assert_eq!(read("run/a"), b"overlay-a");
+        assert_eq!(read("run/bdir/c"), b"data-c");
+        assert!(td.join("out/run/bdir/empty").is_dir());
+
+        // merging into an empty base or with an empty overlay
+        let merged = merge_under(&[], "run", &overlay).unwrap();
+        assert_eq!(unpack_to_hashmap(&merged).unwrap().len(), 2);
+        let merged = merge_under(&base, "run", &[]).unwrap();
+        assert_eq!(archive_stats(&merged).unwrap().dirs, 2);
+
+        assert_eq!(merge_under(&base, "adir", &overlay), Err(Error::NameExists));
+        assert_eq!(merge_under(&base, "a", &overlay), Err(Error::NameExists));
+        for name in ["", ".", "..", "a/b", "a\0b"] {
+            assert_eq!(
+                merge_under(&base, name, &overlay),
+                Err(Error::BadName),
+                "{name:?}"
+            );
+        }
+        assert_eq!(
+            merge_under(&base, "run", &overlay[..overlay.len() - 1]),
+            Err(Error::ArchiveTruncated)
+        );
+    }
+
+    #[test]
+    fn sendfile_all_nonblocking_pipe() {
+        // much bigger than the pipe buffer so this takes many sendfile calls and hits EAGAIN
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut f = tempfile();
+        f.write_all(&data).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+
+        let (rx, tx) = rustix::pipe::pipe().unwrap();
+        rustix::fs::fcntl_setfl(&tx, rustix::fs::OFlags::NONBLOCK).unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut out = vec![];
+            File::from(rx).read_to_end(&mut out).unwrap();
+            out
+        });
+        sendfile_all(&f, &tx, data.len() as u64).unwrap();
+        drop(tx);
+        assert!(reader.join().unwrap() == data);
+    }
+
+    #[test]
+    fn sendfile_all_short_file() {
+        let mut f = tempfile();
+        f.write_all(b"hello").unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let out = tempfile();
+        assert_eq!(sendfile_all(&f, &out, 10), Err(Error::SendFileEof));
+    }
+
+    #[test]
+    fn unpack_with_filter_subtree() {
+        let tree = Tree::from([
+            (
+                "usr".to_string(),
+                Node::Dir(Tree::from([
+                    (
+                        "bin".to_string(),
+                        Node::Dir(Tree::from([
+                            ("ls".to_string(), Node::File(b"ls".to_vec())),
+                            ("cat".to_string(), Node::File(b"cat".to_vec())),
+                        ])),
+                    ),
+                    (
+                        "lib".to_string(),
+                        Node::Dir(Tree::from([(
+                            "libc.so".to_string(),
+                            Node::File(b"libc".to_vec()),
+                        )])),
+                    ),
+                    // shares a string prefix with bin but isn't under it
+                    ("binx".to_string(), Node::File(b"binx".to_vec())),
+                ])),
+            ),
+            // comes after usr/bin so depth has to be right after skipping usr/lib
+            ("zzz".to_string(), Node::File(b"zzz".to_vec())),
+        ]);
+        let buf = pack_tree(&tree).unwrap();
+
+        let mut visitor = UnpackToHashmap::new();
+        unpack_with_filter(&buf, &["/usr/bin"], &mut visitor).unwrap();
+        let hm = visitor.into_hashmap();
+        assert_eq!(hm.len(), 2);
+        assert_eq!(hm.get(Path::new("usr/bin/ls")).unwrap(), b"ls");
+        assert_eq!(hm.get(Path::new("usr/bin/cat")).unwrap(), b"cat");
+
+        let mut visitor = UnpackToHashmap::new();
+        unpack_with_filter(&buf, &["usr/lib", "zzz"], &mut visitor).unwrap();
+        let hm = visitor.into_hashmap();
+        assert_eq!(hm.len(), 2);
+        assert_eq!(hm.get(Path::new("usr/lib/libc.so")).unwrap(), b"libc");
+        assert_eq!(hm.get(Path::new("zzz")).unwrap(), b"zzz");
+
+        // skipped bodies are still bounds checked
+        let mut visitor = UnpackToHashmap::new();
+        assert_eq!(
+            Err(Error::ArchiveTruncated),
+            unpack_with_filter(&buf[..buf.len() - 1], &["nothing"], &mut visitor)
+        );
+        assert!(visitor.into_hashmap().is_empty());
+    }
+
+    #[test]
+    fn archive_index_get() {
+        let tree = Tree::from([
+            ("b".to_string(), Node::File(b"data-b".to_vec())),
+            ("empty".to_string(), Node::File(vec![])),
+            (
+                "adir".to_string(),
+                Node::Dir(Tree::from([
+                    ("c".to_string(), Node::File(b"data-c".to_vec())),
+                    (
+                        "bdir".to_string(),
+                        Node::Dir(Tree::from([(
+                            "d".to_string(),
+                            Node::File(b"data-d".to_vec()),
+                        )])),
+                    ),
+                ])),
+            ),
+        ]);
+        let buf = pack_tree(&tree).unwrap();
+        let index = ArchiveIndex::new(&buf).unwrap();
+        assert_eq!(index.len(), 4);
+
+        let range = buf.as_ptr_range();
+        for (path, expected) in [
+            ("b", &b"data-b"[..]),
+            ("/b", b"data-b"),
+            ("empty", b""),
+            ("adir/c", b"data-c"),
+            ("adir/bdir/d", b"data-d"),
+        ] {
+            let data = index.get(path).unwrap_or_else(|| panic!("{path}"));
+            assert_eq!(data, expected, "{path}");
+            // zero copy
+            let data_range = data.as_ptr_range();
+            assert!(range.start <= data_range.start && data_range.end <= range.end);
+        }
+
+        assert!(index.contains("adir/bdir/d"));
+        assert!(!index.contains("adir"));
+        assert!(!index.contains("adir/bdir"));
+        assert!(!index.contains("c"));
+        assert!(index.get("nope").is_none());
+
+        let mut paths: Vec<_> = index.paths().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                Path::new("adir/bdir/d"),
+                Path::new("adir/c"),
+                Path::new("b"),
+                Path::new("empty")
+            ]
+        );
+
+        assert!(ArchiveIndex::new(&buf[..buf.len() - 1]).is_err());
+    }
 }
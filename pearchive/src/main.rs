@@ -5,13 +5,16 @@ use std::os::fd::FromRawFd;
 use std::path::Path;
 
 use pearchive::{
-    pack_dir_to_file, unpack_data_to_dir_with_unshare_chroot,
+    pack_dir_to_file, pack_dir_to_writer_limit, unpack_data_to_dir_with_unshare_chroot,
     unpack_file_to_dir_with_unshare_chroot,
 };
 
 use byteorder::{WriteBytesExt, LE};
 use memmap2::MmapOptions;
 
+// packfd exits with this when the archive was cut short by <max len>
+const PACKFD_EXIT_TRUNCATED: i32 = 2;
+
 #[derive(Debug)]
 enum Error {
     MissingArg,
@@ -85,7 +88,8 @@ fn unpackfd(args: &[String]) {
     unpack_data_to_dir_with_unshare_chroot(mmap.as_ref(), outpath).unwrap();
 }
 
-/// args: <input dir> <output fd>
+/// args: <input dir> <output fd> [<max len>]
+/// with max len, files that don't fit are left out and we exit with PACKFD_EXIT_TRUNCATED
 #[allow(clippy::get_first)]
 fn packfd(args: &[String]) {
     let indir = args.get(0).ok_or(Error::MissingArg).unwrap();
@@ -95,6 +99,7 @@ fn packfd(args: &[String]) {
         .unwrap()
         .parse::<i32>()
         .unwrap();
+    let max_len = args.get(2).map(|x| x.parse::<u64>().unwrap());
     let indirpath = Path::new(indir);
     assert!(indirpath.is_dir(), "{:?} should be a dir", indirpath);
 
@@ -103,10 +108,13 @@ fn packfd(args: &[String]) {
 
     // its a bit quirky that we move fileout in and get it back out, which should be the same as an
     // &mut, but then the type of BufWriter<&mut File> gets weird and I don't know what to do
-    let mut fileout = pack_dir_to_file(indirpath, fileout).unwrap();
+    let (mut fileout, truncated) = match max_len {
+        Some(max_len) => pack_dir_to_writer_limit(indirpath, fileout, max_len).unwrap(),
+        None => (pack_dir_to_file(indirpath, fileout).unwrap(), false),
+    };
 
     let ending_offset = fileout.stream_position().unwrap();
-    assert!(ending_offset > offset);
+    assert!(ending_offset > offset || truncated);
     let archive_size = ending_offset - offset;
     let encoded_size: u32 = archive_size.try_into().unwrap();
     fileout.seek(SeekFrom::Start(0)).unwrap();
@@ -114,6 +122,10 @@ fn packfd(args: &[String]) {
     // this is to be extra sure the write through the pmem device has finished
     // only hit a bad case in the panic handler's write not getting sync'd
     fileout.sync_data().unwrap();
+
+    if truncated {
+        std::process::exit(PACKFD_EXIT_TRUNCATED);
+    }
 }
 
 fn main() {
@@ -134,7 +146,7 @@ fn main() {
         _ => {
             println!("pack <input-dir> <output-file>");
             println!("unpack <input-file> <output-dir>");
-            println!("packdev <input-file> <output-dir>");
+            println!("packfd <input-dir> <output-fd> [<max-len>]");
             println!("unpackfd <input-fd> <output-dir> <len>");
             std::process::exit(1);
         }
@@ -4,6 +4,7 @@ use std::ffi::CStr;
 use rustix::{
     fd::{AsFd, OwnedFd},
     fs::{Mode, OFlags, ResolveFlags},
+    io::Errno,
 };
 
 // idk if openat2 is useful here since we work in a chroot anyways
@@ -68,3 +69,40 @@ pub(crate) fn openpathat<Fd: AsFd>(fd: &Fd, name: &CStr) -> Result<OwnedFd, Erro
 pub(crate) fn mkdirat<Fd: AsFd>(fd: &Fd, name: &CStr) -> Result<(), Error> {
     rustix::fs::mkdirat(fd, name, Mode::from_bits_truncate(MKDIR_MODE)).map_err(Error::MkdirAt)
 }
+
+// the _beneath variants are for unpacking outside of a chroot. openat2 refuses to resolve outside
+// of fd or through any symlink, and on kernels without openat2 we fall back to plain openat with
+// O_NOFOLLOW which is only safe because unpack checks every name is a single component
+fn openat2_or_nofollow<Fd: AsFd>(
+    fd: &Fd,
+    name: &CStr,
+    flags: OFlags,
+    mode: Mode,
+) -> Result<OwnedFd, Error> {
+    let flags = flags | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+    match rustix::fs::openat2(
+        fd,
+        name,
+        flags,
+        mode,
+        ResolveFlags::BENEATH | ResolveFlags::NO_SYMLINKS,
+    ) {
+        Err(Errno::NOSYS) => rustix::fs::openat(fd, name, flags, mode),
+        x => x,
+    }
+    .map_err(Error::OpenAt)
+}
+
+// O_EXCL so we never write through an existing file, which could be a hardlink to anywhere
+pub(crate) fn openat_w_beneath<Fd: AsFd>(fd: &Fd, name: &CStr) -> Result<OwnedFd, Error> {
+    openat2_or_nofollow(
+        fd,
+        name,
+        OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL,
+        Mode::from_bits_truncate(FILE_MODE),
+    )
+}
+
+pub(crate) fn openpathat_beneath<Fd: AsFd>(fd: &Fd, name: &CStr) -> Result<OwnedFd, Error> {
+    openat2_or_nofollow(fd, name, OFlags::PATH | OFlags::DIRECTORY, Mode::empty())
+}
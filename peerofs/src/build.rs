@@ -1,4 +1,5 @@
-use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::unix::ffi::OsStrExt;
@@ -15,6 +16,9 @@ use crate::disk::{
 
 const MAX_DEPTH: usize = 32; // TODO could be configurable
 
+// EROFS_NAME_LEN, same as NAME_MAX
+const MAX_NAME_LEN: usize = 255;
+
 // NOTES:
 // Our strategy for building an erofs image is different than mkfs.erofs. From what I understand
 // (when building from a tar stream), their approach first writes all file contents to something
@@ -84,8 +88,14 @@ const MAX_DEPTH: usize = 32; // TODO could be configurable
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    FileExists,
-    BadFilename,
+    // path already exists as a file (or symlink)
+    FileExists(PathBuf),
+    // path already exists as a dir and we're trying to put a file there
+    DirExists(PathBuf),
+    // path (or one of its parents) exists as a file but we need it to be a dir
+    ExpectedDir(PathBuf),
+    // name component is too long, not utf8, or contains a nul or slash
+    BadFilename(OsString),
     EmptyPath,
     EmptyFilename,
     NotADir,
@@ -107,6 +117,11 @@ pub enum Error {
     HardlinkToDir,
     HardlinkNotResolved,
     HardlinkMultiNotHandled,
+    DedupNotFound,
+    DedupNotAFile,
+    DedupLenMismatch,
+    DedupNotLastFile,
+    NotSpecialFile,
     UnhandledPrefixComponent,
     PathWithDotDot,
     WeirdPath,
@@ -135,21 +150,86 @@ impl std::fmt::Display for Error {
 pub struct Stats {
     files: usize,
     symlinks: usize,
+    specials: usize,
     dirs: usize,
     tails: usize,
     tail_size: usize,
     block_end_padding: usize,
+    dedup_files: usize,
+    dedup_blocks: usize,
+    image_len: u64,
+}
+
+impl Stats {
+    // where the image ends, the writer can be longer than this (after dedup_file) but everything
+    // past here is zeros
+    pub fn image_len(&self) -> u64 {
+        self.image_len
+    }
+}
+
+// running totals of file data added so far, passed to BuilderConfig::progress
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+pub type ProgressFn = Box<dyn FnMut(Progress) + Send>;
+
+// progress is reported after whichever of these comes first since the last report
+const PROGRESS_INTERVAL_BYTES: u64 = 1 << 20;
+const PROGRESS_INTERVAL_FILES: usize = 1024;
+
+// applied to the uid and gid of everything added to the builder
+#[derive(Debug, Default, Clone)]
+pub enum IdMapping {
+    #[default]
+    None,
+    // added to the ids, erroring with UidGidTooBig on overflow
+    Offset {
+        uid: u32,
+        gid: u32,
+    },
+    // uids and gids both go through the same map, ids not in it are kept as is
+    Explicit(HashMap<u32, u32>),
+}
+
+impl IdMapping {
+    fn map(&self, uid: u32, gid: u32) -> Result<(u32, u32), Error> {
+        match self {
+            IdMapping::None => Ok((uid, gid)),
+            IdMapping::Offset {
+                uid: uid_offset,
+                gid: gid_offset,
+            } => Ok((
+                uid.checked_add(*uid_offset).ok_or(Error::UidGidTooBig)?,
+                gid.checked_add(*gid_offset).ok_or(Error::UidGidTooBig)?,
+            )),
+            IdMapping::Explicit(map) => Ok((
+                map.get(&uid).copied().unwrap_or(uid),
+                map.get(&gid).copied().unwrap_or(gid),
+            )),
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct BuilderConfig {
     pub max_file_size: Option<u64>,
-    pub increment_uid_gid: Option<u32>,
+    pub id_mapping: IdMapping,
+    // called periodically from add_file and once more from into_inner with the final totals
+    pub progress: Option<ProgressFn>,
+    // written as is to the superblock, nothing is taken from the clock or rng so the same tree
+    // builds the same image. The default is all zeros
+    pub uuid: [u8; 16],
+    pub build_time: u64,
+    pub build_time_nsec: u32,
 }
 
 pub struct Builder<W: Write + Seek> {
     root: Option<Root>,
-    increment_uid_gid: Option<u32>,
+    id_mapping: IdMapping,
     writer: BufWriter<W>,
     superblock: Superblock,
     block_size_bits: u8,
@@ -164,6 +244,14 @@ pub struct Builder<W: Write + Seek> {
     max_depth: usize,
     max_file_size: u64,
     cur_file_size: u64,
+    // [start, end) blocks of the last file added, if nothing has been written since
+    last_file_blocks: Option<(u64, u64)>,
+    // furthest we've written before giving blocks back in dedup_file, so we know how much to zero
+    // if the image ends up shorter
+    written_end: u64,
+    progress: Option<ProgressFn>,
+    progress_cur: Progress,
+    progress_last: Progress,
 }
 
 pub type XattrMap = BTreeMap<Box<[u8]>, Box<[u8]>>;
@@ -215,6 +303,16 @@ struct Symlink {
     disk_id: Option<u32>,
 }
 
+// fifo, socket, char or block device. These have no data, rdev is only meaningful for devices
+#[derive(Debug)]
+struct Special {
+    meta: Meta,
+    file_type: FileType,
+    rdev: (u32, u32),
+    n_links: u32,
+    disk_id: Option<u32>,
+}
+
 #[derive(Debug)]
 struct Dir {
     children: BTreeMap<OsString, Dirent>,
@@ -250,6 +348,7 @@ enum Dirent {
     File(File),
     Dir(Dir),
     Symlink(Symlink),
+    Special(Special),
     Dot,
     DotDot,
 }
@@ -274,6 +373,13 @@ impl Dirent {
         match self {
             Dirent::File(_) => DirentFileType::RegularFile,
             Dirent::Symlink(_) => DirentFileType::Symlink,
+            Dirent::Special(s) => match s.file_type {
+                FileType::CharacterDevice => DirentFileType::CharacterDevice,
+                FileType::BlockDevice => DirentFileType::BlockDevice,
+                FileType::Fifo => DirentFileType::Fifo,
+                FileType::Socket => DirentFileType::Socket,
+                _ => DirentFileType::Unknown,
+            },
             Dirent::Dot | Dirent::DotDot | Dirent::Dir(_) => DirentFileType::Directory,
         }
     }
@@ -286,6 +392,9 @@ trait TreeVisitor {
     fn on_symlink(&mut self, _symlink: &mut Symlink) -> Result<(), Error> {
         Ok(())
     }
+    fn on_special(&mut self, _special: &mut Special) -> Result<(), Error> {
+        Ok(())
+    }
     fn on_dir_exit(&mut self, _dir: &mut Dir) -> Result<(), Error> {
         Ok(())
     }
@@ -322,6 +431,9 @@ fn walk_tree<V: TreeVisitor>(
                 Dirent::Symlink(s) => {
                     visitor.on_symlink(s)?;
                 }
+                Dirent::Special(s) => {
+                    visitor.on_special(s)?;
+                }
                 Dirent::Dir(d) => {
                     recur(d, visitor, depth + 1, max_depth)?;
                 }
@@ -454,6 +566,27 @@ impl<W: Write + Seek> TreeVisitor for BuilderTreeVisitorWriteInodes<'_, W> {
         assert!(prev.is_none());
         Ok(())
     }
+
+    fn on_special(&mut self, special: &mut Special) -> Result<(), Error> {
+        self.builder.stats.specials += 1;
+        let mut inode = make_inode(
+            special.file_type,
+            0,
+            EROFS_NULL_ADDR,
+            &special.meta,
+            &None,
+            special.n_links,
+        )?;
+        // the info union is rdev instead of a block addr
+        inode.info = InodeInfo::new_rdev(special.rdev.0, special.rdev.1);
+
+        let disk_id =
+            self.builder
+                .write_inode(Inode::Extended(inode), &None, &special.meta.xattrs)?;
+        let prev = special.disk_id.replace(disk_id);
+        assert!(prev.is_none());
+        Ok(())
+    }
 }
 
 impl<W: Write + Seek> TreeVisitor for BuilderTreeVisitorWriteDirents<'_, W> {
@@ -475,6 +608,7 @@ impl<W: Write + Seek> TreeVisitor for BuilderTreeVisitorWriteDirents<'_, W> {
                     Dirent::File(f) => f.disk_id,
                     Dirent::Dir(d) => d.disk_id,
                     Dirent::Symlink(d) => d.disk_id,
+                    Dirent::Special(d) => d.disk_id,
                     Dirent::Dot => dir.disk_id,
                     // if there is no parent, then this is the root dir and we point to ourselves
                     Dirent::DotDot => self.parents.last().or(dir.disk_id.as_ref()).copied(),
@@ -534,6 +668,13 @@ impl Root {
         self.insert(path, Dirent::Symlink(symlink))
     }
 
+    fn add_special<P: AsRef<Path>>(&mut self, path: P, special: Special) -> Result<(), Error> {
+        if path.as_ref().as_os_str().as_bytes().ends_with(b"/") {
+            return Err(Error::PathTrailingSlash);
+        }
+        self.insert(path, Dirent::Special(special))
+    }
+
     fn upsert_dir<P: AsRef<Path>>(&mut self, path: P, meta: Meta) -> Result<(), Error> {
         //eprintln!("upsert dir {:?}", path.as_ref());
         match self.lookup_create(path.as_ref())? {
@@ -543,7 +684,9 @@ impl Root {
                 Ok(())
             }
             (dir, Some(name)) => {
-                let dir = dir.get_or_create_dir(name)?;
+                let dir = dir
+                    .get_or_create_dir(name)
+                    .map_err(|_| Error::ExpectedDir(path.as_ref().into()))?;
                 dir.meta = meta;
                 Ok(())
             }
@@ -552,8 +695,20 @@ impl Root {
 
     fn insert<P: AsRef<Path>>(&mut self, path: P, entry: Dirent) -> Result<(), Error> {
         if let (dir, Some(name)) = self.lookup_create(path.as_ref())? {
-            dir.children.insert(name.into(), entry);
-            Ok(())
+            match dir.children.entry(name.into()) {
+                Entry::Vacant(e) => {
+                    e.insert(entry);
+                    Ok(())
+                }
+                Entry::Occupied(e) => match e.get() {
+                    Dirent::File(_) | Dirent::Symlink(_) | Dirent::Special(_) => {
+                        Err(Error::FileExists(path.as_ref().into()))
+                    }
+                    Dirent::Dir(_) | Dirent::Dot | Dirent::DotDot => {
+                        Err(Error::DirExists(path.as_ref().into()))
+                    }
+                },
+            }
         } else {
             // this can only happen if trying to insert at the root like . ./ or /
             // for which the only valid thing to do is upsert_dir
@@ -606,13 +761,17 @@ impl Root {
 
         let mut cur = &mut self.root;
         let mut iter = path.components().peekable();
+        // number of components consumed, used to report which parent is in the way
+        let mut depth = 0;
 
         let name = {
             loop {
                 if let Some(part) = iter.next() {
+                    depth += 1;
                     if iter.peek().is_none() {
                         match part {
                             Normal(part) => {
+                                check_name(part)?;
                                 break Some(part);
                             }
                             CurDir => {
@@ -635,8 +794,11 @@ impl Root {
                             return Err(Error::PathWithDotDot);
                         }
                         Normal(part) => {
+                            check_name(part)?;
                             if create {
-                                cur = cur.get_or_create_dir(part)?;
+                                cur = cur.get_or_create_dir(part).map_err(|_| {
+                                    Error::ExpectedDir(path.components().take(depth).collect())
+                                })?;
                             } else {
                                 cur = cur.get_dir(part)?;
                             }
@@ -651,6 +813,18 @@ impl Root {
     }
 }
 
+// components from Path can't have a slash but it doesn't hurt to check
+fn check_name(name: &OsStr) -> Result<(), Error> {
+    let bytes = name.as_bytes();
+    if bytes.len() > MAX_NAME_LEN
+        || bytes.iter().any(|&b| b == b'\0' || b == b'/')
+        || name.to_str().is_none()
+    {
+        return Err(Error::BadFilename(name.into()));
+    }
+    Ok(())
+}
+
 impl Dir {
     fn get_or_create_dir(&mut self, name: &OsStr) -> Result<&mut Dir, Error> {
         // annoying that there is no entry api without Borrow<Q> like get_mut b/c we have to
@@ -731,7 +905,7 @@ impl<W: Write + Seek> Builder<W> {
         let block_size_bits = 12; // TODO configurable
         let mut ret = Builder {
             root: Some(Root::default()),
-            increment_uid_gid: config.increment_uid_gid,
+            id_mapping: config.id_mapping,
             writer: BufWriter::with_capacity(32 * 1024, writer),
             superblock: Superblock::new_zeroed(),
             cur_data_block: 1,
@@ -746,7 +920,15 @@ impl<W: Write + Seek> Builder<W> {
             max_depth: MAX_DEPTH,
             max_file_size: config.max_file_size.unwrap_or(u64::MAX),
             cur_file_size: 0,
+            last_file_blocks: None,
+            written_end: 0,
+            progress: config.progress,
+            progress_cur: Progress::default(),
+            progress_last: Progress::default(),
         };
+        ret.superblock.uuid = config.uuid;
+        ret.superblock.build_time = config.build_time.into();
+        ret.superblock.build_time_nsec = config.build_time_nsec.into();
         // manually advance to first block
         ret.writer
             .seek(SeekFrom::Start(ret.block_addr(ret.cur_data_block)))?;
@@ -849,6 +1031,7 @@ impl<W: Write + Seek> Builder<W> {
             EROFS_NULL_ADDR
         };
 
+        self.last_file_blocks = None;
         if block_len > 0 {
             std::io::copy(&mut contents.take(block_len as u64), &mut self.writer)?;
             self.cur_data_block += n_blocks as u64;
@@ -856,6 +1039,7 @@ impl<W: Write + Seek> Builder<W> {
             // block_len is not necessarily a multiple of blocks, so zero fill the rest
             // we could also seek but that causes a buffer flush and seek
             self.zero_fill_block(block_len)?;
+            self.last_file_blocks = Some((start_block as u64, self.cur_data_block));
         }
         let tail = if tail_len > 0 {
             // TODO could we ever figure out how to read into uninit vector?
@@ -873,14 +1057,27 @@ impl<W: Write + Seek> Builder<W> {
             n_links: 1,
             ..Default::default()
         };
-        self.root.as_mut().expect("not none").add_file(path, file)
+        self.root.as_mut().expect("not none").add_file(path, file)?;
+        self.update_progress(len);
+        Ok(())
     }
 
-    fn hook_meta(&self, mut meta: Meta) -> Result<Meta, Error> {
-        if let Some(inc) = self.increment_uid_gid {
-            meta.uid = meta.uid.checked_add(inc).ok_or(Error::UidGidTooBig)?;
-            meta.gid = meta.gid.checked_add(inc).ok_or(Error::UidGidTooBig)?;
+    fn update_progress(&mut self, len: usize) {
+        let Some(progress) = self.progress.as_mut() else {
+            return;
+        };
+        self.progress_cur.files += 1;
+        self.progress_cur.bytes += len as u64;
+        if self.progress_cur.bytes - self.progress_last.bytes >= PROGRESS_INTERVAL_BYTES
+            || self.progress_cur.files - self.progress_last.files >= PROGRESS_INTERVAL_FILES
+        {
+            progress(self.progress_cur);
+            self.progress_last = self.progress_cur;
         }
+    }
+
+    fn hook_meta(&self, mut meta: Meta) -> Result<Meta, Error> {
+        (meta.uid, meta.gid) = self.id_mapping.map(meta.uid, meta.gid)?;
         Ok(meta)
     }
 
@@ -932,6 +1129,34 @@ impl<W: Write + Seek> Builder<W> {
             .add_symlink(path, symlink)
     }
 
+    // file_type should be one of Fifo, Socket, CharacterDevice or BlockDevice. rdev is (major,
+    // minor) and should be (0, 0) for fifos and sockets
+    pub fn add_special<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        file_type: FileType,
+        rdev: (u32, u32),
+        meta: Meta,
+    ) -> Result<(), Error> {
+        if !matches!(
+            file_type,
+            FileType::Fifo | FileType::Socket | FileType::CharacterDevice | FileType::BlockDevice
+        ) {
+            return Err(Error::NotSpecialFile);
+        }
+        let special = Special {
+            meta: self.hook_meta(meta)?,
+            file_type,
+            rdev,
+            n_links: 1,
+            disk_id: None,
+        };
+        self.root
+            .as_mut()
+            .expect("not none")
+            .add_special(path, special)
+    }
+
     pub fn add_link<P1: AsRef<Path>, P2: AsRef<Path>>(
         &mut self,
         path: P1,
@@ -943,6 +1168,52 @@ impl<W: Write + Seek> Builder<W> {
         Ok(())
     }
 
+    // Makes the file at path share the data of the file at target (which must have the same
+    // contents, that is up to the caller). path must be the most recently added file so that its
+    // blocks are the last ones written and we can give them back by rewinding. Unlike add_link,
+    // each file keeps its own inode (meta and link count) and only the data blocks are shared.
+    // NOTE the bytes we rewind over are still in the writer and if not enough comes after they
+    // are zeroed in into_inner, so the writer can be longer than Stats::image_len
+    pub fn dedup_file<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &mut self,
+        path: P1,
+        target: P2,
+    ) -> Result<(), Error> {
+        let root = self.root.as_mut().expect("not none");
+        let (start_block, len, tail) = match root.get(target)?.ok_or(Error::DedupNotFound)? {
+            Dirent::File(f) => (f.start_block, f.len, f.tail.clone()),
+            _ => {
+                return Err(Error::DedupNotAFile);
+            }
+        };
+        let Dirent::File(file) = root.get(path)?.ok_or(Error::DedupNotFound)? else {
+            return Err(Error::DedupNotAFile);
+        };
+        if file.len != len {
+            return Err(Error::DedupLenMismatch);
+        }
+        let (n_blocks, _, _) = size_tail_len(len, self.block_size_bits);
+        let rewind_to = if file.start_block == EROFS_NULL_ADDR {
+            None
+        } else if self.last_file_blocks == Some((file.start_block as u64, self.cur_data_block)) {
+            // can only give the blocks back once, after that someone else could be sharing them
+            self.last_file_blocks = None;
+            Some(file.start_block as u64)
+        } else {
+            return Err(Error::DedupNotLastFile);
+        };
+        file.start_block = start_block;
+        file.tail = tail;
+
+        self.stats.dedup_files += 1;
+        if let Some(block) = rewind_to {
+            self.stats.dedup_blocks += n_blocks;
+            self.written_end = self.written_end.max(self.block_addr(self.cur_data_block));
+            self.seek_block(block)?;
+        }
+        Ok(())
+    }
+
     fn write_superblock(&mut self) -> Result<(), Error> {
         self.superblock.magic = EROFS_SUPER_MAGIG_V1.into();
         self.superblock.blkszbits = self.block_size_bits;
@@ -961,8 +1232,9 @@ impl<W: Write + Seek> Builder<W> {
             .try_into()
             .map_err(|_| Error::RootDiskIdTooBig)?;
         self.superblock.inos = self.n_inodes.into();
-        //self.superblock.feature_compat = 1.into();
-        // TODO checksum (and turn on feature_compat)
+        self.superblock.feature_compat = disk::EROFS_FEATURE_COMPAT_SB_CHKSUM.into();
+        self.superblock.checksum = 0u32.into();
+        self.superblock.checksum = self.superblock_checksum().into();
 
         self.writer
             .seek(SeekFrom::Start(EROFS_SUPER_OFFSET as u64))?;
@@ -970,6 +1242,15 @@ impl<W: Write + Seek> Builder<W> {
         Ok(())
     }
 
+    // crc over the first block from the superblock on with the checksum field zeroed (see disk.rs).
+    // data starts at block 1 so the rest of block 0 is always zeros
+    fn superblock_checksum(&self) -> u32 {
+        let mut block = vec![0u8; self.block_size() as usize - EROFS_SUPER_OFFSET];
+        let sb = self.superblock.as_bytes();
+        block[..sb.len()].copy_from_slice(sb);
+        disk::crc32c(&block)
+    }
+
     #[cfg(debug_assertions)]
     fn check_writer_alignment(&mut self, prepost: &str) {
         let cur = self.writer.stream_position().unwrap();
@@ -1139,6 +1420,19 @@ impl<W: Write + Seek> Builder<W> {
     fn resolve_links(&mut self) -> Result<(), Error> {
         let root = self.root.as_mut().expect("not none");
         for (path, target, meta) in std::mem::take(&mut self.links).into_iter() {
+            // specials have no data to share so the link is just another copy of the inode
+            if let Some(Dirent::Special(s)) = root.get(&target)? {
+                s.n_links += 1;
+                let special = Special {
+                    meta,
+                    file_type: s.file_type,
+                    rdev: s.rdev,
+                    n_links: 2,
+                    disk_id: None,
+                };
+                root.add_special(path, special)?;
+                continue;
+            }
             let (start_block, len, tail) = {
                 // TODO we're not handling the case of multiple hardlinks that try to get resolved
                 // in the wrong order like:
@@ -1156,6 +1450,7 @@ impl<W: Write + Seek> Builder<W> {
                         s.n_links += 1;
                         Ok((s.start_block, s.len, s.tail.clone()))
                     }
+                    Dirent::Special(_) => unreachable!("handled above"),
                     Dirent::Dot | Dirent::DotDot | Dirent::Dir(_) => Err(Error::HardlinkToDir),
                 }?
             };
@@ -1174,9 +1469,27 @@ impl<W: Write + Seek> Builder<W> {
         Ok(())
     }
 
+    // the inodes are the last thing in the image, so anything after them is left over from blocks
+    // dedup_file gave back
+    fn zero_after_inodes(&mut self) -> Result<(), Error> {
+        self.stats.image_len = self.inode_addr;
+        if self.written_end > self.inode_addr {
+            self.writer.seek(SeekFrom::Start(self.inode_addr))?;
+            let zeros = vec![0; self.block_size() as usize];
+            let mut remaining = self.written_end - self.inode_addr;
+            while remaining > 0 {
+                let n = remaining.min(zeros.len() as u64);
+                self.writer.write_all(&zeros[..n as usize])?;
+                remaining -= n;
+            }
+        }
+        Ok(())
+    }
+
     fn finalize(&mut self) -> Result<(), Error> {
         self.resolve_links()?;
         self.write_inodes()?;
+        self.zero_after_inodes()?;
         self.write_superblock()?;
         self.writer.flush()?;
         Ok(())
@@ -1184,6 +1497,11 @@ impl<W: Write + Seek> Builder<W> {
 
     pub fn into_inner(mut self) -> Result<(Stats, W), Error> {
         self.finalize()?;
+        if let Some(progress) = self.progress.as_mut() {
+            if self.progress_cur != self.progress_last {
+                progress(self.progress_cur);
+            }
+        }
         self.writer
             .into_inner()
             .map_err(|e| e.into_error().into())
@@ -1240,7 +1558,7 @@ fn make_xattr_entries(xattrs: &XattrMap) -> Result<Vec<(u8, XattrEntry)>, Error>
 mod tests {
     use super::*;
 
-    use std::collections::{BTreeSet, HashSet};
+    use std::collections::{BTreeMap, BTreeSet, HashSet};
     use std::io::Cursor;
     use std::path::{Path, PathBuf};
     use std::process::Command;
@@ -1643,6 +1961,92 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tree_conflicts() {
+        let mut tree = Root {
+            root: Dir::default(),
+        };
+        tree.add_file("/a/b", File::default()).unwrap();
+        tree.upsert_dir("/d", Meta::default()).unwrap();
+
+        // file over file
+        match tree.add_file("a/b", File::default()) {
+            Err(Error::FileExists(p)) => assert_eq!(p, Path::new("a/b")),
+            e => panic!("expected FileExists got {:?}", e),
+        }
+        match tree.add_symlink("/a/b", Symlink::default()) {
+            Err(Error::FileExists(p)) => assert_eq!(p, Path::new("/a/b")),
+            e => panic!("expected FileExists got {:?}", e),
+        }
+
+        // file over dir
+        for p in ["/a", "/d"] {
+            match tree.add_file(p, File::default()) {
+                Err(Error::DirExists(got)) => assert_eq!(got, Path::new(p)),
+                e => panic!("expected DirExists got {:?}", e),
+            }
+        }
+
+        // dir over file
+        match tree.upsert_dir("/a/b", Meta::default()) {
+            Err(Error::ExpectedDir(p)) => assert_eq!(p, Path::new("/a/b")),
+            e => panic!("expected ExpectedDir got {:?}", e),
+        }
+        // file under a file, reports the parent that is in the way
+        match tree.add_file("/a/b/c/d", File::default()) {
+            Err(Error::ExpectedDir(p)) => assert_eq!(p, Path::new("/a/b")),
+            e => panic!("expected ExpectedDir got {:?}", e),
+        }
+        match tree.upsert_dir("a/b/c", Meta::default()) {
+            Err(Error::ExpectedDir(p)) => assert_eq!(p, Path::new("a/b")),
+            e => panic!("expected ExpectedDir got {:?}", e),
+        }
+
+        // upserting an existing dir is still fine
+        tree.upsert_dir("/a", Meta::default()).unwrap();
+        tree.upsert_dir("/d/", Meta::default()).unwrap();
+    }
+
+    #[test]
+    fn test_bad_filenames() {
+        let mut tree = Root {
+            root: Dir::default(),
+        };
+        let name = "a".repeat(MAX_NAME_LEN);
+        tree.add_file(&name, File::default()).unwrap();
+        tree.upsert_dir(format!("/d/{name}"), Meta::default())
+            .unwrap();
+
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        for p in [long.clone(), format!("/d/{long}"), format!("{long}/f")] {
+            match tree.add_file(&p, File::default()) {
+                Err(Error::BadFilename(got)) => assert_eq!(got, OsStr::new(&long)),
+                e => panic!("expected BadFilename got {:?}", e),
+            }
+        }
+
+        let nul = OsStr::from_bytes(b"x\0y");
+        match tree.add_file(Path::new("/d").join(nul), File::default()) {
+            Err(Error::BadFilename(got)) => assert_eq!(got, nul),
+            e => panic!("expected BadFilename got {:?}", e),
+        }
+        let not_utf8 = OsStr::from_bytes(b"x\xffy");
+        match tree.add_symlink(not_utf8, Symlink::default()) {
+            Err(Error::BadFilename(got)) => assert_eq!(got, not_utf8),
+            e => panic!("expected BadFilename got {:?}", e),
+        }
+
+        // can't get a slash in through a path, but names are checked on their own too
+        match check_name(OsStr::new("x/y")) {
+            Err(Error::BadFilename(got)) => assert_eq!(got, OsStr::new("x/y")),
+            e => panic!("expected BadFilename got {:?}", e),
+        }
+
+        // nothing got added for the bad ones
+        let names: Vec<_> = tree.root.children.keys().collect();
+        assert_eq!(names, [".", "..", name.as_str(), "d"]);
+    }
+
     #[test]
     fn test_builder_simple() -> Result<(), Error> {
         let mut b = Builder::new(NamedTempFile::new().expect("tf"), BuilderConfig::default())?;
@@ -1799,6 +2203,224 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_walk() {
+        let mut b = Builder::new(Cursor::new(vec![]), BuilderConfig::default()).unwrap();
+        b.add_file("/b/y", Meta::default(), 2, &mut &b"hi"[..])
+            .unwrap();
+        b.add_file("/b/x", Meta::default(), 0, &mut &b""[..])
+            .unwrap();
+        b.add_file("/a", Meta::default(), 0, &mut &b""[..]).unwrap();
+        b.upsert_dir("/c", Meta::default()).unwrap();
+        b.add_symlink("/b/z", "../a", Meta::default()).unwrap();
+        let (_, buf) = b.into_inner().unwrap();
+        let buf = buf.into_inner();
+        let erofs = disk::Erofs::new(&buf).unwrap();
+
+        let got: Vec<_> = erofs
+            .walk()
+            .unwrap()
+            .map(|x| {
+                let x = x.unwrap();
+                (x.path, x.inode.file_type())
+            })
+            .collect();
+        let expected: Vec<(PathBuf, FileType)> = vec![
+            ("".into(), FileType::Directory),
+            ("a".into(), FileType::RegularFile),
+            ("b".into(), FileType::Directory),
+            ("b/x".into(), FileType::RegularFile),
+            ("b/y".into(), FileType::RegularFile),
+            ("b/z".into(), FileType::Symlink),
+            ("c".into(), FileType::Directory),
+        ];
+        assert_eq!(got, expected);
+
+        let b = erofs.lookup("b").unwrap().unwrap();
+        let got: Vec<_> = erofs.walk_from(b).map(|x| x.unwrap().path).collect();
+        let expected: Vec<PathBuf> = vec!["".into(), "x".into(), "y".into(), "z".into()];
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_special_files() {
+        let mut b = Builder::new(Cursor::new(vec![]), BuilderConfig::default()).unwrap();
+        b.upsert_dir("/dev", Meta::default()).unwrap();
+        // /dev/null and /dev/sda
+        b.add_special(
+            "/dev/null",
+            FileType::CharacterDevice,
+            (1, 3),
+            Meta::default(),
+        )
+        .unwrap();
+        b.add_special("/dev/sda", FileType::BlockDevice, (8, 0), Meta::default())
+            .unwrap();
+        // big enough to use all of the encoding
+        b.add_special(
+            "/dev/big",
+            FileType::CharacterDevice,
+            (0xabc, 0xabcde),
+            Meta::default(),
+        )
+        .unwrap();
+        b.add_special("/fifo", FileType::Fifo, (0, 0), Meta::default())
+            .unwrap();
+        b.add_special("/sock", FileType::Socket, (0, 0), Meta::default())
+            .unwrap();
+        b.add_link("/dev/null2", "/dev/null", Meta::default())
+            .unwrap();
+        assert!(matches!(
+            b.add_special("/x", FileType::RegularFile, (0, 0), Meta::default()),
+            Err(Error::NotSpecialFile)
+        ));
+        let (stats, buf) = b.into_inner().unwrap();
+        assert_eq!(stats.specials, 6);
+
+        let buf = buf.into_inner();
+        let erofs = disk::Erofs::new(&buf).unwrap();
+        for (path, file_type, rdev) in [
+            ("dev/null", FileType::CharacterDevice, Some((1, 3))),
+            ("dev/null2", FileType::CharacterDevice, Some((1, 3))),
+            ("dev/sda", FileType::BlockDevice, Some((8, 0))),
+            ("dev/big", FileType::CharacterDevice, Some((0xabc, 0xabcde))),
+            ("fifo", FileType::Fifo, None),
+            ("sock", FileType::Socket, None),
+        ] {
+            let inode = erofs.lookup(path).unwrap().unwrap();
+            assert_eq!(inode.file_type(), file_type, "{path}");
+            assert_eq!(inode.data_size(), 0);
+            assert_eq!(inode.rdev(), rdev, "{path}");
+        }
+        let dirents = erofs
+            .get_dirents(&erofs.lookup("dev").unwrap().unwrap())
+            .unwrap();
+        let types: BTreeMap<_, _> = dirents
+            .iter()
+            .unwrap()
+            .map(|x| {
+                let x = x.unwrap();
+                (x.name.to_vec(), x.file_type)
+            })
+            .collect();
+        assert_eq!(types[b"null".as_slice()], DirentFileType::CharacterDevice);
+        assert_eq!(types[b"sda".as_slice()], DirentFileType::BlockDevice);
+        assert_eq!(erofs.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_long_symlinks() {
+        let mut b = Builder::new(Cursor::new(vec![]), BuilderConfig::default()).unwrap();
+        // tail packed
+        let short = "a".repeat(100);
+        // tail would be more than half a block so it all goes in a block
+        let block = "b".repeat(3000);
+        // biggest allowed target
+        let max = "c".repeat(4095);
+        // block plus tail, but over PATH_MAX with 4K blocks
+        let long = "d".repeat(5000);
+        for (name, target) in [
+            ("short", &short),
+            ("block", &block),
+            ("max", &max),
+            ("long", &long),
+        ] {
+            b.add_symlink(format!("/{name}"), target, Meta::default())
+                .unwrap();
+        }
+        let (_, buf) = b.into_inner().unwrap();
+        let buf = buf.into_inner();
+        let erofs = disk::Erofs::new(&buf).unwrap();
+
+        for (name, target) in [("short", &short), ("block", &block), ("max", &max)] {
+            let inode = erofs.lookup(name).unwrap().unwrap();
+            assert_eq!(
+                erofs.get_symlink(&inode).unwrap().as_ref(),
+                target.as_bytes()
+            );
+        }
+        let inode = erofs.lookup("block").unwrap().unwrap();
+        assert_eq!(inode.layout(), disk::Layout::FlatPlain);
+
+        let inode = erofs.lookup("long").unwrap().unwrap();
+        let (block, tail) = erofs.get_data(&inode).unwrap();
+        assert_eq!((block.len(), tail.len()), (4096, 904));
+        assert_eq!(erofs.get_symlink(&inode), Err(disk::Error::SymlinkTooLong));
+        assert_eq!(erofs.read_file(&inode).unwrap(), long.as_bytes());
+    }
+
+    #[test]
+    fn test_dedup_file() {
+        let data = vec![42u8; 3 * 4096 + 10];
+        let mut b = Builder::new(Cursor::new(vec![]), BuilderConfig::default()).unwrap();
+        b.add_file("/x", Meta::default(), data.len(), &mut Cursor::new(&data))
+            .unwrap();
+        b.add_file("/y", Meta::default(), data.len(), &mut Cursor::new(&data))
+            .unwrap();
+        b.dedup_file("/y", "/x").unwrap();
+        // x's blocks are last again but y shares them now
+        assert!(matches!(
+            b.dedup_file("/x", "/y"),
+            Err(Error::DedupNotLastFile)
+        ));
+        b.add_file(
+            "/z",
+            Meta::default(),
+            4096,
+            &mut Cursor::new(vec![1u8; 4096]),
+        )
+        .unwrap();
+        assert!(matches!(
+            b.dedup_file("/z", "/x"),
+            Err(Error::DedupLenMismatch)
+        ));
+        let (stats, buf) = b.into_inner().unwrap();
+        assert_eq!(stats.dedup_files, 1);
+        assert_eq!(stats.dedup_blocks, 3);
+
+        let buf = buf.into_inner();
+        let erofs = disk::Erofs::new(&buf).unwrap();
+        let x = erofs.lookup("x").unwrap().unwrap();
+        let y = erofs.lookup("y").unwrap().unwrap();
+        let z = erofs.lookup("z").unwrap().unwrap();
+        assert_ne!(x.disk_id(), y.disk_id());
+        assert_eq!(x.raw_block_addr(), y.raw_block_addr());
+        // z went where y's blocks were given back
+        assert_eq!(z.raw_block_addr(), x.raw_block_addr() + 3);
+        for inode in [&x, &y] {
+            let (block, tail) = erofs.get_data(inode).unwrap();
+            assert_eq!([block, tail].concat(), data);
+        }
+        let (block, _) = erofs.get_data(&z).unwrap();
+        assert_eq!(block, vec![1u8; 4096]);
+    }
+
+    #[test]
+    fn test_dedup_file_stale_end() {
+        // y gives back more blocks than the inodes after it take up
+        let data = vec![42u8; 10 * 4096];
+        let mut b = Builder::new(Cursor::new(vec![]), BuilderConfig::default()).unwrap();
+        b.add_file("/x", Meta::default(), data.len(), &mut Cursor::new(&data))
+            .unwrap();
+        b.add_file("/y", Meta::default(), data.len(), &mut Cursor::new(&data))
+            .unwrap();
+        b.dedup_file("/y", "/x").unwrap();
+        let (stats, buf) = b.into_inner().unwrap();
+        let buf = buf.into_inner();
+        assert_eq!(buf.len(), 21 * 4096);
+        let image_len = stats.image_len() as usize;
+        assert!(image_len < 13 * 4096, "{image_len}");
+        assert!(buf[image_len..].iter().all(|x| *x == 0));
+        // and nothing of y is left past x's blocks
+        assert!(!buf[11 * 4096..].contains(&42));
+
+        let erofs = disk::Erofs::new(&buf[..image_len]).unwrap();
+        let y = erofs.lookup("y").unwrap().unwrap();
+        let (block, _) = erofs.get_data(&y).unwrap();
+        assert_eq!(block, data);
+        assert_eq!(erofs.check(), Ok(()));
+    }
+
     #[test]
     fn test_link_count() {
         // TODO this test would fail if we added E::link("/z", "/y") which should give everyone a
@@ -1830,4 +2452,256 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_id_mapping() {
+        let build = |id_mapping: IdMapping| {
+            let config = BuilderConfig {
+                id_mapping: id_mapping,
+                ..Default::default()
+            };
+            let mut b = Builder::new(Cursor::new(vec![]), config).unwrap();
+            let meta = |uid, gid| Meta {
+                uid: uid,
+                gid: gid,
+                ..Default::default()
+            };
+            b.upsert_dir("/d", meta(1, 2)).unwrap();
+            b.add_file("/d/x", meta(0, 0), 2, &mut &b"hi"[..]).unwrap();
+            b.add_symlink("/y", "d/x", meta(3, 1)).unwrap();
+            let (_, buf) = b.into_inner().unwrap();
+            buf.into_inner()
+        };
+        let ids = |buf: &[u8]| -> Vec<(u32, u32)> {
+            let erofs = disk::Erofs::new(buf).unwrap();
+            ["d", "d/x", "y"]
+                .iter()
+                .map(|p| {
+                    let inode = erofs.lookup(p).unwrap().unwrap();
+                    (inode.uid(), inode.gid())
+                })
+                .collect()
+        };
+
+        assert_eq!(ids(&build(IdMapping::None)), vec![(1, 2), (0, 0), (3, 1)]);
+        assert_eq!(
+            ids(&build(IdMapping::Offset { uid: 1000, gid: 0 })),
+            vec![(1001, 2), (1000, 0), (1003, 1)]
+        );
+        assert_eq!(
+            ids(&build(IdMapping::Offset { uid: 0, gid: 2000 })),
+            vec![(1, 2002), (0, 2000), (3, 2001)]
+        );
+        assert_eq!(
+            ids(&build(IdMapping::Explicit(HashMap::from([
+                (0, 1000),
+                (1, 1001)
+            ])))),
+            vec![(1001, 2), (1000, 1000), (3, 1001)]
+        );
+
+        let mut b = Builder::new(
+            Cursor::new(vec![]),
+            BuilderConfig {
+                id_mapping: IdMapping::Offset { uid: 1, gid: 0 },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let meta = Meta {
+            uid: u32::MAX,
+            ..Default::default()
+        };
+        assert!(matches!(b.upsert_dir("/d", meta), Err(Error::UidGidTooBig)));
+    }
+
+    #[test]
+    fn test_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reports_clone = reports.clone();
+        let config = BuilderConfig {
+            progress: Some(Box::new(move |p| reports_clone.lock().unwrap().push(p))),
+            ..Default::default()
+        };
+        let mut b = Builder::new(Cursor::new(vec![]), config).unwrap();
+        let data = vec![42u8; 300 * 1024];
+        for i in 0..10 {
+            b.add_file(
+                format!("/big{i}"),
+                Meta::default(),
+                data.len(),
+                &mut Cursor::new(&data),
+            )
+            .unwrap();
+        }
+        // crosses the bytes interval every 4 files
+        assert_eq!(
+            reports
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|p| p.files)
+                .collect::<Vec<_>>(),
+            vec![4, 8]
+        );
+
+        for i in 0..(PROGRESS_INTERVAL_FILES + 10) {
+            b.add_file(
+                format!("/small{i}"),
+                Meta::default(),
+                1,
+                &mut Cursor::new(b"x"),
+            )
+            .unwrap();
+        }
+        // and the files interval once
+        assert_eq!(reports.lock().unwrap().len(), 3);
+
+        b.into_inner().unwrap();
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 4);
+        let total = PROGRESS_INTERVAL_FILES + 20;
+        assert_eq!(
+            *reports.last().unwrap(),
+            Progress {
+                files: total,
+                bytes: 10 * data.len() as u64 + (PROGRESS_INTERVAL_FILES + 10) as u64,
+            }
+        );
+        assert!(reports.windows(2).all(|w| w[0].files < w[1].files));
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let build = |uuid: [u8; 16]| {
+            let config = BuilderConfig {
+                uuid: uuid,
+                build_time: 1700000000,
+                build_time_nsec: 42,
+                ..Default::default()
+            };
+            let mut b = Builder::new(Cursor::new(vec![]), config).unwrap();
+            let mut xattrs = XattrMap::new();
+            xattrs.insert((*b"user.attr").into(), (*b"value").into());
+            let meta = Meta {
+                xattrs: xattrs,
+                ..Default::default()
+            };
+            b.add_file("/b/y", meta, 2, &mut &b"hi"[..]).unwrap();
+            b.add_file("/a", Meta::default(), 5000, &mut &[7u8; 5000][..])
+                .unwrap();
+            b.upsert_dir("/c", Meta::default()).unwrap();
+            b.add_symlink("/b/z", "../a", Meta::default()).unwrap();
+            b.add_link("/b/a", "/a", Meta::default()).unwrap();
+            let (_, buf) = b.into_inner().unwrap();
+            buf.into_inner()
+        };
+
+        let uuid = *b"0123456789abcdef";
+        let first = build(uuid);
+        assert!(first == build(uuid));
+
+        let erofs = disk::Erofs::new(&first).unwrap();
+        assert_eq!(erofs.sb.uuid, uuid);
+        assert_eq!(erofs.sb.build_time.get(), 1700000000);
+        assert_eq!(erofs.sb.build_time_nsec.get(), 42);
+        let inode = erofs.lookup("b/y").unwrap().unwrap();
+        assert_eq!(
+            erofs.get_xattr(&inode, b"user.attr").unwrap(),
+            Some(&b"value"[..])
+        );
+        assert_eq!(erofs.get_xattr(&inode, b"user.other").unwrap(), None);
+        let inode = erofs.lookup("a").unwrap().unwrap();
+        assert_eq!(erofs.get_xattr(&inode, b"user.attr").unwrap(), None);
+
+        // only the uuid (and so the checksum) differs
+        let other = build([0; 16]);
+        assert_eq!(first.len(), other.len());
+        let diff: Vec<usize> = (0..first.len()).filter(|&i| first[i] != other[i]).collect();
+        assert!(!diff.is_empty());
+        let uuid_offset = 1024 + std::mem::offset_of!(Superblock, uuid);
+        let checksum_offset = 1024 + std::mem::offset_of!(Superblock, checksum);
+        assert!(diff
+            .iter()
+            .all(|i| (uuid_offset..uuid_offset + 16).contains(i)
+                || (checksum_offset..checksum_offset + 4).contains(i)));
+    }
+
+    #[test]
+    fn test_superblock_checksum() {
+        let mut b = Builder::new(Cursor::new(vec![]), BuilderConfig::default()).unwrap();
+        b.add_file("/a", Meta::default(), 5000, &mut &[7u8; 5000][..])
+            .unwrap();
+        b.add_file("/b/c", Meta::default(), 2, &mut &b"hi"[..])
+            .unwrap();
+        b.add_symlink("/d", "a", Meta::default()).unwrap();
+        let (_, buf) = b.into_inner().unwrap();
+        let mut buf = buf.into_inner();
+
+        let erofs = disk::Erofs::new(&buf).unwrap();
+        assert_eq!(
+            u32::from(erofs.sb.feature_compat) & disk::EROFS_FEATURE_COMPAT_SB_CHKSUM,
+            disk::EROFS_FEATURE_COMPAT_SB_CHKSUM
+        );
+        assert_ne!(u32::from(erofs.sb.checksum), 0);
+        assert!(erofs.check_checksum().unwrap());
+
+        // covers the whole first block, not just the superblock
+        buf[4000] = 1;
+        assert!(!disk::Erofs::new(&buf).unwrap().check_checksum().unwrap());
+    }
+
+    #[test]
+    fn test_superblock_display() {
+        let config = BuilderConfig {
+            uuid: *b"0123456789abcdef",
+            build_time: 1700000000,
+            build_time_nsec: 42,
+            ..Default::default()
+        };
+        let mut b = Builder::new(Cursor::new(vec![]), config).unwrap();
+        b.add_file("/a", Meta::default(), 5000, &mut &[7u8; 5000][..])
+            .unwrap();
+        b.upsert_dir("/b", Meta::default()).unwrap();
+        let (_, buf) = b.into_inner().unwrap();
+        let mut buf = buf.into_inner();
+
+        let s = disk::Erofs::new(&buf).unwrap().sb.to_string();
+        for line in [
+            "block size: 4096",
+            // root, /a and /b
+            "inodes: 3",
+            "uuid: 30313233-3435-3637-3839-616263646566",
+            "volume name: \"\"",
+            "build time: 1700000000.000000042",
+            "feature compat: 0x1 (sb_chksum)",
+            "feature incompat: 0x0 (none)",
+        ] {
+            assert!(s.lines().any(|x| x == line), "{line}");
+        }
+
+        let set = |buf: &mut [u8], offset: usize, value: u32| {
+            let offset = 1024 + offset;
+            buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        set(
+            &mut buf,
+            std::mem::offset_of!(Superblock, feature_compat),
+            disk::EROFS_FEATURE_COMPAT_SB_CHKSUM | 0x8000,
+        );
+        set(
+            &mut buf,
+            std::mem::offset_of!(Superblock, feature_incompat),
+            disk::EROFS_FEATURE_INCOMPAT_ZERO_PADDING | disk::EROFS_FEATURE_INCOMPAT_FRAGMENTS,
+        );
+        let s = disk::Erofs::new(&buf).unwrap().sb.to_string();
+        assert!(s
+            .lines()
+            .any(|x| x == "feature compat: 0x8001 (sb_chksum, unknown(0x8000))"));
+        assert!(s
+            .lines()
+            .any(|x| x == "feature incompat: 0x21 (zero_padding, fragments)"));
+    }
 }
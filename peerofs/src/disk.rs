@@ -1,8 +1,13 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
 use std::fmt;
 #[allow(unused)]
-use std::io::Write;
+use std::io::{Read, Write};
 use std::num::NonZero;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 #[allow(unused)]
 use log::trace;
@@ -17,6 +22,47 @@ pub const EROFS_SUPER_MAGIG_V1: u32 = 0xe0f5e1e2;
 pub const INODE_ALIGNMENT: u64 = 32;
 // if an inode has only tail data, its blkaddr gets set to -1
 pub const EROFS_NULL_ADDR: u32 = u32::MAX;
+// linux PATH_MAX, includes the nul
+const PATH_MAX: u64 = 4096;
+pub const EROFS_FEATURE_INCOMPAT_ZERO_PADDING: u32 = 0x00000001;
+// also BIG_PCLUSTER
+pub const EROFS_FEATURE_INCOMPAT_COMPR_CFGS: u32 = 0x00000002;
+pub const EROFS_FEATURE_INCOMPAT_CHUNKED_FILE: u32 = 0x00000004;
+// also COMPR_HEAD2, the device table is really only there when extra_devices != 0
+pub const EROFS_FEATURE_INCOMPAT_DEVICE_TABLE: u32 = 0x00000008;
+pub const EROFS_FEATURE_INCOMPAT_ZTAILPACKING: u32 = 0x00000010;
+pub const EROFS_FEATURE_INCOMPAT_FRAGMENTS: u32 = 0x00000020;
+pub const EROFS_FEATURE_INCOMPAT_DEDUPE: u32 = 0x00000040;
+pub const EROFS_FEATURE_INCOMPAT_XATTR_PREFIXES: u32 = 0x00000080;
+pub const EROFS_FEATURE_INCOMPAT_48BIT: u32 = 0x00000100;
+pub const EROFS_FEATURE_INCOMPAT_METABOX: u32 = 0x00000200;
+// what we can open, head2 is still rejected per lcluster with Head2NotSupported
+const SUPPORTED_FEATURE_INCOMPAT: u32 = EROFS_FEATURE_INCOMPAT_ZERO_PADDING
+    | EROFS_FEATURE_INCOMPAT_COMPR_CFGS
+    | EROFS_FEATURE_INCOMPAT_DEVICE_TABLE
+    | EROFS_FEATURE_INCOMPAT_FRAGMENTS;
+const FEATURE_INCOMPAT_NAMES: [(u32, &str); 10] = [
+    (EROFS_FEATURE_INCOMPAT_ZERO_PADDING, "zero_padding"),
+    (EROFS_FEATURE_INCOMPAT_COMPR_CFGS, "compr_cfgs"),
+    (EROFS_FEATURE_INCOMPAT_CHUNKED_FILE, "chunked_file"),
+    (EROFS_FEATURE_INCOMPAT_DEVICE_TABLE, "device_table"),
+    (EROFS_FEATURE_INCOMPAT_ZTAILPACKING, "ztailpacking"),
+    (EROFS_FEATURE_INCOMPAT_FRAGMENTS, "fragments"),
+    (EROFS_FEATURE_INCOMPAT_DEDUPE, "dedupe"),
+    (EROFS_FEATURE_INCOMPAT_XATTR_PREFIXES, "xattr_prefixes"),
+    (EROFS_FEATURE_INCOMPAT_48BIT, "48bit"),
+    (EROFS_FEATURE_INCOMPAT_METABOX, "metabox"),
+];
+pub const EROFS_FEATURE_COMPAT_SB_CHKSUM: u32 = 0x00000001;
+pub const EROFS_FEATURE_COMPAT_MTIME: u32 = 0x00000002;
+pub const EROFS_FEATURE_COMPAT_XATTR_FILTER: u32 = 0x00000004;
+const FEATURE_COMPAT_NAMES: [(u32, &str); 3] = [
+    (EROFS_FEATURE_COMPAT_SB_CHKSUM, "sb_chksum"),
+    (EROFS_FEATURE_COMPAT_MTIME, "mtime"),
+    (EROFS_FEATURE_COMPAT_XATTR_FILTER, "xattr_filter"),
+];
+// bit 7 of MapHeader cluster_bits
+const FRAGMENT_INODE_BIT: u8 = 0x80;
 
 // NOTES:
 // Blocks
@@ -62,6 +108,15 @@ pub const EROFS_NULL_ADDR: u32 = u32::MAX;
 //   - Plain type LCI are uncompressed data of length up to B
 //   - Head1 and Head2 are the two variants of Head LCI which allow multiple compression methods to
 //   be used for the same file which is stored in the MapHeader algorithmtype
+// - Fragments
+//   - with -Efragments, mkfs appends the tail pcluster of files (or small files entirely) to a
+//   special packed inode found by the SB packed_nid. The packed inode is a regular inode and is
+//   usually compressed itself
+//   - if the MapHeader has FragmentPcluster set, the extent containing the last byte of the file
+//   (found like a random read of it would) is stored decompressed in the packed inode at the
+//   fragment offset. For CompressedFull, the high 32 bits of the offset are in that LCI's blkaddr
+//   - if the MapHeader cluster_bits has bit 7 set, the whole file is in the packed inode and the
+//   8 byte MapHeader is the offset as a le64 with that bit cleared. There are no LCI's
 //
 // Directories
 // - Dirent's are stored as above, either as FlatPlain or FlatInline, and in descending sorted order
@@ -123,11 +178,12 @@ pub enum Error {
     Oob,
     NotDir,
     NotSymlink,
+    EmptySymlink,
+    SymlinkTooLong,
     NotRegDirLink,
     DirentBadSize,
     BadFileType,
     InodeTooBig,
-    NotExpectingBlockData,
     BlockLenShouldBeZero,
     NotCompressed,
     NotCompressedFull,
@@ -136,12 +192,26 @@ pub enum Error {
     XattrPrefixTableNotHandled,
     Decompress,
     LciMalformed,
+    // the image ends before all of an inode's logical cluster indices, ie it is truncated
+    LciTruncated {
+        needed: usize,
+        available: usize,
+    },
     Write,
     Underflow,
     UnknownCompression,
     Head2NotSupported,
+    NoPackedInode,
+    // the packed inode can't have fragments of its own
+    PackedInodeFragment,
     CompressionNotSupported(CompressionType),
     LayoutNotHandled(Layout),
+    DirectoryCycle,
+    // feature_incompat bits we can't read, unknown has any bits we don't have a name for
+    UnsupportedFeatures {
+        names: Vec<&'static str>,
+        unknown: u32,
+    },
 }
 
 // how wrong is this?
@@ -498,9 +568,31 @@ impl InodeInfo {
         U32::from_bytes(self.data).into()
     }
 
+    pub fn new_rdev(major: u32, minor: u32) -> Self {
+        Self {
+            data: U32::new(encode_dev(major, minor)).to_bytes(),
+        }
+    }
+
+    pub fn rdev(&self) -> (u32, u32) {
+        decode_dev(U32::from_bytes(self.data).into())
+    }
+
     // TODO this needs to handle the other union fields
 }
 
+// rdev is stored in the linux new_encode_dev format (12 bit major, 20 bit minor with the low 8 bits
+// of minor first)
+pub fn encode_dev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
+pub fn decode_dev(dev: u32) -> (u32, u32) {
+    let major = (dev & 0xfff00) >> 8;
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+    (major, minor)
+}
+
 impl MapHeader {
     pub fn compression_type_1(&self) -> Result<CompressionType, Error> {
         (self.algorithm & 0b1111).try_into()
@@ -512,6 +604,29 @@ impl MapHeader {
         // bits 0-2
         self.cluster_bits & 0b111
     }
+    pub fn has_config(&self, config: MapHeaderConfig) -> bool {
+        u16::from(self.config) & config as u16 != 0
+    }
+    // offset into the packed inode when the whole file is stored there
+    pub fn fragment_inode_offset(&self) -> Option<u64> {
+        if self.cluster_bits & FRAGMENT_INODE_BIT == 0 {
+            return None;
+        }
+        let low = unsafe { self.fragment_offset_or_data_size.fragment_offset };
+        let high = u16::from(self.config) as u64
+            | (self.algorithm as u64) << 16
+            | ((self.cluster_bits & !FRAGMENT_INODE_BIT) as u64) << 24;
+        Some(high << 32 | u32::from(low) as u64)
+    }
+    // low 32 bits of the offset into the packed inode of the tail extent
+    pub fn fragment_offset(&self) -> Option<u32> {
+        if self.fragment_inode_offset().is_some()
+            || !self.has_config(MapHeaderConfig::FragmentPcluster)
+        {
+            return None;
+        }
+        Some(unsafe { self.fragment_offset_or_data_size.fragment_offset }.into())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -650,6 +765,18 @@ impl Inode<'_> {
         }
     }
 
+    // (major, minor) for char and block devices, the info union is rdev instead of a block addr
+    pub fn rdev(&self) -> Option<(u32, u32)> {
+        match self.file_type() {
+            FileType::CharacterDevice | FileType::BlockDevice => {}
+            _ => return None,
+        }
+        match self {
+            Inode::Compact((_, x)) => Some(x.info.rdev()),
+            Inode::Extended((_, x)) => Some(x.info.rdev()),
+        }
+    }
+
     pub fn block_addr(&self) -> Result<u64, Error> {
         match self.file_type() {
             FileType::RegularFile | FileType::Directory | FileType::Symlink => {
@@ -665,6 +792,11 @@ impl Inode<'_> {
             Inode::Extended((_, x)) => x.nlink.into(),
         }
     }
+
+    // a dir's nlink counts its subdirs, not names for it, so a dir is never hardlinked
+    pub fn is_hardlinked(&self) -> bool {
+        self.file_type() != FileType::Directory && self.link_count() > 1
+    }
 }
 
 #[derive(Debug)]
@@ -970,9 +1102,361 @@ impl TryFrom<u16> for Layout {
     }
 }
 
+// lookup next head
+// length of pcluster is difference in logical address of the two heads
+// LA of an LCI = LCI_index * block_len + LCI_cluster_offset
+// with rearranging, you get (j-i)*block_len + next_cluster_offset + cur_cluster_offset
+fn pcluster_len(
+    lcis: &[LogicalClusterIndex],
+    i: usize,
+    block_len: usize,
+    file_size: usize,
+) -> Result<(Option<usize>, usize), Error> {
+    debug_assert!(lcis[i].is_head());
+    // should always be ok b/c there is always a Plain LCI at the end (TODO not sure about
+    // that)
+    let cur = lcis.get(i).ok_or(Error::Oob)?;
+    let next = lcis.get(i + 1).ok_or(Error::Oob)?;
+    trace!("{}: {:?}", i, cur);
+    trace!("{}: {:?}", i + 1, next);
+    let (j, next_head) = match next.typ() {
+        LogicalClusterType::Head1 | LogicalClusterType::Head2 | LogicalClusterType::Plain => {
+            (i + 1, next)
+        }
+        LogicalClusterType::NonHead => {
+            let n = u16::from(next.block_addr_or_delta.delta()[1]) as usize;
+            trace!("trying to get {}/{}", i + 1 + n, lcis.len());
+            let Some(next_head) = lcis.get(i + 1 + n) else {
+                // it can be that there is no next head
+                if i + 1 + n == lcis.len() {
+                    // TODO check sub
+                    let len = file_size
+                        .checked_sub(i * block_len + cur.cluster_offset())
+                        .ok_or(Error::Underflow)?;
+                    return Ok((None, len));
+                } else {
+                    // true oob
+                    return Err(Error::Oob);
+                }
+            };
+            trace!("{}: {:?}", i + 1 + n, next_head);
+            debug_assert!(next_head.typ() != LogicalClusterType::NonHead);
+            (i + 1 + n, next_head)
+        }
+    };
+    // j > i
+    let len = (j - i) * block_len + next_head.cluster_offset() - cur.cluster_offset();
+    Ok((Some(j), len))
+}
+
+// head of the extent containing the last byte of the file, which is the one stored as a fragment
+// (the kernel's z_tailextent_headlcn). Plain counts as a head here
+fn tail_head_lci(
+    lcis: &[LogicalClusterIndex],
+    block_len: usize,
+    file_size: usize,
+) -> Result<usize, Error> {
+    let last = file_size.checked_sub(1).ok_or(Error::Underflow)?;
+    let mut i = last / block_len;
+    let cur = lcis.get(i).ok_or(Error::Oob)?;
+    // an extent starting after the last byte (ie at EOF) means it belongs to the previous one
+    if cur.typ() != LogicalClusterType::NonHead && last % block_len < cur.cluster_offset() {
+        i = i.checked_sub(1).ok_or(Error::LciMalformed)?;
+    }
+    loop {
+        let cur = lcis.get(i).ok_or(Error::Oob)?;
+        if cur.typ() != LogicalClusterType::NonHead {
+            return Ok(i);
+        }
+        let lookback = u16::from(cur.block_addr_or_delta.delta()[0]) as usize;
+        if lookback == 0 {
+            return Err(Error::LciMalformed);
+        }
+        i = i.checked_sub(lookback).ok_or(Error::LciMalformed)?;
+    }
+}
+
+// where CompressedReader's current chunk lives
+enum Chunk<'a> {
+    // straight out of the image or the packed inode
+    Plain(&'a [u8]),
+    // the first n bytes of buf
+    Decompressed(usize),
+}
+
+// pulls the decompressed contents of a compressed inode one pcluster at a time, so a large file
+// can be streamed without decompressing all of it up front. Plain clusters are handed out without
+// copying
+pub struct CompressedReader<'e, 'a> {
+    erofs: &'e Erofs<'a>,
+    lcis: &'a [LogicalClusterIndex],
+    // None when the whole file is a fragment
+    decompressor: Option<Box<dyn decompressor::Decompressor>>,
+    block_len: usize,
+    file_size: usize,
+    // None once we're done
+    next_lci: Option<usize>,
+    // LCI index of the tail extent and its data
+    fragment: Option<(usize, &'e [u8])>,
+    buf: Vec<u8>,
+    chunk: Chunk<'e>,
+    // how much of chunk read() has handed out
+    pos: usize,
+}
+
+impl CompressedReader<'_, '_> {
+    // the next pcluster (or plain cluster) of data, None at the end
+    // terminates either when we reach the last LCI that is Plain with 0 blkaddr or pcluster_len
+    // returns None
+    pub fn next_chunk(&mut self) -> Result<Option<&[u8]>, Error> {
+        let Some(i) = self.next_lci else {
+            return Ok(None);
+        };
+        if let Some((tail, data)) = self.fragment {
+            if i == tail {
+                self.next_lci = None;
+                self.chunk = Chunk::Plain(data);
+                self.pos = 0;
+                return Ok(Some(self.remaining()));
+            }
+        }
+        let lcis = self.lcis;
+        let block_len = self.block_len;
+        let cur = &lcis.get(i).ok_or(Error::Oob)?;
+        match cur.typ() {
+            // TODO different
+            LogicalClusterType::Head1 => {
+                let block_addr: u32 = cur.block_addr_or_delta.block_addr().into();
+                let data_begin = self.erofs.block_offset(block_addr) as usize;
+                let data = self
+                    .erofs
+                    .data
+                    .get(data_begin..data_begin + block_len)
+                    .ok_or(Error::Oob)?;
+                let (next_i, decompress_len) = pcluster_len(lcis, i, block_len, self.file_size)?;
+                trace!("lci {i} decompress_len={decompress_len} pa={data_begin}");
+
+                if self.buf.len() < decompress_len {
+                    self.buf.resize(decompress_len, 0);
+                }
+                let decompressor = self.decompressor.as_ref().ok_or(Error::LciMalformed)?;
+                let decompressed_len =
+                    // This highly depends on decompress_partial for slightly unknown reasons
+                    decompressor.decompress(data, &mut self.buf, decompress_len)
+                        .ok_or(Error::Decompress)?;
+                debug_assert!(decompressed_len == decompress_len);
+
+                self.next_lci = next_i;
+                self.chunk = Chunk::Decompressed(decompressed_len);
+            }
+            LogicalClusterType::Plain => {
+                trace!("{}: {:?}", i, cur);
+                let block_addr: u32 = cur.block_addr_or_delta.block_addr().into();
+                if block_addr == 0 {
+                    if i + 1 == lcis.len() {
+                        // this LCI is the last entry and is expected
+                        self.next_lci = None;
+                        return Ok(None);
+                    } else {
+                        return Err(Error::LciMalformed);
+                    }
+                }
+                // TODO can't there be a Plain at the end with partial data and we have to
+                // instead use the file size instead of the next LCI?
+                let next = lcis.get(i + 1).ok_or(Error::Oob)?;
+                trace!("{}: {:?}", i + 1, next);
+                let data_begin = self.erofs.block_offset(block_addr) as usize;
+                let data_len = block_len + next.cluster_offset() - cur.cluster_offset();
+                trace!("copying {data_len}");
+                let data = self
+                    .erofs
+                    .data
+                    .get(data_begin..data_begin + data_len)
+                    .ok_or(Error::Oob)?;
+
+                self.next_lci = Some(i + 1);
+                self.chunk = Chunk::Plain(data);
+            }
+            LogicalClusterType::Head2 => {
+                return Err(Error::Head2NotSupported);
+            }
+            LogicalClusterType::NonHead => {
+                return Err(Error::LciMalformed);
+            }
+        }
+        self.pos = 0;
+        Ok(Some(self.remaining()))
+    }
+
+    fn remaining(&self) -> &[u8] {
+        match self.chunk {
+            Chunk::Plain(data) => &data[self.pos..],
+            Chunk::Decompressed(len) => &self.buf[self.pos..len],
+        }
+    }
+}
+
+impl Read for CompressedReader<'_, '_> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.remaining().is_empty() {
+            if self.next_chunk().map_err(std::io::Error::other)?.is_none() {
+                return Ok(0);
+            }
+        }
+        let remaining = self.remaining();
+        let n = remaining.len().min(out.len());
+        out[..n].copy_from_slice(&remaining[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
 pub struct Erofs<'a> {
     data: &'a [u8],
     pub sb: &'a Superblock,
+    // contents of the packed inode, read on first use
+    packed_data: OnceLock<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct WalkItem<'a> {
+    // relative to where the walk started, which is itself the empty path
+    pub path: PathBuf,
+    pub inode: Inode<'a>,
+}
+
+// depth first, a directory comes before its contents and siblings are in dirent (so name) order.
+// the first error ends the walk
+pub struct Walk<'e, 'a> {
+    erofs: &'e Erofs<'a>,
+    stack: Vec<(PathBuf, Inode<'a>)>,
+    // a directory can only be reached once unless the image is broken
+    seen_dirs: HashSet<u32>,
+}
+
+impl<'a> Walk<'_, 'a> {
+    fn push_children(&mut self, path: &Path, inode: &Inode<'a>) -> Result<(), Error> {
+        let start = self.stack.len();
+        for item in self.erofs.get_dirents(inode)?.iter()? {
+            let item = item?;
+            if item.name == b"." || item.name == b".." {
+                continue;
+            }
+            let child = self.erofs.get_inode_from_dirent(&item)?;
+            self.stack
+                .push((path.join(OsStr::from_bytes(item.name)), child));
+        }
+        self.stack[start..].reverse();
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Walk<'_, 'a> {
+    type Item = Result<WalkItem<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, inode) = self.stack.pop()?;
+        if inode.file_type() == FileType::Directory {
+            let res = if self.seen_dirs.insert(inode.disk_id()) {
+                self.push_children(&path, &inode)
+            } else {
+                Err(Error::DirectoryCycle)
+            };
+            if let Err(e) = res {
+                self.stack.clear();
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(WalkItem { path, inode }))
+    }
+}
+
+// better to fail on open than to hand back garbage or a confusing error from deep in a read
+fn check_feature_incompat(sb: &Superblock) -> Result<(), Error> {
+    let incompat = u32::from(sb.feature_incompat);
+    let mut names = vec![];
+    let mut unknown = incompat & !SUPPORTED_FEATURE_INCOMPAT;
+    for (bit, name) in FEATURE_INCOMPAT_NAMES {
+        if unknown & bit != 0 {
+            names.push(name);
+            unknown &= !bit;
+        }
+    }
+    if incompat & EROFS_FEATURE_INCOMPAT_DEVICE_TABLE != 0 && u16::from(sb.extra_devices) != 0 {
+        names.push("device_table");
+    }
+    if names.is_empty() && unknown == 0 {
+        Ok(())
+    } else {
+        Err(Error::UnsupportedFeatures { names, unknown })
+    }
+}
+
+// like "zero_padding, fragments, unknown(0x400)" or "none"
+fn format_features(flags: u32, names: &[(u32, &str)]) -> String {
+    let mut ret = vec![];
+    let mut unknown = flags;
+    for (bit, name) in names {
+        if flags & bit != 0 {
+            ret.push(name.to_string());
+            unknown &= !bit;
+        }
+    }
+    if unknown != 0 {
+        ret.push(format!("unknown({unknown:#x})"));
+    }
+    if ret.is_empty() {
+        "none".to_string()
+    } else {
+        ret.join(", ")
+    }
+}
+
+// one "key: value" per line, for looking at an image without reading the struct by hand
+impl fmt::Display for Superblock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let uuid: String = self.uuid.iter().map(|x| format!("{x:02x}")).collect();
+        let volume_name = self
+            .volume_name
+            .split(|&x| x == 0)
+            .next()
+            .unwrap_or_default();
+        let feature_compat = u32::from(self.feature_compat);
+        let feature_incompat = u32::from(self.feature_incompat);
+        writeln!(f, "block size: {}", 1u64 << self.blkszbits)?;
+        writeln!(f, "blocks: {}", self.blocks)?;
+        writeln!(f, "inodes: {}", self.inos)?;
+        writeln!(f, "root nid: {}", self.root_disk_id)?;
+        writeln!(f, "meta blkaddr: {}", self.meta_blkaddr)?;
+        writeln!(f, "xattr blkaddr: {}", self.xattr_blkaddr)?;
+        writeln!(
+            f,
+            "uuid: {}-{}-{}-{}-{}",
+            &uuid[..8],
+            &uuid[8..12],
+            &uuid[12..16],
+            &uuid[16..20],
+            &uuid[20..]
+        )?;
+        writeln!(f, "volume name: \"{}\"", volume_name.escape_ascii())?;
+        writeln!(
+            f,
+            "build time: {}.{:09}",
+            self.build_time, self.build_time_nsec
+        )?;
+        writeln!(
+            f,
+            "feature compat: {:#x} ({})",
+            feature_compat,
+            format_features(feature_compat, &FEATURE_COMPAT_NAMES)
+        )?;
+        write!(
+            f,
+            "feature incompat: {:#x} ({})",
+            feature_incompat,
+            format_features(feature_incompat, &FEATURE_INCOMPAT_NAMES)
+        )
+    }
 }
 
 impl<'a> Erofs<'a> {
@@ -983,7 +1467,12 @@ impl<'a> Erofs<'a> {
         if sb.magic != EROFS_SUPER_MAGIG_V1 {
             return Err(Error::BadMagic);
         }
-        Ok(Self { data, sb })
+        check_feature_incompat(sb)?;
+        Ok(Self {
+            data,
+            sb,
+            packed_data: OnceLock::new(),
+        })
     }
 
     fn block_size(&self) -> u64 {
@@ -1041,6 +1530,36 @@ impl<'a> Erofs<'a> {
         self.get_inode(self.sb.root_disk_id.into())
     }
 
+    pub fn get_packed_inode(&self) -> Result<Option<Inode<'a>>, Error> {
+        let disk_id = u64::from(self.sb.packed_nid);
+        if u32::from(self.sb.feature_incompat) & EROFS_FEATURE_INCOMPAT_FRAGMENTS == 0
+            || disk_id == 0
+        {
+            return Ok(None);
+        }
+        self.get_inode(disk_id.try_into().map_err(|_| Error::InodeTooBig)?)
+            .map(Some)
+    }
+
+    // every fragmented file reads from the packed inode, so we decompress it once
+    fn packed_data(&self) -> Result<&[u8], Error> {
+        if let Some(data) = self.packed_data.get() {
+            return Ok(data);
+        }
+        let inode = self.get_packed_inode()?.ok_or(Error::NoPackedInode)?;
+        let data = self.read_file(&inode)?;
+        Ok(self.packed_data.get_or_init(|| data))
+    }
+
+    fn get_fragment(&self, inode: &Inode<'a>, offset: u64, len: usize) -> Result<&[u8], Error> {
+        if u64::from(self.sb.packed_nid) == inode.disk_id() as u64 {
+            return Err(Error::PackedInodeFragment);
+        }
+        let start = usize::try_from(offset).map_err(|_| Error::Oob)?;
+        let end = start.checked_add(len).ok_or(Error::Oob)?;
+        self.packed_data()?.get(start..end).ok_or(Error::Oob)
+    }
+
     fn compute_block_tail_len(&self, size: u64) -> (usize, usize) {
         compute_block_tail_len(self.block_size() as usize, size as usize)
     }
@@ -1134,6 +1653,21 @@ impl<'a> Erofs<'a> {
         }
     }
 
+    // key is the full name, ie prefix + name like b"user.foo"
+    pub fn get_xattr(&self, inode: &Inode<'a>, key: &[u8]) -> Result<Option<&'a [u8]>, Error> {
+        let Some(xattrs) = self.get_xattrs(inode)? else {
+            return Ok(None);
+        };
+        for item in xattrs.iter() {
+            let item = item?;
+            let prefix = self.get_xattr_prefix(&item)?;
+            if key.strip_prefix(prefix) == Some(item.name) {
+                return Ok(Some(item.value));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn get_map_header(&self, inode: &Inode<'a>) -> Result<&'a MapHeader, Error> {
         if !inode.layout().is_compressed() {
             return Err(Error::NotCompressed);
@@ -1154,27 +1688,45 @@ impl<'a> Erofs<'a> {
         // NOTE the raw_compressed_blocks count is the number of physical clusters I think,
         // the number of LCI's is just the number of blocks necessary to cover the whole file size
         // TODO whether this is in superblock block size blocks or the map header block size
-        let n = inode.data_size().div_ceil(self.block_size()) as usize;
-        // TBD why there is a +8 here (it might be so that looking up the -1 LCI is valid?)
-        let start = round_up_to::<8usize>(self.inode_end(inode) as usize)
-            + std::mem::size_of::<MapHeader>()
-            + 8;
-        let data = self.data.get(start..).ok_or(Error::Oob)?;
+        let n = inode.data_size().div_ceil(self.block_size());
+        let data = self.data.get(self.lci_offset(inode)..).ok_or(Error::Oob)?;
+        let available = data.len() / std::mem::size_of::<LogicalClusterIndex>();
+        // data_size comes straight from the inode so don't trust it to fit in memory either
+        let n = usize::try_from(n)
+            .ok()
+            .filter(|n| *n <= available)
+            .ok_or(Error::LciTruncated {
+                needed: n.try_into().unwrap_or(usize::MAX),
+                available,
+            })?;
         <[LogicalClusterIndex]>::ref_from_prefix_with_elems(data, n)
             .map_err(|_| Error::BadConversion)
             .map(|(x, _)| x)
     }
 
-    pub fn get_symlink(&self, inode: &Inode<'a>) -> Result<&'a [u8], Error> {
+    fn lci_offset(&self, inode: &Inode<'a>) -> usize {
+        // TBD why there is a +8 here (it might be so that looking up the -1 LCI is valid?)
+        round_up_to::<8usize>(self.inode_end(inode) as usize) + std::mem::size_of::<MapHeader>() + 8
+    }
+
+    // short targets are tail packed (fast symlink) and longer ones can be in a block, or in a block
+    // plus tail if the block size is small enough. Only the last case has to copy
+    pub fn get_symlink(&self, inode: &Inode<'a>) -> Result<Cow<'a, [u8]>, Error> {
         if inode.file_type() != FileType::Symlink {
             return Err(Error::NotSymlink);
         }
-        let (block, tail) = self.get_data(inode)?;
-        // TODO I don't know if this is always right
-        if !block.is_empty() {
-            return Err(Error::NotExpectingBlockData);
+        let size = inode.data_size();
+        if size == 0 {
+            return Err(Error::EmptySymlink);
+        }
+        if size >= PATH_MAX {
+            return Err(Error::SymlinkTooLong);
+        }
+        match self.get_data(inode)? {
+            (block, []) => Ok(Cow::Borrowed(block)),
+            ([], tail) => Ok(Cow::Borrowed(tail)),
+            (block, tail) => Ok(Cow::Owned([block, tail].concat())),
         }
-        Ok(tail)
     }
 
     pub fn get_compressed_data_vec(&self, inode: &Inode<'a>) -> Result<Vec<u8>, Error> {
@@ -1183,6 +1735,20 @@ impl<'a> Erofs<'a> {
         Ok(buf)
     }
 
+    // whole contents of the inode regardless of how it is stored
+    pub fn read_file(&self, inode: &Inode<'a>) -> Result<Vec<u8>, Error> {
+        match inode.layout() {
+            Layout::FlatPlain | Layout::FlatInline => {
+                let (block, tail) = self.get_data(inode)?;
+                Ok([block, tail].concat())
+            }
+            Layout::CompressedFull | Layout::CompressedCompact => {
+                self.get_compressed_data_vec(inode)
+            }
+            layout => Err(Error::LayoutNotHandled(layout)),
+        }
+    }
+
     pub fn get_decompressor(
         &self,
         compression_type: CompressionType,
@@ -1194,147 +1760,206 @@ impl<'a> Erofs<'a> {
         }
     }
 
+    // decompress one pcluster at a time into writer, see compressed_reader for a pull interface
     pub fn get_compressed_data<W>(&self, inode: &Inode<'a>, writer: &mut W) -> Result<(), Error>
     where
         W: Write,
     {
+        let mut reader = self.compressed_reader(inode)?;
+        while let Some(chunk) = reader.next_chunk()? {
+            writer.write_all(chunk).map_err(|_| Error::Write)?;
+        }
+        Ok(())
+    }
+
+    pub fn compressed_reader<'e>(
+        &'e self,
+        inode: &Inode<'a>,
+    ) -> Result<CompressedReader<'e, 'a>, Error> {
         let map_header = self.get_map_header(inode)?;
+        let file_size = inode.data_size() as usize;
+
+        if let Some(offset) = map_header.fragment_inode_offset() {
+            return Ok(CompressedReader {
+                erofs: self,
+                lcis: &[],
+                decompressor: None,
+                block_len: self.block_size() as usize,
+                file_size: file_size,
+                next_lci: Some(0),
+                fragment: Some((0, self.get_fragment(inode, offset, file_size)?)),
+                buf: vec![],
+                chunk: Chunk::Plain(&[]),
+                pos: 0,
+            });
+        }
 
         // TODO handle head_2
         let compression_type_1 = map_header.compression_type_1()?;
         let decompressor_1 = self.get_decompressor(compression_type_1)?;
         let block_len = 1usize << (self.sb.blkszbits + map_header.cluster_size_bits());
-        let file_size = inode.data_size() as usize;
-
-        // lookup next head
-        // length of pcluster is difference in logical address of the two heads
-        // LA of an LCI = LCI_index * block_len + LCI_cluster_offset
-        // with rearranging, you get (j-i)*block_len + next_cluster_offset + cur_cluster_offset
-        fn pcluster_len(
-            lcis: &[LogicalClusterIndex],
-            i: usize,
-            block_len: usize,
-            file_size: usize,
-        ) -> Result<(Option<usize>, usize), Error> {
-            debug_assert!(lcis[i].is_head());
-            // should always be ok b/c there is always a Plain LCI at the end (TODO not sure about
-            // that)
-            let cur = lcis.get(i).ok_or(Error::Oob)?;
-            let next = lcis.get(i + 1).ok_or(Error::Oob)?;
-            trace!("{}: {:?}", i, cur);
-            trace!("{}: {:?}", i + 1, next);
-            let (j, next_head) = match next.typ() {
-                LogicalClusterType::Head1
-                | LogicalClusterType::Head2
-                | LogicalClusterType::Plain => (i + 1, next),
-                LogicalClusterType::NonHead => {
-                    let n = u16::from(next.block_addr_or_delta.delta()[1]) as usize;
-                    trace!("trying to get {}/{}", i + 1 + n, lcis.len());
-                    let Some(next_head) = lcis.get(i + 1 + n) else {
-                        // it can be that there is no next head
-                        if i + 1 + n == lcis.len() {
-                            // TODO check sub
-                            let len = file_size
-                                .checked_sub(i * block_len + cur.cluster_offset())
-                                .ok_or(Error::Underflow)?;
-                            return Ok((None, len));
-                        } else {
-                            // true oob
-                            return Err(Error::Oob);
-                        }
-                    };
-                    trace!("{}: {:?}", i + 1 + n, next_head);
-                    debug_assert!(next_head.typ() != LogicalClusterType::NonHead);
-                    (i + 1 + n, next_head)
-                }
-            };
-            // j > i
-            let len = (j - i) * block_len + next_head.cluster_offset() - cur.cluster_offset();
-            Ok((Some(j), len))
-        }
 
         let lcis = self.get_logical_cluster_indices(inode)?;
 
-        // not sure this is possible (and if so whether malformed or not)
-        if lcis.is_empty() {
-            return Ok(());
-        }
+        let fragment = if let Some(offset_low) = map_header.fragment_offset() {
+            let tail = tail_head_lci(lcis, block_len, file_size)?;
+            let head = &lcis[tail];
+            let offset_high: u32 = head.block_addr_or_delta.block_addr().into();
+            let offset = (offset_high as u64) << 32 | offset_low as u64;
+            let len = file_size
+                .checked_sub(tail * block_len + head.cluster_offset())
+                .ok_or(Error::Underflow)?;
+            Some((tail, self.get_fragment(inode, offset, len)?))
+        } else {
+            None
+        };
 
-        let mut total: usize = 0;
-        let mut buf = vec![];
+        Ok(CompressedReader {
+            erofs: self,
+            lcis: lcis,
+            decompressor: Some(decompressor_1),
+            block_len: block_len,
+            file_size: file_size,
+            // not sure empty lcis is possible (and if so whether malformed or not)
+            next_lci: if lcis.is_empty() { None } else { Some(0) },
+            fragment: fragment,
+            buf: vec![],
+            chunk: Chunk::Plain(&[]),
+            pos: 0,
+        })
+    }
 
-        let mut i_ = Some(0);
-
-        // loop terminates either when we reach the last LCI that is Plain with 0 blkaddr
-        // or pcluster_len can return None
-        while let Some(i) = i_ {
-            let cur = &lcis.get(i).ok_or(Error::Oob)?;
-            match cur.typ() {
-                // TODO different
-                LogicalClusterType::Head1 => {
-                    let block_addr: u32 = cur.block_addr_or_delta.block_addr().into();
-                    let data_begin = self.block_offset(block_addr) as usize;
-                    let data = self
-                        .data
-                        .get(data_begin..data_begin + block_len)
-                        .ok_or(Error::Oob)?;
-                    let (next_i, decompress_len) = pcluster_len(lcis, i, block_len, file_size)?;
-                    trace!("lci {i} decompress_len={decompress_len} pa={data_begin}");
+    // walks every inode reachable from the root and checks that the things it references are in
+    // bounds and parse. Doesn't decompress anything, just checks the map header and LCI's. Keeps
+    // going after an error so you get all of them
+    pub fn check(&self) -> Result<(), Vec<Error>> {
+        let mut errors = vec![];
+        let mut seen = std::collections::HashSet::new();
+        let mut stack = vec![];
 
-                    if buf.len() < decompress_len {
-                        buf.resize(decompress_len, 0);
+        match self.get_root_inode() {
+            Ok(root) => {
+                seen.insert(root.disk_id());
+                stack.push(root);
+            }
+            Err(e) => {
+                return Err(vec![e]);
+            }
+        }
+
+        while let Some(inode) = stack.pop() {
+            if let Err(e) = self.check_inode(&inode) {
+                errors.push(e);
+                continue;
+            }
+            if inode.file_type() != FileType::Directory {
+                continue;
+            }
+            let res = self.get_dirents(&inode).and_then(|dirents| {
+                for item in dirents.iter()? {
+                    let item = item?;
+                    if item.name == b"." || item.name == b".." {
+                        continue;
                     }
-                    let decompressed_len =
-                        // This highly depends on decompress_partial for slightly unknown reasons
-                        decompressor_1.decompress(data, &mut buf, decompress_len)
-                            .ok_or(Error::Decompress)?;
-                    debug_assert!(decompressed_len == decompress_len);
-                    writer
-                        .write_all(&buf[..decompressed_len])
-                        .map_err(|_| Error::Write)?;
-                    total += decompressed_len;
-                    trace!("written {total}");
-
-                    i_ = next_i;
-                }
-                LogicalClusterType::Plain => {
-                    trace!("{}: {:?}", i, cur);
-                    let block_addr: u32 = cur.block_addr_or_delta.block_addr().into();
-                    if block_addr == 0 {
-                        if i + 1 == lcis.len() {
-                            // this LCI is the last entry and is expected
-                            break;
-                        } else {
-                            return Err(Error::LciMalformed);
+                    match self.get_inode_from_dirent(&item) {
+                        Ok(child) => {
+                            // hardlinks share an inode so only check once
+                            if seen.insert(child.disk_id()) {
+                                stack.push(child);
+                            }
                         }
+                        Err(e) => errors.push(e),
                     }
-                    // TODO can't there be a Plain at the end with partial data and we have to
-                    // instead use the file size instead of the next LCI?
-                    let next = lcis.get(i + 1).ok_or(Error::Oob)?;
-                    trace!("{}: {:?}", i + 1, next);
-                    let data_begin = self.block_offset(block_addr) as usize;
-                    let data_len = block_len + next.cluster_offset() - cur.cluster_offset();
-                    trace!("copying {data_len}");
-                    let data = self
-                        .data
-                        .get(data_begin..data_begin + data_len)
-                        .ok_or(Error::Oob)?;
-                    writer.write_all(data).map_err(|_| Error::Write)?;
-                    total += data_len;
-                    trace!("written {total}");
-                    i_ = Some(i + 1);
                 }
-                LogicalClusterType::Head2 => {
-                    return Err(Error::Head2NotSupported);
-                }
-                LogicalClusterType::NonHead => {
-                    return Err(Error::LciMalformed);
+                Ok(())
+            });
+            if let Err(e) = res {
+                errors.push(e);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn check_inode(&self, inode: &Inode<'a>) -> Result<(), Error> {
+        if let Some(xattrs) = self.get_xattrs(inode)? {
+            for item in xattrs.iter() {
+                self.get_xattr_prefix(&item?)?;
+            }
+        }
+
+        match inode.layout() {
+            Layout::FlatPlain | Layout::FlatInline => {
+                self.get_data(inode)?;
+            }
+            // whole file fragments have no LCI's
+            Layout::CompressedFull
+                if self
+                    .get_map_header(inode)?
+                    .fragment_inode_offset()
+                    .is_none() =>
+            {
+                for lci in self.get_logical_cluster_indices(inode)? {
+                    if matches!(
+                        lci.typ(),
+                        LogicalClusterType::Head1
+                            | LogicalClusterType::Head2
+                            | LogicalClusterType::Plain
+                    ) {
+                        let block_addr: u32 = lci.block_addr_or_delta.block_addr().into();
+                        if self.block_offset(block_addr) > self.data.len() as u64 {
+                            return Err(Error::Oob);
+                        }
+                    }
                 }
             }
+            Layout::CompressedFull | Layout::CompressedCompact => {
+                self.get_map_header(inode)?;
+            }
+            layout => {
+                return Err(Error::LayoutNotHandled(layout));
+            }
+        }
+
+        if inode.file_type() == FileType::Symlink {
+            self.get_symlink(inode)?;
         }
+
         Ok(())
     }
 
+    // paths in walk order of each inode that more than one dirent points at, keyed by disk_id. To
+    // export as a tar, write the first path as the file and the rest as hardlinks to it. Only
+    // inodes shared on disk are grouped (like mkfs.erofs makes), Builder::add_link gives every
+    // path its own inode
+    pub fn hardlinks(&self) -> Result<BTreeMap<u32, Vec<PathBuf>>, Error> {
+        let mut ret: BTreeMap<u32, Vec<PathBuf>> = BTreeMap::new();
+        for item in self.walk()? {
+            let item = item?;
+            if item.inode.is_hardlinked() {
+                ret.entry(item.inode.disk_id()).or_default().push(item.path);
+            }
+        }
+        ret.retain(|_, paths| paths.len() > 1);
+        Ok(ret)
+    }
+
+    pub fn walk(&self) -> Result<Walk<'_, 'a>, Error> {
+        Ok(self.walk_from(self.get_root_inode()?))
+    }
+
+    pub fn walk_from(&self, inode: Inode<'a>) -> Walk<'_, 'a> {
+        Walk {
+            erofs: self,
+            stack: vec![(PathBuf::new(), inode)],
+            seen_dirs: HashSet::new(),
+        }
+    }
+
     // TODO uses linear search
     pub fn lookup(&self, p: impl AsRef<Path>) -> Result<Option<Inode>, Error> {
         let mut cur = self.get_root_inode()?;
@@ -1551,7 +2176,7 @@ pub fn xattr_builtin_prefix(key: &[u8]) -> Option<XattrBuiltinPrefixWithLen> {
 // This is a translated version of what appears in erofs-utils
 // I didn't think a specialized or tabled algo was necessary since we only ever compute up to a
 // single page
-fn crc32c<'a>(data: impl IntoIterator<Item = &'a u8>) -> u32 {
+pub(crate) fn crc32c<'a>(data: impl IntoIterator<Item = &'a u8>) -> u32 {
     let poly = 0x82F63B78;
     let mut crc = u32::MAX;
     for x in data {
@@ -1589,7 +2214,7 @@ mod tests {
     use std::process::Command;
 
     use memmap2::MmapOptions;
-    use rustix::fs::XattrFlags;
+    use rustix::fs::{Mode, XattrFlags};
     use tempfile::{tempdir, NamedTempFile};
 
     #[test]
@@ -1719,6 +2344,16 @@ mod tests {
                     let map = xattr_map(&erofs, &xattrs);
                     assert_eq!(map["user.shared"].as_ref(), b"value-shared");
                     assert_eq!(map["user.attr"].as_ref(), b"unique-a");
+                    assert_eq!(
+                        erofs.get_xattr(&inode, b"user.attr").unwrap(),
+                        Some(&b"unique-a"[..])
+                    );
+                    assert_eq!(
+                        erofs.get_xattr(&inode, b"user.shared").unwrap(),
+                        Some(&b"value-shared"[..])
+                    );
+                    assert_eq!(erofs.get_xattr(&inode, b"user.nope").unwrap(), None);
+                    assert_eq!(erofs.get_xattr(&inode, b"attr").unwrap(), None);
                     assert_eq!(inode.data_size() as usize, b"hello world".len());
                     assert_eq!(inode_data(&erofs, &inode).as_ref(), b"hello world");
                 }
@@ -1750,7 +2385,7 @@ mod tests {
                     // the symlink does get the absolute path...
                     assert_eq!(
                         pa.as_os_str().as_encoded_bytes(),
-                        erofs.get_symlink(&inode).unwrap()
+                        erofs.get_symlink(&inode).unwrap().as_ref()
                     );
                 }
                 name => {
@@ -1800,6 +2435,221 @@ mod tests {
         assert!(erofs.lookup("also/not-a-file").unwrap().is_none());
     }
 
+    #[test]
+    fn test_rdev() {
+        let dir = tempdir().unwrap();
+        let dest = NamedTempFile::new().unwrap();
+        fs::write(dir.path().join("file"), b"hi").unwrap();
+        // needs CAP_MKNOD
+        for (name, file_type, (major, minor)) in [
+            ("null", FileType::CharacterDevice, (1, 3)),
+            ("sda", FileType::BlockDevice, (8, 0)),
+            ("big", FileType::CharacterDevice, (0xabc, 0xabcde)),
+        ] {
+            rustix::fs::mknodat(
+                rustix::fs::CWD,
+                dir.path().join(name),
+                file_type,
+                Mode::RUSR | Mode::WUSR,
+                rustix::fs::makedev(major, minor),
+            )
+            .unwrap();
+        }
+
+        let out = Command::new("mkfs.erofs")
+            .arg(dest.path())
+            .arg(dir.path())
+            .output()
+            .unwrap();
+        if !out.status.success() {
+            println!("{}", out.stdout.escape_ascii());
+            println!("{}", out.stderr.escape_ascii());
+        }
+        assert!(out.status.success());
+
+        let mmap = unsafe { MmapOptions::new().map(&dest).unwrap() };
+        let erofs = Erofs::new(&mmap).unwrap();
+        let rdev = |name| erofs.lookup(name).unwrap().unwrap().rdev();
+        assert_eq!(rdev("null"), Some((1, 3)));
+        assert_eq!(rdev("sda"), Some((8, 0)));
+        assert_eq!(rdev("big"), Some((0xabc, 0xabcde)));
+        assert_eq!(rdev("file"), None);
+        assert_eq!(erofs.get_root_inode().unwrap().rdev(), None);
+    }
+
+    #[test]
+    fn test_read_file() {
+        let dir = tempdir().unwrap();
+        let dest = NamedTempFile::new().unwrap();
+        let zeros = vec![0u8; 4096 + 33];
+        let counting: Vec<u8> = (0..5000).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(dir.path().join("small"), b"hello world").unwrap();
+        fs::write(dir.path().join("zeros"), &zeros).unwrap();
+        fs::write(dir.path().join("counting"), &counting).unwrap();
+
+        let out = Command::new("mkfs.erofs")
+            .arg(dest.path())
+            .arg(dir.path())
+            .arg("-zlz4")
+            .arg("-Elegacy-compress")
+            .output()
+            .unwrap();
+        if !out.status.success() {
+            println!("{}", out.stdout.escape_ascii());
+            println!("{}", out.stderr.escape_ascii());
+        }
+        assert!(out.status.success());
+
+        let mmap = unsafe { MmapOptions::new().map(&dest).unwrap() };
+        let erofs = Erofs::new(&mmap).unwrap();
+
+        let small = erofs.lookup("small").unwrap().unwrap();
+        assert!(!small.layout().is_compressed());
+        assert_eq!(erofs.read_file(&small).unwrap(), b"hello world");
+
+        let zeros_inode = erofs.lookup("zeros").unwrap().unwrap();
+        assert_eq!(zeros_inode.layout(), Layout::CompressedFull);
+        #[cfg(feature = "lz4")]
+        {
+            assert_eq!(erofs.read_file(&zeros_inode).unwrap(), zeros);
+            let inode = erofs.lookup("counting").unwrap().unwrap();
+            assert_eq!(erofs.read_file(&inode).unwrap(), counting);
+        }
+        #[cfg(not(feature = "lz4"))]
+        assert_eq!(
+            erofs.read_file(&zeros_inode),
+            Err(Error::CompressionNotSupported(CompressionType::Lz4))
+        );
+    }
+
+    #[test]
+    fn test_truncated_lcis() {
+        let dir = tempdir().unwrap();
+        let dest = NamedTempFile::new().unwrap();
+        let counting: Vec<u8> = (0..3 * 4096).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(dir.path().join("counting"), &counting).unwrap();
+
+        let out = Command::new("mkfs.erofs")
+            .arg(dest.path())
+            .arg(dir.path())
+            .arg("-zlz4")
+            .arg("-Elegacy-compress")
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+
+        let mmap = unsafe { MmapOptions::new().map(&dest).unwrap() };
+        let erofs = Erofs::new(&mmap).unwrap();
+        let inode = erofs.lookup("counting").unwrap().unwrap();
+        assert_eq!(inode.layout(), Layout::CompressedFull);
+        assert_eq!(erofs.get_logical_cluster_indices(&inode).unwrap().len(), 3);
+
+        // cut the image off after the first lci
+        let lci_size = std::mem::size_of::<LogicalClusterIndex>();
+        let end = erofs.lci_offset(&inode) + lci_size;
+        let truncated = Erofs::new(&mmap[..end]).unwrap();
+        let inode = truncated.get_inode(inode.disk_id()).unwrap();
+        assert_eq!(
+            truncated.get_logical_cluster_indices(&inode).err(),
+            Some(Error::LciTruncated {
+                needed: 3,
+                available: 1
+            })
+        );
+        assert!(truncated.read_file(&inode).is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempdir().unwrap();
+        let dest = NamedTempFile::new().unwrap();
+
+        fs::write(dir.path().join("a"), b"hello world").unwrap();
+        fs::write(dir.path().join("b"), vec![0; 8192]).unwrap();
+        fs::create_dir(dir.path().join("c")).unwrap();
+        fs::write(dir.path().join("c/d"), vec![1; 4097]).unwrap();
+        symlink("a", dir.path().join("e")).unwrap();
+        set_xattr(dir.path().join("a"), "user.attr", "value");
+
+        let out = Command::new("mkfs.erofs")
+            .arg(dest.path())
+            .arg(dir.path())
+            .output()
+            .unwrap();
+        if !out.status.success() {
+            println!("{}", out.stdout.escape_ascii());
+            println!("{}", out.stderr.escape_ascii());
+        }
+        assert!(out.status.success());
+
+        let mut data = fs::read(dest.path()).unwrap();
+        let offset = {
+            let erofs = Erofs::new(&data).unwrap();
+            assert_eq!(erofs.check(), Ok(()));
+            let inode = erofs.lookup("b").unwrap().unwrap();
+            assert_eq!(inode.layout(), Layout::FlatPlain);
+            // raw_blkaddr lives at byte 16 in both compact and extended inodes
+            erofs.inode_offset(&inode) as usize + 16
+        };
+
+        data[offset..offset + 4].copy_from_slice(&0x00ffffffu32.to_le_bytes());
+        let erofs = Erofs::new(&data).unwrap();
+        assert_eq!(erofs.check(), Err(vec![Error::Oob]));
+    }
+
+    #[test]
+    fn test_feature_incompat() {
+        let image = |incompat: u32, extra_devices: u16| {
+            let mut sb = Superblock::new_zeroed();
+            sb.magic = EROFS_SUPER_MAGIG_V1.into();
+            sb.blkszbits = 12;
+            sb.feature_incompat = incompat.into();
+            sb.extra_devices = extra_devices.into();
+            let mut data = vec![0; EROFS_SUPER_OFFSET];
+            data.extend_from_slice(sb.as_bytes());
+            data
+        };
+        let open = |incompat, extra_devices| {
+            Erofs::new(&image(incompat, extra_devices))
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(open(0, 0), Ok(()));
+        assert_eq!(open(SUPPORTED_FEATURE_INCOMPAT, 0), Ok(()));
+        assert_eq!(
+            open(EROFS_FEATURE_INCOMPAT_FRAGMENTS | 0x8000_0000, 0),
+            Err(Error::UnsupportedFeatures {
+                names: vec![],
+                unknown: 0x8000_0000
+            }
+            .to_string())
+        );
+        assert_eq!(
+            open(
+                EROFS_FEATURE_INCOMPAT_ZERO_PADDING
+                    | EROFS_FEATURE_INCOMPAT_CHUNKED_FILE
+                    | EROFS_FEATURE_INCOMPAT_ZTAILPACKING
+                    | EROFS_FEATURE_INCOMPAT_48BIT
+                    | 0x400,
+                0
+            ),
+            Err(Error::UnsupportedFeatures {
+                names: vec!["chunked_file", "ztailpacking", "48bit"],
+                unknown: 0x400
+            }
+            .to_string())
+        );
+        assert_eq!(
+            open(EROFS_FEATURE_INCOMPAT_DEVICE_TABLE, 1),
+            Err(Error::UnsupportedFeatures {
+                names: vec!["device_table"],
+                unknown: 0
+            }
+            .to_string())
+        );
+    }
+
     #[allow(dead_code)]
     fn test_legacy_compression_mkfs<F>(
         data: &[u8],
@@ -1840,6 +2690,162 @@ mod tests {
         erofs.get_compressed_data_vec(&inode)
     }
 
+    #[test]
+    fn test_compressed_reader() {
+        let dir = tempdir().unwrap();
+        let dest = NamedTempFile::new().unwrap();
+
+        // few MB of a mix of compressible and not so compressible runs
+        let mut data = vec![];
+        for i in 0..(1024 * 1024 * 3) {
+            data.push(if (i / 10000) % 2 == 0 { i as u8 } else { 0 });
+        }
+        fs::write(dir.path().join("file"), &data).unwrap();
+
+        let out = Command::new("mkfs.erofs")
+            .arg(dest.path())
+            .arg(dir.path())
+            .arg("-zlz4")
+            .arg("-Elegacy-compress")
+            .output()
+            .unwrap();
+        if !out.status.success() {
+            println!("{}", out.stdout.escape_ascii());
+            println!("{}", out.stderr.escape_ascii());
+        }
+        assert!(out.status.success());
+
+        let mmap = unsafe { MmapOptions::new().map(&dest).unwrap() };
+        let erofs = Erofs::new(&mmap).unwrap();
+        let inode = erofs.lookup("file").unwrap().unwrap();
+        assert_eq!(inode.layout(), Layout::CompressedFull);
+
+        #[cfg(feature = "lz4")]
+        {
+            let mut reader = erofs.compressed_reader(&inode).unwrap();
+            let mut got = vec![];
+            // smaller than any pcluster so we read across boundaries
+            let mut buf = [0u8; 1000];
+            loop {
+                let n = reader.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                got.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(got.len(), data.len());
+            assert!(got == data);
+            assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        }
+        #[cfg(not(feature = "lz4"))]
+        assert_eq!(
+            erofs.compressed_reader(&inode).err(),
+            Some(Error::CompressionNotSupported(CompressionType::Lz4))
+        );
+    }
+
+    #[test]
+    fn test_fragments() {
+        let dir = tempdir().unwrap();
+        let dest = NamedTempFile::new().unwrap();
+
+        // small ones get packed whole and bigger ones just their tail extent
+        let small = b"hello fragments".repeat(10);
+        let mut big = vec![];
+        for i in 0..(1024 * 300 + 123) {
+            big.push(if (i / 5000) % 2 == 0 {
+                (i * 7 % 251) as u8
+            } else {
+                0
+            });
+        }
+        fs::write(dir.path().join("small"), &small).unwrap();
+        fs::write(dir.path().join("big"), &big).unwrap();
+
+        let out = Command::new("mkfs.erofs")
+            .arg(dest.path())
+            .arg(dir.path())
+            .arg("-zlz4")
+            .arg("-Elegacy-compress")
+            .arg("-Efragments")
+            .output()
+            .unwrap();
+        if !out.status.success() {
+            println!("{}", out.stdout.escape_ascii());
+            println!("{}", out.stderr.escape_ascii());
+        }
+        assert!(out.status.success());
+
+        let mmap = unsafe { MmapOptions::new().map(&dest).unwrap() };
+        let erofs = Erofs::new(&mmap).unwrap();
+        assert!(erofs.get_packed_inode().unwrap().is_some());
+        assert_eq!(erofs.check(), Ok(()));
+
+        for (name, data) in [("small", &small), ("big", &big)] {
+            let inode = erofs.lookup(name).unwrap().unwrap();
+            let map_header = erofs.get_map_header(&inode).unwrap();
+            assert!(
+                map_header.fragment_inode_offset().is_some()
+                    || map_header.fragment_offset().is_some(),
+                "{name}"
+            );
+            #[cfg(feature = "lz4")]
+            assert!(&erofs.read_file(&inode).unwrap() == data, "{name}");
+            #[cfg(not(feature = "lz4"))]
+            {
+                let _ = data;
+                assert_eq!(
+                    erofs.read_file(&inode),
+                    Err(Error::CompressionNotSupported(CompressionType::Lz4))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_hardlinks() {
+        let dir = tempdir().unwrap();
+        let dest = NamedTempFile::new().unwrap();
+
+        fs::write(dir.path().join("a"), b"hello").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::hard_link(dir.path().join("a"), dir.path().join("sub/b")).unwrap();
+        fs::hard_link(dir.path().join("a"), dir.path().join("sub/c")).unwrap();
+        // same contents but its own inode
+        fs::write(dir.path().join("d"), b"hello").unwrap();
+        symlink("a", dir.path().join("e")).unwrap();
+
+        let out = Command::new("mkfs.erofs")
+            .arg(dest.path())
+            .arg(dir.path())
+            .output()
+            .unwrap();
+        if !out.status.success() {
+            println!("{}", out.stdout.escape_ascii());
+            println!("{}", out.stderr.escape_ascii());
+        }
+        assert!(out.status.success());
+
+        let mmap = unsafe { MmapOptions::new().map(&dest).unwrap() };
+        let erofs = Erofs::new(&mmap).unwrap();
+
+        let a = erofs.lookup("a").unwrap().unwrap();
+        assert_eq!(a.link_count(), 3);
+        assert!(a.is_hardlinked());
+        assert_eq!(
+            erofs.lookup("sub/c").unwrap().unwrap().disk_id(),
+            a.disk_id()
+        );
+        assert!(!erofs.lookup("d").unwrap().unwrap().is_hardlinked());
+        assert!(!erofs.lookup("sub").unwrap().unwrap().is_hardlinked());
+
+        let expected: BTreeMap<u32, Vec<PathBuf>> = BTreeMap::from([(
+            a.disk_id(),
+            vec!["a".into(), "sub/b".into(), "sub/c".into()],
+        )]);
+        assert_eq!(erofs.hardlinks().unwrap(), expected);
+    }
+
     #[test]
     fn test_legacy_compression() {
         #[allow(unused_macros)]
@@ -40,7 +40,8 @@ export namespace Api {
         export type Response =
             | {kind: "Ok", siginfo: Siginfo, rusage: Rusage}
             | {kind: "Overtime", siginfo: Siginfo, rusage: Rusage}
-            | {kind: "Panic", message: string};
+            | {kind: "Panic", message: string}
+            | {kind: "ContainerStartError", message: string};
     }
 
     export type Image = {
@@ -545,6 +545,9 @@ class App extends Component {
                 if (parsed.image !== null) {
                     this.s.selectedImage.value = parsed.image
                 }
+            } else if (Array.isArray(data)) {
+                // binary files come back as an array of bytes
+                files.push({path, data: new Uint8Array(data).buffer});
             } else {
                 files.push({path, data});
             }
@@ -591,7 +594,8 @@ class App extends Component {
         });
         const response = await fetch(req);
         // TODO handle 429
-        if (!response.ok) {
+        // ContainerStartError (400) and Panic (500) still come back with the guest's response
+        if (!response.ok && response.headers.get('Content-type') !== 'application/x.pe.archivev1') {
             console.error(response);
             return;
         }
@@ -604,6 +608,7 @@ class App extends Component {
                 case 'Ok': return {Ok: {siginfo: responseTyped.siginfo}};
                 case 'Overtime': return {Overtime: {siginfo: responseTyped.siginfo}};
                 case 'Panic': return {Panic: {message: responseTyped.message}};
+                case 'ContainerStartError': return {ContainerStartError: {message: responseTyped.message}};
             }
             return null;
         })();
@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use log::trace;
+use reqwest::{Method, StatusCode, Url, header};
+use serde::de::DeserializeOwned;
+
+use crate::{Client, Error, Gist, status_not_ok};
+
+// the snippets api doesn't give us the commit, so this is the version of a latest fetch
+const LATEST_VERSION: &str = "HEAD";
+
+mod wire {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub(crate) struct File {
+        pub(crate) path: String,
+        pub(crate) raw_url: String,
+    }
+
+    // older gitlabs only have the single file_name + raw_url, newer have files (and still the
+    // single one for the first file)
+    #[derive(Deserialize)]
+    pub(crate) struct Snippet {
+        #[serde(default)]
+        pub(crate) files: Vec<File>,
+        pub(crate) file_name: Option<String>,
+        pub(crate) raw_url: Option<String>,
+    }
+}
+
+// same shape as Client but for GitLab snippets. Snippets never have their content inline so every
+// file is a raw request. There is also no history endpoint, so versions is always empty and a
+// revision is only used to pick which ref the files are read from
+pub struct GitlabClient {
+    // reused for its ratelimit and semaphore
+    inner: Client,
+    // like https://gitlab.com/api/v4
+    api_url: Url,
+}
+
+impl GitlabClient {
+    pub fn new(host: &str) -> Result<Self, Error> {
+        let client = reqwest::Client::builder().https_only(true).build()?;
+        let api_url = Url::parse(&format!("https://{host}/api/v4")).map_err(|_| Error::BadHost)?;
+        Ok(Self::with_client(client, api_url))
+    }
+
+    fn with_client(client: reqwest::Client, api_url: Url) -> Self {
+        Self {
+            inner: Client::with_client(client),
+            api_url,
+        }
+    }
+
+    pub async fn get_gist_latest(&self, id: &str) -> Result<Option<Gist>, Error> {
+        self.get_gist(id, None).await
+    }
+
+    pub async fn get_gist_version(&self, id: &str, revision: &str) -> Result<Option<Gist>, Error> {
+        self.get_gist(id, Some(revision)).await
+    }
+
+    // the host of the url is ignored, it is whatever we were created with
+    pub async fn get_gist_from_url(&self, url: &str) -> Result<Option<Gist>, Error> {
+        let (_host, id, revision) = parse_snippet_ref(url)?;
+        self.get_gist(id, revision).await
+    }
+
+    // https://docs.gitlab.com/api/snippets/#get-a-single-snippet
+    // https://docs.gitlab.com/api/snippets/#snippet-repository-file-content
+    pub async fn get_gist(&self, id: &str, revision: Option<&str>) -> Result<Option<Gist>, Error> {
+        let Some(snippet) = self
+            .fetch::<wire::Snippet>(self.api_url(&["snippets", id]))
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let files = if snippet.files.is_empty() {
+            match (snippet.file_name, snippet.raw_url) {
+                (Some(path), Some(raw_url)) => vec![wire::File { path, raw_url }],
+                _ => vec![],
+            }
+        } else {
+            snippet.files
+        };
+
+        let mut futs = FuturesUnordered::new();
+        for file in files {
+            let url = match revision {
+                Some(revision) => self
+                    .api_url(&["snippets", id, "files", revision, &file.path, "raw"])
+                    .to_string(),
+                None => file.raw_url,
+            };
+            futs.push(async { (file.path, self.inner.get_raw_url(url).await) });
+        }
+
+        let mut contents = BTreeMap::new();
+        while let Some((name, file)) = futs.next().await {
+            contents.insert(name, file?);
+        }
+
+        Ok(Some(Gist {
+            files: contents,
+            version: revision.unwrap_or(LATEST_VERSION).to_string(),
+            versions: vec![],
+        }))
+    }
+
+    // each part is its own path segment, so a file path with a / in it gets escaped as the api
+    // wants
+    fn api_url(&self, parts: &[&str]) -> Url {
+        let mut url = self.api_url.clone();
+        url.path_segments_mut()
+            .expect("api url is a base")
+            .extend(parts);
+        url
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, url: Url) -> Result<Option<T>, Error> {
+        self.inner.check_ratelimit().await?;
+
+        let res = {
+            let _guard = self.inner.sem.acquire().await;
+
+            self.inner
+                .client
+                .request(Method::GET, url)
+                .header(header::USER_AGENT, crate::USER_AGENT)
+                .header(header::ACCEPT, "application/json")
+                .send()
+                .await?
+        };
+
+        self.inner.handle_ratelimit(&res).await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(Some(res.json::<T>().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => {
+                trace!("snippet request failed");
+                Err(status_not_ok(res).await)
+            }
+        }
+    }
+}
+
+// returns (host, id, revision) from the url shapes of a snippet
+//   https://<host>/-/snippets/<id>
+//   https://<host>/-/snippets/<id>/raw/<revision>/<file>
+//   https://<host>/snippets/<id>
+//   https://<host>/api/v4/snippets/<id>
+// the scheme is optional and a trailing slash, ?query or #fragment are ignored. Any host is
+// allowed since gitlab is self hosted all over, but a bare id isn't since that could be a gist
+pub fn parse_snippet_ref(input: &str) -> Result<(&str, &str, Option<&str>), Error> {
+    let input = input.trim();
+    let rest = input
+        .strip_prefix("https://")
+        .or_else(|| input.strip_prefix("http://"))
+        .unwrap_or(input);
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let (host, path) = rest.split_once('/').ok_or(Error::NotASnippetUrl)?;
+    let parts: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+
+    let (id, revision) = match parts.as_slice() {
+        ["-", "snippets", id] | ["snippets", id] | ["api", "v4", "snippets", id] => (*id, None),
+        // at least one part for the file, which can have /
+        ["-", "snippets", id, "raw", revision, _, ..] => (*id, Some(*revision)),
+        _ => return Err(Error::NotASnippetUrl),
+    };
+    if host.is_empty() || id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::NotASnippetUrl);
+    }
+    Ok((host, id, revision))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GistFile;
+    use crate::tests::serve_paths;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_parse_snippet_ref() {
+        for input in [
+            "https://gitlab.com/-/snippets/4820127",
+            "https://gitlab.com/-/snippets/4820127/",
+            "gitlab.com/-/snippets/4820127#LC1",
+            "http://gitlab.com/snippets/4820127",
+            "https://gitlab.com/api/v4/snippets/4820127",
+            "  https://gitlab.com/-/snippets/4820127?x=1\n",
+        ] {
+            assert_eq!(
+                parse_snippet_ref(input).unwrap(),
+                ("gitlab.com", "4820127", None),
+                "{input}"
+            );
+        }
+
+        assert_eq!(
+            parse_snippet_ref("https://gitlab.example.org/-/snippets/12/raw/main/foo.py").unwrap(),
+            ("gitlab.example.org", "12", Some("main"))
+        );
+        assert_eq!(
+            parse_snippet_ref("https://gitlab.com/-/snippets/12/raw/abc123/dir/foo.py").unwrap(),
+            ("gitlab.com", "12", Some("abc123"))
+        );
+
+        for input in [
+            "",
+            "4820127",
+            "https://gitlab.com/-/snippets/",
+            "https://gitlab.com/-/snippets/abc",
+            "https://gitlab.com/-/snippets/12/raw/main",
+            "https://gitlab.com/user/project/-/snippets/12/edit",
+            "https://gist.github.com/aconz2/a7359c6e3a5704af841389b85dda1e49",
+        ] {
+            assert!(
+                matches!(parse_snippet_ref(input), Err(Error::NotASnippetUrl)),
+                "{input}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_snippet() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        // trimmed down from a real response, the raw urls point back at us
+        let snippet = format!(
+            r#"{{
+                "id": 12,
+                "title": "hello",
+                "file_name": "main.py",
+                "raw_url": "{base}/-/snippets/12/raw",
+                "web_url": "{base}/-/snippets/12",
+                "files": [
+                    {{"path": "main.py", "raw_url": "{base}/-/snippets/12/raw/main/main.py"}},
+                    {{"path": "dir/data.bin", "raw_url": "{base}/-/snippets/12/raw/main/dir/data.bin"}}
+                ]
+            }}"#
+        );
+        let binary = b"\x7fELF\xff".to_vec();
+        let routes = vec![
+            ("/api/v4/snippets/12".to_string(), snippet.into_bytes()),
+            (
+                "/-/snippets/12/raw/main/main.py".to_string(),
+                b"print(1)\n".to_vec(),
+            ),
+            (
+                "/-/snippets/12/raw/main/dir/data.bin".to_string(),
+                binary.clone(),
+            ),
+            (
+                "/api/v4/snippets/12/files/abc123/main.py/raw".to_string(),
+                b"print(0)\n".to_vec(),
+            ),
+            (
+                "/api/v4/snippets/12/files/abc123/dir%2Fdata.bin/raw".to_string(),
+                b"old".to_vec(),
+            ),
+        ];
+        let seen = Arc::new(Mutex::new(vec![]));
+        serve_paths(listener, routes, 7, seen.clone());
+
+        let client = GitlabClient::with_client(
+            reqwest::Client::new(),
+            Url::parse(&format!("{base}/api/v4")).unwrap(),
+        );
+
+        let gist = client.get_gist_latest("12").await.unwrap().unwrap();
+        assert_eq!(gist.version, "HEAD");
+        assert!(gist.versions.is_empty());
+        assert_eq!(
+            gist.files,
+            BTreeMap::from([
+                ("main.py".to_string(), GistFile::Text("print(1)\n".into())),
+                ("dir/data.bin".to_string(), GistFile::Binary(binary)),
+            ])
+        );
+
+        let gist = client
+            .get_gist_from_url("https://gitlab.com/-/snippets/12/raw/abc123/main.py")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(gist.version, "abc123");
+        assert_eq!(
+            gist.files,
+            BTreeMap::from([
+                ("main.py".to_string(), GistFile::Text("print(0)\n".into())),
+                ("dir/data.bin".to_string(), GistFile::Text("old".into())),
+            ])
+        );
+
+        assert!(client.get_gist_latest("13").await.unwrap().is_none());
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                "/-/snippets/12/raw/main/dir/data.bin",
+                "/-/snippets/12/raw/main/main.py",
+                "/api/v4/snippets/12",
+                "/api/v4/snippets/12",
+                "/api/v4/snippets/12/files/abc123/dir%2Fdata.bin/raw",
+                "/api/v4/snippets/12/files/abc123/main.py/raw",
+                "/api/v4/snippets/13",
+            ]
+        );
+    }
+}
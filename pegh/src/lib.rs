@@ -1,13 +1,17 @@
 use std::collections::BTreeMap;
 use std::time::Duration;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use futures::{StreamExt, stream::FuturesUnordered};
 use log::{error, trace, warn};
 use reqwest::{Method, Response, StatusCode, header, header::HeaderValue};
-use serde::Serialize;
+use serde::{Serialize, de::DeserializeOwned};
 use tokio::sync::{RwLock, Semaphore};
 
+pub mod gitlab;
+
+pub use gitlab::{GitlabClient, parse_snippet_ref};
+
 // I think gist truncation happens around 1 MB. this gist has 1 non-truncated and 2 truncated files
 // for testing: https://gist.github.com/aconz2/a7359c6e3a5704af841389b85dda1e49
 
@@ -16,6 +20,8 @@ use tokio::sync::{RwLock, Semaphore};
 // read+write to gists, so I'd rather just stick to unauthenticated for now?
 const USER_AGENT: &str = "aconz2";
 
+const API_URL: &str = "https://api.github.com";
+
 // if they don't send ratelimit-reset, default to 1 minute (guessing)
 const DEFAULT_RATELIMIT_RESET: u64 = 60;
 
@@ -27,6 +33,9 @@ pub enum Error {
     StatusNotOk(StatusCode),
     RatelimitExceeded,
     NoHistory,
+    NotAGistUrl,
+    NotASnippetUrl,
+    BadHost,
     Unknown,
 }
 
@@ -36,9 +45,28 @@ impl std::fmt::Display for Error {
     }
 }
 
+// a file's contents are only a String if they are valid utf-8, anything else (small binaries) is
+// kept as raw bytes instead of lossily converted. untagged so text files serialize as a plain
+// string like before and binary files as an array of bytes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum GistFile {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl GistFile {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(s) => GistFile::Text(s),
+            Err(e) => GistFile::Binary(e.into_bytes()),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct Gist {
-    pub files: BTreeMap<String, String>,
+    pub files: BTreeMap<String, GistFile>,
     pub version: String,
     pub versions: Vec<String>,
 }
@@ -51,7 +79,29 @@ mod wire {
     pub(crate) struct File {
         pub(crate) raw_url: String,
         pub(crate) truncated: bool,
-        pub(crate) content: String,
+        // json can only carry utf-8, so binary files come back with no content (or garbage) and
+        // we go to raw_url for the bytes
+        pub(crate) content: Option<String>,
+        // size in bytes of the real file
+        pub(crate) size: Option<u64>,
+        // mime type, application/octet-stream for anything github doesn't think is text
+        #[serde(rename = "type")]
+        pub(crate) mime_type: Option<String>,
+    }
+
+    impl File {
+        // the inline content if we believe it is the whole file byte for byte. garbage from a
+        // lossy conversion won't be the same length as the real file
+        pub(crate) fn trusted_content(self) -> Option<String> {
+            if self.truncated || self.mime_type.as_deref() == Some("application/octet-stream") {
+                return None;
+            }
+            let content = self.content?;
+            match self.size {
+                Some(size) if size != content.len() as u64 => None,
+                _ => Some(content),
+            }
+        }
     }
 
     #[derive(Deserialize)]
@@ -64,23 +114,36 @@ mod wire {
         pub(crate) files: BTreeMap<String, File>,
         pub(crate) history: Vec<History>,
     }
+
+    // same endpoint as Gist but we don't bother deserializing the files
+    #[derive(Deserialize)]
+    pub(crate) struct GistHistory {
+        pub(crate) history: Vec<History>,
+    }
 }
 
 pub struct Client {
     client: reqwest::Client,
     sem: Semaphore,
     ratelimit: RwLock<Option<UtcInstant>>,
+    // only not API_URL in tests
+    api_url: String,
 }
 
 impl Client {
     pub fn new() -> Result<Self, Error> {
         let client = reqwest::Client::builder().https_only(true).build()?;
-        Ok(Self {
+        Ok(Self::with_client(client))
+    }
+
+    fn with_client(client: reqwest::Client) -> Self {
+        Self {
             client,
             // https://docs.github.com/en/rest/using-the-rest-api/best-practices-for-using-the-rest-api?apiVersion=2022-11-28#avoid-concurrent-requests
             sem: Semaphore::new(1),
             ratelimit: RwLock::new(None),
-        })
+            api_url: API_URL.to_string(),
+        }
     }
 
     pub async fn get_gist_latest(&self, id: &str) -> Result<Option<Gist>, Error> {
@@ -91,14 +154,75 @@ impl Client {
         self.get_gist(id, Some(revision)).await
     }
 
+    // takes anything parse_gist_ref does, so a bare id works too
+    pub async fn get_gist_from_url(&self, url: &str) -> Result<Option<Gist>, Error> {
+        let (id, revision) = parse_gist_ref(url)?;
+        self.get_gist(id, revision).await
+    }
+
     // https://docs.github.com/en/rest/gists/gists?apiVersion=2022-11-28#get-a-gist
     // https://docs.github.com/en/rest/gists/gists?apiVersion=2022-11-28#get-a-gist-revision
     pub async fn get_gist(&self, id: &str, revision: Option<&str>) -> Result<Option<Gist>, Error> {
-        self.check_ratelimit().await?;
+        let Some(gist) = self.fetch_gist::<wire::Gist>(id, revision).await? else {
+            return Ok(None);
+        };
 
+        let version = if let Some(v) = revision {
+            v.to_string()
+        } else {
+            let h = gist.history.last().ok_or(Error::NoHistory)?;
+            h.version.clone()
+        };
+        let versions = gist.history.into_iter().map(|h| h.version).collect();
+        let mut files = BTreeMap::new();
+        let mut futs = FuturesUnordered::new();
+        for (name, file) in gist.files {
+            let url = file.raw_url.clone();
+            match file.trusted_content() {
+                Some(content) => {
+                    files.insert(name, GistFile::Text(content));
+                }
+                None => {
+                    trace!("file is truncated, binary or has no content");
+                    futs.push(async { (name, self.get_raw_url(url).await) });
+                }
+            }
+        }
+
+        while let Some((name, contents)) = futs.next().await {
+            match contents {
+                Ok(contents) => {
+                    files.insert(name, contents);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Some(Gist {
+            files,
+            version,
+            versions,
+        }))
+    }
+
+    // like get_gist but only the versions, so no refetching of truncated files
+    pub async fn list_versions(&self, id: &str) -> Result<Option<Vec<String>>, Error> {
+        Ok(self
+            .fetch_gist::<wire::GistHistory>(id, None)
+            .await?
+            .map(|gist| gist.history.into_iter().map(|h| h.version).collect()))
+    }
+
+    async fn fetch_gist<T: DeserializeOwned>(
+        &self,
+        id: &str,
+        revision: Option<&str>,
+    ) -> Result<Option<T>, Error> {
+        self.check_ratelimit().await?;
 
         let url = format!(
-            "https://api.github.com/gists/{}{}{}",
+            "{}/gists/{}{}{}",
+            self.api_url,
             id,
             if revision.is_some() { "/" } else { "" },
             revision.unwrap_or_default()
@@ -107,8 +231,7 @@ impl Client {
         let res = {
             let _guard = self.sem.acquire().await;
 
-            self
-                .client
+            self.client
                 .request(Method::GET, &url)
                 .header(header::USER_AGENT, USER_AGENT)
                 .header(header::ACCEPT, "application/vnd.github+json")
@@ -125,48 +248,13 @@ impl Client {
         }
 
         match res.status() {
-            StatusCode::OK => {
-                let gist = res.json::<wire::Gist>().await?;
-                let version = if let Some(v) = revision {
-                    v.to_string()
-                } else {
-                    let h = gist.history.last().ok_or(Error::NoHistory)?;
-                    h.version.clone()
-                };
-                let versions = gist.history.into_iter().map(|h| h.version).collect();
-                let mut files = BTreeMap::new();
-                let mut futs = FuturesUnordered::new();
-                for (name, file) in gist.files {
-                    if file.truncated {
-                        trace!("file is truncated");
-                        let url = file.raw_url.to_string();
-                        futs.push(async { (name, self.get_raw_url(url).await) });
-                    } else {
-                        files.insert(name, file.content);
-                    }
-                }
-
-                while let Some((name, contents)) = futs.next().await {
-                    match contents {
-                        Ok(contents) => {
-                            files.insert(name, contents);
-                        }
-                        Err(e) => return Err(e),
-                    }
-                }
-
-                Ok(Some(Gist {
-                    files,
-                    version,
-                    versions,
-                }))
-            }
+            StatusCode::OK => Ok(Some(res.json::<T>().await?)),
             StatusCode::NOT_FOUND => Ok(None),
             _ => Err(status_not_ok(res).await),
         }
     }
 
-    async fn get_raw_url(&self, url: String) -> Result<String, Error> {
+    async fn get_raw_url(&self, url: String) -> Result<GistFile, Error> {
         self.check_ratelimit().await?;
 
         let _guard = self.sem.acquire().await;
@@ -181,33 +269,25 @@ impl Client {
         self.handle_ratelimit(&res).await?;
 
         match res.status() {
-            StatusCode::OK => Ok(res.text().await?),
+            StatusCode::OK => Ok(GistFile::from_bytes(res.bytes().await?.into())),
             _ => Err(status_not_ok(res).await),
         }
     }
 
     async fn handle_ratelimit(&self, res: &Response) -> Result<(), Error> {
-        // ghcr apparently returns either 403 or 429
-        if !matches!(
-            res.status(),
-            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
-        ) {
-            return Ok(());
+        let now = Utc::now();
+        let retry_after = get_retry_after_header(res.headers(), now);
+        match res.status() {
+            // ghcr apparently returns either 403 or 429
+            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => {}
+            // a 503 is only a ratelimit if they tell us when to come back
+            StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {}
+            _ => {
+                return Ok(());
+            }
         }
 
-        let end = if let Some(reset) = get_ratelimit_reset_header(res.headers()) {
-            reset
-                .try_into() // u64 -> i64
-                .ok()
-                .and_then(|x| chrono::DateTime::<chrono::Utc>::from_timestamp(x, 0))
-                .unwrap_or_else(|| {
-                    error!("bad reset timestamp");
-                    Utc::now() + Duration::from_secs(DEFAULT_RATELIMIT_RESET)
-                })
-        } else {
-            warn!("got res status {} but no ratelimit-reset", res.status());
-            Utc::now() + Duration::from_secs(DEFAULT_RATELIMIT_RESET)
-        };
+        let end = ratelimit_end(res.headers(), retry_after, now);
         let _ = self.ratelimit.write().await.insert(end);
 
         Err(Error::RatelimitExceeded)
@@ -230,6 +310,45 @@ impl Client {
     }
 }
 
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// returns (id, revision) from a bare gist id or one of the url shapes people paste
+//   https://gist.github.com/<user>/<id>[/<revision>]
+//   https://gist.github.com/<id>
+//   https://gist.githubusercontent.com/<user>/<id>/raw[/<revision>]/<file>
+//   https://api.github.com/gists/<id>[/<revision>]
+// the scheme is optional and a trailing slash, .git, ?query or #fragment are ignored
+pub fn parse_gist_ref(input: &str) -> Result<(&str, Option<&str>), Error> {
+    let input = input.trim();
+    if is_hex(input) {
+        return Ok((input, None));
+    }
+    let rest = input
+        .strip_prefix("https://")
+        .or_else(|| input.strip_prefix("http://"))
+        .unwrap_or(input);
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let (host, path) = rest.split_once('/').ok_or(Error::NotAGistUrl)?;
+    let parts: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+
+    let (id, revision) = match (host, parts.as_slice()) {
+        ("gist.github.com", [id]) | ("gist.github.com", [_, id]) => (*id, None),
+        ("gist.github.com", [_, id, revision]) => (*id, Some(*revision)),
+        ("gist.githubusercontent.com", [_, id, "raw", revision, _file]) => (*id, Some(*revision)),
+        ("gist.githubusercontent.com", [_, id, "raw", _file]) => (*id, None),
+        ("api.github.com", ["gists", id]) => (*id, None),
+        ("api.github.com", ["gists", id, revision]) => (*id, Some(*revision)),
+        _ => return Err(Error::NotAGistUrl),
+    };
+    let id = id.strip_suffix(".git").unwrap_or(id);
+    if !is_hex(id) || !revision.is_none_or(is_hex) {
+        return Err(Error::NotAGistUrl);
+    }
+    Ok((id, revision))
+}
+
 async fn status_not_ok(res: Response) -> Error {
     let status = res.status();
     if log::log_enabled!(log::Level::Trace) {
@@ -267,3 +386,317 @@ fn parse_ratelimit_reset_header(input: &HeaderValue) -> Option<u64> {
 fn parse_ratelimit_reset_str(input: &str) -> Option<u64> {
     input.parse().ok()
 }
+
+// when we can try again, the later of ratelimit-reset and retry-after if we get both
+fn ratelimit_end(
+    map: &reqwest::header::HeaderMap,
+    retry_after: Option<UtcInstant>,
+    now: UtcInstant,
+) -> UtcInstant {
+    let reset = get_ratelimit_reset_header(map).map(|reset| {
+        reset
+            .try_into() // u64 -> i64
+            .ok()
+            .and_then(|x| chrono::DateTime::<chrono::Utc>::from_timestamp(x, 0))
+            .unwrap_or_else(|| {
+                error!("bad reset timestamp");
+                now + Duration::from_secs(DEFAULT_RATELIMIT_RESET)
+            })
+    });
+    match (reset, retry_after) {
+        (Some(reset), Some(retry_after)) => reset.max(retry_after),
+        (Some(end), None) | (None, Some(end)) => end,
+        (None, None) => {
+            warn!("got ratelimited but no ratelimit-reset or retry-after");
+            now + Duration::from_secs(DEFAULT_RATELIMIT_RESET)
+        }
+    }
+}
+
+// also copied from ocidist.rs
+// https://www.rfc-editor.org/rfc/rfc9110#name-retry-after
+// value is either a number of seconds (relative to now) or an HTTP-date like
+// Wed, 21 Oct 2015 07:28:00 GMT
+fn get_retry_after_header(map: &reqwest::header::HeaderMap, now: UtcInstant) -> Option<UtcInstant> {
+    parse_retry_after_str(map.get(header::RETRY_AFTER)?.to_str().ok()?, now)
+}
+
+fn parse_retry_after_str(input: &str, now: UtcInstant) -> Option<UtcInstant> {
+    let input = input.trim();
+    if let Ok(seconds) = input.parse::<u64>() {
+        // None if it is too far out to represent
+        now.checked_add_signed(TimeDelta::try_seconds(seconds.try_into().ok()?)?)
+    } else {
+        // IMF-fixdate is a subset of rfc2822
+        DateTime::parse_from_rfc2822(input)
+            .ok()
+            .map(|x| x.with_timezone(&Utc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_retry_after() {
+        let now = DateTime::from_timestamp(1445412000, 0).unwrap();
+        assert_eq!(
+            Some(now + Duration::from_secs(120)),
+            parse_retry_after_str("120", now)
+        );
+        assert_eq!(
+            DateTime::from_timestamp(1445412480, 0),
+            parse_retry_after_str("Wed, 21 Oct 2015 07:28:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after_str("soon", now));
+        assert_eq!(None, parse_retry_after_str(&u64::MAX.to_string(), now));
+        assert_eq!(None, parse_retry_after_str(&i64::MAX.to_string(), now));
+
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(
+            ratelimit_end(&headers, None, now),
+            now + Duration::from_secs(DEFAULT_RATELIMIT_RESET)
+        );
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("120"));
+        let retry_after = get_retry_after_header(&headers, now);
+        assert_eq!(
+            ratelimit_end(&headers, retry_after, now),
+            now + Duration::from_secs(120)
+        );
+        // github's reset is a timestamp, the later one wins
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("1445412060"));
+        assert_eq!(
+            ratelimit_end(&headers, retry_after, now),
+            now + Duration::from_secs(120)
+        );
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("1445412600"));
+        assert_eq!(
+            ratelimit_end(&headers, retry_after, now),
+            now + Duration::from_secs(600)
+        );
+    }
+
+    #[test]
+    fn test_parse_gist_ref() {
+        let id = "a7359c6e3a5704af841389b85dda1e49";
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        for input in [
+            id.to_string(),
+            format!("https://gist.github.com/aconz2/{id}"),
+            format!("https://gist.github.com/aconz2/{id}/"),
+            format!("gist.github.com/aconz2/{id}"),
+            format!("http://gist.github.com/{id}"),
+            format!("https://gist.github.com/aconz2/{id}.git"),
+            format!("https://gist.github.com/aconz2/{id}#file-foo-txt"),
+            format!("https://gist.github.com/aconz2/{id}?permalink_comment_id=1"),
+            format!("https://gist.githubusercontent.com/aconz2/{id}/raw/foo.txt"),
+            format!("https://api.github.com/gists/{id}"),
+            format!("  https://gist.github.com/aconz2/{id}\n"),
+        ] {
+            assert_eq!(parse_gist_ref(&input).unwrap(), (id, None), "{input}");
+        }
+
+        for input in [
+            format!("https://gist.github.com/aconz2/{id}/{rev}"),
+            format!("https://gist.github.com/aconz2/{id}/{rev}/"),
+            format!("https://gist.github.com/aconz2/{id}/{rev}#file-foo-txt"),
+            format!("https://gist.githubusercontent.com/aconz2/{id}/raw/{rev}/foo.txt"),
+            format!("https://api.github.com/gists/{id}/{rev}"),
+        ] {
+            assert_eq!(parse_gist_ref(&input).unwrap(), (id, Some(rev)), "{input}");
+        }
+
+        for input in [
+            "".to_string(),
+            "not-an-id".to_string(),
+            format!("https://github.com/aconz2/{id}"),
+            format!("https://gist.github.com/aconz2/{id}/revisions"),
+            format!("https://gist.github.com/aconz2/{id}/{rev}/extra"),
+            "https://gist.github.com/aconz2".to_string(),
+            "https://gist.github.com/".to_string(),
+            format!("https://api.github.com/repos/{id}"),
+        ] {
+            assert!(
+                matches!(parse_gist_ref(&input), Err(Error::NotAGistUrl)),
+                "{input}"
+            );
+        }
+    }
+
+    // answers each request with the body for its path (or 404) and records the paths, one request
+    // per connection
+    pub(crate) fn serve_paths(
+        listener: std::net::TcpListener,
+        routes: Vec<(String, Vec<u8>)>,
+        requests: usize,
+        seen: Arc<Mutex<Vec<String>>>,
+    ) {
+        use std::io::{BufRead, BufReader, Write};
+        std::thread::spawn(move || {
+            for _ in 0..requests {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+                // drain the rest of the request headers
+                line.clear();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let mut stream = reader.into_inner();
+                // before responding so the client can't look at seen before it's there
+                seen.lock().unwrap().push(path.clone());
+                let (status, body) = match routes.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) => ("200 OK", body.as_slice()),
+                    None => ("404 Not Found", &b""[..]),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_raw_url_binary() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let binary = b"\x7fELF\x02\x01\x01\x00\xff\xfe";
+        let routes = vec![
+            ("/raw/text".to_string(), b"hello\n".to_vec()),
+            ("/raw/binary".to_string(), binary.to_vec()),
+        ];
+        serve_paths(listener, routes, 2, Arc::new(Mutex::new(vec![])));
+        let client = Client::with_client(reqwest::Client::new());
+
+        assert_eq!(
+            client
+                .get_raw_url(format!("{base}/raw/text"))
+                .await
+                .unwrap(),
+            GistFile::Text("hello\n".to_string())
+        );
+        assert_eq!(
+            client
+                .get_raw_url(format!("{base}/raw/binary"))
+                .await
+                .unwrap(),
+            GistFile::Binary(binary.to_vec())
+        );
+
+        assert_eq!(
+            serde_json::to_string(&GistFile::Binary(vec![0, 255])).unwrap(),
+            "[0,255]"
+        );
+        assert_eq!(
+            serde_json::to_string(&GistFile::Text("hi".into())).unwrap(),
+            "\"hi\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_gist_raw_url_fallback() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let id = "a7359c6e3a5704af841389b85dda1e49";
+        // trimmed down from a real response, the raw urls point back at us
+        let gist = format!(
+            r#"{{
+                "files": {{
+                    "small.txt": {{
+                        "raw_url": "{base}/raw/small.txt",
+                        "type": "text/plain",
+                        "size": 7,
+                        "truncated": false,
+                        "content": "inline\n"
+                    }},
+                    "lossy.bin": {{
+                        "raw_url": "{base}/raw/lossy.bin",
+                        "type": "text/plain",
+                        "size": 3,
+                        "truncated": false,
+                        "content": "a\ufffd"
+                    }},
+                    "typed.bin": {{
+                        "raw_url": "{base}/raw/typed.bin",
+                        "type": "application/octet-stream",
+                        "size": 2,
+                        "truncated": false,
+                        "content": "hi"
+                    }},
+                    "big.txt": {{
+                        "raw_url": "{base}/raw/big.txt",
+                        "truncated": true,
+                        "content": "trunc"
+                    }},
+                    "data.bin": {{
+                        "raw_url": "{base}/raw/data.bin",
+                        "truncated": false,
+                        "content": null
+                    }}
+                }},
+                "history": [{{"version": "bbbb"}}, {{"version": "aaaa"}}]
+            }}"#
+        );
+        let binary = b"\x7fELF\xff".to_vec();
+        let routes = vec![
+            (format!("/gists/{id}"), gist.into_bytes()),
+            ("/raw/big.txt".to_string(), b"truncated no more\n".to_vec()),
+            ("/raw/data.bin".to_string(), binary.clone()),
+            ("/raw/lossy.bin".to_string(), b"a\xff\xfe".to_vec()),
+            ("/raw/typed.bin".to_string(), b"hi".to_vec()),
+        ];
+        let seen = Arc::new(Mutex::new(vec![]));
+        serve_paths(listener, routes, 6, seen.clone());
+        let client = Client {
+            api_url: base,
+            ..Client::with_client(reqwest::Client::new())
+        };
+
+        let gist = client.get_gist_latest(id).await.unwrap().unwrap();
+        assert_eq!(gist.version, "aaaa");
+        assert_eq!(gist.versions, ["bbbb", "aaaa"]);
+        assert_eq!(
+            gist.files,
+            BTreeMap::from([
+                ("small.txt".to_string(), GistFile::Text("inline\n".into())),
+                (
+                    "big.txt".to_string(),
+                    GistFile::Text("truncated no more\n".into())
+                ),
+                ("data.bin".to_string(), GistFile::Binary(binary)),
+                (
+                    "lossy.bin".to_string(),
+                    GistFile::Binary(b"a\xff\xfe".to_vec())
+                ),
+                ("typed.bin".to_string(), GistFile::Text("hi".into())),
+            ])
+        );
+
+        // only the history, no raw requests
+        let versions = client.list_versions(id).await.unwrap().unwrap();
+        assert_eq!(versions, ["bbbb", "aaaa"]);
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                format!("/gists/{id}"),
+                format!("/gists/{id}"),
+                "/raw/big.txt".to_string(),
+                "/raw/data.bin".to_string(),
+                "/raw/lossy.bin".to_string(),
+                "/raw/typed.bin".to_string(),
+            ]
+        );
+    }
+}
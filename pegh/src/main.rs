@@ -1,10 +1,11 @@
-use pegh::Client;
+use pegh::{Client, GistFile, GitlabClient, parse_gist_ref, parse_snippet_ref};
 
 use clap::Parser;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, disable_version_flag = true)]
 struct Args {
+    // gist id or url, or a gitlab snippet url
     gist: String,
 
     #[arg(long)]
@@ -16,18 +17,24 @@ async fn main() {
     env_logger::init();
     let args = Args::parse();
 
-    let client = Client::new().unwrap();
-
     //let gist = if let Some(version) = args.version {
     //    client.get_gist_version(&args.gist, &version).await.unwrap()
     //} else {
     //    client.get_gist_latest(&args.gist).await.unwrap()
     //};
 
-    let gist = client
-        .get_gist(&args.gist, args.version.as_deref())
-        .await
-        .unwrap();
+    let gist = if let Ok((id, url_version)) = parse_gist_ref(&args.gist) {
+        let version = args.version.as_deref().or(url_version);
+        let client = Client::new().unwrap();
+        client.get_gist(id, version).await.unwrap()
+    } else if let Ok((host, id, url_version)) = parse_snippet_ref(&args.gist) {
+        let version = args.version.as_deref().or(url_version);
+        let client = GitlabClient::new(host).unwrap();
+        client.get_gist(id, version).await.unwrap()
+    } else {
+        eprintln!("{:?} is not a gist id or url or a snippet url", args.gist);
+        std::process::exit(1);
+    };
 
     if let Some(gist) = gist {
         println!("gist.version = {}", gist.version);
@@ -37,7 +44,10 @@ async fn main() {
         }
         for (name, contents) in &gist.files {
             println!("=== {name} ===");
-            println!("{contents}");
+            match contents {
+                GistFile::Text(s) => println!("{s}"),
+                GistFile::Binary(b) => println!("<binary {} bytes>", b.len()),
+            }
         }
     } else {
         println!("oops not found");
@@ -3,7 +3,7 @@ use std::time::Instant;
 use memmap2::MmapOptions;
 use oci_spec::image::{Arch, Os};
 use peerofs::disk::Erofs;
-use peimage_service::{Request, request_erofs_image};
+use peimage_service::{Request, prefetch_erofs_image, request_erofs_image};
 
 async fn main_() -> anyhow::Result<()> {
     let args = std::env::args().collect::<Vec<_>>();
@@ -11,6 +11,15 @@ async fn main_() -> anyhow::Result<()> {
     let reference = args.get(2).expect("give me an image reference");
 
     let request = Request::new(reference, &Arch::Amd64, &Os::Linux).unwrap();
+
+    if args.get(3).is_some_and(|x| x == "prefetch") {
+        let t0 = Instant::now();
+        let response = prefetch_erofs_image(socket_path, request).await?;
+        let elapsed = t0.elapsed().as_secs_f32();
+        println!("got response in {elapsed:.3}s {response:?}");
+        return Ok(());
+    }
+
     let t0 = Instant::now();
     let response = request_erofs_image(socket_path, request).await?;
     let elapsed = t0.elapsed().as_secs_f32();
@@ -46,6 +46,15 @@ pub struct Request {
     // timeout and cancel the request
 }
 
+// optionally sent after a Request. Older clients only ever send the Request so it has to keep
+// its encoding, and no kind means Image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub enum RequestKind {
+    Image,
+    // build the image if it isn't already cached but only ack, no fd. for warming the cache
+    Prefetch,
+}
+
 impl Request {
     pub fn new(reference: &str, arch: &Arch, os: &Os) -> Result<Self, Error> {
         let Ok(_ref): Result<oci_spec::distribution::Reference, _> = reference.parse() else {
@@ -65,6 +74,17 @@ impl Request {
     }
 }
 
+// a Request and the kind after it, if there is one
+pub fn decode_request(buf: &[u8]) -> Result<(Request, RequestKind), Error> {
+    let (req, n) = bincode::decode_from_slice::<Request, _>(buf, bincode::config::standard())?;
+    let kind = if n == buf.len() {
+        RequestKind::Image
+    } else {
+        bincode::decode_from_slice::<RequestKind, _>(&buf[n..], bincode::config::standard())?.0
+    };
+    Ok((req, kind))
+}
+
 // this should maybe not be pub but pub(crate) doesn't work with main.rs I think?
 #[derive(Debug, bincode::Encode, bincode::Decode)]
 pub enum WireResponse {
@@ -79,6 +99,12 @@ pub enum WireResponse {
     Err {
         message: String,
     },
+    // after Err so the existing variants keep their encoding
+    BadReference,
+    Prefetched {
+        manifest_digest: String,
+        size: u64,
+    },
 }
 
 pub struct Response {
@@ -87,14 +113,48 @@ pub struct Response {
     pub fd: OwnedFd,
 }
 
+#[derive(Debug)]
+pub struct Prefetched {
+    pub manifest_digest: String,
+    pub size: u64,
+}
+
+// an Image request is sent without its kind, so it is exactly what older servers expect
+async fn send_request(
+    socket_addr: impl AsRef<Path>,
+    req: &Request,
+    kind: RequestKind,
+    buf: &mut [u8; MAX_MESSAG_LEN],
+) -> Result<UnixSeqpacket, Error> {
+    let socket = UnixSeqpacket::connect(socket_addr).await?;
+    let n = match kind {
+        RequestKind::Image => bincode::encode_into_slice(req, buf, bincode::config::standard())?,
+        RequestKind::Prefetch => {
+            bincode::encode_into_slice((req, kind), buf, bincode::config::standard())?
+        }
+    };
+    let _ = socket.send(&buf[..n]).await?;
+    Ok(socket)
+}
+
+fn wire_error(wire_response: WireResponse) -> Error {
+    match wire_response {
+        WireResponse::NoMatchingManifest => Error::NoMatchingManifest,
+        WireResponse::ManifestNotFound => Error::ManifestNotFound,
+        WireResponse::ImageTooBig => Error::ImageTooBig,
+        WireResponse::RatelimitExceeded => Error::RatelimitExceeded,
+        WireResponse::Err { message } => Error::ServerError(message),
+        WireResponse::BadReference => Error::BadReference,
+        WireResponse::Ok { .. } | WireResponse::Prefetched { .. } => Error::Unknown,
+    }
+}
+
 pub async fn request_erofs_image(
     socket_addr: impl AsRef<Path>,
     req: Request,
 ) -> Result<Response, Error> {
-    let socket = UnixSeqpacket::connect(socket_addr).await?;
     let mut buf = [0; MAX_MESSAG_LEN];
-    let n = bincode::encode_into_slice(&req, &mut buf, bincode::config::standard())?;
-    let _ = socket.send(&buf[..n]).await?;
+    let socket = send_request(socket_addr, &req, RequestKind::Image, &mut buf).await?;
 
     let mut ancillary_buffer = [0; 128];
     let (n, ancillary) = socket
@@ -128,11 +188,31 @@ pub async fn request_erofs_image(
             manifest_digest,
             fd,
         }),
-        (_, WireResponse::NoMatchingManifest) => Err(Error::NoMatchingManifest),
-        (_, WireResponse::ManifestNotFound) => Err(Error::ManifestNotFound),
-        (_, WireResponse::ImageTooBig) => Err(Error::ImageTooBig),
-        (_, WireResponse::RatelimitExceeded) => Err(Error::RatelimitExceeded),
-        (_, WireResponse::Err { message }) => Err(Error::ServerError(message)),
-        (None, _) => Err(Error::MissingFd),
+        (None, WireResponse::Ok { .. }) => Err(Error::MissingFd),
+        (_, wire_response) => Err(wire_error(wire_response)),
+    }
+}
+
+// same request as request_erofs_image but we only get an ack once the image is cached
+pub async fn prefetch_erofs_image(
+    socket_addr: impl AsRef<Path>,
+    req: Request,
+) -> Result<Prefetched, Error> {
+    let mut buf = [0; MAX_MESSAG_LEN];
+    let socket = send_request(socket_addr, &req, RequestKind::Prefetch, &mut buf).await?;
+    let n = socket.recv(&mut buf).await?;
+
+    let (wire_response, _) =
+        bincode::decode_from_slice::<WireResponse, _>(&buf[..n], bincode::config::standard())?;
+
+    match wire_response {
+        WireResponse::Prefetched {
+            manifest_digest,
+            size,
+        } => Ok(Prefetched {
+            manifest_digest,
+            size,
+        }),
+        wire_response => Err(wire_error(wire_response)),
     }
 }
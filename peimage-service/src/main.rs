@@ -7,8 +7,9 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, atomic::AtomicU64};
 use std::time::Instant;
 
+use anyhow::Context;
 use clap::Parser;
-use log::{error, info};
+use log::{debug, error, info};
 use moka::future::Cache;
 use oci_spec::{
     distribution::Reference,
@@ -19,27 +20,76 @@ use tokio::sync::Semaphore;
 use tokio_seqpacket::{UnixSeqpacket, UnixSeqpacketListener, ancillary::AncillaryMessageWriter};
 
 use peimage::squash::squash_to_erofs;
-use peimage_service::{Request, WireResponse};
+use peimage_service::{RequestKind, WireResponse};
 use peoci::{
     blobcache,
-    blobcache::{BlobKey, atomic_inc, atomic_take},
+    blobcache::{BlobDir, BlobKey, atomic_inc, atomic_take},
     compression::Compression,
     ocidist,
-    ocidist::{Auth, AuthMap},
+    ocidist::{Auth, AuthMap, RegistryTls, TlsMap},
     ocidist_cache,
     ocidist_cache::Client,
     spec,
 };
 
+mod singleflight;
+use singleflight::SingleFlight;
+
+// defaults for --max-total-layer-size and --max-image-size
 // max sum of compressed layer sizes
 const MAX_TOTAL_LAYER_SIZE: u64 = 2_000_000_000;
 // this is the max erofs image size (of just the file data portion)
 const MAX_IMAGE_SIZE: u64 = 3_000_000_000;
 
+#[derive(Debug, Clone, Copy)]
+struct ImageLimits {
+    max_total_layer_size: u64,
+    max_image_size: u64,
+}
+
+impl ImageLimits {
+    // max decompressed size of a single layer (tar headers and all), anything bigger can't fit in
+    // the image anyways so we stop before writing it all out
+    fn max_layer_size(&self) -> u64 {
+        self.max_image_size
+    }
+
+    fn check_total_layer_size(&self, manifest: &spec::ImageManifest) -> Result<(), Error> {
+        let total_layer_size = manifest
+            .layers
+            .iter()
+            .map(|layer| layer.size)
+            .fold(0u64, |x, y| x.saturating_add(y));
+        if total_layer_size > self.max_total_layer_size {
+            return Err(Error::TotalLayerSizeTooBig);
+        }
+        Ok(())
+    }
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_total_layer_size: MAX_TOTAL_LAYER_SIZE,
+            max_image_size: MAX_IMAGE_SIZE,
+        }
+    }
+}
+
+// what every image build shares, the semaphore bounds how many run at once
+struct ImageWorkers {
+    semaphore: Semaphore,
+    limits: ImageLimits,
+}
+
 #[derive(Deserialize)]
 struct AuthEntry {
-    username: String,
-    password: String,
+    username: Option<String>,
+    password: Option<String>,
+    // pem files for a registry that wants a client cert, tls_ca for one with a private ca
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_ca: Option<PathBuf>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -78,13 +128,54 @@ struct Stats {
 
 type StoredAuth = BTreeMap<String, AuthEntry>;
 type ImageCache = Cache<BlobKey, u64>;
+// keyed by the reference string, this sits in front of the client's ref and manifest caches so
+// that concurrent first-time requests for a reference make one trip to the registry
+type ManifestFlight = SingleFlight<String, ManifestResult>;
+type ManifestResult =
+    Result<Arc<ocidist_cache::PackedImageAndConfiguration>, Arc<ocidist_cache::Error>>;
 
-fn load_stored_auth(p: impl AsRef<Path>) -> anyhow::Result<AuthMap> {
+fn load_stored_auth(p: impl AsRef<Path>) -> anyhow::Result<(AuthMap, TlsMap)> {
     let stored: StoredAuth = serde_json::from_str(&std::fs::read_to_string(p)?)?;
-    Ok(stored
-        .into_iter()
-        .map(|(k, v)| (k, Auth::UserPass(v.username, v.password)))
-        .collect::<AuthMap>())
+    let mut auth = AuthMap::new();
+    let mut tls = TlsMap::new();
+    for (registry, v) in stored {
+        let entry = match (v.username, v.password) {
+            (Some(username), Some(password)) => Auth::UserPass(username, password),
+            (None, None) => Auth::None,
+            _ => anyhow::bail!("{registry} needs both username and password or neither"),
+        };
+        auth.insert(registry.clone(), entry);
+
+        let identity_pem = match (v.tls_cert, v.tls_key) {
+            (Some(cert), Some(key)) => {
+                let mut pem = std::fs::read(&cert)
+                    .with_context(|| format!("{registry} tls_cert {}", cert.display()))?;
+                pem.extend(
+                    std::fs::read(&key)
+                        .with_context(|| format!("{registry} tls_key {}", key.display()))?,
+                );
+                Some(pem)
+            }
+            (None, None) => None,
+            _ => anyhow::bail!("{registry} needs both tls_cert and tls_key or neither"),
+        };
+        let ca_pem = v
+            .tls_ca
+            .map(|ca| {
+                std::fs::read(&ca).with_context(|| format!("{registry} tls_ca {}", ca.display()))
+            })
+            .transpose()?;
+        if identity_pem.is_some() || ca_pem.is_some() {
+            tls.insert(
+                registry,
+                RegistryTls {
+                    identity_pem,
+                    ca_pem,
+                },
+            );
+        }
+    }
+    Ok((auth, tls))
 }
 
 pub fn round_up_file_to_pmem_size<F: rustix::fd::AsFd>(f: F) -> rustix::io::Result<u64> {
@@ -104,25 +195,51 @@ pub fn round_up_file_to_pmem_size<F: rustix::fd::AsFd>(f: F) -> rustix::io::Resu
     Ok(newlen)
 }
 
+// Request::new checks the reference but we can't trust every client to have used it
+fn decode_request(buf: &[u8]) -> anyhow::Result<(RequestKind, Reference)> {
+    let (req, kind) = peimage_service::decode_request(buf)?;
+    let reference = req.parse_reference().ok_or(Error::BadReference)?;
+    Ok((kind, reference))
+}
+
+enum ConnResponse {
+    Image(Digest, spec::ImageConfiguration, OwnedFd),
+    Prefetched(Digest, u64),
+}
+
+// fetch is only run by the first of any concurrent requests for the same reference
+async fn get_manifest_and_configuration<F, Fut>(
+    manifest_flight: &ManifestFlight,
+    reference: &Reference,
+    fetch: F,
+) -> anyhow::Result<spec::ImageManifestAndConfiguration>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ManifestResult>,
+{
+    Ok(manifest_flight
+        .run(reference.to_string(), fetch)
+        .await?
+        .get()?)
+}
+
 async fn handle_conn(
-    worker_semaphore: Arc<Semaphore>,
+    workers: Arc<ImageWorkers>,
     conn: &UnixSeqpacket,
     client: Client,
     img_cache: ImageCache,
-    imgs_dir: Arc<OwnedFd>,
+    imgs_dir: Arc<BlobDir>,
     counters: Arc<Counters>,
-) -> anyhow::Result<(Digest, spec::ImageConfiguration, OwnedFd)> {
+    manifest_flight: Arc<ManifestFlight>,
+) -> anyhow::Result<ConnResponse> {
     let mut buf = [0; 1024];
     let len = conn.recv(&mut buf).await?;
-    let (req, _) =
-        bincode::decode_from_slice::<Request, _>(&buf[..len], bincode::config::standard())?;
-
-    let reference = req.parse_reference().ok_or(Error::BadReference)?;
+    let (kind, reference) = decode_request(&buf[..len])?;
 
-    let image_and_config = client
-        .get_image_manifest_and_configuration(&reference, Arch::Amd64, Os::Linux)
-        .await?
-        .get()?;
+    let image_and_config = get_manifest_and_configuration(&manifest_flight, &reference, || {
+        client.get_image_manifest_and_configuration(&reference, Arch::Amd64, Os::Linux)
+    })
+    .await?;
 
     let digest: Digest = image_and_config.manifest_digest.into();
     let config = image_and_config.configuration;
@@ -134,31 +251,66 @@ async fn handle_conn(
     let (fd_tx, fd_rx) = tokio::sync::oneshot::channel();
 
     let key = BlobKey::new(digest.to_string()).ok_or(Error::BadDigest)?;
-    let entry = img_cache
-        .entry_by_ref(&key)
-        .or_try_insert_with(make_erofs_image(
-            worker_semaphore,
+    let want_fd = kind == RequestKind::Image;
+    let (size, fd) = get_or_make_image(
+        &img_cache,
+        &imgs_dir,
+        &counters,
+        &key,
+        want_fd,
+        make_erofs_image(
+            workers,
             client,
             &reference,
             &image_and_config.manifest,
             &imgs_dir,
             &key,
             fd_tx,
-        ))
+        ),
+        fd_rx,
+    )
+    .await?;
+
+    match fd {
+        Some(fd) => Ok(ConnResponse::Image(digest, config, fd)),
+        None => Ok(ConnResponse::Prefetched(digest, size)),
+    }
+}
+
+// returns the image size and with want_fd, an fd for it. make runs on a cache miss and has to
+// send the fd it built on fd_rx's sender
+async fn get_or_make_image(
+    img_cache: &ImageCache,
+    imgs_dir: &BlobDir,
+    counters: &Counters,
+    key: &BlobKey,
+    want_fd: bool,
+    make: impl Future<Output = anyhow::Result<u64>>,
+    fd_rx: tokio::sync::oneshot::Receiver<OwnedFd>,
+) -> anyhow::Result<(u64, Option<OwnedFd>)> {
+    let entry = img_cache
+        .entry_by_ref(key)
+        .or_try_insert_with(make)
         .await
         .map_err(Error::Arc)?;
+    let size = *entry.value();
 
     if entry.is_fresh() {
         atomic_inc(&counters.img_cache_miss);
-        let size = *entry.value();
         info!("img_cache miss digest={key} size={size}");
+        if !want_fd {
+            return Ok((size, None));
+        }
         let fd = fd_rx.await.map_err(|_| Error::OneshotRx)?;
-        Ok((digest, config, fd))
+        Ok((size, Some(fd)))
     } else {
         atomic_inc(&counters.img_cache_hit);
         info!("img_cache hit digest={key}");
-        match blobcache::openat_read_key(&imgs_dir, &key) {
-            Ok(Some(file)) => Ok((digest, config, file.into())),
+        if !want_fd {
+            return Ok((size, None));
+        }
+        match blobcache::openat_read_key(imgs_dir, key) {
+            Ok(Some(file)) => Ok((size, Some(file.into()))),
             Ok(None) => {
                 error!("image cache missing file {}", key);
                 Err(Error::MissingFile.into())
@@ -172,25 +324,18 @@ async fn handle_conn(
 }
 
 async fn make_erofs_image(
-    worker_semaphore: Arc<Semaphore>,
+    workers: Arc<ImageWorkers>,
     client: Client,
     reference: &Reference,
     manifest: &peoci::spec::ImageManifest,
-    imgs_dir: &Arc<OwnedFd>,
+    imgs_dir: &Arc<BlobDir>,
     key: &BlobKey,
     fd_tx: tokio::sync::oneshot::Sender<OwnedFd>,
 ) -> anyhow::Result<u64> {
     let key = key.clone();
+    let limits = workers.limits;
 
-    let total_layer_size = manifest
-        .layers
-        .iter()
-        .map(|layer| layer.size)
-        .fold(0u64, |x, y| x.saturating_add(y));
-
-    if total_layer_size > MAX_TOTAL_LAYER_SIZE {
-        return Err(Error::TotalLayerSizeTooBig.into());
-    }
+    limits.check_total_layer_size(manifest)?;
 
     let fds = client.get_layers(reference, manifest).await?;
     let mut layers: Vec<_> = manifest
@@ -198,23 +343,33 @@ async fn make_erofs_image(
         .iter()
         .zip(fds.into_iter())
         .map(|(descriptor, fd)| -> Result<_, Error> {
-            let comp: Compression = descriptor.into();
             let reader: File = fd.into();
+            let comp = Compression::from(descriptor)
+                .reconcile_file(&reader)
+                .map_err(|_| Error::OpenFile)?;
             Ok((comp, reader))
         })
         .collect::<Result<Vec<_>, _>>()?;
     let imgs_dir = imgs_dir.clone();
 
-    let _guard = worker_semaphore.acquire().await;
+    let _guard = workers.semaphore.acquire().await;
     tokio::task::spawn_blocking(move || -> anyhow::Result<u64> {
         let (mut file, guard) = blobcache::openat_create_write_with_guard(&imgs_dir, &key)?;
 
         let t0 = Instant::now();
         let builder = peerofs::build::Builder::new(&mut file, peerofs::build::BuilderConfig{
-            max_file_size: Some(MAX_IMAGE_SIZE),
-            increment_uid_gid: Some(1000), // TODO magic constant
+            max_file_size: Some(limits.max_image_size),
+            id_mapping: peerofs::build::IdMapping::Offset { uid: 1000, gid: 1000 }, // TODO magic constant
+            progress: Some(Box::new({
+                let key = key.clone();
+                move |p| debug!("building image for {key} {p:?}")
+            })),
+            // zero uuid and build time so the same layers give the same image
+            ..Default::default()
         })?;
-        let (squash_stats, erofs_stats) = squash_to_erofs(&mut layers, builder)?;
+        let (squash_stats, erofs_stats) = squash_to_erofs(&mut layers, builder, Some(limits.max_layer_size()))?;
+        // dedup can leave (zeroed) blocks past the end of the image
+        file.set_len(erofs_stats.image_len())?;
         let elapsed = t0.elapsed().as_secs_f32();
         guard.success()?;
         round_up_file_to_pmem_size(&file)?;
@@ -233,9 +388,13 @@ async fn make_erofs_image(
 async fn make_img_cache(
     dir: impl AsRef<Path>,
     img_capacity: u64,
-) -> anyhow::Result<(ImageCache, OwnedFd)> {
+    img_shard_depth: u8,
+) -> anyhow::Result<(ImageCache, BlobDir)> {
     let cache_dir = blobcache::open_or_create_dir_at(None, dir.as_ref())?;
-    let imgs_dir = blobcache::open_or_create_dir_at(Some(&cache_dir), "imgs")?;
+    let imgs_dir = BlobDir::new(
+        blobcache::open_or_create_dir_at(Some(&cache_dir), "imgs")?,
+        img_shard_depth,
+    );
     let imgs_dir_clone = imgs_dir.try_clone()?;
 
     let image_cache = Cache::builder()
@@ -281,6 +440,16 @@ async fn respond_ok(
     Ok(())
 }
 
+async fn respond_prefetched(conn: UnixSeqpacket, digest: Digest, size: u64) -> anyhow::Result<()> {
+    let wire_response = WireResponse::Prefetched {
+        manifest_digest: digest.to_string(),
+        size: size,
+    };
+    let buf = bincode::encode_to_vec(&wire_response, bincode::config::standard())?;
+    conn.send(&buf).await?;
+    Ok(())
+}
+
 // these errors are super leaky but not sure something nicer right now
 async fn respond_err(conn: UnixSeqpacket, error: anyhow::Error) -> anyhow::Result<()> {
     error!("responding_err {}", error);
@@ -306,11 +475,15 @@ async fn respond_err(conn: UnixSeqpacket, error: anyhow::Error) -> anyhow::Resul
                 Error::TotalLayerSizeTooBig => Some(WireResponse::ImageTooBig),
                 _ => None,
             }
+        } else if let Some(e) = error.downcast_ref::<Error>() {
+            match e {
+                Error::BadReference => Some(WireResponse::BadReference),
+                _ => None,
+            }
         } else if let Some(e) = error.downcast_ref::<Arc<peimage::squash::Error>>() {
             match **e {
-                peimage::squash::Error::Erofs(peerofs::build::Error::MaxSizeExceeded) => {
-                    Some(WireResponse::ImageTooBig)
-                }
+                peimage::squash::Error::Erofs(peerofs::build::Error::MaxSizeExceeded)
+                | peimage::squash::Error::LayerTooBig => Some(WireResponse::ImageTooBig),
                 _ => None,
             }
         } else {
@@ -354,6 +527,19 @@ struct Args {
 
     #[arg(long, default_value_t = 50_000_000_000)]
     img_capacity: u64,
+
+    // number of two hex char digest prefix dirs images are stored under, ie imgs/sha256/ab/abcd...
+    // for 1; existing images are moved on startup when this changes
+    #[arg(long, default_value_t = 1)]
+    img_shard_depth: u8,
+
+    // images whose compressed layers sum to more than this are rejected before fetching them
+    #[arg(long, default_value_t = MAX_TOTAL_LAYER_SIZE)]
+    max_total_layer_size: u64,
+
+    // max erofs image size (of just the file data portion)
+    #[arg(long, default_value_t = MAX_IMAGE_SIZE)]
+    max_image_size: u64,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -361,8 +547,12 @@ async fn main() {
     env_logger::init();
     let args = Args::parse();
 
-    let auth = load_stored_auth(args.auth).unwrap();
-    info!("loaded {} entries into auth", auth.len());
+    let (auth, tls) = load_stored_auth(args.auth).unwrap();
+    info!(
+        "loaded {} entries into auth, {} with tls",
+        auth.len(),
+        tls.len()
+    );
 
     let cache_dir = args.cache.unwrap_or_else(|| {
         let home = std::env::vars()
@@ -372,13 +562,16 @@ async fn main() {
         PathBuf::from(home).join(".local/share/peoci")
     });
 
-    let (cache, imgs_dir) = make_img_cache(&cache_dir, args.img_capacity).await.unwrap();
+    let (cache, imgs_dir) = make_img_cache(&cache_dir, args.img_capacity, args.img_shard_depth)
+        .await
+        .unwrap();
     let imgs_dir = Arc::new(imgs_dir);
 
     let client = Client::builder()
         .dir(cache_dir)
         .load_from_disk(true)
         .auth(auth)
+        .tls(tls)
         .ref_capacity(args.ref_capacity)
         .manifest_capacity(args.manifest_capacity)
         .blob_capacity(args.blob_capacity)
@@ -386,8 +579,18 @@ async fn main() {
         .await
         .unwrap();
 
-    let worker_semaphore = Arc::new(Semaphore::new(1));
+    let limits = ImageLimits {
+        max_total_layer_size: args.max_total_layer_size,
+        max_image_size: args.max_image_size,
+    };
+    info!("image limits {limits:?}");
+
+    let workers = Arc::new(ImageWorkers {
+        semaphore: Semaphore::new(1),
+        limits: limits,
+    });
     let counters = Arc::new(Counters::default());
+    let manifest_flight = Arc::new(ManifestFlight::default());
 
     let _ = std::fs::remove_file(&args.listen);
     let mut socket =
@@ -423,19 +626,26 @@ async fn main() {
             accept = socket.accept() => {
                  match accept {
                     Ok(conn) => {
-                        let worker_semaphore_ = worker_semaphore.clone();
+                        let workers_ = workers.clone();
                         let client_ = client.clone();
                         let cache_ = cache.clone();
                         let imgs_dir_ = imgs_dir.clone();
                         let counters_ = counters.clone();
+                        let manifest_flight_ = manifest_flight.clone();
                         tokio::spawn(async move {
-                            match handle_conn(worker_semaphore_, &conn, client_, cache_, imgs_dir_, counters_).await {
-                                Ok((digest, config, fd)) => match respond_ok(conn, digest, config, fd).await {
+                            match handle_conn(workers_, &conn, client_, cache_, imgs_dir_, counters_, manifest_flight_).await {
+                                Ok(ConnResponse::Image(digest, config, fd)) => match respond_ok(conn, digest, config, fd).await {
                                     Ok(_) => {}
                                     Err(e) => {
                                         error!("error sending ok {:?}", e);
                                     }
                                 },
+                                Ok(ConnResponse::Prefetched(digest, size)) => match respond_prefetched(conn, digest, size).await {
+                                    Ok(_) => {}
+                                    Err(e) => {
+                                        error!("error sending prefetched {:?}", e);
+                                    }
+                                },
                                 Err(e) => match respond_err(conn, e).await {
                                     Ok(_) => {}
                                     Err(e) => {
@@ -453,3 +663,281 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    use peimage_service::Request;
+
+    #[test]
+    fn stored_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cert.pem"), "cert\n").unwrap();
+        std::fs::write(dir.path().join("key.pem"), "key\n").unwrap();
+        std::fs::write(dir.path().join("ca.pem"), "ca\n").unwrap();
+        let auth_path = dir.path().join("auth.json");
+        let write_auth = |json: serde_json::Value| {
+            std::fs::write(&auth_path, json.to_string()).unwrap();
+        };
+        let d = dir.path().display();
+
+        write_auth(serde_json::json!({
+            "docker.io": {"username": "user", "password": "pass"},
+            "mtls.example.com": {
+                "tls_cert": format!("{d}/cert.pem"),
+                "tls_key": format!("{d}/key.pem"),
+                "tls_ca": format!("{d}/ca.pem"),
+            },
+            "ca.example.com": {"tls_ca": format!("{d}/ca.pem")},
+        }));
+        let (auth, tls) = load_stored_auth(&auth_path).unwrap();
+        assert!(matches!(&auth["docker.io"], Auth::UserPass(u, p) if u == "user" && p == "pass"));
+        assert!(matches!(auth["mtls.example.com"], Auth::None));
+        assert!(matches!(auth["ca.example.com"], Auth::None));
+        assert_eq!(tls.len(), 2);
+        assert_eq!(
+            tls["mtls.example.com"].identity_pem.as_deref(),
+            Some(&b"cert\nkey\n"[..])
+        );
+        assert_eq!(
+            tls["mtls.example.com"].ca_pem.as_deref(),
+            Some(&b"ca\n"[..])
+        );
+        assert!(tls["ca.example.com"].identity_pem.is_none());
+
+        // a cert without its key
+        write_auth(serde_json::json!({
+            "mtls.example.com": {"tls_cert": format!("{d}/cert.pem")},
+        }));
+        assert!(load_stored_auth(&auth_path).is_err());
+
+        write_auth(serde_json::json!({
+            "mtls.example.com": {
+                "tls_cert": format!("{d}/cert.pem"),
+                "tls_key": format!("{d}/nope.pem"),
+            },
+        }));
+        let e = load_stored_auth(&auth_path).unwrap_err();
+        assert!(e.to_string().contains("tls_key"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn bad_reference_response() {
+        // same encoding as a Request, which Request::new wouldn't let us build with a bad reference
+        let buf = bincode::encode_to_vec(
+            (
+                "not a reference!".to_string(),
+                spec::Arch::Amd64,
+                spec::Os::Linux,
+            ),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        let error = decode_request(&buf).unwrap_err();
+
+        let (client, server) = UnixSeqpacket::pair().unwrap();
+        respond_err(server, error).await.unwrap();
+        let mut buf = [0; 1024];
+        let n = client.recv(&mut buf).await.unwrap();
+        let (response, _) =
+            bincode::decode_from_slice::<WireResponse, _>(&buf[..n], bincode::config::standard())
+                .unwrap();
+        assert!(matches!(response, WireResponse::BadReference));
+
+        let buf = bincode::encode_to_vec(
+            (
+                "docker.io/library/busybox:1.37".to_string(),
+                spec::Arch::Amd64,
+                spec::Os::Linux,
+            ),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        assert!(decode_request(&buf).is_ok());
+    }
+
+    #[test]
+    fn request_kind() {
+        let request =
+            Request::new("docker.io/library/busybox:1.37", &Arch::Amd64, &Os::Linux).unwrap();
+        let old = bincode::encode_to_vec(
+            (
+                "docker.io/library/busybox:1.37".to_string(),
+                spec::Arch::Amd64,
+                spec::Os::Linux,
+            ),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        // an image request is still byte for byte what it was before there were kinds
+        let buf = bincode::encode_to_vec(&request, bincode::config::standard()).unwrap();
+        assert_eq!(buf, old);
+        assert_eq!(decode_request(&buf).unwrap().0, RequestKind::Image);
+
+        let buf = bincode::encode_to_vec(
+            (&request, RequestKind::Prefetch),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        assert!(buf.starts_with(&old));
+        assert_eq!(decode_request(&buf).unwrap().0, RequestKind::Prefetch);
+
+        // still checks the reference
+        let buf = bincode::encode_to_vec(
+            (
+                "not a reference!".to_string(),
+                spec::Arch::Amd64,
+                spec::Os::Linux,
+                RequestKind::Prefetch,
+            ),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        assert!(decode_request(&buf).is_err());
+
+        // trailing junk that isn't a kind
+        let mut buf = old.clone();
+        buf.push(0xff);
+        assert!(decode_request(&buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn manifest_flight_one_fetch() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let manifest_flight = ManifestFlight::default();
+        let buf = bincode::encode_to_vec(
+            Request::new("docker.io/library/busybox:1.37", &Arch::Amd64, &Os::Linux).unwrap(),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        let fetches = AtomicUsize::new(0);
+        let fetches_ = &fetches;
+        // stands in for the registry, slow enough that everyone else finds it inflight
+        let fetch = move || async move {
+            fetches_.fetch_add(1, Ordering::SeqCst);
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            Err(Arc::new(ocidist_cache::Error::ManifestNotFound))
+        };
+        // what handle_conn does with each request it gets
+        let request = || async {
+            let (_, reference) = decode_request(&buf).unwrap();
+            get_manifest_and_configuration(&manifest_flight, &reference, fetch).await
+        };
+
+        let (a, b, c, d) = tokio::join!(request(), request(), request(), request());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        for x in [a, b, c, d] {
+            let error = x.unwrap_err();
+            let error = error.downcast_ref::<Arc<ocidist_cache::Error>>().unwrap();
+            assert!(matches!(**error, ocidist_cache::Error::ManifestNotFound));
+        }
+
+        // nothing inflight anymore so the next one fetches again
+        assert!(request().await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn lowered_limit_image_too_big() {
+        let layer = spec::LayerDescriptor {
+            media_type: spec::MediaType::ImageLayerGzip,
+            digest: spec::Digest::Sha256([0; 32]),
+            size: 600,
+        };
+        let manifest = spec::ImageManifest {
+            layers: vec![layer; 2],
+        };
+
+        assert!(
+            ImageLimits::default()
+                .check_total_layer_size(&manifest)
+                .is_ok()
+        );
+        let limits = ImageLimits {
+            max_total_layer_size: 1200,
+            ..Default::default()
+        };
+        assert!(limits.check_total_layer_size(&manifest).is_ok());
+
+        let limits = ImageLimits {
+            max_total_layer_size: 1000,
+            ..Default::default()
+        };
+        let error = limits.check_total_layer_size(&manifest).unwrap_err();
+        assert!(matches!(error, Error::TotalLayerSizeTooBig));
+
+        // make_erofs_image runs inside the image cache which hands back its error in an Arc
+        let (client, server) = UnixSeqpacket::pair().unwrap();
+        respond_err(server, Arc::new(error).into()).await.unwrap();
+        let mut buf = [0; 1024];
+        let n = client.recv(&mut buf).await.unwrap();
+        let (response, _) =
+            bincode::decode_from_slice::<WireResponse, _>(&buf[..n], bincode::config::standard())
+                .unwrap();
+        assert!(matches!(response, WireResponse::ImageTooBig));
+    }
+
+    #[tokio::test]
+    async fn prefetch_then_hit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (img_cache, imgs_dir) = make_img_cache(dir.path(), 1_000_000, 1).await.unwrap();
+        let counters = Counters::default();
+        let key = BlobKey::new("sha256:abcd".to_string()).unwrap();
+
+        // stand in for make_erofs_image
+        let make = |fd_tx: tokio::sync::oneshot::Sender<OwnedFd>| {
+            let imgs_dir = &imgs_dir;
+            let key = &key;
+            async move {
+                let (mut file, guard) = blobcache::openat_create_write_with_guard(imgs_dir, key)?;
+                file.write_all(b"image")?;
+                guard.success()?;
+                fd_tx.send(file.into()).map_err(|_| Error::OneshotTx)?;
+                anyhow::Ok(5)
+            }
+        };
+
+        let (fd_tx, fd_rx) = tokio::sync::oneshot::channel();
+        let (size, fd) = get_or_make_image(
+            &img_cache,
+            &imgs_dir,
+            &counters,
+            &key,
+            false,
+            make(fd_tx),
+            fd_rx,
+        )
+        .await
+        .unwrap();
+        assert_eq!(size, 5);
+        assert!(fd.is_none());
+        assert_eq!(atomic_take(&counters.img_cache_miss), 1);
+
+        // the real request shouldn't build anything
+        let (fd_tx, fd_rx) = tokio::sync::oneshot::channel();
+        drop(fd_tx);
+        let (size, fd) = get_or_make_image(
+            &img_cache,
+            &imgs_dir,
+            &counters,
+            &key,
+            true,
+            async { Err(anyhow::anyhow!("should be a hit")) },
+            fd_rx,
+        )
+        .await
+        .unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(atomic_take(&counters.img_cache_hit), 1);
+        assert_eq!(atomic_take(&counters.img_cache_miss), 0);
+        let mut got = vec![];
+        File::from(fd.unwrap()).read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"image");
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+// Coalesces concurrent calls for the same key so that only one future runs and the rest wait on
+// its result. Unlike the moka entry api, nothing is kept after the call completes; the next call
+// for the key after that runs again (and presumably hits a cache further down).
+// If the leading caller is dropped before finishing, one of the waiters picks up the init.
+pub struct SingleFlight<K, V> {
+    inflight: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let ret = cell.get_or_init(f).await.clone();
+
+        // only remove our own cell, a later caller may have already put in a new one
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(&key).is_some_and(|x| Arc::ptr_eq(x, &cell)) {
+            inflight.remove(&key);
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let sf: SingleFlight<String, Result<u32, Arc<String>>> = SingleFlight::default();
+        let fetches = AtomicUsize::new(0);
+        let fetches_ = &fetches;
+
+        let fetch = move || async move {
+            fetches_.fetch_add(1, Ordering::SeqCst);
+            // give the other callers a chance to find the inflight entry
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            Ok(42)
+        };
+        let key = "docker.io/library/busybox:latest".to_string();
+
+        let (a, b, c, d) = tokio::join!(
+            sf.run(key.clone(), fetch),
+            sf.run(key.clone(), fetch),
+            sf.run(key.clone(), fetch),
+            sf.run(key.clone(), fetch),
+        );
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        for x in [a, b, c, d] {
+            assert_eq!(x, Ok(42));
+        }
+        assert!(sf.inflight.lock().unwrap().is_empty());
+
+        // not inflight anymore, so runs again
+        assert_eq!(sf.run(key.clone(), fetch).await, Ok(42));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // different keys don't wait on each other
+        let (a, b) = tokio::join!(
+            sf.run("a".to_string(), fetch),
+            sf.run("b".to_string(), fetch),
+        );
+        assert_eq!((a, b), (Ok(42), Ok(42)));
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+    }
+}
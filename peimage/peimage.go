@@ -37,6 +37,7 @@ const ImageRefName = "org.opencontainers.image.ref.name"
 const UidGidOffset = 1000
 const TwoMBAlignment = 0x20_0000  // 2MB alignment size
 const IndexJsonMagic = uint64(0x1db56abd7b82da38)  // magic to be put at end of image
+const IndexVersion = 1  // bump when index.json changes incompatibly, keep in sync with src/index.rs
 
 type HeaderXform func(*tar.Header) (error)
 
@@ -58,6 +59,7 @@ type PEImageIndexEntry struct {
 }
 
 type PEImageIndex struct {
+    Version int                `json:"version"`
     Images []PEImageIndexEntry `json:"images"`
 }
 
@@ -199,7 +201,7 @@ func makePEImageIndex(selected map[string]OCIIndexEntry, rootfsPrefix map[v1.Has
         return strings.Compare(a.Id.Digest.String(), b.Id.Digest.String())
     })
 
-    return PEImageIndex { Images: images }
+    return PEImageIndex { Version: IndexVersion, Images: images }
 }
 
 func isNonNumericUidGid(user string) bool {
@@ -398,6 +400,10 @@ func readPeIndexJson(infile string) (*PEImageIndex, error) {
     if err = json.Unmarshal(buf, peImageIndex); err != nil {
         return nil, fmt.Errorf("reading json %s %w", infile, err)
     }
+    // missing version means the index predates the field
+    if peImageIndex.Version != 0 && peImageIndex.Version != IndexVersion {
+        return nil, fmt.Errorf("unsupported index version %s %d", infile, peImageIndex.Version)
+    }
     return peImageIndex, nil
 }
 
@@ -25,8 +25,9 @@ fn main() {
         eprintln!("{stats:?}");
     } else if output.ends_with(".erofs") {
         let out = File::create(output).unwrap();
-        let builder = peerofs::build::Builder::new(out, peerofs::build::BuilderConfig::default()).unwrap();
-        let (squash_stats, erofs_stats) = squash_to_erofs(&mut readers, builder).unwrap();
+        let builder = peerofs::build::Builder::new(&out, peerofs::build::BuilderConfig::default()).unwrap();
+        let (squash_stats, erofs_stats) = squash_to_erofs(&mut readers, builder, None).unwrap();
+        out.set_len(erofs_stats.image_len()).unwrap();
         eprintln!("{squash_stats:?}");
         eprintln!("{erofs_stats:?}");
     }
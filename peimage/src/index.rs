@@ -1,16 +1,37 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-use byteorder::{ReadBytesExt, LE};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use oci_spec::image as oci_image;
 use peinit::RootfsKind;
 use serde::{Deserialize, Serialize};
 
 const INDEX_JSON_MAGIC: u64 = 0x1db56abd7b82da38;
 
+// bump this when index.json changes in a way old readers can't handle. indexes written before the
+// version field existed are version 1
+pub const INDEX_VERSION: u32 = 1;
+
+// labels in the image config that give per image defaults for a run, so a big compiler image can
+// get more than a tiny script image. Values are plain integers, anything else is ignored
+pub const LABEL_MEMORY_BYTES: &str = "dev.programexplorer.limits.memory-bytes";
+pub const LABEL_PIDS: &str = "dev.programexplorer.limits.pids";
+pub const LABEL_TIMEOUT_MS: &str = "dev.programexplorer.limits.timeout-ms";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PEImageLimits {
+    pub memory_bytes: Option<i64>,
+    pub pids: Option<i64>,
+    pub timeout_ms: Option<u64>,
+}
+
+fn default_index_version() -> u32 {
+    1
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PEImageId {
     pub digest: String,
@@ -44,7 +65,7 @@ impl PEImageId {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PEImageIndexEntry {
     pub rootfs: String,
     pub config: oci_image::ImageConfiguration,
@@ -52,11 +73,52 @@ pub struct PEImageIndexEntry {
     pub id: PEImageId,
 }
 
-#[derive(Debug, Deserialize)]
+impl PEImageIndexEntry {
+    // (total, per layer) compressed sizes as the registry reported them in the manifest, so not
+    // the size of the rootfs we built
+    pub fn sizes(&self) -> (u64, Vec<u64>) {
+        let layers: Vec<u64> = self.manifest.layers().iter().map(|x| x.size()).collect();
+        (layers.iter().sum(), layers)
+    }
+
+    pub fn limits(&self) -> PEImageLimits {
+        let Some(labels) = self.config.labels_of_config() else {
+            return PEImageLimits::default();
+        };
+        let memory_bytes = labels
+            .get(LABEL_MEMORY_BYTES)
+            .and_then(|x| x.parse().ok())
+            .filter(|x| *x > 0);
+        let pids = labels
+            .get(LABEL_PIDS)
+            .and_then(|x| x.parse().ok())
+            .filter(|x| *x > 0);
+        let timeout_ms = labels
+            .get(LABEL_TIMEOUT_MS)
+            .and_then(|x| x.parse().ok())
+            .filter(|x| *x > 0);
+        PEImageLimits {
+            memory_bytes,
+            pids,
+            timeout_ms,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PEImageIndex {
+    #[serde(default = "default_index_version")]
+    pub version: u32,
     pub images: Vec<PEImageIndexEntry>,
 }
 
+// parsed first so that a newer schema gets a version error instead of a generic parse error
+#[derive(Deserialize)]
+struct PEImageIndexVersion {
+    #[serde(default = "default_index_version")]
+    version: u32,
+}
+
 impl PEImageIndex {
     pub fn from_path<P: AsRef<Path>>(p: P) -> io::Result<Self> {
         Self::from_file(&mut File::open(p)?)
@@ -88,6 +150,14 @@ impl PEImageIndex {
         f.seek(SeekFrom::End(-i64::from(8 + 4 + data_size)))?;
         let mut buf = vec![0; data_size as usize];
         f.read_exact(&mut buf)?;
+        let PEImageIndexVersion { version } = serde_json::from_slice(buf.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "index.json not valid json"))?;
+        if version != INDEX_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported index version {version}, expected {INDEX_VERSION}"),
+            ));
+        }
         serde_json::from_slice(buf.as_slice()).map_err(|_| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -95,6 +165,20 @@ impl PEImageIndex {
             )
         })
     }
+
+    // appends index.json and the trailer to the end of f, the inverse of from_file
+    pub fn write_to_file(&self, f: &mut File) -> io::Result<()> {
+        let buf = serde_json::to_vec(self)?;
+        let data_size: u32 = buf
+            .len()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "index.json too large"))?;
+        f.seek(SeekFrom::End(0))?;
+        f.write_all(&buf)?;
+        f.write_u32::<LE>(data_size)?;
+        f.write_u64::<LE>(INDEX_JSON_MAGIC)?;
+        Ok(())
+    }
 }
 
 pub struct PEImageMultiIndexEntry {
@@ -138,6 +222,16 @@ impl PEImageMultiIndex {
     }
 
     pub fn add_dir<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.add_dir_impl(path.as_ref(), false)
+    }
+
+    /// like add_dir but an image whose id name was already added replaces the existing entry
+    /// instead of being an error, so a dir of overrides can be layered over a base dir
+    pub fn add_dir_override<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.add_dir_impl(path.as_ref(), true)
+    }
+
+    fn add_dir_impl(&mut self, path: &Path, replace: bool) -> io::Result<()> {
         fn is_erofs_or_sqfs(p: &Path) -> bool {
             match p.extension() {
                 // boo we can't match a static str against OsStr...
@@ -147,24 +241,39 @@ impl PEImageMultiIndex {
             }
         }
 
-        for entry in (path.as_ref().read_dir()?).flatten() {
-            let p = entry.path();
-            if p.is_file() && is_erofs_or_sqfs(&p) {
-                self.add_path(p)?;
-            }
+        // sorted so that which file wins within an override dir doesn't depend on readdir order
+        let mut paths: Vec<PathBuf> = (path.read_dir()?)
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|p| p.is_file() && is_erofs_or_sqfs(p))
+            .collect();
+        paths.sort();
+        for p in paths {
+            self.add_path_impl(&p, replace)?;
         }
         Ok(())
     }
 
     pub fn add_path<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
-        let idx = PEImageIndex::from_path(&path)?;
-        let rootfs_kind = RootfsKind::try_from_path_name(&path).ok_or_else(|| {
+        self.add_path_impl(path.as_ref(), false)
+    }
+
+    pub fn add_path_override<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.add_path_impl(path.as_ref(), true)
+    }
+
+    fn add_path_impl(&mut self, path: &Path, replace: bool) -> io::Result<()> {
+        let idx = PEImageIndex::from_path(path)?;
+        let rootfs_kind = RootfsKind::try_from_path_name(path).ok_or_else(|| {
             io::Error::new(io::ErrorKind::InvalidData, "couldn't determine rootfs kind")
         })?;
-        let pathbuf: PathBuf = path.as_ref().to_path_buf();
+        let pathbuf: PathBuf = path.to_path_buf();
         for image in idx.images {
             let key = image.id.name();
-            if self.map.contains_key(&key) {
+            if replace {
+                // the map isn't necessarily keyed by name (and the old digest can differ)
+                self.map.retain(|_, v| v.image.id.name() != key);
+            } else if self.map.contains_key(&key) {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
                     "duplicate image id name",
@@ -198,6 +307,22 @@ impl PEImageMultiIndex {
         self.map.get(key)
     }
 
+    /// accepts a digest as sha256:abcd or sha256/abcd no matter how this index is keyed. A name
+    /// keyed index has to scan
+    pub fn get_by_digest<'a>(&'a self, digest: &str) -> Option<&'a PEImageMultiIndexEntry> {
+        let (algorithm, hex) = digest.split_once([':', '/'])?;
+        match self.key_type {
+            PEImageMultiIndexKeyType::Name => {
+                let digest = format!("{algorithm}:{hex}");
+                self.map.values().find(|x| x.image.id.digest == digest)
+            }
+            PEImageMultiIndexKeyType::DigestWithSlash => {
+                self.map.get(&format!("{algorithm}/{hex}"))
+            }
+            PEImageMultiIndexKeyType::Digest => self.map.get(&format!("{algorithm}:{hex}")),
+        }
+    }
+
     pub fn map(&self) -> &HashMap<String, PEImageMultiIndexEntry> {
         &self.map
     }
@@ -212,3 +337,255 @@ impl Default for PEImageMultiIndex {
         PEImageMultiIndex::new(PEImageMultiIndexKeyType::Digest)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempfile;
+
+    fn index_file(json: &str) -> File {
+        let mut f = tempfile().unwrap();
+        f.write_all(b"not really an image").unwrap();
+        f.write_all(json.as_bytes()).unwrap();
+        f.write_u32::<LE>(json.len() as u32).unwrap();
+        f.write_u64::<LE>(INDEX_JSON_MAGIC).unwrap();
+        f
+    }
+
+    #[test]
+    fn test_index_version() {
+        let idx = PEImageIndex::from_file(&mut index_file(r#"{"images": []}"#)).unwrap();
+        assert_eq!(idx.version, 1);
+        assert!(idx.images.is_empty());
+
+        let idx =
+            PEImageIndex::from_file(&mut index_file(r#"{"version": 1, "images": []}"#)).unwrap();
+        assert_eq!(idx.version, INDEX_VERSION);
+
+        // a future version may well have a different shape so we shouldn't try to parse it
+        let err = PEImageIndex::from_file(&mut index_file(r#"{"version": 2, "entries": {}}"#))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("unsupported index version 2"));
+
+        let err = PEImageIndex::from_file(&mut index_file(r#"{"version": 1}"#)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = PEImageIndex::from_file(&mut index_file("{")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn busybox_entry(tag: &str, digest: &str) -> PEImageIndexEntry {
+        let manifest: oci_image::ImageManifest = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "digest": "sha256:b5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7",
+                    "size": 7023
+                },
+                "layers": []
+            }"#,
+        )
+        .unwrap();
+        let config: oci_image::ImageConfiguration = serde_json::from_str(
+            r#"{
+                "architecture": "amd64",
+                "os": "linux",
+                "rootfs": {"type": "layers", "diff_ids": []},
+                "history": []
+            }"#,
+        )
+        .unwrap();
+        PEImageIndexEntry {
+            rootfs: "abcd".into(),
+            config,
+            manifest,
+            id: PEImageId {
+                digest: digest.into(),
+                repository: "library/busybox".into(),
+                registry: "index.docker.io".into(),
+                tag: tag.into(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_entry_sizes() {
+        let mut entry = busybox_entry("1.37", "sha256:1234");
+        assert_eq!(entry.sizes(), (0, vec![]));
+
+        entry.manifest = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "digest": "sha256:b5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7",
+                    "size": 7023
+                },
+                "layers": [
+                    {
+                        "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                        "digest": "sha256:61dfb50712f5b7b4ebd5d5b9b8a8e8a5e3b8e5a3a2a1a0a9a8a7a6a5a4a3a2a1",
+                        "size": 2150272
+                    },
+                    {
+                        "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                        "digest": "sha256:0a1a2a3a4a5a6a7a8a9a0a1a2a3a5e8b3e5a8a8b9b5d5dbe4b7b5f21705bfd16",
+                        "size": 96
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        // the config size doesn't count
+        assert_eq!(entry.sizes(), (2150368, vec![2150272, 96]));
+    }
+
+    #[test]
+    fn test_entry_limits() {
+        let mut entry = busybox_entry("1.37", "sha256:1234");
+        assert_eq!(entry.limits(), PEImageLimits::default());
+
+        entry.config = serde_json::from_str(
+            r#"{
+                "architecture": "amd64",
+                "os": "linux",
+                "config": {
+                    "Labels": {
+                        "dev.programexplorer.limits.memory-bytes": "1073741824",
+                        "dev.programexplorer.limits.pids": "not a number",
+                        "dev.programexplorer.limits.timeout-ms": "30000"
+                    }
+                },
+                "rootfs": {"type": "layers", "diff_ids": []},
+                "history": []
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            entry.limits(),
+            PEImageLimits {
+                memory_bytes: Some(1 << 30),
+                pids: None,
+                timeout_ms: Some(30000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        let idx = PEImageIndex {
+            version: INDEX_VERSION,
+            images: vec![busybox_entry("1.37", "sha256:1234")],
+        };
+
+        let mut f = tempfile().unwrap();
+        f.write_all(b"not really an image").unwrap();
+        idx.write_to_file(&mut f).unwrap();
+
+        let got = PEImageIndex::from_file(&mut f).unwrap();
+        assert_eq!(got.version, INDEX_VERSION);
+        assert_eq!(got.images.len(), 1);
+        let image = &got.images[0];
+        assert_eq!(image.rootfs, "abcd");
+        assert_eq!(image.id.name(), "index.docker.io/library/busybox:1.37");
+        assert_eq!(image.id.digest, "sha256:1234");
+        assert_eq!(image.manifest, idx.images[0].manifest);
+        assert_eq!(image.config, idx.images[0].config);
+
+        // the image data before the index is left alone
+        let mut buf = vec![0; 19];
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, b"not really an image");
+    }
+
+    #[test]
+    fn test_add_dir_override() {
+        let write_image = |path: PathBuf, images: Vec<PEImageIndexEntry>| {
+            let mut f = File::create(path).unwrap();
+            f.write_all(b"not really an image").unwrap();
+            let idx = PEImageIndex {
+                version: INDEX_VERSION,
+                images: images,
+            };
+            idx.write_to_file(&mut f).unwrap();
+        };
+        let base = tempfile::tempdir().unwrap();
+        let overrides = tempfile::tempdir().unwrap();
+        write_image(
+            base.path().join("base.erofs"),
+            vec![
+                busybox_entry("1.36", "sha256:1111"),
+                busybox_entry("1.37", "sha256:2222"),
+            ],
+        );
+        write_image(
+            overrides.path().join("override.erofs"),
+            vec![busybox_entry("1.37", "sha256:3333")],
+        );
+
+        let mut idx = PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name);
+        idx.add_dir(base.path()).unwrap();
+        let err = idx.add_dir(overrides.path()).err().unwrap();
+        assert!(err.to_string().contains("duplicate image id name"));
+
+        let mut idx = PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name);
+        idx.add_dir(base.path()).unwrap();
+        idx.add_dir_override(overrides.path()).unwrap();
+        assert_eq!(idx.map().len(), 2);
+        let entry = idx.get("index.docker.io/library/busybox:1.37").unwrap();
+        assert_eq!(entry.image.id.digest, "sha256:3333");
+        assert_eq!(entry.path, overrides.path().join("override.erofs"));
+        let entry = idx.get("index.docker.io/library/busybox:1.36").unwrap();
+        assert_eq!(entry.path, base.path().join("base.erofs"));
+
+        // keyed by digest the overridden image's old digest goes away too
+        let mut idx = PEImageMultiIndex::new(PEImageMultiIndexKeyType::Digest);
+        idx.add_dir(base.path()).unwrap();
+        idx.add_dir_override(overrides.path()).unwrap();
+        assert_eq!(idx.map().len(), 2);
+        assert!(idx.get("sha256:1111").is_some());
+        assert!(idx.get("sha256:2222").is_none());
+        assert!(idx.get("sha256:3333").is_some());
+    }
+    #[test]
+    fn test_get_by_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.erofs");
+        let mut f = File::create(&path).unwrap();
+        f.write_all(b"not really an image").unwrap();
+        PEImageIndex {
+            version: INDEX_VERSION,
+            images: vec![busybox_entry("1.37", "sha256:2222")],
+        }
+        .write_to_file(&mut f)
+        .unwrap();
+
+        for key_type in [
+            PEImageMultiIndexKeyType::Digest,
+            PEImageMultiIndexKeyType::DigestWithSlash,
+            PEImageMultiIndexKeyType::Name,
+        ] {
+            let mut idx = PEImageMultiIndex::new(key_type);
+            idx.add_path(&path).unwrap();
+            for digest in ["sha256:2222", "sha256/2222"] {
+                let entry = idx.get_by_digest(digest).unwrap();
+                assert_eq!(entry.image.id.digest, "sha256:2222");
+            }
+            assert!(idx.get_by_digest("sha256:3333").is_none());
+            assert!(idx.get_by_digest("sha256/3333").is_none());
+            assert!(idx.get_by_digest("2222").is_none());
+        }
+
+        // plain get still wants the exact key
+        let mut idx = PEImageMultiIndex::new(PEImageMultiIndexKeyType::Digest);
+        idx.add_path(&path).unwrap();
+        assert!(idx.get("sha256:2222").is_some());
+        assert!(idx.get("sha256/2222").is_none());
+    }
+}
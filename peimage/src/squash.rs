@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::io;
 use std::io::{BufReader, Read, Seek, Write};
@@ -9,7 +9,8 @@ use std::path::{Path, PathBuf};
 #[cfg(feature = "nocrc")]
 use flate2::bufread::DeflateDecoder;
 use flate2::bufread::GzDecoder;
-use rustix::fs::Mode;
+use rustix::fs::{FileType, Mode};
+use sha2::{Digest, Sha256};
 use tar::{Archive, Builder as ArchiveBuilder, Entry, EntryType};
 use zstd::stream::Decoder as ZstdDecoder;
 
@@ -29,6 +30,7 @@ pub enum Error {
     GidTooBig,
     UnhandledEntryType(EntryType),
     Erofs(#[from] ErofsError),
+    LayerTooBig,
 }
 
 // how wrong is this?
@@ -202,7 +204,7 @@ where
     let mut helper = SquashToTar {
         archive: ArchiveBuilder::new(out),
     };
-    let stats = squash_cb(layer_readers, &mut helper)?;
+    let stats = squash_cb(layer_readers, &mut helper, None)?;
     helper.archive.finish()?;
 
     Ok(stats)
@@ -210,6 +212,25 @@ where
 
 struct SquashToErofs<W: Write + Seek> {
     builder: ErofsBuilder<W>,
+    // (len, sha256) -> first path written with those contents
+    dedup: HashMap<(usize, [u8; 32]), PathBuf>,
+}
+
+// files smaller than this are (mostly) stored inline in a tail so there isn't anything to share
+const DEDUP_MIN_SIZE: usize = 4096;
+
+// hashes everything read through it so we can dedup files after the builder has streamed them
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
 }
 
 fn header_to_meta(header: &tar::Header, xattrs: XattrMap) -> Result<ErofsMeta, Error> {
@@ -250,8 +271,24 @@ impl<W: Write + Seek> EntryCallback for SquashToErofs<W> {
         match entry.header().entry_type() {
             EntryType::Regular => {
                 let path = entry.path()?.into_owned();
-                self.builder
-                    .add_file(path, meta, header.size()? as usize, entry)?;
+                let len = header.size()? as usize;
+                if len < DEDUP_MIN_SIZE {
+                    self.builder.add_file(path, meta, len, entry)?;
+                } else {
+                    // we don't know the contents until they've been written, so the builder
+                    // gives back the blocks if it turns out to be a dup
+                    let mut reader = HashReader {
+                        inner: entry,
+                        hasher: Sha256::new(),
+                    };
+                    self.builder.add_file(&path, meta, len, &mut reader)?;
+                    let key = (len, reader.hasher.finalize().into());
+                    if let Some(target) = self.dedup.get(&key) {
+                        self.builder.dedup_file(&path, target)?;
+                    } else {
+                        self.dedup.insert(key, path);
+                    }
+                }
             }
             EntryType::Directory => {
                 let path = entry.path()?.into_owned();
@@ -267,6 +304,18 @@ impl<W: Write + Seek> EntryCallback for SquashToErofs<W> {
                 let link = entry.link_name()?.ok_or(Error::HardlinkNoLink)?;
                 self.builder.add_link(path, link, meta)?;
             }
+            t @ (EntryType::Char | EntryType::Block | EntryType::Fifo) => {
+                let path = entry.path()?;
+                let file_type = match t {
+                    EntryType::Char => FileType::CharacterDevice,
+                    EntryType::Block => FileType::BlockDevice,
+                    _ => FileType::Fifo,
+                };
+                let major = header.device_major()?.unwrap_or(0);
+                let minor = header.device_minor()?.unwrap_or(0);
+                self.builder
+                    .add_special(path, file_type, (major, minor), meta)?;
+            }
             t => {
                 return Err(Error::UnhandledEntryType(t));
             }
@@ -275,16 +324,22 @@ impl<W: Write + Seek> EntryCallback for SquashToErofs<W> {
     }
 }
 
+// max_layer_size limits the decompressed size of each layer so that a small layer can't decompress
+// to something huge before the builder's max_file_size gets a chance to stop it
 pub fn squash_to_erofs<W, R>(
     layer_readers: &mut [(Compression, R)],
     builder: ErofsBuilder<W>,
+    max_layer_size: Option<u64>,
 ) -> Result<(Stats, ErofsStats), Error>
 where
     W: Write + Seek,
     R: Read,
 {
-    let mut helper = SquashToErofs { builder };
-    let squash_stats = squash_cb(layer_readers, &mut helper)?;
+    let mut helper = SquashToErofs {
+        builder,
+        dedup: HashMap::new(),
+    };
+    let squash_stats = squash_cb(layer_readers, &mut helper, max_layer_size)?;
     let (erofs_stats, _) = helper.builder.into_inner()?;
 
     Ok((squash_stats, erofs_stats))
@@ -372,9 +427,58 @@ where
     Ok(())
 }
 
+// counts the decompressed bytes of a layer and errors once there are more than the limit
+struct LimitReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> Read for LimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        match self.remaining.checked_sub(n as u64) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(n)
+            }
+            None => {
+                self.exceeded = true;
+                Err(io::Error::other("layer too big"))
+            }
+        }
+    }
+}
+
+fn squash_layer_limited<R, D, F>(
+    cb: &mut F,
+    i: usize,
+    stats: &mut Stats,
+    deletions: &mut D,
+    reader: R,
+    max_layer_size: Option<u64>,
+) -> Result<(), Error>
+where
+    R: Read,
+    D: Deletions,
+    F: EntryCallback,
+{
+    let mut reader = LimitReader {
+        inner: reader,
+        remaining: max_layer_size.unwrap_or(u64::MAX),
+        exceeded: false,
+    };
+    // the io error can get wrapped up by tar so we check the flag instead
+    match squash_layer(cb, i, stats, deletions, Archive::new(&mut reader)) {
+        Err(_) if reader.exceeded => Err(Error::LayerTooBig),
+        ret => ret,
+    }
+}
+
 pub fn squash_cb<R, F>(
     layer_readers: &mut [(Compression, R)],
     cb: &mut F,
+    max_layer_size: Option<u64>,
 ) -> Result<Stats, Error>
 where
     R: Read,
@@ -392,12 +496,13 @@ where
     for (i, (compression, reader)) in layer_readers.iter_mut().enumerate().rev() {
         match compression {
             Compression::None => {
-                squash_layer(
+                squash_layer_limited(
                     cb,
                     i,
                     &mut stats,
                     &mut deletions,
-                    Archive::new(BufReader::with_capacity(32 * 1024, &mut *reader)),
+                    BufReader::with_capacity(32 * 1024, &mut *reader),
+                    max_layer_size,
                 )?;
             }
             Compression::Gzip => {
@@ -407,22 +512,20 @@ where
                     let _ = reader
                         .header()
                         .expect("only way this can be none is if reader EWOULDBLOCK");
-                    Archive::new(DeflateDecoder::new(reader.into_inner()))
+                    DeflateDecoder::new(reader.into_inner())
                 };
                 #[cfg(not(feature = "nocrc"))]
-                let reader = Archive::new(GzDecoder::new(BufReader::with_capacity(
-                    32 * 1024,
-                    &mut *reader,
-                )));
-                squash_layer(cb, i, &mut stats, &mut deletions, reader)?;
+                let reader = GzDecoder::new(BufReader::with_capacity(32 * 1024, &mut *reader));
+                squash_layer_limited(cb, i, &mut stats, &mut deletions, reader, max_layer_size)?;
             }
             Compression::Zstd => {
-                squash_layer(
+                squash_layer_limited(
                     cb,
                     i,
                     &mut stats,
                     &mut deletions,
-                    Archive::new(ZstdDecoder::new(&mut *reader)?),
+                    ZstdDecoder::new(&mut *reader)?,
+                    max_layer_size,
                 )?;
             }
         }
@@ -1091,6 +1194,114 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_squash_to_erofs_whiteout() {
+        use peerofs::build::BuilderConfig;
+        use peerofs::disk::Erofs;
+
+        let layers = vec![
+            vec![
+                E::file("a", b"lower"),
+                E::file("b", b"lower"),
+                E::dir("d"),
+                E::file("d/x", b"lower"),
+            ],
+            vec![
+                E::file(".wh.a", b""),
+                E::file("d/.wh..wh..opq", b""),
+                E::file("d/y", b"upper"),
+            ],
+        ];
+        let mut readers: Vec<_> = layers
+            .into_iter()
+            .map(|x| (Compression::Gzip, Cursor::new(serialize_gz(&x))))
+            .collect();
+        let mut buf = Cursor::new(vec![]);
+        let builder = ErofsBuilder::new(&mut buf, BuilderConfig::default()).unwrap();
+        let (stats, _) = squash_to_erofs(&mut readers, builder, None).unwrap();
+        assert_eq!(stats.deletions, 1);
+        assert_eq!(stats.opaques, 1);
+
+        let data = buf.into_inner();
+        let erofs = Erofs::new(&data).unwrap();
+        assert!(erofs.lookup("a").unwrap().is_none());
+        assert!(erofs.lookup("b").unwrap().is_some());
+        assert!(erofs.lookup("d").unwrap().is_some());
+        assert!(erofs.lookup("d/x").unwrap().is_none());
+        assert!(erofs.lookup("d/y").unwrap().is_some());
+        assert!(erofs.lookup(".wh.a").unwrap().is_none());
+        assert!(erofs.lookup("d/.wh..wh..opq").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_squash_to_erofs_dedup() {
+        use peerofs::build::BuilderConfig;
+        use peerofs::disk::Erofs;
+
+        let size = 1024 * 1024;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let layers = vec![vec![
+            E::file("a", &data),
+            E::file("b", &data),
+            E::file("c", &data[..size - 1]),
+        ]];
+        let mut readers: Vec<_> = layers
+            .into_iter()
+            .map(|x| (Compression::Gzip, Cursor::new(serialize_gz(&x))))
+            .collect();
+        let mut buf = Cursor::new(vec![]);
+        let builder = ErofsBuilder::new(&mut buf, BuilderConfig::default()).unwrap();
+        squash_to_erofs(&mut readers, builder, None).unwrap();
+
+        let image = buf.into_inner();
+        let erofs = Erofs::new(&image).unwrap();
+        let a = erofs.lookup("a").unwrap().unwrap();
+        let b = erofs.lookup("b").unwrap().unwrap();
+        let c = erofs.lookup("c").unwrap().unwrap();
+        assert_eq!(a.raw_block_addr(), b.raw_block_addr());
+        assert_ne!(a.raw_block_addr(), c.raw_block_addr());
+        for (inode, expected) in [(&a, &data[..]), (&b, &data[..]), (&c, &data[..size - 1])] {
+            let (block, tail) = erofs.get_data(inode).unwrap();
+            assert_eq!([block, tail].concat(), expected);
+        }
+        // one copy each of a and c, c gets written over where b's blocks were given back
+        assert!(image.len() < 2 * size + 64 * 1024);
+    }
+
+    #[test]
+    fn test_squash_to_erofs_layer_too_big() {
+        use peerofs::build::BuilderConfig;
+
+        // a small layer that decompresses to a 64M file of zeros
+        let size = 64 * 1024 * 1024;
+        let layer = {
+            let mut writer = ArchiveBuilder::new(ZstdEncoder::new(vec![], 3).unwrap());
+            let mut h = Header::new_ustar();
+            h.set_mode(0o644);
+            h.set_size(size);
+            writer
+                .append_data(&mut h, "zeros", io::repeat(0).take(size))
+                .unwrap();
+            writer.into_inner().unwrap().finish().unwrap()
+        };
+        assert!(layer.len() < 64 * 1024);
+
+        let mut readers = vec![(Compression::Zstd, Cursor::new(layer))];
+        let mut buf = Cursor::new(vec![]);
+        let builder = ErofsBuilder::new(&mut buf, BuilderConfig::default()).unwrap();
+        let max_layer_size = 1024 * 1024;
+        let ret = squash_to_erofs(&mut readers, builder, Some(max_layer_size));
+        assert!(matches!(ret, Err(Error::LayerTooBig)), "{ret:?}");
+        // stopped before writing much of the file
+        assert!(buf.get_ref().len() < 2 * max_layer_size as usize);
+
+        // under the limit is fine
+        readers[0].1.set_position(0);
+        let mut buf = Cursor::new(vec![]);
+        let builder = ErofsBuilder::new(&mut buf, BuilderConfig::default()).unwrap();
+        squash_to_erofs(&mut readers, builder, Some(2 * size)).unwrap();
+    }
+
     #[rustfmt::skip]
     #[test]
     fn test_squash_deletion_state_update() {
@@ -1,12 +1,17 @@
+use std::ffi::CString;
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Child;
 use std::time::Duration;
 
+use bincode::{Decode, Encode};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use serde::{Deserialize, Serialize};
-use bincode::{Encode, Decode};
+use waitid_timeout::Siginfo;
 
 const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
+// enough room for "pack_at_ms":<u64>, "pack_ms":<u64> and "output_truncated":true
+const RESPONSE_PADDING: usize = 128;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Encode, Decode)]
 pub enum RootfsKind {
@@ -51,6 +56,222 @@ pub struct Config {
     pub response_format: ResponseFormat,
     pub kernel_inspect: bool,
     pub manifest_digest: String,
+    pub binaries: Binaries,
+    // run with the seccomp default action switched to log, see seccomp_log_runtime_config
+    #[serde(default)]
+    pub seccomp_log: bool,
+    // false runs crun in the foreground, which skips the pidfile but also our timeout so we rely
+    // on the host killing the vm, see SigInfoRedux::from_foreground_crun
+    #[serde(default = "default_detach")]
+    pub detach: bool,
+    // size of the tmpfs the overlay upper and work dirs live on, which bounds how much can be
+    // written into the rootfs. None is OVERLAY_DEFAULT_SIZE
+    #[serde(default)]
+    pub overlay_size_mb: Option<u32>,
+    // false mounts the rootfs read only with no overlay, which is only possible when every mount
+    // point already exists in the image, see rootfs_mountpoints. peinit falls back to the overlay
+    // when one doesn't
+    #[serde(default = "default_overlay")]
+    pub overlay: bool,
+    // (key, value) written to /proc/sys before running the container. keys are like
+    // vm.overcommit_memory or vm/overcommit_memory, see sysctl_path
+    #[serde(default)]
+    pub sysctls: Vec<(String, String)>,
+    // have the kernel write a core file into the output dir (so it comes back in the output
+    // archive) when something in the container dumps core, see CORE_PATTERN
+    #[serde(default)]
+    pub capture_core: bool,
+    // run in the same rootfs (with the same mounts) after the container exits and before the
+    // output is packed, to gather files the program doesn't write to the output dir itself. Gets
+    // POST_RUN_TIMEOUT and only shows up in the response as a warning if it doesn't exit 0. What
+    // the program left in /tmp is still there, see shared_tmp_runtime_config, but nothing else it
+    // wrote outside the output dir is unless the rootfs is an overlay
+    #[serde(default)]
+    pub post_run_cmd: Option<Vec<String>>,
+}
+
+fn default_detach() -> bool {
+    true
+}
+
+fn default_overlay() -> bool {
+    true
+}
+
+// a Config with nothing turned on, for tests (here and in the crates that build configs) to fill
+// in whatever they care about with struct update syntax
+#[cfg(any(test, feature = "test-util"))]
+pub fn test_config() -> Config {
+    Config {
+        oci_runtime_config: "{}".into(),
+        timeout: Duration::from_secs(1),
+        stdin: None,
+        strace: false,
+        crun_debug: false,
+        rootfs_dir: None,
+        rootfs_kind: RootfsKind::Erofs,
+        response_format: ResponseFormat::JsonV1,
+        kernel_inspect: false,
+        manifest_digest: "sha256:abcd".into(),
+        binaries: Binaries::default(),
+        seccomp_log: false,
+        detach: true,
+        overlay_size_mb: None,
+        overlay: true,
+        sysctls: vec![],
+        capture_core: false,
+        post_run_cmd: None,
+    }
+}
+
+// same as the tmpfs default but spelled out
+pub const OVERLAY_DEFAULT_SIZE: &str = "50%";
+
+// what /run/output is mounted with at boot, see mount_output in main.rs
+pub const OUTPUT_DEFAULT_SIZE_MB: u64 = 2;
+// with capture_core the output tmpfs grows by this much and it is the core rlimit, so the core
+// always fits (and truncated cores aren't much use)
+pub const CORE_MAX_SIZE_MB: u64 = 64;
+// the container's view of /run/output/dir
+pub const CONTAINER_OUTPUT_DIR: &str = "/run/pe/output";
+// the kernel opens the core file from the crashing process's root, so this has to be the
+// container's path to the output dir. %e is the executable name and %p the pid as the container
+// sees it
+pub const CORE_PATTERN: &str = "/run/pe/output/core.%e.%p";
+pub const CORE_FILE_PREFIX: &str = "core.";
+
+// on top of Config.timeout, so the host has to wait this much longer, see post_run_timeout
+pub const POST_RUN_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Config {
+    pub fn overlay_tmpfs_options(&self) -> CString {
+        let size = match self.overlay_size_mb {
+            Some(mb) => format!("{}m", mb),
+            None => OVERLAY_DEFAULT_SIZE.to_string(),
+        };
+        CString::new(format!("size={},mode=755", size)).unwrap()
+    }
+
+    // options to remount /run/output with, None leaves it at OUTPUT_DEFAULT_SIZE_MB
+    pub fn output_tmpfs_options(&self) -> Option<CString> {
+        if !self.capture_core {
+            return None;
+        }
+        let size = OUTPUT_DEFAULT_SIZE_MB + CORE_MAX_SIZE_MB;
+        Some(CString::new(format!("size={}m", size)).unwrap())
+    }
+
+    // the host has to make the io file at least this big for the output to fit, since by default it
+    // is only as big as the input (rounded up to the pmem alignment). 0 when that is enough
+    pub fn io_file_min_len(&self) -> u64 {
+        if self.capture_core {
+            // the output tmpfs can hold this much, plus some room for the response before it
+            (OUTPUT_DEFAULT_SIZE_MB + CORE_MAX_SIZE_MB + 1) * 1024 * 1024
+        } else {
+            0
+        }
+    }
+
+    // RLIMIT_CORE for crun (and so the container) to inherit
+    pub fn core_rlimit(&self) -> u64 {
+        if self.capture_core {
+            CORE_MAX_SIZE_MB * 1024 * 1024
+        } else {
+            0
+        }
+    }
+
+    // None when there is nothing to run, which includes an empty cmd
+    pub fn post_run_args(&self) -> Option<&[String]> {
+        self.post_run_cmd.as_deref().filter(|x| !x.is_empty())
+    }
+
+    // extra time the host should allow for the post run cmd
+    pub fn post_run_timeout(&self) -> Duration {
+        if self.post_run_args().is_some() {
+            POST_RUN_TIMEOUT
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+// name of a core file written to dir through CORE_PATTERN, if there is one. Only when the
+// container's siginfo says it dumped core, otherwise a file the program wrote that looks like a
+// core would count too
+pub fn find_core_file(dir: &Path, siginfo: &SigInfoRedux) -> Option<String> {
+    if !matches!(siginfo, SigInfoRedux::Dumped(_)) {
+        return None;
+    }
+    let mut names: Vec<_> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(CORE_FILE_PREFIX))
+        .collect();
+    names.sort();
+    names.into_iter().next()
+}
+
+// None when the key isn't a plain path under /proc/sys, so no .. or empty parts. like sysctl(8) we
+// take either . or / as the separator but not both
+pub fn sysctl_path(key: &str) -> Option<PathBuf> {
+    let sep = if key.contains('/') { '/' } else { '.' };
+    let mut path = PathBuf::from("/proc/sys");
+    for part in key.split(sep) {
+        if part.is_empty() || part == "." || part == ".." || part.contains('\0') {
+            return None;
+        }
+        path.push(part);
+    }
+    Some(path)
+}
+
+// what the files under /proc/sys we wrote held before, so a guest serving more than one request
+// can put them back instead of the next request inheriting them
+#[derive(Debug, Default)]
+pub struct SavedSysctls {
+    saved: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl SavedSysctls {
+    // only the value from before the first write to a path is kept
+    pub fn write<V: AsRef<[u8]>>(&mut self, path: &Path, value: V) -> std::io::Result<()> {
+        let old = std::fs::read(path)?;
+        std::fs::write(path, value)?;
+        if !self.saved.iter().any(|(x, _)| x == path) {
+            self.saved.push((path.to_owned(), old));
+        }
+        Ok(())
+    }
+
+    // puts back every value even if some fail, returning the first error
+    pub fn restore(self) -> std::io::Result<()> {
+        let mut ret = Ok(());
+        for (path, old) in self.saved.into_iter().rev() {
+            if let Err(e) = std::fs::write(path, old) {
+                ret = ret.and(Err(e));
+            }
+        }
+        ret
+    }
+}
+
+// paths to the binaries we run inside the guest
+#[derive(Debug, Serialize, Deserialize, Clone, Encode, Decode, PartialEq)]
+pub struct Binaries {
+    pub crun: String,
+    pub strace: String,
+    pub pearchive: String,
+}
+
+impl Default for Binaries {
+    fn default() -> Self {
+        Self {
+            crun: "/bin/crun".into(),
+            strace: "/bin/strace".into(),
+            pearchive: "/bin/pearchive".into(),
+        }
+    }
 }
 
 // this is returned in the API json response, maybe not the right place for it
@@ -60,24 +281,397 @@ pub enum Response {
     Ok {
         siginfo: SigInfoRedux,
         rusage: Rusage,
+        #[serde(default)]
+        timings: Timings,
         #[serde(skip_serializing_if = "Option::is_none")]
         stdout: Option<String>, // not included in ResponseFormat::PeArchiveV1
         #[serde(skip_serializing_if = "Option::is_none")]
         stderr: Option<String>, // not included in ResponseFormat::PeArchiveV1
         manifest_digest: String,
+        // the output archive didn't fit in the io file so some files were left out
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        output_truncated: bool,
+        // kernel audit lines for syscalls the seccomp policy would have denied, only with
+        // Config.seccomp_log
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        seccomp_log: Vec<String>,
+        // things we changed to get the container to run, like a missing working_dir
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+        // None when the cgroup was already gone, like after crun run without --detach
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cgroup_cpu: Option<CgroupCpu>,
+        // name in the output archive of the core file, only with Config.capture_core
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        core_file: Option<String>,
     },
     Overtime {
         siginfo: SigInfoRedux,
         rusage: Rusage,
+        #[serde(default)]
+        timings: Timings,
         #[serde(skip_serializing_if = "Option::is_none")]
         stdout: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         stderr: Option<String>,
         manifest_digest: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        output_truncated: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        seccomp_log: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
     },
+    // something in init itself broke
     Panic {
         message: String,
     },
+    // crun refused to start the container, which is almost always the request's fault (bad oci
+    // config, entrypoint that doesn't exist, ...) and not ours
+    ContainerStartError {
+        message: String,
+    },
+}
+
+// guest side stage timings. The *_at_ms are timestamps in ms since the guest kernel booted
+// (CLOCK_BOOTTIME) taken at the end of each stage, so they are non-decreasing in this order and
+// boot_at_ms is when init started. The other *_ms are how long each stage took.
+// pack is only filled in for ResponseFormat::PeArchiveV1 and is written after the fact with
+// rewrite_io_file_response since the archive comes after the response
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct Timings {
+    pub boot_at_ms: u64,
+    pub unpack_at_ms: u64,
+    pub run_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pack_at_ms: Option<u64>,
+    pub unpack_ms: u64,
+    pub run_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pack_ms: Option<u64>,
+}
+
+impl Timings {
+    pub fn now_ms() -> u64 {
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+        assert!(ret == 0, "clock_gettime failed");
+        (ts.tv_sec as u64) * 1000 + (ts.tv_nsec as u64) / 1_000_000
+    }
+
+    pub fn new(boot_at_ms: u64) -> Self {
+        Self {
+            boot_at_ms: boot_at_ms,
+            ..Default::default()
+        }
+    }
+
+    // each of these ends a stage now
+    pub fn unpacked(&mut self) {
+        self.unpack_at_ms = Self::now_ms();
+        self.unpack_ms = self.unpack_at_ms.saturating_sub(self.boot_at_ms);
+    }
+
+    pub fn ran(&mut self) {
+        self.run_at_ms = Self::now_ms();
+        self.run_ms = self.run_at_ms.saturating_sub(self.unpack_at_ms);
+    }
+
+    pub fn packed(&mut self) {
+        let now = Self::now_ms();
+        self.pack_at_ms = Some(now);
+        self.pack_ms = Some(now.saturating_sub(self.run_at_ms));
+    }
+}
+
+impl Response {
+    pub fn timings_mut(&mut self) -> Option<&mut Timings> {
+        match self {
+            Response::Ok { timings, .. } | Response::Overtime { timings, .. } => Some(timings),
+            Response::Panic { .. } | Response::ContainerStartError { .. } => None,
+        }
+    }
+
+    // we don't know which files were left out so the core might have been one of them
+    pub fn set_output_truncated(&mut self) {
+        match self {
+            Response::Ok {
+                output_truncated,
+                core_file,
+                ..
+            } => {
+                *output_truncated = true;
+                *core_file = None;
+            }
+            Response::Overtime {
+                output_truncated, ..
+            } => {
+                *output_truncated = true;
+            }
+            Response::Panic { .. } | Response::ContainerStartError { .. } => {}
+        }
+    }
+
+    pub fn set_seccomp_log(&mut self, lines: Vec<String>) {
+        match self {
+            Response::Ok { seccomp_log, .. } | Response::Overtime { seccomp_log, .. } => {
+                *seccomp_log = lines;
+            }
+            Response::Panic { .. } | Response::ContainerStartError { .. } => {}
+        }
+    }
+
+    pub fn set_warnings(&mut self, lines: Vec<String>) {
+        match self {
+            Response::Ok { warnings, .. } | Response::Overtime { warnings, .. } => {
+                *warnings = lines;
+            }
+            Response::Panic { .. } | Response::ContainerStartError { .. } => {}
+        }
+    }
+}
+
+// errors getting the container running, split so the response says whose fault it was
+#[derive(Debug)]
+pub enum ContainerError {
+    Io(std::io::Error),
+    Start(String),
+}
+
+impl From<std::io::Error> for ContainerError {
+    fn from(e: std::io::Error) -> Self {
+        ContainerError::Io(e)
+    }
+}
+
+impl From<ContainerError> for Response {
+    fn from(e: ContainerError) -> Self {
+        match e {
+            ContainerError::Io(e) => Response::Panic {
+                message: format!("{:?}", e),
+            },
+            ContainerError::Start(message) => Response::ContainerStartError { message },
+        }
+    }
+}
+
+const CRUN_STDERR_MAX_LEN: usize = 2000;
+
+// a detached `crun run` exits once the container is started, so it exiting unclean means the
+// container never started and its stderr says why
+pub fn wait_crun_started(mut child: Child, stderr_path: &Path) -> Result<(), ContainerError> {
+    let exit_status = child.wait()?;
+    if !exit_status.success() {
+        let stderr = read_n_or_str_error(stderr_path, CRUN_STDERR_MAX_LEN);
+        return Err(ContainerError::Start(format!(
+            "crun unclean exit status {:?} {}",
+            exit_status, stderr
+        )));
+    }
+    Ok(())
+}
+
+fn read_n_or_str_error(path: &Path, n: usize) -> String {
+    match std::fs::File::open(path) {
+        Err(e) => format!("error opening file {} {:?}", path.display(), e),
+        Ok(f) => {
+            let mut buf = String::with_capacity(n);
+            match f.take(n as u64).read_to_string(&mut buf) {
+                Ok(_) => buf,
+                Err(e) => format!("error reading file {} {:?}", path.display(), e),
+            }
+        }
+    }
+}
+
+// a program hitting a denied syscall normally just sees ENOSYS and dies in some confusing way. in
+// log mode the kernel lets the syscall through but writes an audit record (type=1326) to the
+// kernel log for each one, which we pick back up from /dev/kmsg and put in the response
+pub const SECCOMP_LOG_ACTION: &str = "SCMP_ACT_LOG";
+pub const SECCOMP_LOG_MAX_ENTRIES: usize = 32;
+const AUDIT_SECCOMP: &str = "type=1326";
+
+// only the default action is switched, explicit rules in the policy are left alone. a spec without
+// a seccomp policy is passed through unchanged
+pub fn seccomp_log_runtime_config(oci_runtime_config: &str) -> Result<String, Error> {
+    let mut spec: serde_json::Value =
+        serde_json::from_str(oci_runtime_config).map_err(|_| Error::Ser)?;
+    if let Some(seccomp) = spec
+        .pointer_mut("/linux/seccomp")
+        .and_then(|x| x.as_object_mut())
+    {
+        seccomp.insert("defaultAction".into(), SECCOMP_LOG_ACTION.into());
+    }
+    serde_json::to_string(&spec).map_err(|_| Error::Ser)
+}
+
+// the same container but running args instead, with no terminal since there's no one to talk to
+pub fn post_run_runtime_config(oci_runtime_config: &str, args: &[String]) -> Result<String, Error> {
+    let mut spec: serde_json::Value =
+        serde_json::from_str(oci_runtime_config).map_err(|_| Error::Ser)?;
+    let process = spec
+        .get_mut("process")
+        .and_then(|x| x.as_object_mut())
+        .ok_or(Error::Ser)?;
+    process.insert("args".into(), args.into());
+    process.insert("terminal".into(), false.into());
+    serde_json::to_string(&spec).map_err(|_| Error::Ser)
+}
+
+// with a post run cmd the /tmp tmpfs has to outlive the first container, so instead of crun
+// mounting a fresh one in each we mount it once at source and both get it as a bind mount. Returns
+// the new runtime config and the tmpfs mount options, or None when there's no tmpfs at /tmp
+pub fn shared_tmp_runtime_config(
+    oci_runtime_config: &str,
+    source: &str,
+) -> Result<Option<(String, String)>, Error> {
+    let mut spec: serde_json::Value =
+        serde_json::from_str(oci_runtime_config).map_err(|_| Error::Ser)?;
+    let Some(mount) = spec
+        .get_mut("mounts")
+        .and_then(|x| x.as_array_mut())
+        .and_then(|mounts| {
+            mounts
+                .iter_mut()
+                .find(|x| x["destination"] == "/tmp" && x["type"] == "tmpfs")
+        })
+    else {
+        return Ok(None);
+    };
+    let options = match mount.get("options").and_then(|x| x.as_array()) {
+        Some(options) => options
+            .iter()
+            .map(|x| x.as_str().ok_or(Error::Ser))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(","),
+        None => String::new(),
+    };
+    *mount = serde_json::json!({
+        "destination": "/tmp",
+        "type": "bind",
+        "source": source,
+        "options": ["rw", "rbind"],
+    });
+    let config = serde_json::to_string(&spec).map_err(|_| Error::Ser)?;
+    Ok(Some((config, options)))
+}
+
+// the image's working_dir ends up as process.cwd and crun fails with a not very helpful error when
+// it isn't a dir in the rootfs, so we swap it for / instead. symlinks are resolved within rootfs.
+// returns the new runtime config and a warning for the response when we changed it
+pub fn fallback_missing_cwd(
+    oci_runtime_config: &str,
+    rootfs: &Path,
+) -> Result<Option<(String, String)>, Error> {
+    let mut spec: serde_json::Value =
+        serde_json::from_str(oci_runtime_config).map_err(|_| Error::Ser)?;
+    let Some(cwd) = spec.pointer_mut("/process/cwd") else {
+        return Ok(None);
+    };
+    let warning = match cwd.as_str() {
+        Some(path) if dir_exists_in_root(rootfs, path) => {
+            return Ok(None);
+        }
+        Some(path) => format!("working_dir {path:?} does not exist in the image, using /"),
+        None => "working_dir is not a string, using /".to_string(),
+    };
+    *cwd = "/".into();
+    let config = serde_json::to_string(&spec).map_err(|_| Error::Ser)?;
+    Ok(Some((config, warning)))
+}
+
+fn dir_exists_in_root(root: &Path, path: &str) -> bool {
+    use rustix::fs::{Mode, OFlags, ResolveFlags};
+    let flags = OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC;
+    let Ok(root) = rustix::fs::open(root, flags, Mode::empty()) else {
+        return false;
+    };
+    rustix::fs::openat2(root, path, flags, Mode::empty(), ResolveFlags::IN_ROOT).is_ok()
+}
+
+// mount destinations in the runtime config that crun would have to find (or create) in the rootfs
+// itself, ie those not nested under another mount like /dev/pts under /dev
+pub fn rootfs_mountpoints(oci_runtime_config: &str) -> Result<Vec<String>, Error> {
+    let spec: serde_json::Value =
+        serde_json::from_str(oci_runtime_config).map_err(|_| Error::Ser)?;
+    let destinations: Vec<&str> = spec
+        .get("mounts")
+        .and_then(|x| x.as_array())
+        .map(|mounts| {
+            mounts
+                .iter()
+                .filter_map(|x| x.get("destination")?.as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(destinations
+        .iter()
+        .filter(|x| {
+            !destinations
+                .iter()
+                .any(|parent| parent != *x && Path::new(x).starts_with(parent))
+        })
+        .map(|x| x.to_string())
+        .collect())
+}
+
+// a /dev/kmsg record is "prio,seq,usec,flags;message" followed by optional " KEY=value" lines
+pub fn parse_seccomp_log_record(record: &str) -> Option<&str> {
+    let (_, message) = record.split_once(';')?;
+    let message = message.lines().next()?;
+    if message.contains(AUDIT_SECCOMP) {
+        Some(message)
+    } else {
+        None
+    }
+}
+
+// crun writes --pid-file itself and on a very fast exit we can go to read it before the pid is
+// there, so give it a few tries before giving up
+pub const PIDFILE_ATTEMPTS: u32 = 20;
+pub const PIDFILE_RETRY_DELAY: Duration = Duration::from_millis(5);
+
+pub fn read_pidfile<P: AsRef<Path>>(
+    path: P,
+    attempts: u32,
+    delay: Duration,
+) -> std::io::Result<i32> {
+    let path = path.as_ref();
+    let mut last = None;
+    for i in 0..attempts {
+        if i > 0 {
+            std::thread::sleep(delay);
+        }
+        match std::fs::read_to_string(path) {
+            Ok(contents) => match contents.trim().parse::<i32>() {
+                Ok(pid) if pid > 0 => {
+                    return Ok(pid);
+                }
+                _ => {
+                    last = Some(contents);
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+    let message = match last {
+        Some(contents) => format!(
+            "pidfile {} has no pid after {attempts} attempts, contents {contents:?}",
+            path.display()
+        ),
+        None => format!(
+            "pidfile {} does not exist after {attempts} attempts",
+            path.display()
+        ),
+    };
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        message,
+    ))
 }
 
 //#[derive(Debug, Serialize, Deserialize, Clone)]
@@ -95,7 +689,7 @@ pub struct TimeVal {
 }
 
 // this is a portion of siginfo interpreted from waitid(2)
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum SigInfoRedux {
     Exited(i32),
     Killed(i32),
@@ -126,6 +720,38 @@ pub struct Rusage {
     pub ru_nivcsw: i64,    /* involuntary context switches */
 }
 
+// cpu time of everything in the container's cgroup (from its cpu.stat), unlike rusage which is for
+// the process we waited on and doesn't include crun's own setup when it is the process
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct CgroupCpu {
+    pub usage_usec: u64,
+    pub user_usec: u64,
+    pub system_usec: u64,
+}
+
+// cpu.stat is "key value" lines, we only need the three that are always there (the rest depend on
+// which controllers are enabled)
+pub fn parse_cpu_stat(stat: &str) -> Option<CgroupCpu> {
+    let (mut usage, mut user, mut system) = (None, None, None);
+    for line in stat.lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+            continue;
+        };
+        let slot = match key {
+            "usage_usec" => &mut usage,
+            "user_usec" => &mut user,
+            "system_usec" => &mut system,
+            _ => continue,
+        };
+        *slot = Some(value.trim().parse().ok()?);
+    }
+    Some(CgroupCpu {
+        usage_usec: usage?,
+        user_usec: user?,
+        system_usec: system?,
+    })
+}
+
 // impl From<libc::c_int> for Status {
 //     fn from(status: libc::c_int) -> Self {
 //         Self {
@@ -137,21 +763,36 @@ pub struct Rusage {
 
 impl From<libc::siginfo_t> for SigInfoRedux {
     fn from(siginfo: libc::siginfo_t) -> Self {
-        let status = unsafe { siginfo.si_status() }; // why is this unsafe?
-        match siginfo.si_code {
-            libc::CLD_EXITED => SigInfoRedux::Exited(status),
-            libc::CLD_KILLED => SigInfoRedux::Killed(status),
-            libc::CLD_DUMPED => SigInfoRedux::Dumped(status),
-            libc::CLD_TRAPPED => SigInfoRedux::Trapped(status),
-            libc::CLD_CONTINUED => SigInfoRedux::Continued(status),
-            _ => SigInfoRedux::Unk {
-                code: siginfo.si_code,
+        match Siginfo::from(&siginfo) {
+            Siginfo::Exited(status) => SigInfoRedux::Exited(status),
+            Siginfo::Killed(status) => SigInfoRedux::Killed(status),
+            Siginfo::Dumped(status) => SigInfoRedux::Dumped(status),
+            Siginfo::Stopped(status) => SigInfoRedux::Stopped(status),
+            Siginfo::Trapped(status) => SigInfoRedux::Trapped(status),
+            Siginfo::Continued(status) => SigInfoRedux::Continued(status),
+            Siginfo::Unk { code, status } => SigInfoRedux::Unk {
+                code: code,
                 status: status,
             },
         }
     }
 }
 
+impl SigInfoRedux {
+    // crun run (without --detach) exits with the container's exit code or 128 + signal if it was
+    // killed, so turn that back into what waitid on the container would have given us. This is
+    // lossy: a core dump looks like a kill, a program exiting with 129 looks like a SIGHUP, and
+    // crun failing itself looks like the program exiting with 1
+    pub fn from_foreground_crun(siginfo: libc::siginfo_t) -> Self {
+        match Self::from(siginfo) {
+            SigInfoRedux::Exited(code) if code > 128 && code - 128 <= libc::SIGRTMAX() => {
+                SigInfoRedux::Killed(code - 128)
+            }
+            x => x,
+        }
+    }
+}
+
 impl From<libc::timeval> for TimeVal {
     fn from(tv: libc::timeval) -> Self {
         TimeVal {
@@ -188,6 +829,7 @@ impl From<libc::rusage> for Rusage {
 pub enum Error {
     Io,
     Ser,
+    Sync,
 }
 
 // todo use a single write
@@ -227,13 +869,94 @@ pub fn write_io_file_config<W: Write>(
 }
 
 pub fn read_io_file_config<R: Read>(file: &mut R) -> Result<(u32, Config), Error> {
-    let (archive_size, response_size) = read_u32_le_pair(file).map_err(|_| Error::Io)?;
-    let mut buf = vec![0; response_size as usize];
+    let (archive_size, config_size) = read_u32_le_pair(file).map_err(|_| Error::Io)?;
+    let config = read_config(file, config_size)?;
+    Ok((archive_size, config))
+}
+
+fn read_config<R: Read>(file: &mut R, config_size: u32) -> Result<Config, Error> {
+    let mut buf = vec![0; config_size as usize];
     file.read_exact(&mut buf).map_err(|_| Error::Io)?;
     let (config, _) = bincode::decode_from_slice(&buf, BINCODE_CONFIG).map_err(|_| Error::Ser)?;
+    Ok(config)
+}
+
+// same framing as read_io_file_config but for a stream (like a vsock) where we can't leave the
+// archive in place, so the archive is copied into archive_out. Errors if the stream ends early
+pub fn read_io_stream<R: Read, W: Write>(
+    stream: &mut R,
+    archive_out: &mut W,
+) -> Result<(u32, Config), Error> {
+    let (archive_size, config) = read_io_file_config(stream)?;
+    let copied =
+        std::io::copy(&mut stream.take(archive_size.into()), archive_out).map_err(|_| Error::Io)?;
+    if copied != u64::from(archive_size) {
+        return Err(Error::Io);
+    }
     Ok((archive_size, config))
 }
 
+// when the guest is serving multiple requests off one stream, the host ends the stream with a
+// header of both sizes 0. An encoded Config is never empty so this can't be a real request
+pub fn write_io_stream_end<W: Write>(stream: &mut W) -> Result<(), Error> {
+    write_u32_le_slice(stream, &[0, 0]).map_err(|_| Error::Io)
+}
+
+// like read_io_stream but returns None when the stream was ended with write_io_stream_end
+pub fn read_io_stream_next<R: Read, W: Write>(
+    stream: &mut R,
+    archive_out: &mut W,
+) -> Result<Option<(u32, Config)>, Error> {
+    let (archive_size, config_size) = read_u32_le_pair(stream).map_err(|_| Error::Io)?;
+    if archive_size == 0 && config_size == 0 {
+        return Ok(None);
+    }
+    let config = read_config(stream, config_size)?;
+    let copied =
+        std::io::copy(&mut stream.take(archive_size.into()), archive_out).map_err(|_| Error::Io)?;
+    if copied != u64::from(archive_size) {
+        return Err(Error::Io);
+    }
+    Ok(Some((archive_size, config)))
+}
+
+// written back on the stream after each request's response is in place so the host knows it can
+// read the response and then send the next request
+pub const IO_STREAM_DONE: u8 = 1;
+
+// runs requests off the stream until the end sentinel. archive_out makes somewhere to put each
+// request's archive, run gets the request, and reset is called between runs (not before the first
+// or after the last) to put things back how run expects them. Returns the number of requests run
+pub fn serve_io_stream<S, W, A, R, X>(
+    stream: &mut S,
+    mut archive_out: A,
+    mut run: R,
+    mut reset: X,
+) -> Result<usize, Error>
+where
+    S: Read + Write,
+    W: Write,
+    A: FnMut() -> W,
+    R: FnMut(u32, Config, W),
+    X: FnMut(),
+{
+    let mut n = 0;
+    loop {
+        let mut out = archive_out();
+        let (archive_size, config) = match read_io_stream_next(stream, &mut out)? {
+            Some(x) => x,
+            None => return Ok(n),
+        };
+        if n > 0 {
+            reset();
+        }
+        run(archive_size, config, out);
+        n += 1;
+        stream.write_all(&[IO_STREAM_DONE]).map_err(|_| Error::Io)?;
+        stream.flush().map_err(|_| Error::Io)?;
+    }
+}
+
 // coming out of the guest, we have
 // <u32: archive size> <u32: response size> <response> <archive>
 // response is always in json format and archive_size may be 0
@@ -245,6 +968,64 @@ pub fn write_io_file_response<W: Write>(file: &mut W, response: &Response) -> Re
     Ok(())
 }
 
+// same as write_io_file_response but leaves room after the response (as trailing whitespace which
+// the json parser ignores) so that it can be updated with rewrite_io_file_response after the
+// archive has been written
+pub fn write_io_file_response_padded<W: Write>(
+    file: &mut W,
+    response: &Response,
+) -> Result<(), Error> {
+    let mut response_bytes = serde_json::to_vec(&response).map_err(|_| Error::Ser)?;
+    response_bytes.resize(response_bytes.len() + RESPONSE_PADDING, b' ');
+    let response_size: u32 = response_bytes.len().try_into().unwrap();
+    write_u32_le_slice(file, &[0, response_size]).map_err(|_| Error::Io)?;
+    file.write_all(&response_bytes).map_err(|_| Error::Io)?;
+    Ok(())
+}
+
+// overwrites the response in place, keeping the archive size and response size as is. The new
+// response has to fit in the space of the old one (plus its padding)
+pub fn rewrite_io_file_response<F: Read + Write + Seek>(
+    file: &mut F,
+    response: &Response,
+) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(0)).map_err(|_| Error::Io)?;
+    let (_archive_size, response_size) = read_u32_le_pair(file).map_err(|_| Error::Io)?;
+    let mut response_bytes = serde_json::to_vec(&response).map_err(|_| Error::Ser)?;
+    if response_bytes.len() > response_size as usize {
+        return Err(Error::Ser);
+    }
+    response_bytes.resize(response_size as usize, b' ');
+    file.write_all(&response_bytes).map_err(|_| Error::Io)?;
+    Ok(())
+}
+
+// the io file is a pmem device and writes to it can be silently lost if we exit (or the host reads
+// it) before they're synced, so the final write of a response always goes through sync_io_file
+pub trait SyncData {
+    fn sync_data(&self) -> std::io::Result<()>;
+}
+
+impl SyncData for std::fs::File {
+    fn sync_data(&self) -> std::io::Result<()> {
+        std::fs::File::sync_data(self)
+    }
+}
+
+// a failed sync is Error::Sync (not Io) so the caller knows the response may never be seen
+pub fn sync_io_file<F: Write + SyncData>(file: &mut F) -> Result<(), Error> {
+    file.flush().map_err(|_| Error::Io)?;
+    file.sync_data().map_err(|_| Error::Sync)
+}
+
+pub fn write_io_file_response_synced<F: Write + SyncData>(
+    file: &mut F,
+    response: &Response,
+) -> Result<(), Error> {
+    write_io_file_response(file, response)?;
+    sync_io_file(file)
+}
+
 // coming out of the guest, we have
 // <u32: archive size> <u32: response size> <response> <archive>
 // response is always in json format and archive_size may be 0
@@ -277,3 +1058,848 @@ pub fn read_io_file_response<R: Read + Seek>(file: &mut R) -> Result<(u32, Respo
     let response = serde_json::from_slice(&response_bytes).map_err(|_| Error::Ser)?;
     Ok((archive_size, response))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::Command;
+
+    use waitid_timeout::{ChildWaitIdExt, WaitIdData};
+
+    // records the order of writes/flushes/syncs and can be made to fail the sync
+    struct SyncRecorder {
+        data: Vec<u8>,
+        ops: std::cell::RefCell<Vec<&'static str>>,
+        fail_sync: bool,
+    }
+
+    impl Write for SyncRecorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.ops.get_mut().push("write");
+            self.data.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.ops.get_mut().push("flush");
+            Ok(())
+        }
+    }
+
+    impl SyncData for SyncRecorder {
+        fn sync_data(&self) -> std::io::Result<()> {
+            self.ops.borrow_mut().push("sync");
+            if self.fail_sync {
+                Err(std::io::Error::other("sync failed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_response_synced() {
+        let response = Response::Panic {
+            message: "oh no".into(),
+        };
+        let mut f = SyncRecorder {
+            data: vec![],
+            ops: vec![].into(),
+            fail_sync: false,
+        };
+        write_io_file_response_synced(&mut f, &response).unwrap();
+        // everything is written, then flushed, then synced
+        let ops = f.ops.take();
+        assert!(ops.len() > 2);
+        assert_eq!(ops[ops.len() - 2..], ["flush", "sync"]);
+        assert!(ops[..ops.len() - 2].iter().all(|x| *x == "write"));
+        let (archive_size, read) = read_io_file_response(&mut Cursor::new(&f.data)).unwrap();
+        assert_eq!(archive_size, 0);
+        assert!(matches!(read, Response::Panic { message } if message == "oh no"));
+
+        f.fail_sync = true;
+        assert!(matches!(
+            write_io_file_response_synced(&mut f, &response),
+            Err(Error::Sync)
+        ));
+        assert_eq!(f.ops.take().last(), Some(&"sync"));
+
+        // and with a real file
+        let mut file = tempfile::tempfile().unwrap();
+        write_io_file_response_synced(&mut file, &response).unwrap();
+        let (_, read) = read_io_file_response(&mut file).unwrap();
+        assert!(matches!(read, Response::Panic { .. }));
+    }
+
+    #[test]
+    fn test_read_pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pid");
+        let delay = Duration::from_millis(1);
+
+        let err = read_pidfile(&path, 3, delay).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("does not exist after 3 attempts"));
+
+        // crun created the file but hasn't written the pid yet
+        std::fs::write(&path, "").unwrap();
+        let err = read_pidfile(&path, 3, delay).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("has no pid after 3 attempts"));
+
+        std::fs::write(&path, "12ab").unwrap();
+        assert!(read_pidfile(&path, 1, delay).is_err());
+        std::fs::write(&path, "0").unwrap();
+        assert!(read_pidfile(&path, 1, delay).is_err());
+
+        std::fs::write(&path, "1234").unwrap();
+        assert_eq!(read_pidfile(&path, 1, delay).unwrap(), 1234);
+        std::fs::write(&path, "1234\n").unwrap();
+        assert_eq!(read_pidfile(&path, 1, delay).unwrap(), 1234);
+
+        // pid shows up while we're retrying
+        std::fs::write(&path, "").unwrap();
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                std::fs::write(path, "42").unwrap();
+            })
+        };
+        assert_eq!(
+            read_pidfile(&path, 1000, Duration::from_millis(1)).unwrap(),
+            42
+        );
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_rusage_from_waitid() {
+        // busy loop in the shell to rack up some user time
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg("i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done")
+            .spawn()
+            .unwrap();
+        let WaitIdData::Exited { rusage, .. } =
+            child.wait_timeout(Duration::from_secs(10)).unwrap()
+        else {
+            panic!("not exited");
+        };
+        let rusage: Rusage = rusage.into();
+        assert!(rusage.ru_utime.sec > 0 || rusage.ru_utime.usec > 0);
+        assert!(rusage.ru_maxrss > 0);
+    }
+
+    #[test]
+    fn test_wait_crun_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let stderr_path = dir.path().join("stderr");
+
+        let crun = |script: &str| {
+            let stderr = std::fs::File::create(&stderr_path).unwrap();
+            Command::new("sh")
+                .arg("-c")
+                .arg(script)
+                .stderr(stderr)
+                .spawn()
+                .unwrap()
+        };
+
+        assert!(wait_crun_started(crun("exit 0"), &stderr_path).is_ok());
+
+        // what crun does with a config.json it doesn't like
+        let err = wait_crun_started(
+            crun("echo 'cannot parse config.json' >&2; exit 1"),
+            &stderr_path,
+        )
+        .unwrap_err();
+        match Response::from(err) {
+            Response::ContainerStartError { message } => {
+                assert!(message.contains("cannot parse config.json"), "{message}");
+            }
+            r => panic!("expected ContainerStartError, got {r:?}"),
+        }
+
+        let io_err = ContainerError::from(std::io::Error::other("oh no"));
+        assert!(matches!(Response::from(io_err), Response::Panic { .. }));
+    }
+
+    #[test]
+    fn test_config_binaries() {
+        let mut config = test_config();
+        assert_eq!(config.binaries.crun, "/bin/crun");
+        assert_eq!(config.binaries.strace, "/bin/strace");
+        assert_eq!(config.binaries.pearchive, "/bin/pearchive");
+
+        config.binaries.crun = "/bin/youki".into();
+        let mut file = Cursor::new(vec![]);
+        write_io_file_config(&mut file, &config, 42).unwrap();
+        file.set_position(0);
+        let (archive_size, got) = read_io_file_config(&mut file).unwrap();
+        assert_eq!(archive_size, 42);
+        assert_eq!(
+            got.binaries,
+            Binaries {
+                crun: "/bin/youki".into(),
+                ..Binaries::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_overlay_tmpfs_options() {
+        let mut config = test_config();
+        assert_eq!(
+            config.overlay_tmpfs_options().as_c_str(),
+            c"size=50%,mode=755"
+        );
+
+        config.overlay_size_mb = Some(64);
+        let mut file = Cursor::new(vec![]);
+        write_io_file_config(&mut file, &config, 0).unwrap();
+        file.set_position(0);
+        let (_, got) = read_io_file_config(&mut file).unwrap();
+        assert_eq!(got.overlay_size_mb, Some(64));
+        assert_eq!(got.overlay_tmpfs_options().as_c_str(), c"size=64m,mode=755");
+    }
+
+    #[test]
+    fn test_sysctls() {
+        let config = Config {
+            sysctls: vec![("vm.overcommit_memory".into(), "1".into())],
+            ..test_config()
+        };
+        let mut file = Cursor::new(vec![]);
+        write_io_file_config(&mut file, &config, 0).unwrap();
+        file.set_position(0);
+        let (_, got) = read_io_file_config(&mut file).unwrap();
+        assert_eq!(got.sysctls, config.sysctls);
+
+        // older json configs without the field have none
+        let mut json = serde_json::to_value(&config).unwrap();
+        json.as_object_mut().unwrap().remove("sysctls");
+        let got: Config = serde_json::from_value(json).unwrap();
+        assert!(got.sysctls.is_empty());
+
+        let path = |key| sysctl_path(key).map(|x| x.into_os_string().into_string().unwrap());
+        assert_eq!(
+            path("vm.overcommit_memory").as_deref(),
+            Some("/proc/sys/vm/overcommit_memory")
+        );
+        assert_eq!(
+            path("net/ipv4/conf/eth0.1/rp_filter").as_deref(),
+            Some("/proc/sys/net/ipv4/conf/eth0.1/rp_filter")
+        );
+        for key in [
+            "",
+            "vm..overcommit_memory",
+            ".vm.overcommit_memory",
+            "vm/",
+            "/vm/overcommit_memory",
+            "../../etc/passwd",
+            "vm/../../etc/passwd",
+            "vm/./overcommit_memory",
+            "vm.overcommit\0memory",
+        ] {
+            assert_eq!(path(key), None, "{key:?}");
+        }
+    }
+
+    #[test]
+    fn test_capture_core() {
+        let mut config = Config {
+            response_format: ResponseFormat::PeArchiveV1,
+            ..test_config()
+        };
+        assert_eq!(config.output_tmpfs_options(), None);
+        assert_eq!(config.core_rlimit(), 0);
+        assert_eq!(config.io_file_min_len(), 0);
+
+        config.capture_core = true;
+        let mut file = Cursor::new(vec![]);
+        write_io_file_config(&mut file, &config, 0).unwrap();
+        file.set_position(0);
+        let (_, got) = read_io_file_config(&mut file).unwrap();
+        assert!(got.capture_core);
+        assert_eq!(got.output_tmpfs_options().unwrap().as_c_str(), c"size=66m");
+        assert_eq!(got.core_rlimit(), 64 * 1024 * 1024);
+        // a whole core and the rest of the output fit after the response
+        assert!(got.io_file_min_len() > (66 * 1024 * 1024 + RESPONSE_PADDING) as u64);
+
+        // the core has to land in the output dir for it to be packed
+        let pattern = Path::new(CORE_PATTERN);
+        assert_eq!(pattern.parent(), Some(Path::new(CONTAINER_OUTPUT_DIR)));
+        let name = pattern.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(CORE_FILE_PREFIX));
+        // a pipe would run a helper instead of writing a file
+        assert!(!CORE_PATTERN.starts_with('|'));
+
+        let dumped = SigInfoRedux::Dumped(libc::SIGSEGV);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("stdout"), b"").unwrap();
+        assert_eq!(find_core_file(dir.path(), &dumped), None);
+        std::fs::write(dir.path().join("core.a.out.7"), b"").unwrap();
+        assert_eq!(
+            find_core_file(dir.path(), &dumped).as_deref(),
+            Some("core.a.out.7")
+        );
+        assert_eq!(find_core_file(&dir.path().join("nope"), &dumped), None);
+        // the program wrote a file named like a core but didn't dump one
+        for siginfo in [SigInfoRedux::Exited(0), SigInfoRedux::Killed(libc::SIGSEGV)] {
+            assert_eq!(find_core_file(dir.path(), &siginfo), None);
+        }
+
+        // older json configs without the field don't capture
+        let mut json = serde_json::to_value(&config).unwrap();
+        json.as_object_mut().unwrap().remove("capture_core");
+        let got: Config = serde_json::from_value(json).unwrap();
+        assert!(!got.capture_core);
+    }
+
+    #[test]
+    fn test_post_run_cmd() {
+        let mut config = Config {
+            oci_runtime_config: r#"{
+                "process": {"args": ["/bin/prog"], "cwd": "/", "terminal": true},
+                "mounts": [{"destination": "/run/pe/output", "type": "bind"}]
+            }"#
+            .into(),
+            response_format: ResponseFormat::PeArchiveV1,
+            ..test_config()
+        };
+        assert_eq!(config.post_run_args(), None);
+        assert_eq!(config.post_run_timeout(), Duration::ZERO);
+
+        config.post_run_cmd = Some(vec![]);
+        assert_eq!(config.post_run_args(), None);
+
+        let args = vec![
+            "cp".to_string(),
+            "/tmp/stats.json".to_string(),
+            format!("{CONTAINER_OUTPUT_DIR}/"),
+        ];
+        config.post_run_cmd = Some(args.clone());
+        let mut file = Cursor::new(vec![]);
+        write_io_file_config(&mut file, &config, 0).unwrap();
+        file.set_position(0);
+        let (_, got) = read_io_file_config(&mut file).unwrap();
+        assert_eq!(got.post_run_args(), Some(args.as_slice()));
+        assert_eq!(got.post_run_timeout(), POST_RUN_TIMEOUT);
+
+        // only the process changes, the mounts (and so the output dir) are the same
+        let post_run: serde_json::Value =
+            serde_json::from_str(&post_run_runtime_config(&got.oci_runtime_config, &args).unwrap())
+                .unwrap();
+        assert_eq!(
+            post_run["process"],
+            serde_json::json!({"args": args, "cwd": "/", "terminal": false})
+        );
+        let spec: serde_json::Value = serde_json::from_str(&got.oci_runtime_config).unwrap();
+        assert_eq!(post_run["mounts"], spec["mounts"]);
+        assert!(post_run_runtime_config("{}", &args).is_err());
+
+        // older json configs without the field have nothing to run
+        let mut json = serde_json::to_value(&config).unwrap();
+        json.as_object_mut().unwrap().remove("post_run_cmd");
+        let got: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(got.post_run_args(), None);
+    }
+
+    #[test]
+    fn test_post_run_cmd_shared_tmp() {
+        // where a path in the container ends up under root, going through the bind mounts
+        fn host_path(spec: &serde_json::Value, root: &Path, path: &str) -> PathBuf {
+            let mount = spec["mounts"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|x| x["type"] == "bind")
+                .find(|x| path.starts_with(x["destination"].as_str().unwrap()))
+                .unwrap();
+            let rest = &path[mount["destination"].as_str().unwrap().len()..];
+            let source = mount["source"].as_str().unwrap().trim_start_matches('/');
+            root.join(source).join(rest.trim_start_matches('/'))
+        }
+
+        // the /tmp and output mounts as perunner's create_runtime_spec makes them
+        let oci_runtime_config = r#"{
+            "process": {"args": ["/bin/prog"], "cwd": "/", "terminal": false},
+            "mounts": [
+                {"destination": "/proc", "type": "proc", "source": "proc"},
+                {"destination": "/tmp", "type": "tmpfs", "options": ["size=50%", "mode=777"]},
+                {"destination": "/run/pe/output", "type": "bind", "source": "/run/output/dir",
+                 "options": ["rw", "rbind"]}
+            ]
+        }"#;
+        let (shared, options) = shared_tmp_runtime_config(oci_runtime_config, "/run/tmp")
+            .unwrap()
+            .unwrap();
+        assert_eq!(options, "size=50%,mode=777");
+        let args: Vec<String> = ["cp", "/tmp/stats.json", "/run/pe/output"]
+            .map(String::from)
+            .into();
+        let post_run: serde_json::Value =
+            serde_json::from_str(&post_run_runtime_config(&shared, &args).unwrap()).unwrap();
+        let spec: serde_json::Value = serde_json::from_str(&shared).unwrap();
+        assert_eq!(post_run["mounts"], spec["mounts"]);
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("run/tmp")).unwrap();
+        std::fs::create_dir_all(root.path().join("run/output/dir")).unwrap();
+        // the program writes to its /tmp and exits, then the post run cmd runs with the paths it
+        // would see
+        std::fs::write(host_path(&spec, root.path(), "/tmp/stats.json"), "{}").unwrap();
+        let args: Vec<_> = post_run["process"]["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x.as_str().unwrap())
+            .collect();
+        let status = std::process::Command::new(args[0])
+            .args(
+                args[1..]
+                    .iter()
+                    .map(|x| host_path(&post_run, root.path(), x)),
+            )
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("run/output/dir/stats.json")).unwrap(),
+            "{}"
+        );
+
+        assert_eq!(
+            shared_tmp_runtime_config(r#"{"mounts": []}"#, "/run/tmp").unwrap(),
+            None
+        );
+        assert!(shared_tmp_runtime_config(
+            r#"{"mounts": [{"destination": "/tmp", "type": "tmpfs", "options": [1]}]}"#,
+            "/run/tmp"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_no_overlay() {
+        let config = Config {
+            oci_runtime_config: r#"{"mounts": [
+                {"destination": "/proc", "type": "proc"},
+                {"destination": "/dev", "type": "tmpfs"},
+                {"destination": "/dev/pts", "type": "devpts"},
+                {"destination": "/tmp", "type": "tmpfs"},
+                {"destination": "/run/pe/input", "type": "bind"},
+                {"destination": "/run/pe/output", "type": "bind"}
+            ]}"#
+            .into(),
+            overlay: false,
+            ..test_config()
+        };
+        let mut file = Cursor::new(vec![]);
+        write_io_file_config(&mut file, &config, 0).unwrap();
+        file.set_position(0);
+        let (_, got) = read_io_file_config(&mut file).unwrap();
+        assert!(!got.overlay);
+
+        assert_eq!(
+            rootfs_mountpoints(&got.oci_runtime_config).unwrap(),
+            vec!["/proc", "/dev", "/tmp", "/run/pe/input", "/run/pe/output"]
+        );
+        assert!(rootfs_mountpoints("{}").unwrap().is_empty());
+        assert!(rootfs_mountpoints("{").is_err());
+
+        // older json configs without the field keep the overlay
+        let mut json = serde_json::to_value(&config).unwrap();
+        json.as_object_mut().unwrap().remove("overlay");
+        let got: Config = serde_json::from_value(json).unwrap();
+        assert!(got.overlay);
+    }
+
+    #[test]
+    fn test_foreground_crun_siginfo() {
+        // sh stands in for crun here: it waits on its child and exits with its code or 128 + sig
+        fn detached(script: &str) -> SigInfoRedux {
+            let child = Command::new("sh").arg("-c").arg(script).spawn().unwrap();
+            let data = child.wait_timeout(Duration::from_secs(10)).unwrap();
+            let WaitIdData::Exited { siginfo, .. } = data else {
+                panic!("expected exited");
+            };
+            siginfo.into()
+        }
+        fn foreground(script: &str) -> SigInfoRedux {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(r#"sh -c "$0"; exit $?"#)
+                .arg(script)
+                .spawn()
+                .unwrap();
+            let data = child.wait_timeout(Duration::from_secs(10)).unwrap();
+            let WaitIdData::Exited { siginfo, .. } = data else {
+                panic!("expected exited");
+            };
+            SigInfoRedux::from_foreground_crun(siginfo)
+        }
+
+        for script in [
+            "exit 0",
+            "exit 3",
+            "exit 128",
+            "kill -TERM $$",
+            "kill -KILL $$",
+        ] {
+            assert_eq!(detached(script), foreground(script), "{}", script);
+        }
+        assert_eq!(
+            foreground("kill -KILL $$"),
+            SigInfoRedux::Killed(libc::SIGKILL)
+        );
+        // the known lossy case
+        assert_eq!(detached("exit 129"), SigInfoRedux::Exited(129));
+        assert_eq!(foreground("exit 129"), SigInfoRedux::Killed(libc::SIGHUP));
+    }
+
+    #[test]
+    fn test_read_io_stream() {
+        let config = Config {
+            stdin: Some("stdin".into()),
+            response_format: ResponseFormat::PeArchiveV1,
+            ..test_config()
+        };
+        let archive = b"pretend this is an archive";
+
+        let mut buf = vec![];
+        write_io_file_config(&mut buf, &config, archive.len() as u32).unwrap();
+        buf.extend_from_slice(archive);
+        // anything after the archive is left in the stream
+        buf.extend_from_slice(b"next");
+
+        let mut stream = Cursor::new(&buf);
+        let mut archive_out = vec![];
+        let (archive_size, got) = read_io_stream(&mut stream, &mut archive_out).unwrap();
+        assert_eq!(archive_size as usize, archive.len());
+        assert_eq!(archive_out, archive);
+        assert_eq!(got.stdin.as_deref(), Some("stdin"));
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"next");
+
+        // stream ends before the archive does
+        let short = &buf[..buf.len() - 10];
+        let mut archive_out = vec![];
+        assert!(read_io_stream(&mut Cursor::new(short), &mut archive_out).is_err());
+    }
+
+    // reads from a script and collects whatever is written back
+    struct ScriptedStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for ScriptedStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serve_io_stream() {
+        use std::cell::RefCell;
+
+        let config = |stdin: &str| Config {
+            stdin: Some(stdin.into()),
+            response_format: ResponseFormat::PeArchiveV1,
+            ..test_config()
+        };
+        let mut script = vec![];
+        for (stdin, archive) in [("a", &b"first"[..]), ("b", b""), ("c", b"third")] {
+            write_io_file_config(&mut script, &config(stdin), archive.len() as u32).unwrap();
+            script.extend_from_slice(archive);
+        }
+        write_io_stream_end(&mut script).unwrap();
+        // nothing after the end is read
+        script.extend_from_slice(b"junk");
+
+        let log = RefCell::new(vec![]);
+        let mut stream = ScriptedStream {
+            input: Cursor::new(script.clone()),
+            output: vec![],
+        };
+        let n = serve_io_stream(
+            &mut stream,
+            Vec::new,
+            |archive_size, config, archive: Vec<u8>| {
+                assert_eq!(archive_size as usize, archive.len());
+                log.borrow_mut().push(format!(
+                    "run {} {}",
+                    config.stdin.unwrap(),
+                    String::from_utf8(archive).unwrap()
+                ));
+            },
+            || log.borrow_mut().push("reset".into()),
+        )
+        .unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(
+            log.into_inner(),
+            ["run a first", "reset", "run b ", "reset", "run c third"]
+        );
+        assert_eq!(stream.output, [IO_STREAM_DONE; 3]);
+        let mut rest = vec![];
+        stream.input.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"junk");
+
+        // stream ends without the sentinel, the requests before it still ran
+        let no_end = &script[..script.len() - 12];
+        let mut runs = 0;
+        let mut stream = ScriptedStream {
+            input: Cursor::new(no_end.to_vec()),
+            output: vec![],
+        };
+        assert!(serve_io_stream(&mut stream, Vec::new, |_, _, _| runs += 1, || {}).is_err());
+        assert_eq!(runs, 3);
+        assert_eq!(stream.output.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_cpu_stat() {
+        let stat = "usage_usec 12345\n\
+                    user_usec 10000\n\
+                    system_usec 2345\n\
+                    core_sched.force_idle_usec 0\n\
+                    nr_periods 0\n\
+                    nr_throttled 0\n\
+                    throttled_usec 0\n\
+                    nr_bursts 0\n\
+                    burst_usec 0\n";
+        assert_eq!(
+            parse_cpu_stat(stat),
+            Some(CgroupCpu {
+                usage_usec: 12345,
+                user_usec: 10000,
+                system_usec: 2345,
+            })
+        );
+        // without the cpu controller there are only the three
+        assert_eq!(
+            parse_cpu_stat("usage_usec 3\nuser_usec 2\nsystem_usec 1\n"),
+            Some(CgroupCpu {
+                usage_usec: 3,
+                user_usec: 2,
+                system_usec: 1,
+            })
+        );
+        assert_eq!(parse_cpu_stat("usage_usec 3\nuser_usec 2\n"), None);
+        assert_eq!(
+            parse_cpu_stat("usage_usec x\nuser_usec 2\nsystem_usec 1"),
+            None
+        );
+        assert_eq!(parse_cpu_stat(""), None);
+    }
+
+    #[test]
+    fn test_seccomp_log_runtime_config() {
+        let spec = r#"{
+            "ociVersion": "1.0.2",
+            "linux": {
+                "seccomp": {
+                    "defaultAction": "SCMP_ACT_ERRNO",
+                    "defaultErrnoRet": 38,
+                    "syscalls": [{"names": ["read"], "action": "SCMP_ACT_ALLOW"}]
+                }
+            }
+        }"#;
+        let got: serde_json::Value =
+            serde_json::from_str(&seccomp_log_runtime_config(spec).unwrap()).unwrap();
+        assert_eq!(got["linux"]["seccomp"]["defaultAction"], SECCOMP_LOG_ACTION);
+        assert_eq!(
+            got["linux"]["seccomp"]["syscalls"][0]["action"],
+            "SCMP_ACT_ALLOW"
+        );
+        assert_eq!(got["ociVersion"], "1.0.2");
+
+        // nothing to switch
+        let spec = r#"{"linux":{}}"#;
+        let got: serde_json::Value =
+            serde_json::from_str(&seccomp_log_runtime_config(spec).unwrap()).unwrap();
+        assert!(got["linux"].get("seccomp").is_none());
+
+        assert!(seccomp_log_runtime_config("not json").is_err());
+
+        let record = "5,1234,5678,-;audit: type=1326 audit(1.2:3): pid=99 comm=\"a.out\" syscall=321 code=0x7ffc0000\n SUBSYSTEM=x\n";
+        assert_eq!(
+            parse_seccomp_log_record(record),
+            Some(
+                "audit: type=1326 audit(1.2:3): pid=99 comm=\"a.out\" syscall=321 code=0x7ffc0000"
+            )
+        );
+        assert_eq!(
+            parse_seccomp_log_record("6,1,2,-;virtio_blk virtio1: 1/0/0 queues\n"),
+            None
+        );
+        assert_eq!(parse_seccomp_log_record("garbage"), None);
+    }
+
+    #[test]
+    fn test_fallback_missing_cwd() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("srv/app")).unwrap();
+        // absolute symlinks resolve within the rootfs, not the host
+        std::os::unix::fs::symlink("/srv/app", rootfs.path().join("app")).unwrap();
+        std::fs::write(rootfs.path().join("file"), b"").unwrap();
+
+        let spec = |cwd: &str| format!(r#"{{"process":{{"args":["sh"],"cwd":"{cwd}"}}}}"#);
+        for cwd in ["/", "/srv/app", "/app", "srv"] {
+            assert_eq!(
+                fallback_missing_cwd(&spec(cwd), rootfs.path()).unwrap(),
+                None,
+                "{cwd}"
+            );
+        }
+
+        // an image whose working_dir was never created
+        for cwd in ["/nope", "/file", "/app/nope"] {
+            let (config, warning) = fallback_missing_cwd(&spec(cwd), rootfs.path())
+                .unwrap()
+                .unwrap();
+            let got: serde_json::Value = serde_json::from_str(&config).unwrap();
+            assert_eq!(got["process"]["cwd"], "/");
+            assert_eq!(got["process"]["args"][0], "sh");
+            assert!(warning.contains(cwd), "{warning}");
+        }
+
+        assert_eq!(
+            fallback_missing_cwd(r#"{"process":{}}"#, rootfs.path()).unwrap(),
+            None
+        );
+        assert!(fallback_missing_cwd("not json", rootfs.path()).is_err());
+
+        let mut response = Response::Panic { message: "".into() };
+        response.set_warnings(vec!["w".into()]);
+        assert!(!serde_json::to_string(&response)
+            .unwrap()
+            .contains("warnings"));
+    }
+
+    #[test]
+    fn test_saved_sysctls() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, "1\n").unwrap();
+        std::fs::write(&b, "|/bin/false\n").unwrap();
+
+        let mut saved = SavedSysctls::default();
+        saved.write(&a, "2").unwrap();
+        saved.write(&b, "core").unwrap();
+        saved.write(&a, "3").unwrap();
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "3");
+        assert_eq!(std::fs::read_to_string(&b).unwrap(), "core");
+        // a path that isn't there isn't saved
+        assert!(saved.write(&dir.path().join("nope/c"), "4").is_err());
+
+        saved.restore().unwrap();
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "1\n");
+        assert_eq!(std::fs::read_to_string(&b).unwrap(), "|/bin/false\n");
+    }
+
+    #[test]
+    fn test_timings() {
+        let mut timings = Timings::new(Timings::now_ms());
+        std::thread::sleep(Duration::from_millis(2));
+        timings.unpacked();
+        std::thread::sleep(Duration::from_millis(2));
+        timings.ran();
+
+        let child = Command::new("true").spawn().unwrap();
+        let data = child.wait_timeout(Duration::from_secs(10)).unwrap();
+        let WaitIdData::Exited { siginfo, rusage } = data else {
+            panic!("expected exited");
+        };
+        let mut response = Response::Ok {
+            siginfo: siginfo.into(),
+            rusage: rusage.into(),
+            timings: timings,
+            stdout: None,
+            stderr: None,
+            manifest_digest: "sha256:abcd".into(),
+            output_truncated: false,
+            seccomp_log: vec![],
+            warnings: vec![],
+            cgroup_cpu: None,
+            core_file: Some("dir/core.a.out.7".into()),
+        };
+        assert!(!serde_json::to_string(&response)
+            .unwrap()
+            .contains("output_truncated"));
+
+        let mut file = Cursor::new(vec![]);
+        write_io_file_response_padded(&mut file, &response).unwrap();
+        // archive would go here
+        file.write_all(b"archive").unwrap();
+        response.timings_mut().unwrap().pack_at_ms = Some(u64::MAX);
+        response.timings_mut().unwrap().pack_ms = Some(u64::MAX);
+        // both have to fit in the padding
+        response.set_output_truncated();
+        rewrite_io_file_response(&mut file, &response).unwrap();
+        // we don't know if the core made it into the archive
+        assert!(matches!(
+            response,
+            Response::Ok {
+                core_file: None,
+                ..
+            }
+        ));
+        response.timings_mut().unwrap().packed();
+        rewrite_io_file_response(&mut file, &response).unwrap();
+
+        // the durations are what json consumers see
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        for key in [
+            "boot_at_ms",
+            "unpack_at_ms",
+            "run_at_ms",
+            "pack_at_ms",
+            "unpack_ms",
+            "run_ms",
+            "pack_ms",
+        ] {
+            assert!(json["timings"][key].is_u64(), "{key}");
+        }
+
+        let (_, response) = read_io_file_response(&mut file).unwrap();
+        let mut archive = vec![];
+        file.read_to_end(&mut archive).unwrap();
+        assert_eq!(archive, b"archive");
+
+        let Response::Ok {
+            timings,
+            output_truncated,
+            ..
+        } = response
+        else {
+            panic!("expected Ok");
+        };
+        assert!(output_truncated);
+        assert!(timings.boot_at_ms > 0);
+        assert!(timings.boot_at_ms < timings.unpack_at_ms);
+        assert!(timings.unpack_at_ms < timings.run_at_ms);
+        assert!(timings.run_at_ms <= timings.pack_at_ms.unwrap());
+        assert!(timings.unpack_ms >= 2);
+        assert!(timings.run_ms >= 2);
+        assert_eq!(
+            timings.pack_ms,
+            Some(timings.pack_at_ms.unwrap() - timings.run_at_ms)
+        );
+    }
+}
@@ -10,21 +10,47 @@ use std::process::{Command, Stdio};
 use std::time::Instant;
 
 use command_fds::{CommandFdExt, FdMapping};
-use rustix::fs::{chown, mkdir, open, Mode, OFlags};
+use rustix::fs::{access, chmod, chown, mkdir, open, Access, Mode, OFlags};
 use rustix::mount::MountFlags as MS;
-use rustix::mount::{mount, mount_bind, mount_bind_recursive};
-use rustix::process::{chdir, chroot};
+use rustix::mount::{mount, mount_bind, mount_bind_recursive, mount_remount};
+use rustix::process::{chdir, chroot, setrlimit, Resource, Rlimit};
 use rustix::system::{reboot, RebootCommand};
 
-use peinit::{read_io_file_config, write_io_file_response};
-use peinit::{Config, Response, ResponseFormat, RootfsKind};
-use waitid_timeout::{PidFd, PidFdWaiter, WaitIdDataOvertime};
+use peinit::{
+    fallback_missing_cwd, find_core_file, parse_cpu_stat, parse_seccomp_log_record,
+    post_run_runtime_config, read_io_file_config, read_pidfile, rewrite_io_file_response,
+    rootfs_mountpoints, seccomp_log_runtime_config, shared_tmp_runtime_config, sync_io_file,
+    sysctl_path, wait_crun_started, write_io_file_response, write_io_file_response_padded,
+    write_io_file_response_synced, ContainerError, SavedSysctls, CORE_PATTERN, PIDFILE_ATTEMPTS,
+    PIDFILE_RETRY_DELAY, POST_RUN_TIMEOUT, SECCOMP_LOG_MAX_ENTRIES,
+};
+use peinit::{Config, Response, ResponseFormat, RootfsKind, SigInfoRedux, Timings};
+use waitid_timeout::{
+    waitid_pidfd_exited_hang, PidFd, PidFdWaiter, WaitIdData, WaitIdDataOvertime,
+};
 
 const IMAGE_DEVICE: &CStr = c"/dev/pmem0";
 const INOUT_DEVICE: &str = "/dev/pmem1";
+// when the host boots us with the image as a vhost-user disk, the io file is the only pmem
+const IMAGE_DEVICE_DISK: &CStr = c"/dev/vda";
+const INOUT_DEVICE_DISK: &str = "/dev/pmem0";
 const STDOUT_FILE: &str = "/run/output/stdout";
 const STDERR_FILE: &str = "/run/output/stderr";
 const RESPSONSE_JSON_STDOUT_SIZE: u64 = 1024;
+const KMSG: &str = "/dev/kmsg";
+const CONTAINER_ID: &str = "cid-1234";
+// crun's default cgroup (no cgroupsPath in the spec) is named after the container id. It sticks
+// around after a detached container exits, until crun delete
+fn container_cpu_stat_path() -> String {
+    format!("/sys/fs/cgroup/{CONTAINER_ID}/cpu.stat")
+}
+// Config.post_run_cmd runs as its own container from the same bundle
+const POST_RUN_CONTAINER_ID: &str = "cid-1234-post";
+const POST_RUN_CONFIG: &str = "/run/bundle/post-run.json";
+const POST_RUN_PIDFILE: &str = "/run/post-run.pid";
+const POST_RUN_STDERR_FILE: &str = "/run/post-run.stderr";
+// the container's /tmp when there's a post run cmd, see shared_tmp_runtime_config
+const SHARED_TMP_DIR: &CStr = c"/run/tmp";
 
 //fn sha2_hex(buf: &[u8]) -> String {
 //    use sha2::{Sha256,Digest};
@@ -49,9 +75,28 @@ fn exit() {
     std::process::exit(1);
 }
 
+fn image_is_disk() -> bool {
+    access(IMAGE_DEVICE_DISK, Access::EXISTS).is_ok()
+}
+
+fn image_device() -> &'static CStr {
+    if image_is_disk() {
+        IMAGE_DEVICE_DISK
+    } else {
+        IMAGE_DEVICE
+    }
+}
+
+fn inout_device() -> &'static str {
+    if image_is_disk() {
+        INOUT_DEVICE_DISK
+    } else {
+        INOUT_DEVICE
+    }
+}
+
 // NOTE: the host can still not receive this message if the pmem is configured incorrectly, for
 // example by having discard_writes=on accidentally in which case the writes are silently dropped
-// and also if the data wasn't sync'd then the host never sees our response
 fn write_panic_response(message: &str) -> Result<(), peinit::Error> {
     println!("writing panic response: {message}");
 
@@ -59,11 +104,8 @@ fn write_panic_response(message: &str) -> Result<(), peinit::Error> {
         message: message.into(),
     };
 
-    let mut f = File::create(INOUT_DEVICE).map_err(|_| peinit::Error::Io)?;
-    write_io_file_response(&mut f, &response)?;
-    // have gotten bit by the write not being visible since we exit so quickly after the write
-    f.sync_data().map_err(|_| peinit::Error::Io)?;
-    Ok(())
+    let mut f = File::create(inout_device()).map_err(|_| peinit::Error::Io)?;
+    write_io_file_response_synced(&mut f, &response)
 }
 
 fn setup_panic() {
@@ -138,24 +180,137 @@ fn parent_rootfs(_pivot_dir: &CStr) -> io::Result<()> {
     Ok(())
 }
 
-fn unpack_input(archive: &str, dir: &str) -> Config {
+// where the config and input archive come from. The default is the io file pmem, but when resuming
+// a snapshot we want the host to send the request over vsock instead. This is picked by the
+// kernel cmdline (ex peinit_input=vsock:1234) which the kernel hands us as an env var since we're
+// init. vsockloop:1234 keeps reading requests off the vsock and running them until the host ends
+// the stream, with each response still going to the io file
+enum InputSource {
+    Pmem,
+    #[cfg(feature="vsock")]
+    Vsock(u32),
+    #[cfg(feature="vsock")]
+    VsockLoop(u32),
+}
+
+impl InputSource {
+    fn from_env() -> Self {
+        match std::env::var("peinit_input").as_deref() {
+            #[cfg(feature="vsock")]
+            Ok(x) if x.starts_with("vsock:") => {
+                let port = x["vsock:".len()..].parse().expect("bad vsock port");
+                InputSource::Vsock(port)
+            }
+            #[cfg(feature="vsock")]
+            Ok(x) if x.starts_with("vsockloop:") => {
+                let port = x["vsockloop:".len()..].parse().expect("bad vsock port");
+                InputSource::VsockLoop(port)
+            }
+            _ => InputSource::Pmem,
+        }
+    }
+}
+
+fn unpack_input(source: InputSource, dir: &str) -> Config {
+    match source {
+        InputSource::Pmem => unpack_input_pmem(inout_device(), dir),
+        #[cfg(feature="vsock")]
+        InputSource::Vsock(port) => unpack_input_vsock(port, dir),
+        #[cfg(feature="vsock")]
+        InputSource::VsockLoop(_) => unreachable!("looping input is handled by serve_vsock"),
+    }
+}
+
+fn unpack_input_pmem(archive: &str, dir: &str) -> Config {
     let mut file: File = open(archive, OFlags::RDONLY | OFlags::CLOEXEC, Mode::empty())
         .unwrap()
         .into();
     let (archive_size, config) = read_io_file_config(&mut file).unwrap();
+    // file is left at the start of the archive
+    unpack_archive(file.into(), archive_size, dir, &config);
+    config
+}
+
+#[cfg(feature="vsock")]
+fn unpack_input_vsock(port: u32, dir: &str) -> Config {
+    use vsock::{VsockStream, VMADDR_CID_HOST};
+
+    let mut stream = VsockStream::connect_with_cid_port(VMADDR_CID_HOST, port).unwrap();
+    let (archive_size, config, file) = read_input_stream(&mut stream);
+    unpack_archive(file.into(), archive_size, dir, &config);
+    config
+}
+
+// the archive can't be unpacked straight off the stream, so it goes into a memfd which is
+// returned positioned at the start of the archive. Unused without the vsock feature
+#[allow(dead_code)]
+fn read_input_stream<S: Read>(stream: &mut S) -> (u32, Config, File) {
+    use std::io::Seek;
 
+    let mut file = archive_memfd();
+    let (archive_size, config) = peinit::read_io_stream(stream, &mut file).unwrap();
+    file.rewind().unwrap();
+    (archive_size, config, file)
+}
+
+#[allow(dead_code)]
+fn archive_memfd() -> File {
+    rustix::fs::memfd_create("archive", rustix::fs::MemfdFlags::CLOEXEC)
+        .unwrap()
+        .into()
+}
+
+#[cfg(feature="vsock")]
+fn serve_vsock(port: u32, boot_ms: u64) {
+    use std::cell::Cell;
+    use std::io::Seek;
+    use vsock::{VsockStream, VMADDR_CID_HOST};
+
+    let mut stream = VsockStream::connect_with_cid_port(VMADDR_CID_HOST, port).unwrap();
+    let mut boot_ms = Some(boot_ms);
+    // what the last request changed in /proc/sys, undone before the next one
+    let saved = Cell::new(None);
+    let n = peinit::serve_io_stream(
+        &mut stream,
+        archive_memfd,
+        |archive_size, config, mut file| {
+            // only the first request pays for the boot, after that we start timing once the
+            // request has been read
+            let mut timings = Timings::new(boot_ms.take().unwrap_or_else(Timings::now_ms));
+            file.rewind().unwrap();
+            unpack_archive(file.into(), archive_size, "/run/input", &config);
+            timings.unpacked();
+            saved.set(Some(run_request(&config, timings)));
+            if config.detach {
+                // a detached container is left around after it exits and would clash with the
+                // next one's id
+                let _ = Command::new(&config.binaries.crun)
+                    .arg("delete")
+                    .arg("--force")
+                    .arg(CONTAINER_ID)
+                    .status();
+            }
+        },
+        || reset_request(saved.take().unwrap_or_default()),
+    )
+    .unwrap();
+    println!("V served {n} requests");
+}
+
+// archive fd should be positioned at the start of the archive
+fn unpack_archive(archive: OwnedFd, archive_size: u32, dir: &str, config: &Config) {
     let fd_mappings = vec![FdMapping {
-        parent_fd: file.into(),
+        parent_fd: archive,
         child_fd: 3,
     }];
 
     let mut cmd = if config.strace {
-        Command::new("strace")
+        Command::new(&config.binaries.strace)
     } else {
-        Command::new("/bin/pearchive")
+        Command::new(&config.binaries.pearchive)
     };
     if config.strace {
-        cmd.arg("/bin/pearchive");
+        cmd.arg(&config.binaries.pearchive);
     }
     let ret = cmd
         .arg("unpackfd")
@@ -171,28 +326,40 @@ fn unpack_input(archive: &str, dir: &str) -> Config {
         .code()
         .expect("pearchive unpackdev had no status");
     assert!(ret == 0, "pearchive unpackdev failed with status {}", ret);
+}
 
-    config
+// must match pearchive's PACKFD_EXIT_TRUNCATED
+const PEARCHIVE_EXIT_TRUNCATED: i32 = 2;
+
+// room left in the io file after the current position, capped to what the archive size can encode
+fn archive_capacity(f: &mut File) -> io::Result<u64> {
+    use std::io::{Seek, SeekFrom};
+    let pos = f.stream_position()?;
+    let end = f.seek(SeekFrom::End(0))?;
+    f.seek(SeekFrom::Start(pos))?;
+    Ok(end.saturating_sub(pos).min(u32::MAX.into()))
 }
 
-fn pack_output<P: AsRef<OsStr>>(dir: P, archive: OwnedFd, strace: bool) {
+// returns true if the output didn't fit in max_len and some files were left out
+fn pack_output<P: AsRef<OsStr>>(dir: P, archive: OwnedFd, max_len: u64, config: &Config) -> bool {
     let fd_mappings = vec![FdMapping {
         parent_fd: archive,
         child_fd: 3,
     }];
 
-    let mut cmd = if strace {
-        Command::new("strace")
+    let mut cmd = if config.strace {
+        Command::new(&config.binaries.strace)
     } else {
-        Command::new("/bin/pearchive")
+        Command::new(&config.binaries.pearchive)
     };
-    if strace {
-        cmd.arg("/bin/pearchive");
+    if config.strace {
+        cmd.arg(&config.binaries.pearchive);
     }
     let ret = cmd
         .arg("packfd")
         .arg(dir)
         .arg("3")
+        .arg(max_len.to_string())
         .uid(1000)
         .gid(1000)
         .fd_mappings(fd_mappings)
@@ -201,10 +368,15 @@ fn pack_output<P: AsRef<OsStr>>(dir: P, archive: OwnedFd, strace: bool) {
         .unwrap()
         .code()
         .expect("pearchive packdev had no status");
-    assert!(ret == 0, "pearchive packdev failed with status {}", ret);
+    assert!(
+        ret == 0 || ret == PEARCHIVE_EXIT_TRUNCATED,
+        "pearchive packdev failed with status {}",
+        ret
+    );
+    ret == PEARCHIVE_EXIT_TRUNCATED
 }
 
-fn run_container(config: &Config) -> io::Result<WaitIdDataOvertime> {
+fn run_container(config: &Config) -> Result<WaitIdDataOvertime, ContainerError> {
     let outfile = File::create_new(STDOUT_FILE).unwrap();
     let errfile = File::create_new(STDERR_FILE).unwrap();
     let run_input = Path::new("/run/input");
@@ -232,9 +404,9 @@ fn run_container(config: &Config) -> io::Result<WaitIdDataOvertime> {
 
     let start = Instant::now();
     let mut cmd = if config.strace {
-        Command::new("/bin/strace")
+        Command::new(&config.binaries.strace)
     } else {
-        Command::new("/bin/crun")
+        Command::new(&config.binaries.crun)
     };
     if config.strace {
         cmd.arg("-e")
@@ -243,65 +415,201 @@ fn run_container(config: &Config) -> io::Result<WaitIdDataOvertime> {
             .arg("-o")
             .arg("/run/crun.strace")
             .arg("--decode-pids=comm")
-            .arg("/bin/crun");
+            .arg(&config.binaries.crun);
     }
     if config.crun_debug {
         cmd.arg("--debug").arg("--log=/run/crun.log");
     }
     cmd.arg("run")
         .arg("-b") // --bundle
-        .arg("/run/bundle")
-        .arg("-d") // --detach
-        .arg("--pid-file=/run/pid")
-        .arg("cid-1234")
+        .arg("/run/bundle");
+    if config.detach {
+        cmd.arg("-d") // --detach
+            .arg("--pid-file=/run/pid");
+    }
+    cmd.arg(CONTAINER_ID)
         .stdout(Stdio::from(outfile))
         .stderr(Stdio::from(errfile))
         .stdin(stdin);
 
-    let exit_status = cmd.spawn().unwrap().wait().unwrap();
+    let core_rlimit = config.core_rlimit();
+    if core_rlimit > 0 {
+        // only crun and the container get the limit, we keep dumping nothing ourselves
+        unsafe {
+            cmd.pre_exec(move || {
+                let limit = Rlimit {
+                    current: Some(core_rlimit),
+                    maximum: Some(core_rlimit),
+                };
+                setrlimit(Resource::Core, limit).map_err(io::Error::from)
+            });
+        }
+    }
+
+    if !config.detach {
+        // crun waits on the container itself, so its exit status is the container's; no timeout
+        // here since killing crun wouldn't kill the container
+        let child = cmd.spawn().unwrap();
+        let pidfd = PidFd::new(&child)?;
+        let ret = waitid_pidfd_exited_hang(&pidfd)?;
+        println!("V crun ran in {:?}", start.elapsed());
+        cat_crun_debug_files(config);
+        return Ok(match ret {
+            WaitIdData::Exited { siginfo, rusage } => {
+                WaitIdDataOvertime::Exited { siginfo, rusage }
+            }
+            WaitIdData::NotExited => WaitIdDataOvertime::NotExited,
+            WaitIdData::Cancelled => WaitIdDataOvertime::Cancelled,
+        });
+    }
+
+    let started = wait_crun_started(cmd.spawn().unwrap(), Path::new(STDERR_FILE));
 
     let elapsed = start.elapsed();
     println!("V crun ran in {elapsed:?}");
 
-    if config.strace {
-        cat_file_if_exists("crun.strace", "/run/crun.strace");
-    }
-    if config.crun_debug {
-        cat_file_if_exists("crun.log", "/run/crun.log");
-    }
+    cat_crun_debug_files(config);
 
-    if !exit_status.success() {
-        // println!("V crun stdout");
-        // io::copy(&mut File::open("/run/output/stdout").unwrap(), &mut io::stdout());
-        // println!("V crun stderr");
-        // io::copy(&mut File::open("/run/output/stderr").unwrap(), &mut io::stdout());
-        //let stderr = fs::read_to_string("/run/output/stderr").unwrap();
-
-        let stderr = read_n_or_str_error("/run/output/stderr", 2000);
-        panic!("crun unclean exit status {:?} {}", exit_status, stderr);
-    }
+    started?;
     // we wait on crun since it should run to completion and leave the pid in pidfd
 
     //Command::new("busybox").arg("ls").arg("/run").spawn().unwrap().wait().unwrap();
-    let pid = fs::read_to_string("/run/pid")
-        .unwrap()
-        .parse::<i32>()
-        .unwrap();
+    let pid = read_pidfile("/run/pid", PIDFILE_ATTEMPTS, PIDFILE_RETRY_DELAY)?;
 
     // this can verify the Uid/Gid is not 0 0 0 0 DOES NOT WORK WITH STRACE
     // Command::new("/bin/busybox").arg("cat").arg(format!("/proc/{}/status", pid)).spawn().unwrap();
-    let mut pidfd = PidFd::open(pid, 0).unwrap();
-    let mut waiter = PidFdWaiter::new(&mut pidfd).unwrap();
+    let mut pidfd = PidFd::open(pid, 0)?;
+    let mut waiter = PidFdWaiter::new(&mut pidfd)?;
+
+    Ok(waiter.wait_timeout_or_kill(config.timeout)?)
+}
+
+// stdout is dropped and stderr is only read when crun fails to start it. The container is deleted
+// after so a later request can reuse the id
+fn run_post_run_cmd(
+    config: &Config,
+    oci_runtime_config: &str,
+    args: &[String],
+) -> Result<WaitIdDataOvertime, ContainerError> {
+    let post_run_config = post_run_runtime_config(oci_runtime_config, args)
+        .map_err(|e| ContainerError::Start(format!("bad runtime config {e:?}")))?;
+    fs::write(POST_RUN_CONFIG, post_run_config)?;
+    let errfile = File::create(POST_RUN_STDERR_FILE)?;
+
+    let mut cmd = Command::new(&config.binaries.crun);
+    cmd.arg("run")
+        .arg("-b") // --bundle
+        .arg("/run/bundle")
+        .arg(format!("--config={POST_RUN_CONFIG}"))
+        .arg("-d") // --detach
+        .arg(format!("--pid-file={POST_RUN_PIDFILE}"))
+        .arg(POST_RUN_CONTAINER_ID)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::from(errfile));
+
+    let mut run = || -> Result<WaitIdDataOvertime, ContainerError> {
+        wait_crun_started(cmd.spawn()?, Path::new(POST_RUN_STDERR_FILE))?;
+        let pid = read_pidfile(POST_RUN_PIDFILE, PIDFILE_ATTEMPTS, PIDFILE_RETRY_DELAY)?;
+        let mut pidfd = PidFd::open(pid, 0)?;
+        let mut waiter = PidFdWaiter::new(&mut pidfd)?;
+        Ok(waiter.wait_timeout_or_kill(POST_RUN_TIMEOUT)?)
+    };
+    let ret = run();
+
+    let _ = Command::new(&config.binaries.crun)
+        .arg("delete")
+        .arg("--force")
+        .arg(POST_RUN_CONTAINER_ID)
+        .status();
+    ret
+}
+
+// None when the post run cmd went fine
+fn post_run_warning(result: Result<WaitIdDataOvertime, ContainerError>) -> Option<String> {
+    let problem = match result {
+        Ok(WaitIdDataOvertime::Exited { siginfo, .. }) => match SigInfoRedux::from(siginfo) {
+            SigInfoRedux::Exited(0) => return None,
+            siginfo => format!("{siginfo:?}"),
+        },
+        Ok(WaitIdDataOvertime::ExitedOvertime { .. }) => {
+            format!("killed after {POST_RUN_TIMEOUT:?}")
+        }
+        Ok(WaitIdDataOvertime::NotExited) => "not exited".to_string(),
+        Ok(WaitIdDataOvertime::Cancelled) => "wait cancelled".to_string(),
+        Err(e) => format!("{e:?}"),
+    };
+    Some(format!("post_run_cmd {problem}"))
+}
 
-    waiter.wait_timeout_or_kill(config.timeout)
+// undo everything run_request did so the next request starts from the same state as a fresh boot.
+// Not everything is mounted on every run (the overlay, the multi-image) so not mounted is fine.
+// The core rlimit needs nothing since it is only ever set in crun's pre_exec
+#[cfg(feature="vsock")]
+fn reset_request(saved: SavedSysctls) {
+    use rustix::io::Errno;
+    use rustix::mount::{unmount, UnmountFlags};
+
+    let _ = fs::remove_file("/run/pid");
+    let _ = fs::remove_file(POST_RUN_PIDFILE);
+
+    for dir in [
+        c"/run/bundle/rootfs",
+        c"/mnt/overlay",
+        c"/mnt/rootfs",
+        c"/mnt/image",
+        c"/run/output",
+        SHARED_TMP_DIR,
+    ] {
+        match unmount(dir, UnmountFlags::empty()) {
+            Ok(()) | Err(Errno::INVAL) => {}
+            Err(e) => panic!("unmount {dir:?} failed {e:?}"),
+        }
+    }
+    mount_output();
+
+    // /run/input isn't a mount so we empty it instead
+    for entry in fs::read_dir("/run/input").unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(path).unwrap();
+        } else {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    saved.restore().unwrap();
+}
+
+// mountpoints that don't exist (as a dir, not following a final symlink) in the rootfs
+fn missing_rootfs_mountpoints(config: &Config) -> Vec<String> {
+    let rootfs = Path::new("/mnt/rootfs");
+    rootfs_mountpoints(&config.oci_runtime_config)
+        .unwrap()
+        .into_iter()
+        .filter(|x| {
+            !fs::symlink_metadata(rootfs.join(x.trim_start_matches('/')))
+                .map(|m| m.is_dir())
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn cat_crun_debug_files(config: &Config) {
+    if config.strace {
+        cat_file_if_exists("crun.strace", "/run/crun.strace");
+    }
+    if config.crun_debug {
+        cat_file_if_exists("crun.log", "/run/crun.log");
+    }
 }
 
 #[cfg(not(feature="snapshotting"))]
-fn snapshot() {
+fn snapshot(_t0: Instant) {
 }
 
 #[cfg(feature="snapshotting")]
-fn snapshot() {
+fn snapshot(t0: Instant) {
     use std::io::Write;
     use vsock::{VsockStream, VMADDR_CID_HOST};
     let mut vsock = {
@@ -318,7 +626,7 @@ fn snapshot() {
     };
     println!("{} ms: connected to vsock", t0.elapsed().as_millis());
     let mut buf = [0u8; 1];
-    vsock.write_all(&mut buf).unwrap(); // signal ready
+    vsock.write_all(&buf).unwrap(); // signal ready
     println!("{} ms: written to vsock", t0.elapsed().as_millis());
     // read doesn't error out if we disconnect the vsock after pause + before snapshot
     match vsock.read_exact(&mut buf) {
@@ -358,8 +666,8 @@ fn block_testing() {
 }
 
 fn main() {
-    #[cfg(feature="snapshotting")]
-    let t0 = std::time::Instant::now();
+    let mut timings = Timings::new(Timings::now_ms());
+    let t0 = Instant::now();
     setup_panic();
     #[cfg(feature="snapshotting")]
     println!("{} ms: setup_panic", t0.elapsed().as_millis());
@@ -373,33 +681,53 @@ fn main() {
         mount(c"none", c"/proc", c"proc", MS::SILENT, None).unwrap();
         mount(c"none", c"/sys/fs/cgroup", c"cgroup2", MS::SILENT, None).unwrap();
         mount(c"none", c"/dev", c"devtmpfs", MS::SILENT, None).unwrap();
-        mount(
-            c"none",
-            c"/run/output",
-            c"tmpfs",
-            MS::SILENT,
-            Some(c"size=2M,mode=777"),
-        )
-        .unwrap();
-        // the umask 022 means mkdir creates with 755, mkdir(1) does a mkdir then chmod. we could also
-        // have set umask
-        mkdir(c"/run/output/dir", 0o777.into()).unwrap();
-        //chmod(c"/run/output/dir", 0o777).unwrap();
-        chown(
-            c"/run/output/dir",
-            Some(rustix::fs::Uid::from_raw(1000)),
-            Some(rustix::fs::Gid::from_raw(1000)),
-        )
-        .unwrap();
+        mount_output();
     }
     #[cfg(feature="snapshotting")]
     println!("{} ms: mount stuff", t0.elapsed().as_millis());
 
-    snapshot();
+    snapshot(t0);
     block_testing();
 
-    let config = unpack_input(INOUT_DEVICE, "/run/input");
+    match InputSource::from_env() {
+        #[cfg(feature="vsock")]
+        InputSource::VsockLoop(port) => serve_vsock(port, timings.boot_at_ms),
+        source => {
+            let config = unpack_input(source, "/run/input");
+            timings.unpacked();
+            // nothing to put back, we power off after this
+            let _ = run_request(&config, timings);
+        }
+    }
 
+    exit()
+}
+
+fn mount_output() {
+    mount(
+        c"none",
+        c"/run/output",
+        c"tmpfs",
+        MS::SILENT,
+        Some(c"size=2M,mode=777"),
+    )
+    .unwrap();
+    // the umask 022 means mkdir creates with 755, mkdir(1) does a mkdir then chmod. we could also
+    // have set umask
+    mkdir(c"/run/output/dir", 0o777.into()).unwrap();
+    //chmod(c"/run/output/dir", 0o777).unwrap();
+    chown(
+        c"/run/output/dir",
+        Some(rustix::fs::Uid::from_raw(1000)),
+        Some(rustix::fs::Gid::from_raw(1000)),
+    )
+    .unwrap();
+}
+
+// everything after the input is unpacked: mount the rootfs, run the container and write the
+// response to the io file. Has to be undone with reset_request before running another, which is
+// also handed back what this changed in /proc/sys
+fn run_request(config: &Config, mut timings: Timings) -> SavedSysctls {
     // mount index
     let rootfs_kind = match config.rootfs_kind {
         RootfsKind::Sqfs => c"squashfs",
@@ -408,30 +736,112 @@ fn main() {
 
     // rootfs_dir can be None, in which case this isn't a multi-image
     if let Some(rootfs_dir) = config.rootfs_dir.as_ref() {
-        mount(IMAGE_DEVICE, c"/mnt/image", rootfs_kind, MS::SILENT, None).unwrap();
+        mount(image_device(), c"/mnt/image", rootfs_kind, MS::SILENT, None).unwrap();
         let rootfs_dir = CString::new(format!("/mnt/image/{}", rootfs_dir)).unwrap();
         mount_bind(&rootfs_dir, c"/mnt/rootfs").unwrap();
     } else {
-        mount(IMAGE_DEVICE, c"/mnt/rootfs", rootfs_kind, MS::SILENT, None).unwrap();
+        mount(image_device(), c"/mnt/rootfs", rootfs_kind, MS::SILENT, None).unwrap();
     }
 
-    // We have to use an overlayfs because we have a read only rootfs and want to mount in
-    // /run/pe/{input,output} and be writable
-    mount(
-        c"none",
-        c"/run/bundle/rootfs",
-        c"overlay",
-        MS::SILENT,
-        Some(c"lowerdir=/mnt/rootfs,upperdir=/mnt/upper,workdir=/mnt/work"),
-    )
-    .unwrap();
+    let overlay = config.overlay || {
+        let missing = missing_rootfs_mountpoints(config);
+        if !missing.is_empty() {
+            println!("V rootfs is missing mountpoints {missing:?}, using overlay");
+        }
+        !missing.is_empty()
+    };
+
+    if overlay {
+        // upper and work have to be on the same fs and giving them their own tmpfs bounds how much
+        // can be written into the rootfs
+        mount(
+            c"none",
+            c"/mnt/overlay",
+            c"tmpfs",
+            MS::SILENT,
+            Some(config.overlay_tmpfs_options().as_c_str()),
+        )
+        .unwrap();
+        mkdir(c"/mnt/overlay/work", 0o755.into()).unwrap();
+        mkdir(c"/mnt/overlay/upper", 0o777.into()).unwrap();
+        // umask
+        chmod(c"/mnt/overlay/upper", 0o777.into()).unwrap();
+        chown(
+            c"/mnt/overlay/upper",
+            Some(rustix::fs::Uid::from_raw(1000)),
+            Some(rustix::fs::Gid::from_raw(1000)),
+        )
+        .unwrap();
+
+        // We use an overlayfs because we have a read only rootfs and want to mount in
+        // /run/pe/{input,output} (which may not exist in the image) and be writable
+        mount(
+            c"none",
+            c"/run/bundle/rootfs",
+            c"overlay",
+            MS::SILENT,
+            Some(c"lowerdir=/mnt/rootfs,upperdir=/mnt/overlay/upper,workdir=/mnt/overlay/work"),
+        )
+        .unwrap();
+    } else {
+        // the rootfs is already read only and crun only has to mount onto existing dirs
+        mount_bind(c"/mnt/rootfs", c"/run/bundle/rootfs").unwrap();
+    }
 
     // println!("V config is {config:?}");
-    fs::write(
-        "/run/bundle/config.json",
-        config.oci_runtime_config.as_bytes(),
-    )
-    .unwrap();
+    let mut warnings = vec![];
+    let mut oci_runtime_config = if config.seccomp_log {
+        seccomp_log_runtime_config(&config.oci_runtime_config).unwrap()
+    } else {
+        config.oci_runtime_config.clone()
+    };
+    if let Some((fixed, warning)) =
+        fallback_missing_cwd(&oci_runtime_config, Path::new("/run/bundle/rootfs")).unwrap()
+    {
+        println!("W {warning}");
+        oci_runtime_config = fixed;
+        warnings.push(warning);
+    }
+    if config.post_run_args().is_some() {
+        let source = SHARED_TMP_DIR.to_str().unwrap();
+        if let Some((shared, options)) =
+            shared_tmp_runtime_config(&oci_runtime_config, source).unwrap()
+        {
+            let options = CString::new(options).unwrap();
+            mount(
+                c"none",
+                SHARED_TMP_DIR,
+                c"tmpfs",
+                MS::SILENT,
+                Some(options.as_c_str()),
+            )
+            .unwrap();
+            oci_runtime_config = shared;
+        }
+    }
+    fs::write("/run/bundle/config.json", oci_runtime_config.as_bytes()).unwrap();
+
+    let mut saved = SavedSysctls::default();
+    for (key, value) in &config.sysctls {
+        let result = match sysctl_path(key) {
+            Some(path) => saved.write(&path, value).map_err(|e| e.to_string()),
+            None => Err("not a sysctl".to_string()),
+        };
+        if let Err(e) = result {
+            let warning = format!("sysctl {key}: {e}");
+            println!("W {warning}");
+            warnings.push(warning);
+        }
+    }
+
+    if config.capture_core {
+        if let Some(options) = config.output_tmpfs_options() {
+            mount_remount(c"/run/output", MS::SILENT, options.as_c_str()).unwrap();
+        }
+        saved
+            .write(Path::new("/proc/sys/kernel/core_pattern"), CORE_PATTERN)
+            .unwrap();
+    }
 
     if config.kernel_inspect {
         walkdir_files("/proc/sys".as_ref(), &|entry: &DirEntry| {
@@ -446,7 +856,30 @@ fn main() {
         .unwrap();
     }
 
-    let container_output = run_container(&config);
+    // skip records from boot and from earlier requests when serving over vsock
+    let mut kmsg = config.seccomp_log.then(open_kmsg_at_end).flatten();
+    let container_output = run_container(config);
+    timings.ran();
+
+    // only once the container actually ran, there's nothing to gather otherwise
+    if let (Ok(_), Some(args)) = (&container_output, config.post_run_args()) {
+        let start = Instant::now();
+        let result = run_post_run_cmd(config, &oci_runtime_config, args);
+        println!("V post run cmd ran in {:?}", start.elapsed());
+        if let Some(warning) = post_run_warning(result) {
+            println!("W {warning}");
+            warnings.push(warning);
+        }
+    }
+
+    let detach = config.detach;
+    let container_siginfo = |siginfo| {
+        if detach {
+            SigInfoRedux::from(siginfo)
+        } else {
+            SigInfoRedux::from_foreground_crun(siginfo)
+        }
+    };
 
     let (stdout, stderr) = match config.response_format {
         ResponseFormat::PeArchiveV1 => (None, None),
@@ -456,57 +889,131 @@ fn main() {
         ),
     };
 
-    let response = match container_output {
-        Err(e) => Response::Panic {
-            message: format!("{:?}", e),
-        },
+    let cgroup_cpu = fs::read_to_string(container_cpu_stat_path())
+        .ok()
+        .and_then(|x| parse_cpu_stat(&x));
+
+    // pack_output packs /run/output so the name in the archive is under dir/. There's no archive at
+    // all with JsonV1
+    let find_core = |siginfo: &SigInfoRedux| match config.response_format {
+        ResponseFormat::PeArchiveV1 if config.capture_core => {
+            find_core_file(Path::new("/run/output/dir"), siginfo).map(|x| format!("dir/{x}"))
+        }
+        _ => None,
+    };
+
+    let mut response = match container_output {
+        Err(e) => e.into(),
         Ok(WaitIdDataOvertime::NotExited) => Response::Panic {
             message: "ch not exited overtime".into(),
         },
-        Ok(WaitIdDataOvertime::Exited { siginfo, rusage }) => Response::Ok {
-            siginfo: siginfo.into(),
-            rusage: rusage.into(),
-            stdout: stdout,
-            stderr: stderr,
-            manifest_digest: config.manifest_digest,
+        Ok(WaitIdDataOvertime::Cancelled) => Response::Panic {
+            message: "wait cancelled".into(),
         },
+        Ok(WaitIdDataOvertime::Exited { siginfo, rusage }) => {
+            let siginfo = container_siginfo(siginfo);
+            let core_file = find_core(&siginfo);
+            Response::Ok {
+                siginfo: siginfo,
+                rusage: rusage.into(),
+                timings: timings,
+                stdout: stdout,
+                stderr: stderr,
+                manifest_digest: config.manifest_digest.clone(),
+                output_truncated: false,
+                seccomp_log: vec![],
+                warnings: vec![],
+                cgroup_cpu: cgroup_cpu,
+                core_file: core_file,
+            }
+        }
         Ok(WaitIdDataOvertime::ExitedOvertime { siginfo, rusage }) => Response::Overtime {
             siginfo: siginfo.into(),
             rusage: rusage.into(),
+            timings: timings,
             stdout: stdout,
             stderr: stderr,
-            manifest_digest: config.manifest_digest,
+            manifest_digest: config.manifest_digest.clone(),
+            output_truncated: false,
+            seccomp_log: vec![],
+            warnings: vec![],
         },
     };
 
+    if let Some(kmsg) = kmsg.as_mut() {
+        response.set_seccomp_log(read_seccomp_log(kmsg));
+    }
+    response.set_warnings(warnings);
+
     {
-        let mut f: File = open(INOUT_DEVICE, OFlags::RDWR | OFlags::CLOEXEC, Mode::empty())
+        let mut f: File = open(inout_device(), OFlags::RDWR | OFlags::CLOEXEC, Mode::empty())
             .unwrap()
             .into();
-        write_io_file_response(&mut f, &response).unwrap();
-
         match config.response_format {
             ResponseFormat::PeArchiveV1 => {
-                pack_output("/run/output", f.into(), config.strace);
+                // the archive goes after the response, so we go back and fill in the pack time
+                // after it is written
+                write_io_file_response_padded(&mut f, &response).unwrap();
+                let max_len = archive_capacity(&mut f).unwrap();
+                if pack_output(
+                    "/run/output",
+                    f.try_clone().unwrap().into(),
+                    max_len,
+                    config,
+                ) {
+                    response.set_output_truncated();
+                }
+                if let Some(timings) = response.timings_mut() {
+                    timings.packed();
+                    rewrite_io_file_response(&mut f, &response).unwrap();
+                }
+            }
+            ResponseFormat::JsonV1 => {
+                write_io_file_response(&mut f, &response).unwrap();
             }
-            ResponseFormat::JsonV1 => {}
+        }
+        // the host may read this as soon as we're done (or told it we're done) so it has to be
+        // visible before then. If it isn't, the panic hook gets a (best-effort) panic response out
+        if let Err(e) = sync_io_file(&mut f) {
+            panic!("syncing response failed {e:?}");
         }
     }
 
-    exit()
+    saved
+}
+
+// seeking /dev/kmsg to the end skips every record logged so far
+fn open_kmsg_at_end() -> Option<File> {
+    use std::io::{Seek, SeekFrom};
+    let fd = open(
+        KMSG,
+        OFlags::RDONLY | OFlags::NONBLOCK | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .ok()?;
+    let mut f: File = fd.into();
+    f.seek(SeekFrom::End(0)).ok()?;
+    Some(f)
 }
 
-fn read_n_or_str_error<P: AsRef<Path> + std::fmt::Display>(path: P, n: usize) -> String {
-    match File::open(&path) {
-        Err(e) => format!("error opening file {} {:?}", path, e),
-        Ok(f) => {
-            let mut buf = String::with_capacity(n);
-            match f.take(n as u64).read_to_string(&mut buf) {
-                Ok(_) => buf,
-                Err(e) => format!("error reading file {} {:?}", path, e),
+// each read of /dev/kmsg gives one record, and EAGAIN once we've caught up with the log
+fn read_seccomp_log<R: Read>(f: &mut R) -> Vec<String> {
+    let mut buf = vec![0; 8192];
+    let mut ret = vec![];
+    while ret.len() < SECCOMP_LOG_MAX_ENTRIES {
+        match f.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                if let Some(line) = parse_seccomp_log_record(&String::from_utf8_lossy(&buf[..n])) {
+                    ret.push(line.to_string());
+                }
             }
+            // the record we were about to read got overwritten, next read continues
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            Err(_) => break,
         }
     }
+    ret
 }
 
 fn read_if_exists_max_len_lossy<P: AsRef<Path>>(p: P, len: u64) -> Option<String> {
@@ -539,3 +1046,94 @@ fn walkdir_files(dir: &Path, cb: &dyn Fn(&DirEntry)) -> io::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    // one record per read like /dev/kmsg, and EAGAIN once caught up
+    struct FakeKmsg {
+        records: Vec<String>,
+        pos: usize,
+    }
+
+    impl Read for FakeKmsg {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(record) = self.records.get(self.pos) else {
+                return Err(io::ErrorKind::WouldBlock.into());
+            };
+            self.pos += 1;
+            buf[..record.len()].copy_from_slice(record.as_bytes());
+            Ok(record.len())
+        }
+    }
+
+    impl std::io::Seek for FakeKmsg {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> io::Result<u64> {
+            assert_eq!(pos, std::io::SeekFrom::End(0));
+            self.pos = self.records.len();
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_seccomp_log_second_request() {
+        use std::io::{Seek, SeekFrom};
+
+        let record = |pid| {
+            format!("5,{pid},1000,-;audit: type=1326 audit(1.2:3): pid={pid} comm=\"a.out\" syscall=321 code=0x7ffc0000\n")
+        };
+        let mut kmsg = FakeKmsg {
+            records: vec!["6,1,2,-;virtio_blk virtio1: 1/0/0 queues\n".into()],
+            pos: 0,
+        };
+
+        // first request
+        kmsg.seek(SeekFrom::End(0)).unwrap();
+        kmsg.records.push(record(10));
+        let first = read_seccomp_log(&mut kmsg);
+        assert_eq!(first.len(), 1);
+        assert!(first[0].contains("pid=10 "));
+
+        // second request only sees its own
+        kmsg.pos = 0;
+        kmsg.seek(SeekFrom::End(0)).unwrap();
+        kmsg.records.push(record(20));
+        kmsg.records.push(record(21));
+        let second = read_seccomp_log(&mut kmsg);
+        assert_eq!(second.len(), 2);
+        assert!(second[0].contains("pid=20 "));
+        assert!(second[1].contains("pid=21 "));
+    }
+
+    #[test]
+    fn test_read_input_stream() {
+        let config = Config {
+            stdin: Some("stdin".into()),
+            ..peinit::test_config()
+        };
+        let archive = b"pretend this is an archive";
+
+        // the host end, like the vsock would be
+        let (mut host, mut guest) = UnixStream::pair().unwrap();
+        let writer = std::thread::spawn(move || {
+            let mut buf = vec![];
+            peinit::write_io_file_config(&mut buf, &config, archive.len() as u32).unwrap();
+            buf.extend_from_slice(archive);
+            host.write_all(&buf).unwrap();
+            // kept open until we've read so that reading past the archive would hang
+            host
+        });
+
+        let (archive_size, got, mut file) = read_input_stream(&mut guest);
+        drop(writer.join().unwrap());
+        assert_eq!(archive_size as usize, archive.len());
+        assert_eq!(got.stdin.as_deref(), Some("stdin"));
+        let mut unpacked = vec![];
+        file.read_to_end(&mut unpacked).unwrap();
+        assert_eq!(unpacked, archive);
+    }
+}
@@ -4,7 +4,7 @@ use std::sync::{Arc, atomic::AtomicU64};
 use log::{error, info};
 use moka::notification::RemovalCause;
 use rustix::{
-    fd::OwnedFd,
+    fd::{AsFd, BorrowedFd, OwnedFd},
     fs::{AtFlags, Dir, FileType, Mode, OFlags, ResolveFlags},
     io::Errno,
 };
@@ -51,8 +51,24 @@ impl BlobKey {
         BlobKey::new(format!("{}:{}", a, b))
     }
 
-    fn as_path(&self) -> String {
-        self.0.replacen(":", "/", 1)
+    // algo/hex with shard_depth levels of two char prefixes of hex in between, so a depth of 2 is
+    // algo/ab/cd/abcdef...
+    fn as_path(&self, shard_depth: u8) -> String {
+        let (algo, hex) = self.parts();
+        let mut ret = String::with_capacity(algo.len() + hex.len() + 1 + 3 * shard_depth as usize);
+        ret.push_str(algo);
+        for i in 0..shard_depth as usize {
+            match hex.get(2 * i..2 * i + 2) {
+                Some(prefix) => {
+                    ret.push('/');
+                    ret.push_str(prefix);
+                }
+                None => break,
+            }
+        }
+        ret.push('/');
+        ret.push_str(hex);
+        ret
     }
 
     fn parts(&self) -> (&str, &str) {
@@ -78,13 +94,55 @@ impl std::fmt::Display for BlobKey {
     }
 }
 
+// a directory of blobs laid out by BlobKey::as_path; huge flat directories get slow on some
+// filesystems so shard_depth > 0 spreads them out over digest prefix subdirectories
+pub struct BlobDir {
+    fd: OwnedFd,
+    shard_depth: u8,
+}
+
+impl BlobDir {
+    pub fn new(fd: OwnedFd, shard_depth: u8) -> Self {
+        Self { fd, shard_depth }
+    }
+
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            fd: self.fd.try_clone()?,
+            shard_depth: self.shard_depth,
+        })
+    }
+
+    fn path(&self, key: &BlobKey) -> String {
+        key.as_path(self.shard_depth)
+    }
+
+    // mkdir -p for everything but the last component of path
+    fn create_parents(&self, path: &str) -> Result<(), Errno> {
+        for (i, _) in path.match_indices('/') {
+            match rustix::fs::mkdirat(&self.fd, &path[..i], Mode::from_bits_truncate(0o744)) {
+                Ok(()) => {}
+                Err(e) if e == Errno::EXIST => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsFd for BlobDir {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
 pub struct FileGuard<'a> {
-    dir: &'a OwnedFd,
+    dir: &'a BlobDir,
     key: Option<&'a BlobKey>,
 }
 
 impl<'a> FileGuard<'a> {
-    fn new(dir: &'a OwnedFd, key: &'a BlobKey) -> FileGuard<'a> {
+    fn new(dir: &'a BlobDir, key: &'a BlobKey) -> FileGuard<'a> {
         Self {
             dir,
             key: Some(key),
@@ -94,10 +152,10 @@ impl<'a> FileGuard<'a> {
     pub fn success(mut self) -> Result<(), Errno> {
         if let Some(key) = self.key.take() {
             rustix::fs::renameat(
-                &self.dir,
-                key.with_tmp_suffix().as_path(),
-                &self.dir,
-                key.as_path(),
+                self.dir,
+                self.dir.path(&key.with_tmp_suffix()),
+                self.dir,
+                self.dir.path(key),
             )?;
         }
         Ok(())
@@ -186,7 +244,7 @@ pub fn weigher(_key: &BlobKey, size: &u64) -> u32 {
 
 pub fn remove_blob(
     name: &str,
-    blob_dir: &OwnedFd,
+    blob_dir: &BlobDir,
     key: Arc<BlobKey>,
     _value: u64,
     cause: RemovalCause,
@@ -198,8 +256,10 @@ pub fn remove_blob(
     }
 }
 
-// we only care about reading two levels deep
-pub fn read_from_disk(dir: &OwnedFd, mut f: impl FnMut(BlobKey, u64)) -> Result<(), Errno> {
+// blobs are at algo/{shard dirs}/hex, but we read any depth of shard dirs so that changing the
+// shard_depth (including from the old flat layout) migrates existing blobs by renaming them into
+// where they belong now
+pub fn read_from_disk(dir: &BlobDir, mut f: impl FnMut(BlobKey, u64)) -> Result<(), Errno> {
     let mut dir_reader = Dir::read_from(dir)?;
     dir_reader.rewind();
     while let Some(entry_dir) = dir_reader.read() {
@@ -210,38 +270,81 @@ pub fn read_from_disk(dir: &OwnedFd, mut f: impl FnMut(BlobKey, u64)) -> Result<
         if entry_dir.file_type() != FileType::Directory {
             continue;
         }
+        let Ok(algo) = entry_dir.file_name().to_str() else {
+            error!("got weird path {:?}", entry_dir.file_name());
+            continue;
+        };
         let sub_dir = rustix::fs::openat(
             dir,
             entry_dir.file_name(),
             OFlags::DIRECTORY | OFlags::RDONLY | OFlags::CLOEXEC,
             Mode::empty(),
         )?;
-        let mut sub_dir_reader = Dir::read_from(&sub_dir)?;
-        while let Some(entry_file) = sub_dir_reader.read() {
-            let entry_file = entry_file?;
-            if entry_file.file_name() == c"." || entry_file.file_name() == c".." {
-                continue;
-            }
-            if let Some(key) =
-                BlobKey::from_cstr_parts(entry_dir.file_name(), entry_file.file_name())
-            {
-                let stat = rustix::fs::statat(&sub_dir, entry_file.file_name(), AtFlags::empty())?;
-                f(key, stat.st_size as u64);
-            } else {
-                error!(
-                    "got weird path {:?} {:?}",
-                    entry_dir.file_name(),
-                    entry_file.file_name()
-                );
-            }
+        read_shard_dir(dir, &sub_dir, entry_dir.file_name(), algo, &mut f)?;
+    }
+
+    Ok(())
+}
+
+// path is relative to the blob dir
+fn read_shard_dir(
+    dir: &BlobDir,
+    shard_dir: &OwnedFd,
+    algo: &CStr,
+    path: &str,
+    f: &mut impl FnMut(BlobKey, u64),
+) -> Result<(), Errno> {
+    // collect first and then do subdirs before files so that a blob we move doesn't get seen again
+    let mut subdirs = vec![];
+    let mut files = vec![];
+    let mut dir_reader = Dir::read_from(shard_dir)?;
+    while let Some(entry) = dir_reader.read() {
+        let entry = entry?;
+        if entry.file_name() == c"." || entry.file_name() == c".." {
+            continue;
+        }
+        if entry.file_type() == FileType::Directory {
+            subdirs.push(entry.file_name().to_owned());
+        } else {
+            files.push(entry.file_name().to_owned());
         }
     }
 
+    for name in subdirs {
+        let Ok(name_str) = name.to_str() else {
+            error!("got weird path {:?} {:?}", path, name);
+            continue;
+        };
+        let sub_dir = rustix::fs::openat(
+            shard_dir,
+            &name,
+            OFlags::DIRECTORY | OFlags::RDONLY | OFlags::CLOEXEC,
+            Mode::empty(),
+        )?;
+        read_shard_dir(dir, &sub_dir, algo, &format!("{}/{}", path, name_str), f)?;
+    }
+
+    for name in files {
+        let Some(key) = BlobKey::from_cstr_parts(algo, &name) else {
+            error!("got weird path {:?} {:?}", path, name);
+            continue;
+        };
+        let stat = rustix::fs::statat(shard_dir, &name, AtFlags::empty())?;
+        let cur = format!("{}/{}", path, key.parts().1);
+        let want = dir.path(&key);
+        if cur != want {
+            dir.create_parents(&want)?;
+            rustix::fs::renameat(dir, &cur, dir, &want)?;
+            info!("migrated blob {} to {}", cur, want);
+        }
+        f(key, stat.st_size as u64);
+    }
+
     Ok(())
 }
 
 pub fn openat_create_write_with_guard<'a>(
-    dir: &'a OwnedFd,
+    dir: &'a BlobDir,
     key: &'a BlobKey,
 ) -> Result<(std::fs::File, FileGuard<'a>), Errno> {
     let file = openat_create_write(dir, &key.with_tmp_suffix())?;
@@ -265,7 +368,7 @@ pub fn openat_create_write_with_generic_guard<'a>(
 }
 
 pub fn openat_create_write_async_with_guard<'a>(
-    dir: &'a OwnedFd,
+    dir: &'a BlobDir,
     key: &'a BlobKey,
 ) -> Result<(tokio::fs::File, FileGuard<'a>), Errno> {
     let file = openat_create_write_async(dir, &key.with_tmp_suffix())?;
@@ -290,8 +393,8 @@ pub fn openat_read(
     }
 }
 
-pub fn openat_read_key(dir: &OwnedFd, key: &BlobKey) -> Result<Option<std::fs::File>, Errno> {
-    openat_read(dir, key.as_path())
+pub fn openat_read_key(dir: &BlobDir, key: &BlobKey) -> Result<Option<std::fs::File>, Errno> {
+    openat_read(&dir.fd, dir.path(key))
 }
 
 pub fn open_or_create_dir_at(
@@ -325,7 +428,7 @@ pub fn open_or_create_dir_at(
     }
 }
 
-fn openat_create_write(dir: &OwnedFd, key: &BlobKey) -> Result<std::fs::File, Errno> {
+fn openat_create_write(dir: &BlobDir, key: &BlobKey) -> Result<std::fs::File, Errno> {
     let open = || {
         openat_key(
             dir,
@@ -337,14 +440,14 @@ fn openat_create_write(dir: &OwnedFd, key: &BlobKey) -> Result<std::fs::File, Er
     match open() {
         Ok(f) => Ok(f),
         Err(e) if e == Errno::NOENT => {
-            rustix::fs::mkdirat(dir, key.parts().0, Mode::from_bits_truncate(0o744))?;
+            dir.create_parents(&dir.path(key))?;
             open()
         }
         e => e,
     }
 }
 
-fn openat_create_write_async(dir: &OwnedFd, key: &BlobKey) -> Result<tokio::fs::File, Errno> {
+fn openat_create_write_async(dir: &BlobDir, key: &BlobKey) -> Result<tokio::fs::File, Errno> {
     let open = || {
         openat_key(
             dir,
@@ -357,7 +460,7 @@ fn openat_create_write_async(dir: &OwnedFd, key: &BlobKey) -> Result<tokio::fs::
     match open() {
         Ok(f) => Ok(f),
         Err(e) if e == Errno::NOENT => {
-            rustix::fs::mkdirat(dir, key.parts().0, Mode::from_bits_truncate(0o744))?;
+            dir.create_parents(&dir.path(key))?;
             open()
         }
         e => e,
@@ -365,18 +468,18 @@ fn openat_create_write_async(dir: &OwnedFd, key: &BlobKey) -> Result<tokio::fs::
 }
 
 fn openat_key(
-    dir: &OwnedFd,
+    dir: &BlobDir,
     key: &BlobKey,
     mode: Mode,
     flags: OFlags,
 ) -> Result<std::fs::File, Errno> {
-    let fd = rustix::fs::openat2(dir, key.as_path(), flags, mode, ResolveFlags::BENEATH)?;
+    let fd = rustix::fs::openat2(dir, dir.path(key), flags, mode, ResolveFlags::BENEATH)?;
     Ok(fd.into())
 }
 
 // wish there was unlinkat2 with BENEATH
-fn unlinkat(dir: &OwnedFd, key: &BlobKey) -> Result<(), Errno> {
-    rustix::fs::unlinkat(dir, key.as_path(), AtFlags::empty())
+fn unlinkat(dir: &BlobDir, key: &BlobKey) -> Result<(), Errno> {
+    rustix::fs::unlinkat(dir, dir.path(key), AtFlags::empty())
 }
 
 pub fn atomic_inc(x: &AtomicU64) {
@@ -386,3 +489,62 @@ pub fn atomic_inc(x: &AtomicU64) {
 pub fn atomic_take(x: &AtomicU64) -> u64 {
     x.swap(0, std::sync::atomic::Ordering::Relaxed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const HEX: &str = "abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789";
+
+    fn read_all(dir: &BlobDir) -> Vec<(String, u64)> {
+        let mut acc = vec![];
+        read_from_disk(dir, |key, size| acc.push((key.to_string(), size))).unwrap();
+        acc
+    }
+
+    #[test]
+    fn test_as_path() {
+        let key = BlobKey::new(format!("sha256:{}", HEX)).unwrap();
+        assert_eq!(key.as_path(0), format!("sha256/{}", HEX));
+        assert_eq!(key.as_path(2), format!("sha256/ab/cd/{}", HEX));
+        let short = BlobKey::new("sha256:abc".to_string()).unwrap();
+        assert_eq!(short.as_path(3), "sha256/ab/abc");
+    }
+
+    #[test]
+    fn test_sharded_write_and_reload() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fd = open_or_create_dir_at(None, tmp.path()).unwrap();
+        let dir = BlobDir::new(fd, 2);
+        let key = BlobKey::new(format!("sha256:{}", HEX)).unwrap();
+
+        let (mut file, guard) = openat_create_write_with_guard(&dir, &key).unwrap();
+        file.write_all(b"hello").unwrap();
+        guard.success().unwrap();
+
+        assert!(tmp.path().join("sha256/ab/cd").join(HEX).is_file());
+        assert!(openat_read_key(&dir, &key).unwrap().is_some());
+        assert_eq!(read_all(&dir), vec![(key.to_string(), 5)]);
+    }
+
+    #[test]
+    fn test_migrate_flat() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("sha256")).unwrap();
+        std::fs::write(tmp.path().join("sha256").join(HEX), b"hello").unwrap();
+        let key = BlobKey::new(format!("sha256:{}", HEX)).unwrap();
+
+        let fd = open_or_create_dir_at(None, tmp.path()).unwrap();
+        let dir = BlobDir::new(fd, 1);
+        assert_eq!(read_all(&dir), vec![(key.to_string(), 5)]);
+        assert!(!tmp.path().join("sha256").join(HEX).exists());
+        assert!(tmp.path().join("sha256/ab").join(HEX).is_file());
+        assert!(openat_read_key(&dir, &key).unwrap().is_some());
+
+        // and back again
+        let dir = BlobDir::new(dir.fd, 0);
+        assert_eq!(read_all(&dir), vec![(key.to_string(), 5)]);
+        assert!(tmp.path().join("sha256").join(HEX).is_file());
+    }
+}
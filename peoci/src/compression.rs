@@ -1,13 +1,88 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
 use crate::spec;
+use log::warn;
 use oci_spec::image::{Descriptor, MediaType};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Compression {
     None,
     Gzip,
     Zstd,
 }
 
+// what the first bytes of a layer look like. xz is only recognized so we can give a better log
+// message, we don't support decompressing it
+#[derive(Debug, PartialEq)]
+pub enum Magic {
+    Gzip,
+    Zstd,
+    Xz,
+    Tar,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+// ustar magic is in the first tar header at offset 257, old style tar doesn't have it
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+pub const SNIFF_LEN: usize = TAR_MAGIC_OFFSET + TAR_MAGIC.len();
+
+pub fn sniff_magic(head: &[u8]) -> Option<Magic> {
+    if head.starts_with(GZIP_MAGIC) {
+        Some(Magic::Gzip)
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Some(Magic::Zstd)
+    } else if head.starts_with(XZ_MAGIC) {
+        Some(Magic::Xz)
+    } else if head
+        .get(TAR_MAGIC_OFFSET..)
+        .is_some_and(|x| x.starts_with(TAR_MAGIC))
+    {
+        Some(Magic::Tar)
+    } else {
+        None
+    }
+}
+
+impl Compression {
+    // registries sometimes mislabel layers (ex gzip content with a zstd media type), so we trust
+    // the magic over the declared type when they disagree. If we can't tell, go with declared
+    pub fn reconcile(self, head: &[u8]) -> Compression {
+        let sniffed = match sniff_magic(head) {
+            Some(Magic::Gzip) => Compression::Gzip,
+            Some(Magic::Zstd) => Compression::Zstd,
+            Some(Magic::Tar) => Compression::None,
+            Some(Magic::Xz) => {
+                warn!("layer declared as {self:?} looks like xz which is not supported");
+                return self;
+            }
+            None => {
+                return self;
+            }
+        };
+        if sniffed != self {
+            warn!("layer declared as {self:?} looks like {sniffed:?}, using {sniffed:?}");
+        }
+        sniffed
+    }
+
+    // reads the head of the file without moving its offset
+    pub fn reconcile_file(self, file: &File) -> std::io::Result<Compression> {
+        let mut head = [0; SNIFF_LEN];
+        let mut len = 0;
+        while len < head.len() {
+            match file.read_at(&mut head[len..], len as u64)? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Ok(self.reconcile(&head[..len]))
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub struct Error {
     pub media_type: MediaType,
@@ -76,3 +151,71 @@ impl From<&spec::LayerDescriptor> for Compression {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tar_head() -> Vec<u8> {
+        let mut head = vec![0; 512];
+        head[..4].copy_from_slice(b"file");
+        head[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()].copy_from_slice(TAR_MAGIC);
+        head
+    }
+
+    #[test]
+    fn test_sniff_magic() {
+        assert_eq!(sniff_magic(&[0x1f, 0x8b, 0x08, 0x00]), Some(Magic::Gzip));
+        assert_eq!(
+            sniff_magic(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]),
+            Some(Magic::Zstd)
+        );
+        assert_eq!(
+            sniff_magic(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00]),
+            Some(Magic::Xz)
+        );
+        assert_eq!(sniff_magic(&tar_head()), Some(Magic::Tar));
+        assert_eq!(sniff_magic(&[]), None);
+        assert_eq!(sniff_magic(&[0x1f]), None);
+        assert_eq!(sniff_magic(&[0; 512]), None);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let gzip = [0x1f, 0x8b, 0x08, 0x00];
+        let zstd = [0x28, 0xb5, 0x2f, 0xfd, 0x00];
+        let xz = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00];
+
+        for declared in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            assert_eq!(declared.reconcile(&gzip), Compression::Gzip);
+            assert_eq!(declared.reconcile(&zstd), Compression::Zstd);
+            assert_eq!(declared.reconcile(&tar_head()), Compression::None);
+            // can't tell, so go with what we were told
+            assert_eq!(declared.reconcile(&xz), declared);
+            assert_eq!(declared.reconcile(&[]), declared);
+        }
+    }
+
+    #[test]
+    fn test_reconcile_file() {
+        use std::io::{Seek, SeekFrom, Write};
+        let path = std::env::temp_dir().join(format!("peoci-compression-{}", std::process::id()));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // shorter than SNIFF_LEN
+        file.write_all(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(
+            Compression::Gzip.reconcile_file(&file).unwrap(),
+            Compression::Zstd
+        );
+        assert_eq!(file.stream_position().unwrap(), 0);
+    }
+}
@@ -5,7 +5,7 @@ use std::time::{Duration, Instant};
 
 use arc_swap::ArcSwap;
 use bytes::Bytes;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use log::{error, info, trace, warn};
 use moka::{Expiry, future::Cache};
 use oci_spec::{
@@ -119,14 +119,29 @@ pub enum Auth {
 type UtcInstant = DateTime<Utc>;
 
 pub type AuthMap = BTreeMap<String, Auth>;
+
+// for registries that require a client cert (mutual tls) or use a private ca
+#[derive(Debug, Clone)]
+pub struct RegistryTls {
+    // cert and private key, both pem in the one buffer
+    pub identity_pem: Option<Vec<u8>>,
+    // extra root to trust for a registry with a private ca
+    pub ca_pem: Option<Vec<u8>>,
+}
+
+pub type TlsMap = BTreeMap<String, RegistryTls>;
 pub type RatelimitMap = BTreeMap<String, UtcInstant>;
 
 #[derive(Clone)]
 pub struct Client {
     client: reqwest::Client,
+    // always https outside of tests
+    scheme: &'static str,
     token_cache: Cache<TokenCacheKey, Token>,
     auth_store: Arc<ArcSwap<AuthMap>>,
     ratelimit: Arc<RwLock<RatelimitMap>>,
+    // a client cert is set on the reqwest::Client so registries in a TlsMap get their own
+    tls_clients: Arc<ArcSwap<BTreeMap<String, reqwest::Client>>>,
 }
 
 pub struct ImageManifestResponse {
@@ -205,11 +220,11 @@ impl<'a> TagOrDigest<'a> {
 
 impl Client {
     pub fn new() -> Result<Self, Error> {
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::redirect::Policy::limited(2))
-            .https_only(true)
-            .build()?;
+        let client = reqwest_builder().build()?;
+        Ok(Self::with_reqwest_client(client, "https"))
+    }
 
+    fn with_reqwest_client(client: reqwest::Client, scheme: &'static str) -> Self {
         let token_cache = Cache::builder()
             .max_capacity(10_000_000)
             .weigher(|k: &TokenCacheKey, v: &Token| {
@@ -223,13 +238,41 @@ impl Client {
 
         let auth_store = Arc::new(ArcSwap::from_pointee(BTreeMap::new()));
         let ratelimit = Arc::new(RwLock::new(BTreeMap::new()));
+        let tls_clients = Arc::new(ArcSwap::from_pointee(BTreeMap::new()));
 
-        Ok(Client {
+        Client {
             client,
+            scheme,
             token_cache,
             auth_store,
             ratelimit,
-        })
+            tls_clients,
+        }
+    }
+
+    // replaces all the client certs, registries not in tls go back to the shared client
+    pub fn set_tls(&self, tls: TlsMap) -> Result<(), Error> {
+        let mut clients = BTreeMap::new();
+        for (registry, tls) in tls {
+            let mut builder = reqwest_builder();
+            if let Some(identity_pem) = &tls.identity_pem {
+                builder = builder.identity(reqwest::Identity::from_pem(identity_pem)?);
+            }
+            if let Some(ca_pem) = &tls.ca_pem {
+                builder = builder.add_root_certificate(reqwest::Certificate::from_pem(ca_pem)?);
+            }
+            clients.insert(registry, builder.build()?);
+        }
+        self.tls_clients.store(clients.into());
+        Ok(())
+    }
+
+    fn reqwest_client(&self, reference: &Reference) -> reqwest::Client {
+        self.tls_clients
+            .load()
+            .get(reference.resolve_registry())
+            .unwrap_or(&self.client)
+            .clone()
     }
 
     pub async fn set_auth(&self, auth: AuthMap) {
@@ -386,11 +429,15 @@ impl Client {
         let repo = reference.repository();
         let td = TagOrDigest::try_from(reference)?;
 
-        let url = format!("https://{domain}/v2/{repo}/manifests/{}", td.as_str());
+        let url = format!(
+            "{}://{domain}/v2/{repo}/manifests/{}",
+            self.scheme,
+            td.as_str()
+        );
 
         trace!("GET {url}");
         let request = self
-            .client
+            .reqwest_client(reference)
             .request(Method::GET, &url)
             .header(header::ACCEPT, accept);
 
@@ -423,6 +470,74 @@ impl Client {
         }
     }
 
+    // the referrers api lists manifests whose subject is digest (signatures, sboms, attestations)
+    // as an image index. Registries are allowed to ignore the artifactType filter, which they
+    // signal by not sending OCI-Filters-Applied, so we always filter ourselves too.
+    // Ok(None) when the registry doesn't support the api; we don't do the tag schema fallback
+    pub async fn get_referrers(
+        &self,
+        reference: &Reference,
+        digest: &Digest,
+        artifact_type: Option<&str>,
+    ) -> Result<Option<Vec<Descriptor>>, Error> {
+        let domain = reference.resolve_registry();
+        let repo = reference.repository();
+        let url = format!(
+            "{}://{domain}/v2/{repo}/referrers/{}:{}",
+            self.scheme,
+            digest.algorithm().as_ref(),
+            digest.digest()
+        );
+
+        trace!("GET {url} artifactType={artifact_type:?}");
+        let mut request = self
+            .reqwest_client(reference)
+            .request(Method::GET, &url)
+            .header(header::ACCEPT, OCI_IMAGE_INDEX_V1);
+        if let Some(artifact_type) = artifact_type {
+            // media types have a + in them so this needs the escaping
+            request = request.query(&[("artifactType", artifact_type)]);
+        }
+
+        let response = self.auth_and_retry(reference, request).await?;
+
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => {
+                return Ok(None);
+            }
+            _ => {
+                return Err(status_not_ok(response).await);
+            }
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|x| x.to_str().unwrap_or("").to_string())
+            .unwrap_or_else(String::new);
+        if content_type != OCI_IMAGE_INDEX_V1 {
+            return Err(Error::BadContentType(content_type));
+        }
+
+        let data = response.bytes().await?;
+        let index = ImageIndexResponse { data }.get()?;
+        let referrers = index
+            .manifests()
+            .iter()
+            .filter(|descriptor| {
+                artifact_type.is_none_or(|artifact_type| {
+                    descriptor
+                        .artifact_type()
+                        .as_ref()
+                        .is_some_and(|x| x.to_string() == artifact_type)
+                })
+            })
+            .cloned()
+            .collect();
+        Ok(Some(referrers))
+    }
+
     pub async fn get_blob(
         &self,
         reference: &Reference,
@@ -481,12 +596,13 @@ impl Client {
         let domain = reference.resolve_registry();
         let repo = reference.repository();
         let url = format!(
-            "https://{domain}/v2/{repo}/blobs/{}:{}",
+            "{}://{domain}/v2/{repo}/blobs/{}:{}",
+            self.scheme,
             descriptor.digest().algorithm().as_ref(),
             descriptor.digest().digest()
         );
         trace!("GET {url}");
-        self.auth_and_retry(reference, self.client.request(Method::GET, &url))
+        self.auth_and_retry(reference, self.reqwest_client(reference).get(&url))
             .await
     }
 
@@ -504,7 +620,7 @@ impl Client {
                     .token_cache
                     .entry(reference.into())
                     .or_try_insert_with(retreive_token_user_pass(
-                        self.client.clone(),
+                        self.reqwest_client(reference),
                         reference,
                         www_auth,
                         user,
@@ -515,7 +631,10 @@ impl Client {
                         // drop the error to go from Arc<Error> to Error
                         // TODO do something better
                         error!("error in retreive_token_user_pass {:?}", e);
-                        Error::Unknown
+                        match *e {
+                            Error::InvalidAuth => Error::InvalidAuth,
+                            _ => Error::Unknown,
+                        }
                     })?;
                 if entry.is_fresh() {
                     trace!("got new token for {}", entry.key().0);
@@ -606,12 +725,15 @@ impl Client {
     }
 
     async fn handle_ratelimit(&self, reference: &Reference, res: &Response) -> Result<(), Error> {
-        // ghcr apparently returns either 403 or 429
-        if !matches!(
-            res.status(),
-            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
-        ) {
-            return Ok(());
+        let retry_after = get_retry_after_header(res.headers(), Utc::now());
+        match res.status() {
+            // ghcr apparently returns either 403 or 429
+            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => {}
+            // a 503 is only a ratelimit if they tell us when to come back
+            StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {}
+            _ => {
+                return Ok(());
+            }
         }
 
         if let Some(ratelimit_remaining) = get_ratelimit_remaining_header(res.headers()) {
@@ -624,7 +746,7 @@ impl Client {
         }
 
         let registry = reference.resolve_registry();
-        let end: UtcInstant = if let Some(reset) = get_ratelimit_reset_header(res.headers()) {
+        let reset = get_ratelimit_reset_header(res.headers()).map(|reset| {
             let now = Utc::now();
             let time = reset
                 .try_into() // u64 -> i64
@@ -640,13 +762,19 @@ impl Client {
             } else {
                 time
             }
-        } else {
-            warn!(
-                "got res status {} from {} but no ratelimit-reset",
-                res.status(),
-                registry
-            );
-            Utc::now() + Duration::from_secs(DEFAULT_RATELIMIT_RESET)
+        });
+        // if we get both, be conservative and wait for the later one
+        let end: UtcInstant = match (reset, retry_after) {
+            (Some(reset), Some(retry_after)) => reset.max(retry_after),
+            (Some(end), None) | (None, Some(end)) => end,
+            (None, None) => {
+                warn!(
+                    "got res status {} from {} but no ratelimit-reset or retry-after",
+                    res.status(),
+                    registry
+                );
+                Utc::now() + Duration::from_secs(DEFAULT_RATELIMIT_RESET)
+            }
         };
 
         warn!(
@@ -663,6 +791,12 @@ impl Client {
     }
 }
 
+fn reqwest_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(2))
+        .https_only(true)
+}
+
 async fn status_not_ok(res: Response) -> Error {
     let status = res.status();
     if log::log_enabled!(log::Level::Trace) {
@@ -685,28 +819,46 @@ async fn retreive_token_user_pass(
     user: &str,
     pass: &str,
 ) -> Result<Token, Error> {
+    // token is what the spec requires but access_token is accepted for oauth compat and some
+    // registries only send that one (docker hub sends both)
     #[derive(Deserialize)]
     struct JsonToken {
-        token: String,
+        token: Option<String>,
+        access_token: Option<String>,
         expires_in: Option<u64>,
         //issued_at: Option<String>, // "2025-05-12T21:35:54.377188944Z" but not really useful
     }
 
     let scope = format!("repository:{}:pull", reference.repository());
 
-    let token = client
+    let mut req = client
         .request(Method::GET, www_auth.realm)
-        .query(&[("scope", scope), ("service", www_auth.service.to_string())])
-        .basic_auth(user, Some(pass))
-        .send()
-        .await?
-        .json::<JsonToken>()
-        .await?;
+        .query(&[("scope", scope)]);
+    if let Some(service) = www_auth.service {
+        req = req.query(&[("service", service)]);
+    }
+    let res = req.basic_auth(user, Some(pass)).send().await?;
+
+    match res.status() {
+        StatusCode::OK => {}
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            error!("token realm {} rejected credentials", www_auth.realm);
+            return Err(Error::InvalidAuth);
+        }
+        _ => {
+            return Err(status_not_ok(res).await);
+        }
+    }
+
+    let token = res.json::<JsonToken>().await?;
 
     // https://distribution.github.io/distribution/spec/auth/token/#token-response-fields
     // gives the default as 60 seconds
     let expires_in = Duration::from_secs(token.expires_in.unwrap_or(60));
-    let token = token.token;
+    let token = token
+        .token
+        .or(token.access_token)
+        .ok_or(Error::InvalidAuth)?;
     Ok(Token { token, expires_in })
 }
 
@@ -815,7 +967,7 @@ struct WWWAuthenticateBearer<'a> {
 
 struct WWWAuthenticateBearerRealmService<'a> {
     realm: &'a str,
-    service: &'a str,
+    service: Option<&'a str>,
 }
 
 fn parse_www_authenticate_bearer_header(
@@ -824,7 +976,7 @@ fn parse_www_authenticate_bearer_header(
     let res = parse_www_authenticate_bearer_str(input.to_str().ok()?)?;
     Some(WWWAuthenticateBearerRealmService {
         realm: res.realm?,
-        service: res.service?,
+        service: res.service,
     })
 }
 
@@ -955,10 +1107,32 @@ fn parse_ratelimit_reset_str(input: &str) -> Option<u64> {
     input.parse().ok()
 }
 
+// https://www.rfc-editor.org/rfc/rfc9110#name-retry-after
+// value is either a number of seconds (relative to now) or an HTTP-date like
+// Wed, 21 Oct 2015 07:28:00 GMT
+fn get_retry_after_header(map: &reqwest::header::HeaderMap, now: UtcInstant) -> Option<UtcInstant> {
+    parse_retry_after_str(map.get(header::RETRY_AFTER)?.to_str().ok()?, now)
+}
+
+fn parse_retry_after_str(input: &str, now: UtcInstant) -> Option<UtcInstant> {
+    let input = input.trim();
+    if let Ok(seconds) = input.parse::<u64>() {
+        // None if it is too far out to represent
+        now.checked_add_signed(TimeDelta::try_seconds(seconds.try_into().ok()?)?)
+    } else {
+        // IMF-fixdate is a subset of rfc2822
+        DateTime::parse_from_rfc2822(input)
+            .ok()
+            .map(|x| x.with_timezone(&Utc))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::sync::Mutex;
+
     #[test]
     fn test_digest_eq() {
         fn sha256_digest(data: impl AsRef<[u8]>) -> impl sha2::Digest {
@@ -992,6 +1166,355 @@ mod tests {
         );
     }
 
+    const REFERRERS_SUBJECT: &str =
+        "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    // artifactType filter is ignored, like registries are allowed to do
+    const REFERRERS_INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1234,
+                "digest": "sha256:a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0",
+                "artifactType": "application/vnd.dev.cosign.artifact.sig.v1+json"
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 5678,
+                "digest": "sha256:b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1",
+                "artifactType": "application/spdx+json",
+                "annotations": {"org.opencontainers.image.created": "2025-01-01T00:00:00Z"}
+            }
+        ]
+    }"#;
+
+    // just enough of a registry with a token realm on the same port to exercise auth_and_retry,
+    // every connection gets one response and is closed
+    async fn mock_registry(listener: tokio::net::TcpListener, requests: Arc<Mutex<Vec<String>>>) {
+        use tokio::io::AsyncReadExt;
+        let port = listener.local_addr().unwrap().port();
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = vec![];
+            let mut chunk = [0; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = conn.read(&mut chunk).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let req = String::from_utf8(buf).unwrap();
+            let line = req.lines().next().unwrap_or_default().to_string();
+            let auth = req
+                .lines()
+                .find_map(|l| l.strip_prefix("authorization: "))
+                .unwrap_or_default();
+            requests.lock().unwrap().push(line.clone());
+
+            let challenge = format!(
+                "www-authenticate: Bearer realm=\"http://127.0.0.1:{port}/token\",service=\"mock\"\r\n"
+            );
+            let (status, headers, body) = if line.starts_with("GET /token?") {
+                assert!(
+                    line.contains("scope=repository%3Afoo%2Fbar%3Apull"),
+                    "{}",
+                    line
+                );
+                assert!(line.contains("service=mock"), "{}", line);
+                // dXNlcjpwYXNz is user:pass
+                if auth == "Basic dXNlcjpwYXNz" {
+                    (
+                        "200 OK",
+                        String::new(),
+                        r#"{"access_token":"tok","expires_in":300}"#,
+                    )
+                } else {
+                    ("401 Unauthorized", String::new(), "")
+                }
+            } else if auth == "Bearer tok" && line.starts_with("GET /v2/foo/bar/referrers/") {
+                let path = line.split(' ').nth(1).unwrap_or_default();
+                let url = reqwest::Url::parse(&format!("http://127.0.0.1{path}")).unwrap();
+                let artifact_type = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "artifactType")
+                    .map(|(_, v)| v.into_owned());
+                let known_type = artifact_type.as_deref().is_none_or(|x| {
+                    [
+                        "application/vnd.dev.cosign.artifact.sig.v1+json",
+                        "application/spdx+json",
+                    ]
+                    .contains(&x)
+                });
+                if !known_type {
+                    ("400 Bad Request", String::new(), "")
+                } else if url.path() == format!("/v2/foo/bar/referrers/{REFERRERS_SUBJECT}") {
+                    (
+                        "200 OK",
+                        format!("content-type: {OCI_IMAGE_INDEX_V1}\r\n"),
+                        REFERRERS_INDEX,
+                    )
+                } else {
+                    ("404 Not Found", String::new(), "")
+                }
+            } else if auth == "Bearer tok" {
+                ("200 OK", String::new(), "manifest")
+            } else {
+                ("401 Unauthorized", challenge, "")
+            };
+            let res = format!(
+                "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n{headers}\r\n{body}",
+                body.len()
+            );
+            conn.write_all(res.as_bytes()).await.unwrap();
+            conn.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_negotiation() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registry = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let requests = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(mock_registry(listener, requests.clone()));
+
+        // the real client is https only
+        let client = Client::with_reqwest_client(reqwest::Client::new(), "http");
+        let reference: Reference = format!("{registry}/foo/bar:latest").parse().unwrap();
+        let url = format!("http://{registry}/v2/foo/bar/manifests/latest");
+        let user_pass = |pass: &str| {
+            AuthMap::from([(registry.clone(), Auth::UserPass("user".into(), pass.into()))])
+        };
+
+        let res = client
+            .auth_and_retry(&reference, client.reqwest_client(&reference).get(&url))
+            .await;
+        assert!(matches!(res, Err(Error::RegistryNotSupported(_))));
+
+        client.set_auth(user_pass("wrong")).await;
+        let res = client
+            .auth_and_retry(&reference, client.reqwest_client(&reference).get(&url))
+            .await;
+        assert!(matches!(res, Err(Error::InvalidAuth)));
+
+        client.set_auth(user_pass("pass")).await;
+        requests.lock().unwrap().clear();
+        let res = client
+            .auth_and_retry(&reference, client.reqwest_client(&reference).get(&url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "manifest");
+        // challenge, token, retry
+        assert_eq!(requests.lock().unwrap().len(), 3);
+
+        // now the token is cached and sent up front
+        requests.lock().unwrap().clear();
+        let res = client
+            .auth_and_retry(&reference, client.reqwest_client(&reference).get(&url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_referrers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registry = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let requests = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(mock_registry(listener, requests.clone()));
+
+        let client = Client::with_reqwest_client(reqwest::Client::new(), "http");
+        client
+            .set_auth(AuthMap::from([(
+                registry.clone(),
+                Auth::UserPass("user".into(), "pass".into()),
+            )]))
+            .await;
+        let reference: Reference = format!("{registry}/foo/bar:latest").parse().unwrap();
+        let subject: Digest = REFERRERS_SUBJECT.parse().unwrap();
+
+        let referrers = client
+            .get_referrers(&reference, &subject, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let artifact_types: Vec<_> = referrers
+            .iter()
+            .map(|x| x.artifact_type().as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            artifact_types,
+            [
+                "application/vnd.dev.cosign.artifact.sig.v1+json",
+                "application/spdx+json"
+            ]
+        );
+        assert_eq!(referrers[1].size(), 5678);
+
+        let referrers = client
+            .get_referrers(&reference, &subject, Some("application/spdx+json"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(
+            referrers[0].digest().digest(),
+            "b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1b1"
+        );
+        assert!(
+            requests
+                .lock()
+                .unwrap()
+                .last()
+                .unwrap()
+                .contains("?artifactType=application%2Fspdx%2Bjson")
+        );
+
+        let other: Digest =
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000"
+                .parse()
+                .unwrap();
+        let res = client
+            .get_referrers(&reference, &other, None)
+            .await
+            .unwrap();
+        assert!(res.is_none());
+    }
+
+    // server cert for 127.0.0.1 that only accepts clients with a cert from the same ca, every
+    // connection gets one 200 response
+    async fn mock_mtls_registry(
+        listener: tokio::net::TcpListener,
+        ca: &rcgen::Certificate,
+        ca_key: &rcgen::KeyPair,
+    ) {
+        use tokio::io::AsyncReadExt;
+        use tokio_rustls::rustls;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            roots.into(),
+            provider.clone(),
+        )
+        .build()
+        .unwrap();
+        let server_key = rcgen::KeyPair::generate().unwrap();
+        let server_cert = rcgen::CertificateParams::new(vec!["127.0.0.1".to_string()])
+            .unwrap()
+            .signed_by(&server_key, ca, ca_key)
+            .unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![server_cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::try_from(server_key.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            // no client cert fails here
+            let Ok(mut conn) = acceptor.accept(conn).await else {
+                continue;
+            };
+            let mut buf = vec![];
+            let mut chunk = [0; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                match conn.read(&mut chunk).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+            let body = "manifest";
+            let res = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = conn.write_all(res.as_bytes()).await;
+            let _ = conn.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_cert() {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let client_cert = rcgen::CertificateParams::new(vec![])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+        let identity_pem = format!("{}{}", client_cert.pem(), client_key.serialize_pem());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registry = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let ca_pem = ca.pem();
+        tokio::spawn(async move { mock_mtls_registry(listener, &ca, &ca_key).await });
+
+        let client = Client::with_reqwest_client(reqwest::Client::new(), "https");
+        client
+            .set_auth(AuthMap::from([(registry.clone(), Auth::None)]))
+            .await;
+        let reference: Reference = format!("{registry}/foo/bar:latest").parse().unwrap();
+        let url = format!("https://{registry}/v2/foo/bar/manifests/latest");
+        let get = || async {
+            client
+                .auth_and_retry(&reference, client.reqwest_client(&reference).get(&url))
+                .await
+        };
+
+        // trusts the server but has no cert to give
+        client
+            .set_tls(TlsMap::from([(
+                registry.clone(),
+                RegistryTls {
+                    identity_pem: None,
+                    ca_pem: Some(ca_pem.clone().into()),
+                },
+            )]))
+            .unwrap();
+        assert!(matches!(get().await, Err(Error::Reqwest(_))));
+
+        client
+            .set_tls(TlsMap::from([(
+                registry.clone(),
+                RegistryTls {
+                    identity_pem: Some(identity_pem.into()),
+                    ca_pem: Some(ca_pem.into()),
+                },
+            )]))
+            .unwrap();
+        let res = get().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "manifest");
+
+        // and back to the shared client which doesn't know the ca
+        client.set_tls(TlsMap::new()).unwrap();
+        assert!(matches!(get().await, Err(Error::Reqwest(_))));
+
+        assert!(matches!(
+            client.set_tls(TlsMap::from([(
+                registry.clone(),
+                RegistryTls {
+                    identity_pem: Some(b"not pem".to_vec()),
+                    ca_pem: None,
+                },
+            )])),
+            Err(Error::Reqwest(_))
+        ));
+    }
+
     #[test]
     fn test_www_authenticate() {
         // example from https://distribution.github.io/distribution/spec/auth/token/#how-to-authenticate
@@ -1034,4 +1557,22 @@ mod tests {
         assert_eq!(None, parse_ratelimit_remaining_str("x100;w=3600"));
         assert_eq!(None, parse_ratelimit_remaining_str("100x;w=3600"));
     }
+
+    #[test]
+    fn test_retry_after() {
+        let now = DateTime::from_timestamp(1445412000, 0).unwrap();
+        assert_eq!(
+            Some(now + Duration::from_secs(120)),
+            parse_retry_after_str("120", now)
+        );
+        assert_eq!(Some(now), parse_retry_after_str("0", now));
+        assert_eq!(
+            DateTime::from_timestamp(1445412480, 0),
+            parse_retry_after_str("Wed, 21 Oct 2015 07:28:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after_str("-1", now));
+        assert_eq!(None, parse_retry_after_str("soon", now));
+        assert_eq!(None, parse_retry_after_str(&u64::MAX.to_string(), now));
+        assert_eq!(None, parse_retry_after_str(&i64::MAX.to_string(), now));
+    }
 }
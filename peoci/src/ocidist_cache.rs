@@ -17,7 +17,7 @@ use tokio::sync::Semaphore;
 
 use crate::{
     blobcache,
-    blobcache::{BlobKey, atomic_inc, atomic_take},
+    blobcache::{BlobDir, BlobKey, atomic_inc, atomic_take},
     ocidist, spec,
 };
 
@@ -112,6 +112,7 @@ pub struct ClientBuilder {
     blob_capacity: u64,     // in bytes
     max_open_conns: usize,
     auth: Option<ocidist::AuthMap>,
+    tls: Option<ocidist::TlsMap>,
 }
 
 #[derive(bincode::Encode, bincode::Decode)]
@@ -122,7 +123,7 @@ pub struct PackedImageAndConfiguration {
 struct Dirs {
     path: PathBuf, // only storing this for fs::read_dir ...
     cache: OwnedFd,
-    blobs: OwnedFd,
+    blobs: BlobDir,
 }
 
 #[derive(Default)]
@@ -166,6 +167,7 @@ impl Default for ClientBuilder {
             blob_capacity: 1_000_000_000,
             max_open_conns: 10,
             auth: None,
+            tls: None,
         }
     }
 }
@@ -211,6 +213,11 @@ impl ClientBuilder {
         self
     }
 
+    pub fn tls(mut self, tls: ocidist::TlsMap) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
     pub fn ref_capacity(mut self, cap: u64) -> Self {
         self.ref_capacity = cap;
         self
@@ -234,13 +241,17 @@ impl ClientBuilder {
         let dirs = {
             let path = self.cache_dir.ok_or(Error::NoCacheDir)?;
             let cache = blobcache::open_or_create_dir_at(None, &path)?;
-            let blobs = blobcache::open_or_create_dir_at(Some(&cache), "blobs")?;
+            // unsharded like the ocidir layout
+            let blobs = BlobDir::new(blobcache::open_or_create_dir_at(Some(&cache), "blobs")?, 0);
             Dirs { path, cache, blobs }
         };
 
         let blobs_clone = dirs.blobs.try_clone().map_err(|_| Error::FdClone)?;
 
         let client = ocidist::Client::new()?;
+        if let Some(tls) = self.tls {
+            client.set_tls(tls)?;
+        }
 
         let ref_cache = Cache::builder()
             .max_capacity(self.ref_capacity)
@@ -603,7 +614,7 @@ async fn retreive_blob(
     semaphore: &Arc<Semaphore>,
     reference: &Reference,
     descriptor: &Descriptor,
-    blob_dir: &OwnedFd,
+    blob_dir: &BlobDir,
     key: &BlobKey,
     fd_tx: tokio::sync::oneshot::Sender<OwnedFd>,
 ) -> Result<u64, Error> {
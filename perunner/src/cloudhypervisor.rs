@@ -1,4 +1,5 @@
 //use std::os::fd::AsRawFd;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::process::{Child, Command, Stdio};
 //use std::os::unix::net::{UnixListener,UnixStream};
@@ -6,15 +7,21 @@ use std::io;
 use std::os::fd::OwnedFd;
 
 use std::ffi::OsString;
-use std::time::Duration;
+use std::fmt;
+use std::time::{Duration, Instant};
 
 use command_fds::{CommandFdExt, FdMapping};
 use tempfile::NamedTempFile;
-use waitid_timeout::{ChildWaitIdExt, WaitIdDataOvertime};
+use waitid_timeout::{
+    waitid_pidfd_exited_hang, ChildWaitIdExt, PidFd, PidFdWaiter, WaitIdData, WaitIdDataOvertime,
+};
 //use serde::Serialize;
 
 //use api_client;
 
+// what every guest gets, so also the most memory a container can use
+pub const GUEST_MEMORY_MB: u64 = 1024;
+
 // todo thiserror
 #[derive(Debug, Default)]
 pub enum Error {
@@ -22,10 +29,17 @@ pub enum Error {
     Unk,
     TempfileSetup,
     Spawn,
-    SpawnWithArgs(Vec<OsString>),
+    // args is empty unless keep_args
+    SpawnWithArgs {
+        bin: OsString,
+        error: String,
+        args: Vec<OsString>,
+    },
     Socket,
     //Api(api_client::Error),
     Overtime,
+    // ch didn't get the guest booted within the boot timeout
+    BootTimeout,
     Wait,
     BadExit,
     FdSetup,
@@ -46,15 +60,37 @@ pub enum ChLogLevel {
     Trace,
 }
 
+impl ChLogLevel {
+    pub const VALID: [&str; 4] = ["warn", "info", "debug", "trace"];
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChLogLevelError {
+    pub input: String,
+}
+
+impl fmt::Display for ChLogLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid ch log level {:?}, expected one of {}",
+            self.input,
+            ChLogLevel::VALID.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ChLogLevelError {}
+
 impl TryFrom<&str> for ChLogLevel {
-    type Error = io::Error;
-    fn try_from(x: &str) -> io::Result<Self> {
+    type Error = ChLogLevelError;
+    fn try_from(x: &str) -> Result<Self, ChLogLevelError> {
         match x {
             "warn" => Ok(Self::Warn),
             "info" => Ok(Self::Info),
             "debug" => Ok(Self::Debug),
             "trace" => Ok(Self::Trace),
-            _ => Err(io::ErrorKind::InvalidData.into()),
+            _ => Err(ChLogLevelError { input: x.into() }),
         }
     }
 }
@@ -95,6 +131,39 @@ impl PathBufOrOwnedFd {
 //    Two([(PathBufOrOwnedFd, CloudHypervisorPmemMode); 2]),
 //}
 
+// a default kernel plus named alternatives for images that need a kernel with specific modules or
+// config
+#[derive(Debug, Clone)]
+pub struct Kernels {
+    default: OsString,
+    named: BTreeMap<String, OsString>,
+}
+
+impl Kernels {
+    pub fn new(default: OsString) -> Self {
+        Self {
+            default,
+            named: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, name: String, path: OsString) {
+        self.named.insert(name, path);
+    }
+
+    // None gives the default, Some(name) gives None if we don't know about that name
+    pub fn get(&self, name: Option<&str>) -> Option<&OsString> {
+        match name {
+            None => Some(&self.default),
+            Some(name) => self.named.get(name),
+        }
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &OsString> {
+        std::iter::once(&self.default).chain(self.named.values())
+    }
+}
+
 #[derive(Clone)]
 pub struct CloudHypervisorConfig {
     pub bin: OsString,
@@ -104,6 +173,11 @@ pub struct CloudHypervisorConfig {
     pub log_level: Option<ChLogLevel>,
     pub keep_args: bool,
     pub event_monitor: bool,
+    // socket of a vhost-user block backend (like pevub) serving the image. When set the image is
+    // attached with --disk instead of as a pmem, so the guest sees it as /dev/vda
+    pub vhost_user_image: Option<PathBuf>,
+    // appended to the kernel cmdline, eg "loglevel=7" or an env var for peinit
+    pub cmdline_extra: Option<String>,
 }
 
 pub struct CloudHypervisor {
@@ -163,6 +237,10 @@ impl From<Error> for CloudHypervisorPostMortem {
 //    Some((listener, stream))
 //}
 
+// how often we check err_file for the booted event while waiting on boot
+const BOOT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+const BOOTED_EVENT: &[u8] = b"\"booted\"";
+
 // but still no fexecve to actually call ch from fd :(
 
 impl CloudHypervisor {
@@ -192,17 +270,24 @@ impl CloudHypervisor {
             })
             .collect::<Vec<_>>();
 
+        // vhost-user needs the guest memory shared with the backend
+        let memory = if config.vhost_user_image.is_some() {
+            format!("size={GUEST_MEMORY_MB}M,shared=on")
+        } else {
+            format!("size={GUEST_MEMORY_MB}M")
+        };
+
         let mut args = vec![];
         let child = {
             //let socket_fd = listener.as_raw_fd();
-            let mut x = Command::new(config.bin);
+            let mut x = Command::new(&config.bin);
             x.stdin(Stdio::null())
              .stdout(Stdio::null())
              .stderr(Stdio::from(err_file.reopen().unwrap()))
              .arg("--kernel").arg(config.kernel)
              .arg("--initramfs").arg(config.initramfs)
              .arg("--cpus").arg("boot=1")
-             .arg("--memory").arg("size=1024M")
+             .arg("--memory").arg(memory)
              // almalinux 9.5 doesn't have landlock enabled in the kernel config ...
              // zgrep -h "^CONFIG_SECURITY_LANDLOCK=" "/boot/config-$(uname -r)"
              //.arg("--landlock")
@@ -214,14 +299,20 @@ impl CloudHypervisor {
             // NOTE: using --cmdline console=hvc0 --console off causes the guest
             //       to do bad things (guessing because its like a write to a bad "fd"?)
             //             --cmdline console=hvc0 --console null does work though
+            let mut cmdline = vec![];
             if config.console {
-                x.arg("--cmdline")
-                    .arg("console=hvc0")
-                    .arg("--console")
+                cmdline.push("console=hvc0");
+                x.arg("--console")
                     .arg(format!("file={:?}", con_file.path()));
             } else {
                 x.arg("--console").arg("off");
             }
+            if let Some(ref extra) = config.cmdline_extra {
+                cmdline.push(extra);
+            }
+            if !cmdline.is_empty() {
+                x.arg("--cmdline").arg(cmdline.join(" "));
+            }
             if config.event_monitor {
                 x.arg("--event-monitor").arg("fd=2");
             }
@@ -241,6 +332,12 @@ impl CloudHypervisor {
                 }
             }
 
+            if let Some(ref socket) = config.vhost_user_image {
+                x.arg("--disk").arg(format!(
+                    "vhost_user=on,socket={},readonly=on",
+                    socket.display()
+                ));
+            }
             if !pmem_paths_modes.is_empty() {
                 x.arg("--pmem");
             }
@@ -255,7 +352,11 @@ impl CloudHypervisor {
                 args.extend(x.get_args().map(|x| x.into()));
             }
             x.fd_mappings(fd_mappings).map_err(|_| Error::FdSetup)?;
-            x.spawn().map_err(|_| Error::SpawnWithArgs(args.clone()))?
+            x.spawn().map_err(|e| Error::SpawnWithArgs {
+                bin: config.bin.clone(),
+                error: e.to_string(),
+                args: args.clone(),
+            })?
         };
 
         let ret = CloudHypervisor {
@@ -301,7 +402,51 @@ impl CloudHypervisor {
     }
 
     pub fn wait_timeout_or_kill(&mut self, duration: Duration) -> io::Result<WaitIdDataOvertime> {
-        self.child.wait_timeout_or_kill(duration)
+        Ok(self.child.wait_timeout_or_kill(duration)?)
+    }
+
+    // needs the event monitor on, which is where ch tells us the guest booted. Returns None if
+    // that didn't happen within boot_timeout (and ch has been killed), otherwise the same as
+    // wait_timeout_or_kill with duration counted from when we started waiting. Exiting before
+    // booting counts as booted here so that the caller sees the exit status
+    pub fn wait_booted_timeout_or_kill(
+        &mut self,
+        boot_timeout: Duration,
+        duration: Duration,
+    ) -> io::Result<Option<WaitIdDataOvertime>> {
+        let start = Instant::now();
+        let boot_deadline = start + boot_timeout.min(duration);
+        let mut pidfd = PidFd::new(&self.child)?;
+        let mut waiter = PidFdWaiter::new(&mut pidfd)?;
+        loop {
+            if self.booted()? {
+                break;
+            }
+            let now = Instant::now();
+            if now >= boot_deadline {
+                waiter.kill(libc::SIGKILL)?;
+                waitid_pidfd_exited_hang(&pidfd)?;
+                return Ok(None);
+            }
+            match waiter.wait_timeout(BOOT_POLL_INTERVAL.min(boot_deadline - now))? {
+                WaitIdData::NotExited => {}
+                WaitIdData::Exited { siginfo, rusage } => {
+                    return Ok(Some(WaitIdDataOvertime::Exited { siginfo, rusage }));
+                }
+                WaitIdData::Cancelled => return Ok(Some(WaitIdDataOvertime::Cancelled)),
+            }
+        }
+        waiter
+            .wait_timeout_or_kill(duration.saturating_sub(start.elapsed()))
+            .map(Some)
+    }
+
+    // the event monitor writes a {"source": "vm", "event": "booted", ...} to err_file
+    fn booted(&self) -> io::Result<bool> {
+        let contents = std::fs::read(self.err_file.path())?;
+        Ok(contents
+            .windows(BOOTED_EVENT.len())
+            .any(|x| x == BOOTED_EVENT))
     }
 
     pub fn console_file(&self) -> Option<&NamedTempFile> {
@@ -342,3 +487,123 @@ impl CloudHypervisor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ch_log_level() {
+        for x in ChLogLevel::VALID {
+            assert!(ChLogLevel::try_from(x).is_ok(), "{x}");
+        }
+        let err = ChLogLevel::try_from("inf").err().unwrap();
+        assert_eq!(
+            err,
+            ChLogLevelError {
+                input: "inf".into()
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "invalid ch log level \"inf\", expected one of warn, info, debug, trace"
+        );
+    }
+
+    #[test]
+    fn test_named_kernel() {
+        let mut kernels = Kernels::new("/kernels/vmlinux".into());
+        kernels.insert("nfs".into(), "/kernels/vmlinux-nfs".into());
+
+        assert_eq!(kernels.get(None).unwrap(), "/kernels/vmlinux");
+        assert_eq!(kernels.get(Some("nfs")).unwrap(), "/kernels/vmlinux-nfs");
+        assert!(kernels.get(Some("nope")).is_none());
+        assert_eq!(kernels.paths().count(), 2);
+
+        // true stands in for ch so we can check what it would have been launched with
+        let config = CloudHypervisorConfig {
+            bin: "true".into(),
+            kernel: kernels.get(Some("nfs")).unwrap().clone(),
+            initramfs: "/initramfs".into(),
+            console: false,
+            log_level: None,
+            keep_args: true,
+            event_monitor: false,
+            vhost_user_image: None,
+            cmdline_extra: None,
+        };
+        let mut ch = CloudHypervisor::start(config, vec![]).unwrap();
+        let args = ch.args().to_vec();
+        let i = args.iter().position(|x| x == "--kernel").unwrap();
+        assert_eq!(args[i + 1], "/kernels/vmlinux-nfs");
+        ch.wait_timeout_or_kill(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn test_vhost_user_image() {
+        let config = CloudHypervisorConfig {
+            bin: "true".into(),
+            kernel: "/vmlinux".into(),
+            initramfs: "/initramfs".into(),
+            console: false,
+            log_level: None,
+            keep_args: true,
+            event_monitor: false,
+            vhost_user_image: Some("/tmp/pevub.sock".into()),
+            cmdline_extra: None,
+        };
+        let pmems = vec![(
+            PathBufOrOwnedFd::PathBuf("/io-file".into()),
+            CloudHypervisorPmemMode::ReadWrite,
+        )];
+        let mut ch = CloudHypervisor::start(config, pmems).unwrap();
+        let args = ch.args().to_vec();
+
+        let i = args.iter().position(|x| x == "--disk").unwrap();
+        assert_eq!(
+            args[i + 1],
+            "vhost_user=on,socket=/tmp/pevub.sock,readonly=on"
+        );
+        let i = args.iter().position(|x| x == "--memory").unwrap();
+        assert_eq!(args[i + 1], "size=1024M,shared=on");
+        // only the io file is a pmem
+        let i = args.iter().position(|x| x == "--pmem").unwrap();
+        assert_eq!(args.len(), i + 2);
+        ch.wait_timeout_or_kill(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn test_cmdline_extra() {
+        let config = |console, cmdline_extra: Option<&str>| CloudHypervisorConfig {
+            bin: "true".into(),
+            kernel: "/vmlinux".into(),
+            initramfs: "/initramfs".into(),
+            console: console,
+            log_level: None,
+            keep_args: true,
+            event_monitor: false,
+            vhost_user_image: None,
+            cmdline_extra: cmdline_extra.map(Into::into),
+        };
+        let cmdline = |config| {
+            let mut ch = CloudHypervisor::start(config, vec![]).unwrap();
+            let args = ch.args().to_vec();
+            ch.wait_timeout_or_kill(Duration::from_secs(1)).unwrap();
+            assert!(args.iter().filter(|x| *x == "--cmdline").count() <= 1);
+            args.iter()
+                .position(|x| x == "--cmdline")
+                .map(|i| args[i + 1].clone())
+        };
+
+        assert_eq!(cmdline(config(false, None)), None);
+        assert_eq!(cmdline(config(true, None)).unwrap(), "console=hvc0");
+        assert_eq!(
+            cmdline(config(false, Some("loglevel=7 earlyprintk"))).unwrap(),
+            "loglevel=7 earlyprintk"
+        );
+        assert_eq!(
+            cmdline(config(true, Some("loglevel=7 earlyprintk"))).unwrap(),
+            "console=hvc0 loglevel=7 earlyprintk"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::Write;
+use std::path::Path;
+
+use rustix::fs::FileType;
+
+use peerofs::disk::{Erofs, Error, Inode};
+
+// one line per inode under start: mode uid/gid size path, and the target for symlinks
+pub fn write_tree<W: Write>(erofs: &Erofs, start: Inode, out: &mut W) -> Result<(), Error> {
+    for item in erofs.walk_from(start) {
+        let item = item?;
+        let inode = &item.inode;
+        write!(
+            out,
+            "{:06o} {:>5}/{:<5} {:>10} {}",
+            inode.mode(),
+            inode.uid(),
+            inode.gid(),
+            inode.data_size(),
+            Path::new("/").join(&item.path).display(),
+        )
+        .map_err(|_| Error::Write)?;
+        if inode.file_type() == FileType::Symlink {
+            let target = erofs.get_symlink(inode)?;
+            write!(out, " -> {}", target.escape_ascii()).map_err(|_| Error::Write)?;
+        }
+        writeln!(out).map_err(|_| Error::Write)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use peerofs::build::{Builder, BuilderConfig, Meta};
+
+    #[test]
+    fn test_write_tree() {
+        let mut b = Builder::new(Cursor::new(vec![]), BuilderConfig::default()).unwrap();
+        let meta = || Meta {
+            uid: 1000,
+            gid: 100,
+            mode: 0o644.into(),
+            ..Meta::default()
+        };
+        b.add_file("/etc/hostname", meta(), 3, &mut &b"pe\n"[..])
+            .unwrap();
+        b.add_file("/bin/busybox", Meta::default(), 4, &mut &b"\x7fELF"[..])
+            .unwrap();
+        b.add_symlink("/bin/sh", "busybox", Meta::default())
+            .unwrap();
+        let (_, buf) = b.into_inner().unwrap();
+        let buf = buf.into_inner();
+        let erofs = Erofs::new(&buf).unwrap();
+
+        let mut out = vec![];
+        write_tree(&erofs, erofs.get_root_inode().unwrap(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+040755     0/0             57 /
+040755     0/0             60 /bin
+100755     0/0              4 /bin/busybox
+120755     0/0              7 /bin/sh -> busybox
+040755     0/0             47 /etc
+100644  1000/100            3 /etc/hostname
+"
+        );
+
+        // paths are relative to where we start
+        let mut out = vec![];
+        let bin = erofs.lookup("bin").unwrap().unwrap();
+        write_tree(&erofs, bin, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let paths: Vec<_> = out
+            .lines()
+            .map(|x| x.split_whitespace().nth(3).unwrap())
+            .collect();
+        assert_eq!(paths, ["/", "/busybox", "/sh"]);
+    }
+}
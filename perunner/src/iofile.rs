@@ -4,7 +4,9 @@ use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
 
 use rustix::fd::AsFd;
-use rustix::fs::{fcntl_add_seals, fstat, ftruncate, memfd_create, MemfdFlags, SealFlags};
+use rustix::fs::{
+    copy_file_range, fcntl_add_seals, fstat, ftruncate, memfd_create, MemfdFlags, SealFlags,
+};
 
 const PMEM_ALIGN_SIZE: u64 = 0x20_0000; // 2 MB
 
@@ -12,6 +14,8 @@ pub struct IoFile {
     file: File,
 }
 
+// backed by a sealed memfd rather than a file in TMPDIR, so building one never touches disk. ch
+// gets it by fd (or /proc/self/fd/N if it ever needs a path)
 pub struct IoFileBuilder {
     file: File,
 }
@@ -25,6 +29,16 @@ impl IoFileBuilder {
         Ok(Self { file: fd.into() })
     }
 
+    // grows the file to at least len so there's room for the guest's output, without moving the
+    // current position. finish still rounds it up after
+    pub fn set_min_len(&mut self, len: u64) -> rustix::io::Result<()> {
+        let cur: u64 = fstat(&self.file)?.st_size.try_into().unwrap_or(0);
+        if cur < len {
+            ftruncate(&self.file, len)?;
+        }
+        Ok(())
+    }
+
     pub fn finish(mut self) -> rustix::io::Result<IoFile> {
         let _ = round_up_file_to_pmem_size(&mut self.file)?;
         fcntl_add_seals(&self.file, SealFlags::SHRINK | SealFlags::GROW)?;
@@ -64,6 +78,29 @@ impl IoFile {
     pub fn into_inner(self) -> File {
         self.file
     }
+
+    // a fresh writable copy, so the same input can go to several runs without packing it again.
+    // The copy stays in the kernel and doesn't care where our file position is
+    pub fn duplicate(&self) -> rustix::io::Result<IoFile> {
+        let len: u64 = fstat(&self.file)?.st_size.try_into().unwrap_or(0);
+        let builder = IoFileBuilder::new()?;
+        let mut off_in = 0;
+        let mut off_out = 0;
+        while off_in < len {
+            let remaining = (len - off_in).try_into().unwrap_or(usize::MAX);
+            let n = copy_file_range(
+                &self.file,
+                Some(&mut off_in),
+                &builder.file,
+                Some(&mut off_out),
+                remaining,
+            )?;
+            if n == 0 {
+                break;
+            }
+        }
+        builder.finish()
+    }
 }
 
 impl Read for IoFile {
@@ -116,6 +153,54 @@ pub fn round_up_file_to_pmem_size<F: AsFd>(f: F) -> rustix::io::Result<u64> {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    use rustix::fs::fcntl_get_seals;
+
+    #[test]
+    fn test_iofile_framing() {
+        let config = peinit::Config {
+            response_format: peinit::ResponseFormat::PeArchiveV1,
+            ..peinit::test_config()
+        };
+        let archive = b"pretend this is an archive";
+
+        let io_file = {
+            let mut builder = IoFileBuilder::new().unwrap();
+            peinit::write_io_file_config(&mut builder, &config, archive.len() as u32).unwrap();
+            builder.write_all(archive).unwrap();
+            builder.finish().unwrap()
+        };
+        let seals = fcntl_get_seals(&io_file).unwrap();
+        assert!(seals.contains(SealFlags::SHRINK | SealFlags::GROW | SealFlags::SEAL));
+
+        // reopening by path gets the same contents, this is how a path-only consumer would see it
+        let mut file = File::open(format!("/proc/self/fd/{}", io_file.as_raw_fd())).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), PMEM_ALIGN_SIZE);
+        let (archive_size, got) = peinit::read_io_file_config(&mut file).unwrap();
+        assert_eq!(archive_size as usize, archive.len());
+        assert_eq!(got.manifest_digest, "sha256:abcd");
+        let mut buf = vec![0; archive.len()];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, archive);
+    }
+
+    #[test]
+    fn test_iofile_min_len() {
+        let mut builder = IoFileBuilder::new().unwrap();
+        builder.write_all(b"hello").unwrap();
+        builder.set_min_len(PMEM_ALIGN_SIZE + 1).unwrap();
+        builder.write_all(b" world").unwrap();
+        // never shrinks
+        builder.set_min_len(1).unwrap();
+        let mut io_file = builder.finish().unwrap().into_inner();
+        assert_eq!(io_file.metadata().unwrap().len(), 2 * PMEM_ALIGN_SIZE);
+
+        io_file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0u8; 12];
+        io_file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello world\0");
+    }
+
     #[test]
     fn test_iofile() {
         let mut io_file = {
@@ -143,6 +228,41 @@ mod tests {
         assert!(io_file.set_len(1024).is_err());
     }
 
+    #[test]
+    fn test_iofile_duplicate() {
+        let mut original = {
+            let mut builder = IoFileBuilder::new().unwrap();
+            builder.write_all(b"hello world").unwrap();
+            builder.finish().unwrap()
+        };
+        // shouldn't matter where the original is at
+        original.seek(SeekFrom::Start(5)).unwrap();
+
+        let read_all = |f: &mut IoFile| {
+            let mut buf = vec![];
+            f.seek(SeekFrom::Start(0)).unwrap();
+            f.read_to_end(&mut buf).unwrap();
+            buf
+        };
+        let mut a = original.duplicate().unwrap();
+        let mut b = original.duplicate().unwrap();
+        let contents = read_all(&mut original);
+        assert_eq!(contents.len() as u64, PMEM_ALIGN_SIZE);
+        assert_eq!(&contents[..11], b"hello world");
+        assert_eq!(read_all(&mut a), contents);
+        assert_eq!(read_all(&mut b), contents);
+
+        let seals = fcntl_get_seals(&a).unwrap();
+        assert!(seals.contains(SealFlags::SHRINK | SealFlags::GROW | SealFlags::SEAL));
+
+        // a run writing its response into one copy doesn't touch the others
+        let mut a = a.into_inner();
+        a.seek(SeekFrom::Start(0)).unwrap();
+        a.write_all(b"HELLO").unwrap();
+        assert_eq!(read_all(&mut b), contents);
+        assert_eq!(read_all(&mut original), contents);
+    }
+
     #[test]
     fn test_round_up_to() {
         assert_eq!(PMEM_ALIGN_SIZE, round_up_to::<PMEM_ALIGN_SIZE>(0));
@@ -1,4 +1,5 @@
 pub mod cloudhypervisor;
+pub mod inspect;
 pub mod iofile;
 pub mod worker;
 
@@ -9,6 +10,10 @@ use once_cell::sync::Lazy;
 pub const UID: u32 = 1000;
 pub const NIDS: u32 = 65534; // size of uid_gid_map
 
+// the only arch our kernel + initramfs are built for
+// TODO multi arch/os
+pub const TARGET_ARCH: peoci::spec::Arch = peoci::spec::Arch::Amd64;
+
 const SECCOMP_JSON: &[u8] = include_bytes!("../seccomp.json");
 
 // TODO should we just desrialize on each access?
@@ -33,6 +38,13 @@ impl std::fmt::Display for Error {
     }
 }
 
+// cgroup limits put on the container, None leaves it unlimited (well, limited by the vm)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ResourceLimits {
+    pub memory_bytes: Option<i64>,
+    pub pids: Option<i64>,
+}
+
 // NOTE: if oci_spec::image::ImageConfiguration was parsed from a vnd.docker.distribution.manifest.v2.json, I'm
 // getting empty strings for a lot of things that are Option
 // the allocations in this make me a bit unhappy, but maybe its okay
@@ -41,9 +53,9 @@ pub fn create_runtime_spec(
     entrypoint: Option<&[String]>,
     cmd: Option<&[String]>,
     env: Option<&[String]>,
+    limits: &ResourceLimits,
 ) -> Result<oci_runtime::Spec, Error> {
-    // TODO multi arch/os
-    if image_config.architecture != peoci::spec::Arch::Amd64 {
+    if image_config.architecture != TARGET_ARCH {
         return Err(Error::BadArch);
     }
     if image_config.os != peoci::spec::Os::Linux {
@@ -74,6 +86,26 @@ pub fn create_runtime_spec(
 
     linux.set_seccomp(Some(SECCOMP.clone()));
 
+    if *limits != ResourceLimits::default() {
+        let resources = linux.resources_mut().get_or_insert_with(Default::default);
+        if let Some(limit) = limits.memory_bytes {
+            resources.set_memory(Some(
+                oci_runtime::LinuxMemoryBuilder::default()
+                    .limit(limit)
+                    .build()
+                    .unwrap(),
+            ));
+        }
+        if let Some(limit) = limits.pids {
+            resources.set_pids(Some(
+                oci_runtime::LinuxPidsBuilder::default()
+                    .limit(limit)
+                    .build()
+                    .unwrap(),
+            ));
+        }
+    }
+
     // TODO how does oci-spec-rs deserialize the config .Env into .env ?
 
     {
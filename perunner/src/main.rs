@@ -2,22 +2,28 @@ use std::ffi::OsString;
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::fd::{AsFd, OwnedFd};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::time::Duration;
 
 use byteorder::{WriteBytesExt, LE};
 use clap::Parser;
 use memmap2::{Mmap, MmapOptions};
 use oci_spec::image::{Arch, Os};
+use serde::Serialize;
 
-use pearchive::{pack_dir_to_writer, unpack_visitor, UnpackVisitor};
+use pearchive::{
+    pack_dir_to_writer, unpack_visitor, visit_tree, Node, PackMemToVec, Tree, UnpackVisitor,
+};
+use peerofs::disk::Erofs;
 use peimage::index::{PEImageMultiIndex, PEImageMultiIndexKeyType};
-use peinit::ResponseFormat;
+use peinit::{Response, ResponseFormat};
 
 use perunner::cloudhypervisor::{ChLogLevel, CloudHypervisorConfig, PathBufOrOwnedFd};
-use perunner::create_runtime_spec;
+use perunner::inspect::write_tree;
 use perunner::iofile::IoFileBuilder;
 use perunner::worker;
+use perunner::{create_runtime_spec, ResourceLimits, TARGET_ARCH};
+use rustix::thread::CpuSet;
 
 //fn sha2_hex(buf: &[u8]) -> String {
 //    use sha2::{Sha256,Digest};
@@ -48,6 +54,142 @@ fn create_pack_file_from_dir<P: AsRef<Path>, W: Write + AsFd + Seek>(
     }
 }
 
+// for an input archive already in memory, like from --input-tar
+fn create_pack_file_from_archive<W: Write>(
+    archive: &[u8],
+    mut file: W,
+    config: &peinit::Config,
+) -> W {
+    let size: u32 = archive.len().try_into().unwrap();
+    peinit::write_io_file_config(&mut file, config, size).unwrap();
+    file.write_all(archive).unwrap();
+    file
+}
+
+fn tar_file_to_archive(path: &Path) -> Result<Vec<u8>, String> {
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    tar_to_archive(io::BufReader::new(file))
+}
+
+// tar entries can come in any order so we collect them into a Tree first, which then drives the
+// visitor with properly nested dirs and pops. The archive has no symlinks (pack_dir_to_writer
+// skips them), so a symlink or hardlink to a file in the tar gets a copy of its data and any other
+// link is skipped
+fn tar_to_archive<R: Read>(reader: R) -> Result<Vec<u8>, String> {
+    let mut tree = Tree::new();
+    // (path, target) resolved once all the files are in, in order so a link to a link works if
+    // it comes after
+    let mut links = vec![];
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = tar_path_components(&entry.path().map_err(|e| e.to_string())?)?;
+        // ./ for the root
+        let Some((name, dirs)) = path.split_last() else {
+            continue;
+        };
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let mut data = vec![];
+                entry.read_to_end(&mut data).map_err(|e| e.to_string())?;
+                tree_dir(&mut tree, dirs)?.insert(name.clone(), Node::File(data));
+            }
+            tar::EntryType::Directory => {
+                tree_dir(&mut tree, &path)?;
+            }
+            typ @ (tar::EntryType::Symlink | tar::EntryType::Link) => {
+                let target = entry
+                    .link_name()
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("link {} has no target", path.join("/")))?;
+                // symlinks are relative to their dir, hardlinks to the root
+                let base = if typ == tar::EntryType::Symlink {
+                    dirs
+                } else {
+                    &[]
+                };
+                links.push((path.clone(), resolve_link(base, &target)));
+            }
+            _ => {}
+        }
+    }
+    for (path, target) in links {
+        let data = match target.as_deref().and_then(|x| tree_get(&tree, x)) {
+            Some(Node::File(data)) => data.clone(),
+            _ => {
+                eprintln!("skipping link {} that isn't to a file", path.join("/"));
+                continue;
+            }
+        };
+        let (name, dirs) = path.split_last().unwrap();
+        tree_dir(&mut tree, dirs)?.insert(name.clone(), Node::File(data));
+    }
+
+    let mut visitor = PackMemToVec::new();
+    visit_tree(&tree, &mut visitor).map_err(|e| e.to_string())?;
+    visitor.into_vec().map_err(|e| e.to_string())
+}
+
+fn tar_path_components(path: &Path) -> Result<Vec<String>, String> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(
+                name.to_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("{} is not utf8", path.display())),
+            ),
+            Component::CurDir | Component::RootDir => None,
+            Component::ParentDir | Component::Prefix(_) => {
+                Some(Err(format!("{} is outside the tar", path.display())))
+            }
+        })
+        .collect()
+}
+
+// None when the target goes above the root of the tar
+fn resolve_link(dir: &[String], target: &Path) -> Option<Vec<String>> {
+    let mut ret = if target.has_root() {
+        vec![]
+    } else {
+        dir.to_vec()
+    };
+    for c in target.components() {
+        match c {
+            Component::Normal(name) => ret.push(name.to_str()?.to_string()),
+            Component::ParentDir => {
+                ret.pop()?;
+            }
+            _ => {}
+        }
+    }
+    Some(ret)
+}
+
+// creates any missing dirs along the way
+fn tree_dir<'a>(mut tree: &'a mut Tree, dirs: &[String]) -> Result<&'a mut Tree, String> {
+    for name in dirs {
+        match tree
+            .entry(name.clone())
+            .or_insert_with(|| Node::Dir(Tree::new()))
+        {
+            Node::Dir(subtree) => tree = subtree,
+            Node::File(_) => return Err(format!("{name} is both a file and a dir")),
+        }
+    }
+    Ok(tree)
+}
+
+fn tree_get<'a>(mut tree: &'a Tree, path: &[String]) -> Option<&'a Node> {
+    let (name, dirs) = path.split_last()?;
+    for dir in dirs {
+        match tree.get(dir)? {
+            Node::Dir(subtree) => tree = subtree,
+            Node::File(_) => return None,
+        }
+    }
+    tree.get(name)
+}
+
 fn escape_bytes(input: &[u8], output: &mut Vec<u8>) {
     output.clear();
     for b in input {
@@ -89,9 +231,119 @@ impl UnpackVisitor for UnpackVisitorPrinter {
     }
 }
 
-fn dump_archive(mmap: &Mmap, stdout: bool) {
+fn check_image_arch(arch: peoci::spec::Arch) -> Result<(), String> {
+    if arch != TARGET_ARCH {
+        return Err(format!(
+            "image architecture is {arch:?} but expected {TARGET_ARCH:?}"
+        ));
+    }
+    Ok(())
+}
+
+fn dump_archive(data: &[u8], stdout: bool) {
     let mut visitor = UnpackVisitorPrinter { stdout: stdout };
-    unpack_visitor(mmap.as_ref(), &mut visitor).unwrap();
+    unpack_visitor(data, &mut visitor).unwrap();
+}
+
+// collects the archive into a Tree keeping at most max bytes of each file
+struct CapOutputVisitor {
+    max: usize,
+    tree: Tree,
+    truncated: Vec<PathBuf>,
+    error: Option<String>,
+}
+
+impl CapOutputVisitor {
+    fn insert(&mut self, path: &Path, data: Option<&[u8]>) -> Result<(), String> {
+        let path: Vec<String> = path
+            .iter()
+            .map(|x| {
+                x.to_str()
+                    .map(String::from)
+                    .ok_or_else(|| format!("non utf8 name {:?}", x))
+            })
+            .collect::<Result<_, _>>()?;
+        match data {
+            Some(data) => {
+                let (name, dirs) = path.split_last().ok_or("empty file name")?;
+                tree_dir(&mut self.tree, dirs)?.insert(name.clone(), Node::File(data.to_vec()));
+            }
+            None => {
+                tree_dir(&mut self.tree, &path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UnpackVisitor for CapOutputVisitor {
+    fn on_file(&mut self, path: &Path, data: &[u8]) -> bool {
+        let data = if data.len() > self.max {
+            self.truncated.push(path.to_path_buf());
+            &data[..self.max]
+        } else {
+            data
+        };
+        match self.insert(path, Some(data)) {
+            Ok(()) => true,
+            Err(e) => {
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    fn on_dir(&mut self, path: &Path) {
+        if self.error.is_none() {
+            self.error = self.insert(path, None).err();
+        }
+    }
+}
+
+// --max-output: a host side safety net so a program that prints gigabytes doesn't get all of it
+// dumped or unpacked. Rebuilds the archive with every file cut to max bytes and returns the paths
+// that were cut
+fn cap_archive(data: &[u8], max: usize) -> Result<(Vec<u8>, Vec<PathBuf>), String> {
+    let mut visitor = CapOutputVisitor {
+        max: max,
+        tree: Tree::new(),
+        truncated: vec![],
+        error: None,
+    };
+    unpack_visitor(data, &mut visitor).map_err(|e| e.to_string())?;
+    if let Some(e) = visitor.error {
+        return Err(e);
+    }
+    let archive = pearchive::pack_tree(&visitor.tree).map_err(|e| e.to_string())?;
+    Ok((archive, visitor.truncated))
+}
+
+fn unpack_archive_to_dir(data: &[u8], dir: &Path) -> Result<(), pearchive::Error> {
+    std::fs::create_dir_all(dir).map_err(|_| pearchive::Error::Create)?;
+    pearchive::unpack_data_to_dir(data, dir)
+}
+
+// --output-dir has to be empty (or not exist yet) since unpacking refuses to overwrite anything
+fn check_output_dir(dir: &Path) -> Result<(), String> {
+    match std::fs::read_dir(dir) {
+        Ok(mut entries) => match entries.next() {
+            None => Ok(()),
+            Some(_) => Err(format!("{} is not empty", dir.display())),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("{}: {e}", dir.display())),
+    }
+}
+
+// --sysctl KEY=VALUE, the key is checked here so a typo doesn't wait until the guest
+fn parse_sysctl(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {arg:?}"))?;
+    if peinit::sysctl_path(key).is_none() {
+        return Err(format!("{key:?} is not a sysctl"));
+    }
+    Ok((key.to_string(), value.to_string()))
 }
 
 fn dump_file<F: Read>(name: &str, file: &mut F) {
@@ -99,10 +351,14 @@ fn dump_file<F: Read>(name: &str, file: &mut F) {
     let _ = io::copy(file, &mut io::stderr());
 }
 
+// with --parallel each worker's output goes in output_dir/<id>
 fn handle_worker_output(
     output: worker::OutputResult,
     response_format: &ResponseFormat,
     stdout: bool,
+    output_dir: Option<&Path>,
+    parallel: bool,
+    max_output: Option<usize>,
 ) {
     match output {
         Ok(worker::Output {
@@ -110,7 +366,6 @@ fn handle_worker_output(
             ch_logs,
             id,
         }) => {
-            let _ = id;
             if let Some(mut err_file) = ch_logs.err_file {
                 dump_file("ch err", &mut err_file);
             }
@@ -136,12 +391,43 @@ fn handle_worker_output(
                             .map(&file)
                             .unwrap()
                     };
+                    let capped = match max_output {
+                        Some(max) => match cap_archive(&mapping, max) {
+                            Ok((archive, truncated)) => {
+                                for path in truncated {
+                                    eprintln!("=== {:?} truncated to {max} bytes ===", path);
+                                }
+                                Some(archive)
+                            }
+                            Err(e) => {
+                                eprintln!("error capping output: {e}");
+                                return;
+                            }
+                        },
+                        None => None,
+                    };
+                    let data = capped.as_deref().unwrap_or(&mapping);
 
-                    dump_archive(&mapping, stdout);
+                    match output_dir {
+                        Some(dir) => {
+                            let dir = if parallel {
+                                dir.join(id.to_string())
+                            } else {
+                                dir.to_path_buf()
+                            };
+                            match unpack_archive_to_dir(data, &dir) {
+                                Ok(()) => eprintln!("unpacked output to {}", dir.display()),
+                                Err(e) => {
+                                    eprintln!("error unpacking output to {}: {e}", dir.display())
+                                }
+                            }
+                        }
+                        None => dump_archive(data, stdout),
+                    }
                 }
             }
         }
-        Err(e) => {
+        Err(worker::OutputError { id, postmortem: e }) => {
             if let Some(mut err_file) = e.logs.err_file {
                 dump_file("ch err", &mut err_file);
             }
@@ -151,7 +437,7 @@ fn handle_worker_output(
             if let Some(mut con_file) = e.logs.con_file {
                 dump_file("ch con", &mut con_file);
             }
-            eprintln!("oh no something went bad {:?}", e.error);
+            eprintln!("oh no something went bad with {id} {:?}", e.error);
             if let Some(args) = e.args {
                 eprintln!("launched ch with args {:?}", args);
             }
@@ -159,6 +445,125 @@ fn handle_worker_output(
     }
 }
 
+// stdout/stderr in each json line get cut to this many bytes
+const JSON_LINE_MAX_OUTPUT: usize = 1024;
+
+// one of these per worker in --parallel --json mode. response is None when ch itself failed
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<&'a Response>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn truncate_string(s: &mut String, max: usize) {
+    if s.len() > max {
+        let mut i = max;
+        while !s.is_char_boundary(i) {
+            i -= 1;
+        }
+        s.truncate(i);
+    }
+}
+
+fn truncate_response_output(response: &mut Response, max: usize) {
+    match response {
+        Response::Ok { stdout, stderr, .. } | Response::Overtime { stdout, stderr, .. } => {
+            for s in [stdout, stderr].into_iter().flatten() {
+                truncate_string(s, max);
+            }
+        }
+        Response::Panic { .. } | Response::ContainerStartError { .. } => {}
+    }
+}
+
+fn write_json_line<W: Write>(out: &mut W, line: &JsonLine) {
+    serde_json::to_writer(&mut *out, line).unwrap();
+    out.write_all(b"\n").unwrap();
+}
+
+// like handle_worker_output but writes a single json line to out and only logs to stderr
+fn handle_worker_output_json_line<W: Write>(output: worker::OutputResult, out: &mut W) {
+    match output {
+        Ok(worker::Output {
+            io_file,
+            ch_logs,
+            id,
+        }) => {
+            if let Some(mut err_file) = ch_logs.err_file {
+                dump_file("ch err", &mut err_file);
+            }
+            if let Some(mut log_file) = ch_logs.log_file {
+                dump_file("ch log", &mut log_file);
+            }
+            if let Some(mut con_file) = ch_logs.con_file {
+                dump_file("ch con", &mut con_file);
+            }
+
+            let mut file = io_file.into_inner();
+            let (_, mut response) = peinit::read_io_file_response(&mut file).unwrap();
+            truncate_response_output(&mut response, JSON_LINE_MAX_OUTPUT);
+            write_json_line(
+                out,
+                &JsonLine {
+                    id: id,
+                    response: Some(&response),
+                    error: None,
+                },
+            );
+        }
+        Err(worker::OutputError { id, postmortem: e }) => {
+            if let Some(mut err_file) = e.logs.err_file {
+                dump_file("ch err", &mut err_file);
+            }
+            if let Some(mut log_file) = e.logs.log_file {
+                dump_file("ch log", &mut log_file);
+            }
+            if let Some(mut con_file) = e.logs.con_file {
+                dump_file("ch con", &mut con_file);
+            }
+            if let Some(args) = e.args {
+                eprintln!("launched ch with args {:?}", args);
+            }
+            write_json_line(
+                out,
+                &JsonLine {
+                    id: id,
+                    response: None,
+                    error: Some(format!("{:?}", e.error)),
+                },
+            );
+        }
+    }
+}
+
+// --parallel: every input is submitted up front (so there can't be more than the pool queues) and
+// each output is handled as its run finishes, in whatever order that is
+fn run_parallel(
+    cpus: &[CpuSet],
+    inputs: impl IntoIterator<Item = worker::Input>,
+    timeout: Duration,
+    mut handle: impl FnMut(worker::OutputResult),
+) {
+    let mut pool = worker::Pool::new(cpus);
+    let mut submitted = 0;
+    for input in inputs {
+        pool.sender().try_send(input).expect("couldn't submit work");
+        submitted += 1;
+    }
+    for _ in 0..submitted {
+        let output = pool
+            .receiver()
+            .recv_timeout(timeout)
+            .expect("should have gotten a response by now");
+        handle(output);
+    }
+    let pool = pool.close_sender();
+    let _ = pool.shutdown();
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -184,6 +589,13 @@ struct Args {
     #[arg(long, help = "name of dir to use as input dir")]
     input: Option<PathBuf>,
 
+    #[arg(
+        long,
+        conflicts_with = "input",
+        help = "tar file to use as input dir instead of --input"
+    )]
+    input_tar: Option<PathBuf>,
+
     #[arg(long, help = "name of file in input dir to use as stdin")]
     stdin: Option<String>,
 
@@ -201,6 +613,12 @@ struct Args {
     )]
     ch_timeout: u64,
 
+    #[arg(
+        long,
+        help = "timeout (ms) for the guest to boot, fails early instead of waiting out the whole ch timeout. Turns on the ch event-monitor"
+    )]
+    ch_boot_timeout: Option<u64>,
+
     #[arg(long, help = "enable ch console")]
     console: bool,
 
@@ -216,13 +634,63 @@ struct Args {
     #[arg(long, help = "pass --debug to crun")]
     crun_debug: bool,
 
+    #[arg(
+        long,
+        help = "log syscalls the seccomp policy would deny instead of failing them"
+    )]
+    seccomp_log: bool,
+
+    #[arg(
+        long,
+        help = "run crun in the foreground, skips the pidfile but relies on the ch timeout"
+    )]
+    crun_foreground: bool,
+
+    #[arg(
+        long,
+        help = "size in MB of the tmpfs backing writes to the rootfs, default is half of guest memory"
+    )]
+    overlay_size_mb: Option<u32>,
+
+    #[arg(
+        long,
+        help = "mount the rootfs read only without an overlay, falls back to the overlay if the image is missing a mountpoint"
+    )]
+    no_overlay: bool,
+
+    #[arg(
+        long = "sysctl",
+        value_parser = parse_sysctl,
+        help = "KEY=VALUE sysctl to set in the guest before running, can be repeated"
+    )]
+    sysctls: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        help = "write a core file into the output when the program dumps core"
+    )]
+    capture_core: bool,
+
+    #[arg(
+        long,
+        value_delimiter = ' ',
+        help = "space separated command to run in the container's rootfs after it exits, eg \"cp /tmp/stats.json /run/pe/output\" (it sees the same /tmp)"
+    )]
+    post_run_cmd: Option<Vec<String>>,
+
     #[arg(long, help = "just build the spec and exit")]
     spec_only: bool,
 
+    #[arg(long, help = "print the image's file tree and exit")]
+    inspect: bool,
+
     #[arg(long, help = "print some stuff to console about the kernel")]
     kernel_inspect: bool,
 
-    #[arg(long, help = "use json output format")]
+    #[arg(
+        long,
+        help = "use json output format, with --parallel prints one json object per line"
+    )]
     json: bool,
 
     #[arg(long, help = "pipe stdout through")]
@@ -231,10 +699,64 @@ struct Args {
     #[arg(long, default_value_t = 0, help = "num workers to run")]
     parallel: u64,
 
+    #[arg(
+        long,
+        help = "with --parallel, pack the input once and give each worker its own copy of it"
+    )]
+    share_input: bool,
+
+    #[arg(
+        long,
+        help = "unpack the output archive into this dir (one subdir per worker with --parallel) instead of printing it"
+    )]
+    output_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "keep at most this many bytes of each file in the output archive"
+    )]
+    max_output: Option<usize>,
+
+    #[arg(
+        long,
+        help = "socket of a vhost-user block backend to boot the image from instead of pmem"
+    )]
+    vhost_user_image: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "extra args for the guest kernel cmdline, eg \"loglevel=7 earlyprintk\""
+    )]
+    cmdline_extra: Option<String>,
+
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     args: Vec<String>,
 }
 
+// rootfs_dir is Some for the old multi-image images where each image is a dir in the erofs
+fn inspect_image(image: &PathBufOrOwnedFd, rootfs_dir: Option<&str>) {
+    let file = match image {
+        PathBufOrOwnedFd::PathBuf(path) => std::fs::File::open(path).unwrap(),
+        PathBufOrOwnedFd::Fd(fd) => fd.try_clone().unwrap().into(),
+    };
+    let mmap = unsafe { Mmap::map(&file).unwrap() };
+    let erofs = Erofs::new(&mmap).expect("image is not erofs");
+    let start = match rootfs_dir {
+        Some(dir) => erofs.lookup(dir).unwrap().expect("rootfs dir not in image"),
+        None => erofs.get_root_inode().unwrap(),
+    };
+    match write_tree(&erofs, start, &mut io::stdout().lock()) {
+        Ok(()) => {}
+        // stdout went away, ie piped into head, there's nobody left to tell
+        Err(peerofs::disk::Error::Write) => {}
+        Err(e) => {
+            eprintln!("error reading image {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     let args = {
         let mut args = Args::parse();
@@ -247,7 +769,23 @@ fn main() {
         eprintln!("--index and --image-service can't both be some");
         std::process::exit(1);
     }
-    let ch_log_level: ChLogLevel = args.ch_log_level.as_str().try_into().unwrap();
+    if let Some(dir) = &args.output_dir {
+        if args.json {
+            eprintln!("--output-dir can't be used with --json");
+            std::process::exit(1);
+        }
+        if let Err(e) = check_output_dir(dir) {
+            eprintln!("--output-dir: {e}");
+            std::process::exit(1);
+        }
+    }
+    let ch_log_level: ChLogLevel = match args.ch_log_level.as_str().try_into() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("--ch-log-level: {e}");
+            std::process::exit(1);
+        }
+    };
     let cwd = std::env::current_dir().unwrap();
 
     // let subscriber = tracing_subscriber::fmt()
@@ -327,7 +865,31 @@ fn main() {
             panic!("--index and --image-service can't both be none");
         }
     };
-    println!("{:?} {:?} {:?}", config, rootfs_dir, image_path_or_fd);
+    // stderr so that stdout is only the output
+    eprintln!("{:?} {:?} {:?}", config, rootfs_dir, image_path_or_fd);
+
+    if args.inspect {
+        inspect_image(&image_path_or_fd, rootfs_dir.as_deref());
+        return;
+    }
+
+    // create_runtime_spec checks this too but we'd rather say which arch we wanted than unwrap
+    // BadArch (and definitely before booting anything)
+    if let Err(e) = check_image_arch(config.architecture) {
+        eprintln!("image {}: {e}", args.image);
+        std::process::exit(1);
+    }
+
+    let input_archive = match &args.input_tar {
+        Some(path) => match tar_file_to_archive(path) {
+            Ok(archive) => Some(archive),
+            Err(e) => {
+                eprintln!("input tar {}: {e}", path.display());
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
     let response_format = match args.json {
         true => ResponseFormat::JsonV1,
@@ -336,9 +898,17 @@ fn main() {
 
     let timeout = Duration::from_millis(args.timeout);
     let ch_timeout = timeout + Duration::from_millis(args.ch_timeout);
+    let boot_timeout = args.ch_boot_timeout.map(Duration::from_millis);
 
     let env = None;
-    let runtime_spec = create_runtime_spec(&config, Some(&[]), Some(&args.args), env).unwrap();
+    let runtime_spec = create_runtime_spec(
+        &config,
+        Some(&[]),
+        Some(&args.args),
+        env,
+        &ResourceLimits::default(),
+    )
+    .unwrap();
 
     if args.spec_only {
         println!("{}", serde_json::to_string_pretty(&runtime_spec).unwrap());
@@ -353,6 +923,8 @@ fn main() {
         console: args.console,
         keep_args: true,
         event_monitor: args.event_monitor,
+        vhost_user_image: args.vhost_user_image,
+        cmdline_extra: args.cmdline_extra,
     };
 
     let pe_config = peinit::Config {
@@ -366,56 +938,413 @@ fn main() {
         response_format: response_format,
         kernel_inspect: args.kernel_inspect,
         manifest_digest,
+        binaries: peinit::Binaries::default(),
+        seccomp_log: args.seccomp_log,
+        detach: !args.crun_foreground,
+        overlay_size_mb: args.overlay_size_mb,
+        overlay: !args.no_overlay,
+        sysctls: args.sysctls,
+        capture_core: args.capture_core,
+        post_run_cmd: args.post_run_cmd,
+    };
+    let ch_timeout = ch_timeout + pe_config.post_run_timeout();
+
+    let build_io_file = || {
+        let mut builder = if let Some(archive) = &input_archive {
+            create_pack_file_from_archive(archive, IoFileBuilder::new().unwrap(), &pe_config)
+        } else {
+            create_pack_file_from_dir(&args.input, IoFileBuilder::new().unwrap(), &pe_config)
+        };
+        // eg room for a whole core with --capture-core
+        builder.set_min_len(pe_config.io_file_min_len()).unwrap();
+        builder.finish().unwrap()
     };
 
     if args.parallel > 0 {
         let num_workers = args.parallel as usize;
-        let cpus = worker::cpuset(2, num_workers, 2).expect("couldn't make cpuset");
-        let mut pool = worker::Pool::new(&cpus);
-        for id in 0..args.parallel {
-            let io_file = {
-                let builder = create_pack_file_from_dir(
-                    &args.input,
-                    IoFileBuilder::new().unwrap(),
-                    &pe_config,
-                );
-                builder.finish().unwrap()
+        let cpus = match worker::cpuset(2, num_workers, 2) {
+            Ok(cpus) => cpus,
+            Err(e) => {
+                eprintln!("--parallel {}: {e}", args.parallel);
+                std::process::exit(1);
+            }
+        };
+        for (id, c) in cpus.iter().enumerate() {
+            eprintln!("worker {id} cpus {:?}", worker::cpuset_cpus(c));
+        }
+        // never given to a worker itself, only copied
+        let shared_io_file = args.share_input.then(build_io_file);
+        let inputs = (0..args.parallel).map(|id| {
+            let io_file = match &shared_io_file {
+                Some(io_file) => io_file.duplicate().unwrap(),
+                None => build_io_file(),
             };
-            let worker_input = worker::Input {
+            worker::Input {
                 id: id,
                 ch_config: ch_config.clone(),
                 ch_timeout: ch_timeout,
+                boot_timeout: boot_timeout,
                 io_file: io_file,
                 image: image_path_or_fd.try_clone().unwrap(),
-            };
-            pool.sender()
-                .try_send(worker_input)
-                .expect("couldn't submit work");
-        }
-        for id in 0..args.parallel {
-            println!("hi trying to get work for {id}");
-            let output = pool
-                .receiver()
-                .recv_timeout(ch_timeout)
-                .expect("should have gotten a response by now");
-            handle_worker_output(output, &response_format, args.stdout);
-        }
-        let pool = pool.close_sender();
-        let _ = pool.shutdown();
+            }
+        });
+        let mut stdout = io::stdout().lock();
+        run_parallel(&cpus, inputs, ch_timeout, |output| {
+            if args.json {
+                handle_worker_output_json_line(output, &mut stdout);
+            } else {
+                handle_worker_output(
+                    output,
+                    &response_format,
+                    args.stdout,
+                    args.output_dir.as_deref(),
+                    true,
+                    args.max_output,
+                );
+            }
+        });
     } else {
-        let io_file = {
-            let builder =
-                create_pack_file_from_dir(&args.input, IoFileBuilder::new().unwrap(), &pe_config);
-            builder.finish().unwrap()
-        };
+        let io_file = build_io_file();
         //std::fs::copy(io_file.path(), "/tmp/perunner-io-file").unwrap();
         let worker_input = worker::Input {
             id: 0,
             ch_config: ch_config,
             ch_timeout: ch_timeout,
+            boot_timeout: boot_timeout,
             io_file: io_file,
             image: image_path_or_fd,
         };
-        handle_worker_output(worker::run(worker_input), &response_format, args.stdout);
+        handle_worker_output(
+            worker::run(worker_input),
+            &response_format,
+            args.stdout,
+            args.output_dir.as_deref(),
+            false,
+            args.max_output,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_json_args() {
+        let args = Args::try_parse_from(["perunner", "--parallel", "2", "--json"]).unwrap();
+        assert_eq!(args.parallel, 2);
+        assert!(args.json);
+        assert!(!args.share_input);
+
+        let args = Args::try_parse_from(["perunner", "--parallel", "2", "--share-input"]).unwrap();
+        assert!(args.share_input);
+    }
+
+    #[test]
+    fn test_output_dir() {
+        let args = Args::try_parse_from(["perunner", "--output-dir", "out"]).unwrap();
+        assert_eq!(args.output_dir, Some(PathBuf::from("out")));
+
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        assert!(check_output_dir(&out).is_ok());
+
+        // what the guest packs up when the program wrote output/result.txt
+        let archive = pearchive::pack_tree(&pearchive::Tree::from([(
+            "result.txt".to_string(),
+            pearchive::Node::File(b"hello".to_vec()),
+        )]))
+        .unwrap();
+        unpack_archive_to_dir(&archive, &out).unwrap();
+        assert_eq!(std::fs::read(out.join("result.txt")).unwrap(), b"hello");
+
+        assert!(check_output_dir(&out).unwrap_err().contains("is not empty"));
+    }
+
+    #[test]
+    fn test_max_output() {
+        let args = Args::try_parse_from(["perunner", "--max-output", "100"]).unwrap();
+        assert_eq!(args.max_output, Some(100));
+
+        // a program that printed more than the cap to stdout
+        let archive = pearchive::pack_tree(&pearchive::Tree::from([
+            (
+                "stdout".to_string(),
+                pearchive::Node::File(vec![b'x'; 5000]),
+            ),
+            (
+                "stderr".to_string(),
+                pearchive::Node::File(b"short".to_vec()),
+            ),
+            (
+                "output".to_string(),
+                pearchive::Node::Dir(pearchive::Tree::from([
+                    ("big".to_string(), pearchive::Node::File(vec![b'y'; 101])),
+                    ("empty".to_string(), pearchive::Node::Dir(Tree::new())),
+                ])),
+            ),
+        ]))
+        .unwrap();
+        let (capped, truncated) = cap_archive(&archive, 100).unwrap();
+        assert_eq!(
+            truncated,
+            vec![PathBuf::from("output/big"), PathBuf::from("stdout")]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        unpack_archive_to_dir(&capped, dir.path()).unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("stdout")).unwrap(),
+            [b'x'; 100]
+        );
+        assert_eq!(std::fs::read(dir.path().join("stderr")).unwrap(), b"short");
+        assert_eq!(
+            std::fs::read(dir.path().join("output/big")).unwrap(),
+            [b'y'; 100]
+        );
+        assert!(dir.path().join("output/empty").is_dir());
+
+        // under the cap is unchanged
+        let (same, truncated) = cap_archive(&archive, 5000).unwrap();
+        assert_eq!(same, archive);
+        assert!(truncated.is_empty());
+    }
+
+    #[test]
+    fn test_input_tar() {
+        let args = Args::try_parse_from(["perunner", "--input-tar", "in.tar"]).unwrap();
+        assert_eq!(args.input_tar, Some(PathBuf::from("in.tar")));
+        assert!(
+            Args::try_parse_from(["perunner", "--input", "in", "--input-tar", "in.tar"]).is_err()
+        );
+
+        let mut builder = tar::Builder::new(vec![]);
+        let mut add_file = |path: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        // file before its dir entry and a ./ prefix
+        add_file("a/b/c.txt", b"c");
+        add_file("./top.txt", b"top");
+        let mut add_entry = |path: &str, typ: tar::EntryType, target: Option<&str>| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(typ);
+            header.set_size(0);
+            header.set_mode(0o755);
+            match target {
+                Some(target) => builder.append_link(&mut header, path, target).unwrap(),
+                None => builder.append_data(&mut header, path, &[][..]).unwrap(),
+            }
+        };
+        add_entry("a/", tar::EntryType::Directory, None);
+        add_entry("empty/", tar::EntryType::Directory, None);
+        add_entry("a/rel", tar::EntryType::Symlink, Some("b/c.txt"));
+        add_entry("a/b/abs", tar::EntryType::Symlink, Some("/top.txt"));
+        add_entry("a/b/up", tar::EntryType::Symlink, Some("../../top.txt"));
+        add_entry("dangling", tar::EntryType::Symlink, Some("nope"));
+        add_entry("escape", tar::EntryType::Symlink, Some("../../etc/passwd"));
+        add_entry("hard", tar::EntryType::Link, Some("a/b/c.txt"));
+        let tar = builder.into_inner().unwrap();
+
+        let archive = tar_to_archive(&tar[..]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        unpack_archive_to_dir(&archive, &out).unwrap();
+
+        let read = |path: &str| std::fs::read(out.join(path)).unwrap();
+        assert_eq!(read("a/b/c.txt"), b"c");
+        assert_eq!(read("top.txt"), b"top");
+        assert_eq!(read("a/rel"), b"c");
+        assert_eq!(read("a/b/abs"), b"top");
+        assert_eq!(read("a/b/up"), b"top");
+        assert_eq!(read("hard"), b"c");
+        assert!(out.join("empty").is_dir());
+        assert!(!out.join("dangling").exists());
+        assert!(!out.join("escape").exists());
+
+        // a file that is also used as a dir
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        builder
+            .append_data(&mut header.clone(), "x", &b"x"[..])
+            .unwrap();
+        builder.append_data(&mut header, "x/y", &b"y"[..]).unwrap();
+        let tar = builder.into_inner().unwrap();
+        assert!(tar_to_archive(&tar[..])
+            .unwrap_err()
+            .contains("both a file and a dir"));
+    }
+
+    #[test]
+    fn test_sysctl_args() {
+        let args = Args::try_parse_from([
+            "perunner",
+            "--sysctl",
+            "vm.overcommit_memory=1",
+            "--sysctl",
+            "net/ipv4/ip_local_port_range=1024 2048",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.sysctls,
+            [
+                ("vm.overcommit_memory".to_string(), "1".to_string()),
+                (
+                    "net/ipv4/ip_local_port_range".to_string(),
+                    "1024 2048".to_string()
+                ),
+            ]
+        );
+        assert!(Args::try_parse_from(["perunner", "--sysctl", "vm.overcommit_memory"]).is_err());
+        assert!(Args::try_parse_from(["perunner", "--sysctl", "../../etc/passwd=x"]).is_err());
+    }
+
+    #[test]
+    fn test_post_run_cmd_args() {
+        let args = Args::try_parse_from(["perunner", "docker.io/library/busybox:1.37"]).unwrap();
+        assert_eq!(args.post_run_cmd, None);
+
+        let args = Args::try_parse_from([
+            "perunner",
+            "--post-run-cmd",
+            "cp /tmp/stats.json /run/pe/output",
+            "docker.io/library/busybox:1.37",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.post_run_cmd.unwrap(),
+            ["cp", "/tmp/stats.json", "/run/pe/output"]
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut out = vec![];
+        let response = Response::Panic {
+            message: "oh no".into(),
+        };
+        write_json_line(
+            &mut out,
+            &JsonLine {
+                id: 0,
+                response: Some(&response),
+                error: None,
+            },
+        );
+        write_json_line(
+            &mut out,
+            &JsonLine {
+                id: 1,
+                response: None,
+                error: Some("Overtime".into()),
+            },
+        );
+
+        let lines: Vec<serde_json::Value> = out
+            .split(|x| *x == b'\n')
+            .filter(|x| !x.is_empty())
+            .map(|x| serde_json::from_slice(x).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["id"], 0);
+        assert_eq!(lines[0]["response"]["kind"], "Panic");
+        assert!(lines[0].get("error").is_none());
+        assert_eq!(lines[1]["id"], 1);
+        assert_eq!(lines[1]["error"], "Overtime");
+    }
+
+    #[test]
+    fn test_parallel_json() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let args = Args::try_parse_from(["perunner", "--parallel", "2", "--json"]).unwrap();
+        assert!(args.json);
+        // stands in for ch, each io file already has its response so there is nothing to do. the
+        // last one fails so there is an error line too
+        let dir = tempfile::tempdir().unwrap();
+        let fake_ch = |name: &str, script: &str| {
+            let bin = dir.path().join(name);
+            std::fs::write(&bin, format!("#!/bin/sh\n{script}\n")).unwrap();
+            std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+            bin
+        };
+        let ok_bin = fake_ch("ch", "");
+        let bad_bin = fake_ch("ch-bad", "exit 1");
+
+        let cpus = vec![rustix::thread::sched_getaffinity(None).unwrap(); args.parallel as usize];
+        let inputs = (0..args.parallel).map(|id| {
+            let mut builder = IoFileBuilder::new().unwrap();
+            let response = Response::Panic {
+                message: format!("run {id}"),
+            };
+            peinit::write_io_file_response(&mut builder, &response).unwrap();
+            let bin = if id + 1 == args.parallel {
+                &bad_bin
+            } else {
+                &ok_bin
+            };
+            worker::Input {
+                id: id,
+                ch_config: CloudHypervisorConfig {
+                    bin: bin.clone().into(),
+                    kernel: "kernel".into(),
+                    initramfs: "initramfs".into(),
+                    console: false,
+                    log_level: None,
+                    keep_args: false,
+                    event_monitor: false,
+                    vhost_user_image: None,
+                    cmdline_extra: None,
+                },
+                image: PathBufOrOwnedFd::PathBuf("/dev/null".into()),
+                io_file: builder.finish().unwrap(),
+                ch_timeout: Duration::from_secs(5),
+                boot_timeout: None,
+            }
+        });
+        let mut out = vec![];
+        run_parallel(&cpus, inputs, Duration::from_secs(5), |output| {
+            handle_worker_output_json_line(output, &mut out);
+        });
+
+        let mut lines: Vec<serde_json::Value> = out
+            .split(|x| *x == b'\n')
+            .filter(|x| !x.is_empty())
+            .map(|x| serde_json::from_slice(x).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        lines.sort_by_key(|x| x["id"].as_u64());
+        assert_eq!(lines[0]["id"], 0);
+        assert_eq!(lines[0]["response"]["kind"], "Panic");
+        assert_eq!(lines[0]["response"]["message"], "run 0");
+        assert!(lines[0].get("error").is_none());
+        // which run failed is still known
+        assert_eq!(lines[1]["id"], 1);
+        assert!(lines[1]["response"].is_null());
+        assert_eq!(lines[1]["error"], "BadExit");
+    }
+
+    #[test]
+    fn test_check_image_arch() {
+        assert!(check_image_arch(peoci::spec::Arch::Amd64).is_ok());
+        let e = check_image_arch(peoci::spec::Arch::Arm64).unwrap_err();
+        assert!(e.contains("Arm64"), "{e}");
+        assert!(e.contains("expected Amd64"), "{e}");
+    }
+
+    #[test]
+    fn test_truncate_string() {
+        let mut s = "hello".to_string();
+        truncate_string(&mut s, 10);
+        assert_eq!(s, "hello");
+        truncate_string(&mut s, 2);
+        assert_eq!(s, "he");
+        // don't split a multibyte char
+        let mut s = "aé".to_string();
+        truncate_string(&mut s, 2);
+        assert_eq!(s, "a");
     }
 }
@@ -1,5 +1,6 @@
 use crossbeam::channel;
 use crossbeam::channel::{Receiver, Sender};
+use std::fmt;
 use std::os::fd::AsFd;
 use std::thread;
 use std::thread::{spawn, JoinHandle};
@@ -24,9 +25,13 @@ type JoinHandleT = JoinHandle<()>;
 pub struct Input {
     pub id: u64,
     pub ch_config: CloudHypervisorConfig,
+    // not used if ch_config.vhost_user_image is set
     pub image: PathBufOrOwnedFd,
     pub io_file: IoFile,
     pub ch_timeout: Duration,
+    // fail early if the guest hasn't booted in this long, instead of waiting out all of
+    // ch_timeout. Turns on the event monitor since that is how we know
+    pub boot_timeout: Option<Duration>,
 }
 
 pub struct Output {
@@ -35,7 +40,13 @@ pub struct Output {
     pub ch_logs: CloudHypervisorLogs,
 }
 
-pub type OutputResult = Result<Output, CloudHypervisorPostMortem>;
+// the id is kept so that with several runs in flight you can tell which one failed
+pub struct OutputError {
+    pub id: u64,
+    pub postmortem: CloudHypervisorPostMortem,
+}
+
+pub type OutputResult = Result<Output, OutputError>;
 
 pub struct Pool {
     sender: Sender<Input>,
@@ -120,41 +131,55 @@ fn spawn_worker(
 
 // a bit ugly since we can't easily use ? to munge the errors
 pub fn run(input: Input) -> OutputResult {
-    let pmems = vec![
-        (input.image, CloudHypervisorPmemMode::ReadOnly),
-        (
-            // child process is scoped to this function, we keep input.io_file alive
-            PathBufOrOwnedFd::Fd(input.io_file.as_fd().try_clone_to_owned().unwrap()),
-            CloudHypervisorPmemMode::ReadWrite,
-        ),
-    ];
+    let id = input.id;
+    let err = |postmortem| OutputError {
+        id: id,
+        postmortem: postmortem,
+    };
+    let mut ch_config = input.ch_config;
+    if input.boot_timeout.is_some() {
+        ch_config.event_monitor = true;
+    }
+    let mut pmems = vec![];
+    if ch_config.vhost_user_image.is_none() {
+        pmems.push((input.image, CloudHypervisorPmemMode::ReadOnly));
+    }
+    pmems.push((
+        // child process is scoped to this function, we keep input.io_file alive
+        PathBufOrOwnedFd::Fd(input.io_file.as_fd().try_clone_to_owned().unwrap()),
+        CloudHypervisorPmemMode::ReadWrite,
+    ));
     let mut ch = {
-        match CloudHypervisor::start(input.ch_config, pmems) {
+        match CloudHypervisor::start(ch_config, pmems) {
             Ok(ch) => ch,
             Err(e) => {
-                return Err(e.into());
+                return Err(err(e.into()));
             }
         }
     };
-    match ch
-        .wait_timeout_or_kill(input.ch_timeout)
-        .map_err(|_| cloudhypervisor::Error::Wait)
-    {
-        Ok(WaitIdDataOvertime::NotExited) => {
+    let waited = match input.boot_timeout {
+        Some(boot_timeout) => ch.wait_booted_timeout_or_kill(boot_timeout, input.ch_timeout),
+        None => ch.wait_timeout_or_kill(input.ch_timeout).map(Some),
+    };
+    match waited.map_err(|_| cloudhypervisor::Error::Wait) {
+        Ok(None) => {
+            return Err(err(ch.postmortem(cloudhypervisor::Error::BootTimeout)));
+        }
+        Ok(Some(WaitIdDataOvertime::NotExited | WaitIdDataOvertime::Cancelled)) => {
             panic!("ch not exited");
             // TODO this is real bad
         }
-        Ok(WaitIdDataOvertime::Exited { siginfo, .. }) => {
+        Ok(Some(WaitIdDataOvertime::Exited { siginfo, .. })) => {
             let info: Siginfo = (&siginfo).into();
             if info != Siginfo::Exited(0) {
-                return Err(ch.postmortem(cloudhypervisor::Error::BadExit));
+                return Err(err(ch.postmortem(cloudhypervisor::Error::BadExit)));
             }
         }
-        Ok(WaitIdDataOvertime::ExitedOvertime { .. }) => {
-            return Err(ch.postmortem(cloudhypervisor::Error::Overtime));
+        Ok(Some(WaitIdDataOvertime::ExitedOvertime { .. })) => {
+            return Err(err(ch.postmortem(cloudhypervisor::Error::Overtime)));
         }
         Err(e) => {
-            return Err(ch.postmortem(e));
+            return Err(err(ch.postmortem(e)));
         }
     }
     Ok(Output {
@@ -184,33 +209,80 @@ pub fn run(input: Input) -> OutputResult {
 //    Some(ret)
 //}
 
+#[derive(Debug, PartialEq)]
+pub enum CpusetError {
+    OddOffset(usize),
+    OddCoresPerWorker(usize),
+    Affinity(rustix::io::Errno),
+    // worker needs a cpu that isn't in our affinity mask (offline or excluded)
+    Unavailable { worker: usize, cpu: usize },
+}
+
+impl fmt::Display for CpusetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpusetError::OddOffset(x) => write!(
+                f,
+                "core offset {x} must be even to keep workers on separate physical cores"
+            ),
+            CpusetError::OddCoresPerWorker(x) => write!(
+                f,
+                "cores per worker {x} must be even to keep workers on separate physical cores"
+            ),
+            CpusetError::Affinity(e) => write!(f, "sched_getaffinity failed: {e}"),
+            CpusetError::Unavailable { worker, cpu } => write!(
+                f,
+                "worker {worker} needs cpu {cpu} which is not available; at most {worker} workers fit"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpusetError {}
+
 pub fn cpuset(
     core_offset: usize,
     n_workers: usize,
     n_cores_per_worker: usize,
-) -> Option<Vec<CpuSet>> {
+) -> Result<Vec<CpuSet>, CpusetError> {
+    let all = sched_getaffinity(None).map_err(CpusetError::Affinity)?; // None means current thread
+    cpuset_in(&all, core_offset, n_workers, n_cores_per_worker)
+}
+
+// worker i gets cpus [core_offset + i * n_cores_per_worker, core_offset + (i + 1) * n_cores_per_worker)
+// and every one of those must be set in available
+pub fn cpuset_in(
+    available: &CpuSet,
+    core_offset: usize,
+    n_workers: usize,
+    n_cores_per_worker: usize,
+) -> Result<Vec<CpuSet>, CpusetError> {
     // restrict to even offset and even cores per worker to keep workers
     // on separate physical cores
     if core_offset % 2 == 1 {
-        return None;
+        return Err(CpusetError::OddOffset(core_offset));
     }
     if n_cores_per_worker % 2 == 1 {
-        return None;
+        return Err(CpusetError::OddCoresPerWorker(n_cores_per_worker));
     }
-    let all = sched_getaffinity(None).ok()?; // None means current thread
     let mut ret = Vec::with_capacity(n_workers);
     for i in 0..n_workers {
         let mut c = CpuSet::new();
         for j in 0..n_cores_per_worker {
             let k = core_offset + i * n_cores_per_worker + j;
-            if !all.is_set(k) {
-                return None;
+            if k >= CpuSet::MAX_CPU || !available.is_set(k) {
+                return Err(CpusetError::Unavailable { worker: i, cpu: k });
             }
             c.set(k);
         }
         ret.push(c);
     }
-    Some(ret)
+    Ok(ret)
+}
+
+// list of cpus set in c, for reporting which cpus a worker got
+pub fn cpuset_cpus(c: &CpuSet) -> Vec<usize> {
+    (0..CpuSet::MAX_CPU).filter(|&i| c.is_set(i)).collect()
 }
 
 pub fn cpuset_range(begin: usize, end: Option<usize>) -> Option<CpuSet> {
@@ -239,6 +311,9 @@ pub fn cpuset_range(begin: usize, end: Option<usize>) -> Option<CpuSet> {
 #[cfg(feature = "asynk")]
 pub mod asynk {
     use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
     use tokio::sync::oneshot;
 
     type SenderElement = (Input, oneshot::Sender<OutputResult>);
@@ -248,19 +323,32 @@ pub mod asynk {
         // TODO are these even useful?
         #[allow(dead_code)]
         handles: Vec<JoinHandleT>,
+        // submitted but not yet finished (queued or running), so drain knows when we're idle
+        pending: Arc<AtomicUsize>,
+        closed: AtomicBool,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum SubmitError {
+        Full,
+        // drain has been called
+        Closed,
     }
 
     impl Pool {
         pub fn new(cores: &[CpuSet]) -> Self {
             let (i_s, i_r) = channel::bounded::<SenderElement>(cores.len() * 2);
+            let pending = Arc::new(AtomicUsize::new(0));
             let handles: Vec<_> = cores
                 .iter()
                 .enumerate()
-                .map(|(i, c)| spawn_worker(i, *c, i_r.clone()))
+                .map(|(i, c)| spawn_worker(i, *c, i_r.clone(), pending.clone()))
                 .collect();
             Self {
                 sender: i_s,
                 handles: handles,
+                pending: pending,
+                closed: AtomicBool::new(false),
             }
         }
 
@@ -269,17 +357,52 @@ pub mod asynk {
             self.handles.len()
         }
 
-        pub fn sender(&self) -> &Sender<SenderElement> {
-            &self.sender
+        pub fn submit(
+            &self,
+            input: Input,
+            output: oneshot::Sender<OutputResult>,
+        ) -> Result<(), SubmitError> {
+            // counted before checking closed so that drain either sees this one as pending or we
+            // see closed
+            self.pending.fetch_add(1, Ordering::SeqCst);
+            if self.closed.load(Ordering::SeqCst) {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                return Err(SubmitError::Closed);
+            }
+            self.sender.try_send((input, output)).map_err(|_| {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                SubmitError::Full
+            })
+        }
+
+        // stops taking new inputs and waits for everything already submitted to finish, so that
+        // we don't exit with ch processes still running. Returns false if the timeout was hit
+        pub fn drain(&self, timeout: Duration) -> bool {
+            self.closed.store(true, Ordering::SeqCst);
+            let deadline = Instant::now() + timeout;
+            while self.pending.load(Ordering::SeqCst) > 0 {
+                if Instant::now() >= deadline {
+                    return false;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            true
         }
     }
 
-    fn spawn_worker(id: usize, cpuset: CpuSet, input: Receiver<SenderElement>) -> JoinHandleT {
+    fn spawn_worker(
+        id: usize,
+        cpuset: CpuSet,
+        input: Receiver<SenderElement>,
+        pending: Arc<AtomicUsize>,
+    ) -> JoinHandleT {
         spawn(move || {
             trace!("starting worker {id}");
             sched_setaffinity(None, &cpuset).unwrap();
             for (msg, output) in input.iter() {
-                match output.send(run(msg)) {
+                let res = output.send(run(msg));
+                pending.fetch_sub(1, Ordering::SeqCst);
+                match res {
                     Ok(_) => {}
                     Err(_) => {
                         // output got disconnected somehow
@@ -304,9 +427,191 @@ mod tests {
         let x = xs[0];
         assert!(x.is_set(2) && x.is_set(3));
 
-        assert!(cpuset(1, 1, 2).is_none()); // odd offset
-        assert!(cpuset(0, 1, 1).is_none()); // odd cores per worker
-        assert!(cpuset(2, 16, 2).is_none()); // too many workers (on a 32 core machine)
+        assert_eq!(cpuset(1, 1, 2).unwrap_err(), CpusetError::OddOffset(1));
+        assert_eq!(
+            cpuset(0, 1, 1).unwrap_err(),
+            CpusetError::OddCoresPerWorker(1)
+        );
+        assert!(matches!(
+            cpuset(2, 16, 2), // too many workers (on a 32 core machine)
+            Err(CpusetError::Unavailable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cpuset_in() {
+        // pretend we have 8 cpus online
+        let mut available = CpuSet::new();
+        for i in 0..8 {
+            available.set(i);
+        }
+        let xs = cpuset_in(&available, 2, 3, 2).unwrap();
+        let cpus: Vec<_> = xs.iter().map(cpuset_cpus).collect();
+        assert_eq!(cpus, vec![vec![2, 3], vec![4, 5], vec![6, 7]]);
+
+        assert_eq!(
+            cpuset_in(&available, 2, 4, 2).unwrap_err(),
+            CpusetError::Unavailable { worker: 3, cpu: 8 }
+        );
+        assert_eq!(
+            cpuset_in(&available, 0, 2, 4)
+                .unwrap()
+                .iter()
+                .map(cpuset_cpus)
+                .collect::<Vec<_>>(),
+            vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]
+        );
+
+        // hole in the middle, ie cpu 4 offline
+        let mut available = CpuSet::new();
+        for i in (0..8).filter(|&i| i != 4) {
+            available.set(i);
+        }
+        assert_eq!(
+            cpuset_in(&available, 2, 2, 2).unwrap_err(),
+            CpusetError::Unavailable { worker: 1, cpu: 4 }
+        );
+    }
+
+    #[cfg(feature = "asynk")]
+    #[test]
+    fn test_asynk_drain() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::sync::oneshot;
+
+        // stands in for ch, takes a bit so the second run is still queued when we drain
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("ch");
+        std::fs::write(&bin, "#!/bin/sh\nsleep 0.2\n").unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let input = || Input {
+            id: 0,
+            ch_config: CloudHypervisorConfig {
+                bin: bin.clone().into(),
+                kernel: "kernel".into(),
+                initramfs: "initramfs".into(),
+                console: false,
+                log_level: None,
+                keep_args: false,
+                event_monitor: false,
+                vhost_user_image: None,
+                cmdline_extra: None,
+            },
+            image: PathBufOrOwnedFd::PathBuf("/dev/null".into()),
+            io_file: crate::iofile::IoFileBuilder::new()
+                .unwrap()
+                .finish()
+                .unwrap(),
+            ch_timeout: Duration::from_secs(5),
+            boot_timeout: None,
+        };
+
+        let pool = asynk::Pool::new(&[sched_getaffinity(None).unwrap()]);
+        let receivers: Vec<_> = (0..2)
+            .map(|_| {
+                let (sender, receiver) = oneshot::channel();
+                pool.submit(input(), sender).unwrap();
+                receiver
+            })
+            .collect();
+        assert!(pool.drain(Duration::from_secs(5)));
+        for mut receiver in receivers {
+            assert!(receiver.try_recv().unwrap().is_ok());
+        }
+
+        let (sender, _receiver) = oneshot::channel();
+        assert_eq!(
+            pool.submit(input(), sender).unwrap_err(),
+            asynk::SubmitError::Closed
+        );
+    }
+
+    // stands in for ch with a shell script
+    fn fake_ch(dir: &std::path::Path, name: &str, script: &str) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let bin = dir.join(name);
+        std::fs::write(&bin, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        bin
+    }
+
+    fn boot_input(bin: &std::path::Path, boot_timeout: Duration) -> Input {
+        Input {
+            id: 0,
+            ch_config: CloudHypervisorConfig {
+                bin: bin.into(),
+                kernel: "kernel".into(),
+                initramfs: "initramfs".into(),
+                console: false,
+                log_level: None,
+                keep_args: true,
+                event_monitor: false,
+                vhost_user_image: None,
+                cmdline_extra: None,
+            },
+            image: PathBufOrOwnedFd::PathBuf("/dev/null".into()),
+            io_file: crate::iofile::IoFileBuilder::new()
+                .unwrap()
+                .finish()
+                .unwrap(),
+            ch_timeout: Duration::from_secs(10),
+            boot_timeout: Some(boot_timeout),
+        }
+    }
+
+    #[test]
+    fn test_boot_timeout() {
+        use std::io::Read;
+        use std::time::Instant;
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let start = Instant::now();
+        let missing = dir.path().join("nope/ch");
+        let err = run(boot_input(&missing, Duration::from_millis(100)))
+            .err()
+            .unwrap()
+            .postmortem;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let msg = format!("{:?}", err.error);
+        assert!(msg.contains("SpawnWithArgs"), "{msg}");
+        assert!(msg.contains(&*missing.to_string_lossy()), "{msg}");
+        assert!(msg.contains("No such file"), "{msg}");
+
+        // never boots, we should give up long before ch_timeout
+        let bin = fake_ch(dir.path(), "stuck", "echo stuck >&2; sleep 10");
+        let start = Instant::now();
+        let err = run(boot_input(&bin, Duration::from_millis(100)))
+            .err()
+            .unwrap()
+            .postmortem;
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(matches!(err.error, cloudhypervisor::Error::BootTimeout));
+        let args = err.args.unwrap();
+        assert!(args.iter().any(|x| x == "--event-monitor"));
+        let mut logs = String::new();
+        err.logs
+            .err_file
+            .unwrap()
+            .read_to_string(&mut logs)
+            .unwrap();
+        assert_eq!(logs, "stuck\n");
+
+        // booted then ran longer than the boot timeout
+        let bin = fake_ch(
+            dir.path(),
+            "booted",
+            r#"echo '{"source": "vm", "event": "booted"}' >&2; sleep 0.3"#,
+        );
+        assert!(run(boot_input(&bin, Duration::from_millis(100))).is_ok());
+
+        // exiting before the booted event is the usual exit handling
+        let bin = fake_ch(dir.path(), "exits", "exit 1");
+        let err = run(boot_input(&bin, Duration::from_millis(100)))
+            .err()
+            .unwrap()
+            .postmortem;
+        assert!(matches!(err.error, cloudhypervisor::Error::BadExit));
     }
 
     #[test]
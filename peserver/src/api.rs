@@ -2,6 +2,8 @@ use std::time::Duration;
 
 pub const APPLICATION_JSON: &str = "application/json";
 pub const APPLICATION_X_PE_ARCHIVEV1: &str = "application/x.pe.archivev1";
+// set by the lb to the downstream client's ip, replacing whatever the client sent
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
 
 // max request per second per client
 pub const MAX_REQ_PER_SEC: isize = 2;
@@ -14,6 +16,7 @@ pub const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
 pub const DOWNSTREAM_READ_TIMEOUT: Duration = Duration::from_secs(5);
 pub const DOWNSTREAM_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ContentType {
     ApplicationJson,
     PeArchiveV1, // <u32 json size> <json> <pearchivev1>
@@ -40,6 +43,53 @@ impl From<ContentType> for &str {
     }
 }
 
+// the response is the same format as the request unless the Accept header asks for one we can
+// produce. Media ranges are tried in the order given (q values are ignored) and anything else, like
+// */*, falls back to the request's format
+pub fn response_content_type(request: ContentType, accept: Option<&str>) -> ContentType {
+    accept
+        .into_iter()
+        .flat_map(|x| x.split(','))
+        .map(|x| x.split(';').next().unwrap_or("").trim())
+        .find_map(|x| ContentType::try_from(x).ok())
+        .unwrap_or(request)
+}
+
+pub mod v1 {
+    pub mod image {
+        use peimage::index::PEImageIndexEntry;
+        use serde::Serialize;
+
+        pub const PREFIX: &str = "/api/v1/image/";
+
+        #[derive(Serialize)]
+        pub struct Response<'a> {
+            #[serde(flatten)]
+            pub entry: &'a PEImageIndexEntry,
+            pub upstream_link: Option<String>,
+        }
+
+        impl<'a> From<&'a PEImageIndexEntry> for Response<'a> {
+            fn from(entry: &'a PEImageIndexEntry) -> Self {
+                Self {
+                    upstream_link: entry.id.upstream_link(),
+                    entry: entry,
+                }
+            }
+        }
+
+        // /api/v1/image/<name or digest>
+        // name is like index.docker.io/library/busybox:1.37 and digest sha256:abcd or sha256/abcd
+        pub fn parse_path(s: &str) -> Option<&str> {
+            let key = s.strip_prefix(PREFIX)?;
+            if key.is_empty() || key.len() > 255 {
+                return None;
+            }
+            Some(key)
+        }
+    }
+}
+
 pub mod v2 {
     pub mod runi {
         use super::super::ContentType;
@@ -56,6 +106,8 @@ pub mod v2 {
             pub entrypoint: Option<Vec<String>>, // as per oci image config
             pub cmd: Option<Vec<String>>,        // as per oci image config
             pub env: Option<Vec<String>>,        // as per oci image config
+            pub kernel: Option<String>,          // named kernel, default if None
+            pub timeout_ms: Option<u64>,         // timeout on the process, server default if None
         }
 
         pub type Response = peinit::Response;
@@ -119,3 +171,47 @@ pub mod v2 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_content_type() {
+        use ContentType::*;
+        // request format, accept, response format
+        let cases = [
+            (ApplicationJson, Some(APPLICATION_JSON), ApplicationJson),
+            (
+                ApplicationJson,
+                Some(APPLICATION_X_PE_ARCHIVEV1),
+                PeArchiveV1,
+            ),
+            (PeArchiveV1, Some(APPLICATION_JSON), ApplicationJson),
+            (PeArchiveV1, Some(APPLICATION_X_PE_ARCHIVEV1), PeArchiveV1),
+            // no preference
+            (ApplicationJson, None, ApplicationJson),
+            (PeArchiveV1, None, PeArchiveV1),
+            (PeArchiveV1, Some("*/*"), PeArchiveV1),
+            (PeArchiveV1, Some("text/html"), PeArchiveV1),
+            // first one we can produce wins
+            (
+                PeArchiveV1,
+                Some("text/html, application/json;q=0.9, */*;q=0.8"),
+                ApplicationJson,
+            ),
+            (
+                ApplicationJson,
+                Some("application/x.pe.archivev1,application/json"),
+                PeArchiveV1,
+            ),
+        ];
+        for (request, accept, expected) in cases {
+            assert_eq!(
+                response_content_type(request, accept),
+                expected,
+                "{request:?} {accept:?}"
+            );
+        }
+    }
+}
@@ -326,6 +326,20 @@ impl ProxyHttp for LB {
     //    Ok(())
     //}
 
+    // workers only see our address, so pass along who it is for their --ip-rate
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut LBCtx,
+    ) -> Result<()> {
+        upstream_request.remove_header(api::X_FORWARDED_FOR);
+        if let Some(ip) = session.client_addr().and_then(|x| x.as_inet()) {
+            upstream_request.insert_header(api::X_FORWARDED_FOR, ip.ip().to_string())?;
+        }
+        Ok(())
+    }
+
     // is it okay to send request upstream?
     async fn proxy_upstream_filter(&self, _session: &mut Session, ctx: &mut LBCtx) -> Result<bool> {
         Ok(ctx.is_some())
@@ -347,14 +361,15 @@ impl ProxyHttp for LB {
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(long)]
+    // ex 0.0.0.0:1234
+    #[arg(long, visible_alias = "listen")]
     tcp: Option<String>,
 
     #[arg(long)]
     uds: Option<String>,
 
-    //#[arg(long, default_value="127.0.0.1:6192")]
-    #[arg(long)]
+    // ex 0.0.0.0:6192, tcp only
+    #[arg(long, visible_alias = "metrics-listen")]
     prom: Option<String>,
 
     #[arg(long)]
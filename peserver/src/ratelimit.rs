@@ -0,0 +1,111 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use moka::sync::Cache;
+
+// holds up to burst tokens and refills at rate tokens/sec, each request takes one
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// one token bucket per client ip. the map is bounded by max_clients so a flood of distinct ips
+// can't grow it forever; an evicted client just comes back with a full bucket
+pub struct IpRateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Cache<IpAddr, Arc<Mutex<TokenBucket>>>,
+}
+
+// anyone with a v6 address usually has the whole /64, so treat that as one client
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ipv6) => {
+            let masked = u128::from(ipv6) & !(u64::MAX as u128);
+            IpAddr::V6(Ipv6Addr::from(masked))
+        }
+    }
+}
+
+impl IpRateLimiter {
+    pub fn new(rate: f64, burst: u32, max_clients: u64) -> Self {
+        Self {
+            rate: rate,
+            burst: burst as f64,
+            buckets: Cache::new(max_clients),
+        }
+    }
+
+    // returns true if the request is allowed
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let bucket = self.buckets.get_with(client_key(ip), || {
+            Arc::new(Mutex::new(TokenBucket {
+                tokens: self.burst,
+                last: now,
+            }))
+        });
+        let mut bucket = bucket.lock().unwrap();
+        bucket.take(self.rate, self.burst, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_one_ip_limited() {
+        let limiter = IpRateLimiter::new(2.0, 5, 100);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        let allowed = (0..100).filter(|_| limiter.check_at(a, now)).count();
+        assert_eq!(allowed, 5);
+        assert!(!limiter.check_at(a, now));
+
+        // other clients have their own bucket
+        for _ in 0..5 {
+            assert!(limiter.check_at(b, now));
+        }
+
+        // refills at rate, but never past burst
+        assert!(limiter.check_at(a, now + Duration::from_millis(500)));
+        assert!(!limiter.check_at(a, now + Duration::from_millis(500)));
+        let later = now + Duration::from_secs(60);
+        let allowed = (0..100).filter(|_| limiter.check_at(a, later)).count();
+        assert_eq!(allowed, 5);
+    }
+
+    #[test]
+    fn test_ipv6_prefix() {
+        let limiter = IpRateLimiter::new(1.0, 1, 100);
+        let now = Instant::now();
+        assert!(limiter.check_at("2001:db8::1".parse().unwrap(), now));
+        assert!(!limiter.check_at("2001:db8::2".parse().unwrap(), now));
+        assert!(limiter.check_at("2001:db8:0:1::1".parse().unwrap(), now));
+    }
+}
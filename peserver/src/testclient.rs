@@ -59,6 +59,12 @@ struct Args {
     #[arg(long)]
     env: Vec<String>,
 
+    #[arg(long)]
+    kernel: Option<String>,
+
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+
     #[arg(long)]
     gzip: bool,
 
@@ -112,6 +118,8 @@ async fn main() {
         entrypoint: Some(vec![]),
         stdin: args.stdin,
         env: Some(args.env),
+        kernel: args.kernel,
+        timeout_ms: args.timeout_ms,
     };
 
     let buf = {
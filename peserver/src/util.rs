@@ -4,7 +4,8 @@ use std::net::{IpAddr, Ipv6Addr};
 use base64::prelude::{Engine, BASE64_STANDARD};
 use bytes::{Bytes, BytesMut};
 use env_logger;
-use http::{Response, StatusCode};
+use flate2::{write::GzEncoder, Compression};
+use http::{header, HeaderValue, Response, StatusCode};
 use log::Level;
 use rustix::fd::AsFd;
 use serde::Serialize;
@@ -122,6 +123,58 @@ pub fn response_json_vec(status: StatusCode, body: Vec<u8>) -> Response<Vec<u8>>
         .unwrap()
 }
 
+// not worth the cpu (or the gzip header) below this
+pub const GZIP_MIN_LEN: usize = 1024;
+
+// gzip listed in Accept-Encoding and not with q=0
+pub fn accepts_gzip(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or("");
+            let q_zero = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case("gzip") && !q_zero
+        })
+}
+
+// like response_json_vec but gzipped when the client takes it and the body is big enough to bother
+pub fn response_json_vec_gzip(
+    status: StatusCode,
+    body: Vec<u8>,
+    accept_gzip: bool,
+) -> Response<Vec<u8>> {
+    if !accept_gzip || body.len() < GZIP_MIN_LEN {
+        let mut response = response_json_vec(status, body);
+        if accept_gzip {
+            response
+                .headers_mut()
+                .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+        }
+        return response;
+    }
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::fast());
+    // writing to a vec can't fail
+    encoder.write_all(&body).unwrap();
+    let body = encoder.finish().unwrap();
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, APPLICATION_JSON)
+        .header(http::header::CONTENT_ENCODING, "gzip")
+        .header(http::header::VARY, "Accept-Encoding")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
 pub fn response_pearchivev1(status: StatusCode, body: Vec<u8>) -> Response<Vec<u8>> {
     // TODO presize headermap
     Response::builder()
@@ -132,6 +185,38 @@ pub fn response_pearchivev1(status: StatusCode, body: Vec<u8>) -> Response<Vec<u
         .unwrap()
 }
 
+// answer to a CORS preflight OPTIONS request, the actual request then gets the allow origin header
+// through add_cors_headers
+pub fn response_cors_preflight(origin: &HeaderValue) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS")
+        .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type")
+        .header(header::ACCESS_CONTROL_MAX_AGE, 86400)
+        .header(header::VARY, "Origin")
+        .header(header::CONTENT_LENGTH, 0)
+        .body(vec![])
+        .unwrap()
+}
+
+// HEAD gets exactly what GET would, content-length included, minus the body
+pub fn response_for_head(mut response: Response<Vec<u8>>) -> Response<Vec<u8>> {
+    response.body_mut().clear();
+    response
+}
+
+pub fn add_cors_headers(response: &mut Response<Vec<u8>>, origin: &HeaderValue) {
+    let headers = response.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("x-request-id"),
+    );
+    // append so we don't clobber Vary: Accept-Encoding
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
+
 pub fn etag(data: &[u8]) -> String {
     let hash = Sha256::digest(data);
     let mut ret = String::with_capacity(16);
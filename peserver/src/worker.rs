@@ -1,35 +1,48 @@
 use std::ffi::OsString;
 use std::fs::Permissions;
 use std::io::{Read, Write};
+use std::net::IpAddr;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::v1::common::header_value_content_length;
 use pingora::protocols::http::ServerSession;
 use pingora::server::configuration::{Opt, ServerConf};
-use pingora::server::Server;
+use pingora::server::{RunArgs, Server};
 use pingora::services::listening::Service;
 use pingora_timeout::timeout;
 
 use async_trait::async_trait;
 use clap::Parser;
-use http::{header, Method, Response, StatusCode};
+use http::{header, HeaderValue, Method, Response, StatusCode};
 use log::{error, info, log_enabled, trace};
 use oci_spec::image::{Arch, Os};
 use once_cell::sync::Lazy;
-use prometheus::{register_int_counter, IntCounter};
-use serde::Serialize;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, Histogram, HistogramVec,
+    IntCounter,
+};
+use serde::{Deserialize, Serialize};
 
-use perunner::cloudhypervisor::{ChLogLevel, CloudHypervisorConfig, PathBufOrOwnedFd};
+use perunner::cloudhypervisor::{
+    ChLogLevel, CloudHypervisorConfig, Kernels, PathBufOrOwnedFd, GUEST_MEMORY_MB,
+};
 use perunner::iofile::IoFileBuilder;
-use perunner::{create_runtime_spec, worker};
+use perunner::{create_runtime_spec, worker, ResourceLimits};
 
+use peimage::index::{PEImageMultiIndex, PEImageMultiIndexKeyType};
 use peserver::api;
+use peserver::api::v1 as apiv1;
 use peserver::api::v2 as apiv2;
 use peserver::api::ContentType;
+use peserver::ratelimit::IpRateLimiter;
 use peserver::util::{
-    read_full_server_request_body, response_json, response_json_vec, response_no_body,
+    accepts_gzip, add_cors_headers, read_full_server_request_body, response_cors_preflight,
+    response_for_head, response_json, response_json_vec_gzip, response_no_body,
     response_pearchivev1, response_string, setup_logs,
 };
 
@@ -39,21 +52,47 @@ static REQ_RUN_COUNT: Lazy<IntCounter> =
 static ERR_CH_COUNT: Lazy<IntCounter> =
     Lazy::new(|| register_int_counter!("worker_err_ch", "Worker number of ch errors").unwrap());
 
-// timeout we put on the user's process (after the initial crun process exits)
-const RUN_TIMEOUT: Duration = Duration::from_millis(1000);
-// overhead from kernel boot and crun start
-const CH_TIMEOUT_EXTRA: Duration = Duration::from_millis(300);
+// a run is bounded by its ch timeout (see RunTimeouts) but can also wait in the queue
+static RUN_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "worker_run_seconds",
+        "Worker time from queueing a run to getting the worker's result",
+        vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 3.0, 5.0]
+    )
+    .unwrap()
+});
+
+// labeled by stage: boot, unpack, run, pack
+static GUEST_STAGE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "worker_guest_stage_seconds",
+        "Time spent in each stage of a run as reported by the guest",
+        &["stage"],
+        vec![0.01, 0.025, 0.05, 0.1, 0.15, 0.2, 0.3, 0.5, 0.75, 1.0, 1.5]
+    )
+    .unwrap()
+});
+
+// seconds we tell clients to wait when the worker queue is full. a run with the default
+// --run-timeout-ms + --ch-timeout-extra-ms is over by then so the queue should have drained some.
+// this doesn't follow those args, so with bigger timeouts it's more of a guess
+const QUEUE_FULL_RETRY_AFTER: u64 = 2;
+// a bucket gets at least one token back within a second for any sane --ip-rate
+const RATE_LIMITED_RETRY_AFTER: u64 = 1;
 
 #[derive(Debug, Serialize, Clone)]
 enum Error {
     ReadTimeout,
     Read,
+    PayloadTooLarge,
     BadRequest,
     BadPath,
     BadReference,
     ImageService,
     IoFileCreate,
     QueueFull,
+    ShuttingDown,
+    RateLimited,
     WorkerRecv,
     BadContentType,
     ResponseRead,
@@ -62,25 +101,116 @@ enum Error {
     OciSpec,
     ArchMismatch,
     OsMismatch,
+    UnknownKernel,
+    BadTimeout,
+    ImageNotFound,
 }
 
 #[derive(Serialize)]
-struct ErrorBody {
+struct ErrorBody<'a> {
     error: Error,
+    request_id: &'a str,
+}
+
+// one of these is logged as json per request
+#[derive(Serialize)]
+struct AccessLog<'a> {
+    request_id: &'a str,
+    method: &'a str,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<&'a str>,
+    status: u16,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Error>,
+}
+
+impl AccessLog<'_> {
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+// seeded from the time we start so ids don't repeat across restarts, then just count up
+static REQUEST_ID: Lazy<AtomicU64> = Lazy::new(|| {
+    let micros = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_micros() as u64)
+        .unwrap_or(0);
+    AtomicU64::new(micros)
+});
+
+fn next_request_id() -> String {
+    format!("{:016x}", REQUEST_ID.fetch_add(1, Ordering::Relaxed))
 }
 
 struct HttpRunnerApp {
-    pool: worker::asynk::Pool,
+    // shared with main so it can drain the pool on shutdown
+    pool: Arc<worker::asynk::Pool>,
     max_conn: usize,
     cloud_hypervisor: OsString,
     initramfs: OsString,
-    kernel: OsString,
+    kernels: Kernels,
     ch_console: bool,
     strace: bool,
     ch_log_level: Option<ChLogLevel>,
     image_service: String,
     arch: Arch,
     os: Os,
+    // None disables CORS, Some("*") allows any origin
+    cors_origin: Option<HeaderValue>,
+    // None disables per client ip limiting
+    ip_rate_limiter: Option<IpRateLimiter>,
+    // take the client ip from x-forwarded-for, only safe when everything comes through the lb
+    trust_forwarded_for: bool,
+    timeouts: RunTimeouts,
+    // images from --index, used for looking up image details and per image limits, runs still go
+    // through the image service
+    images: PEImageMultiIndex,
+    max_image_pids: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RunTimeouts {
+    // timeout we put on the user's process (after the initial crun process exits)
+    run: Duration,
+    // overhead from kernel boot and crun start
+    ch_extra: Duration,
+    // most a request can ask for with timeout_ms
+    max_run: Duration,
+    // how long ch gets to boot the guest before we give up on it
+    boot: Option<Duration>,
+}
+
+impl RunTimeouts {
+    fn from_args(args: &Args) -> Self {
+        let run = Duration::from_millis(args.run_timeout_ms);
+        Self {
+            run: run,
+            ch_extra: Duration::from_millis(args.ch_timeout_extra_ms),
+            max_run: args.max_run_timeout_ms.map_or(run, Duration::from_millis),
+            boot: args.ch_boot_timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    // returns the (peinit::Config.timeout, worker::Input.ch_timeout) for a request. image_run is
+    // the image's own default, which replaces ours but is still capped at max_run since the label
+    // comes from whoever published the image and not from us
+    fn for_request(
+        &self,
+        timeout_ms: Option<u64>,
+        image_run: Option<Duration>,
+    ) -> Result<(Duration, Duration), Error> {
+        let default = image_run.map_or(self.run, |x| x.min(self.max_run));
+        let run = match timeout_ms {
+            None => default,
+            Some(0) => return Err(Error::BadTimeout),
+            Some(ms) if Duration::from_millis(ms) > self.max_run => return Err(Error::BadTimeout),
+            Some(ms) => Duration::from_millis(ms),
+        };
+        Ok((run, run + self.ch_extra))
+    }
 }
 
 //fn response_with_message(status: StatusCode, message: &str) -> Response<Vec<u8>> {
@@ -97,9 +227,12 @@ impl From<Error> for StatusCode {
         use Error::*;
         match val {
             ReadTimeout => StatusCode::REQUEST_TIMEOUT,
+            PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             Read | BadContentType | BadPath | OciSpec | BadReference | BadRequest
-            | ArchMismatch | OsMismatch => StatusCode::BAD_REQUEST,
-            QueueFull => StatusCode::SERVICE_UNAVAILABLE,
+            | ArchMismatch | OsMismatch | UnknownKernel | BadTimeout => StatusCode::BAD_REQUEST,
+            QueueFull | ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ImageNotFound => StatusCode::NOT_FOUND,
             WorkerRecv | IoFileCreate | ResponseRead | Worker | ImageService | Internal => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
@@ -107,19 +240,134 @@ impl From<Error> for StatusCode {
     }
 }
 
+// reject a declared over-limit body before we've read any of it (or done anything else). Without a
+// content-length (chunked) we rely on read_full_server_request_body stopping at the limit
+fn check_content_length(headers: &http::HeaderMap, max_len: usize) -> Result<(), Error> {
+    match header_value_content_length(headers.get(header::CONTENT_LENGTH)) {
+        Some(len) if len > max_len => Err(Error::PayloadTooLarge),
+        _ => Ok(()),
+    }
+}
+
+// key is a name or a digest with either a : or /
+fn image_response(images: &PEImageMultiIndex, path: &str) -> Result<Response<Vec<u8>>, Error> {
+    let key = apiv1::image::parse_path(path).ok_or(Error::BadPath)?;
+    let entry = images
+        .get(key)
+        .or_else(|| images.get_by_digest(key))
+        .ok_or(Error::ImageNotFound)?;
+    response_json(StatusCode::OK, apiv1::image::Response::from(&entry.image))
+        .map_err(|_| Error::Internal)
+}
+
+// (spec limits, run timeout) defaults from the labels of the image if it's in --index
+// the labels come from whoever built the image, so they only get to lower things: memory can't
+// go past what the guest has and pids past --max-image-pids
+fn image_limits(
+    images: &PEImageMultiIndex,
+    manifest_digest: &str,
+    max_pids: i64,
+) -> (ResourceLimits, Option<Duration>) {
+    let limits = images
+        .get_by_digest(manifest_digest)
+        .map(|x| x.image.limits())
+        .unwrap_or_default();
+    let resource_limits = ResourceLimits {
+        memory_bytes: limits
+            .memory_bytes
+            .map(|x| x.min((GUEST_MEMORY_MB << 20) as i64)),
+        pids: limits.pids.map(|x| x.min(max_pids)),
+    };
+    (
+        resource_limits,
+        limits.timeout_ms.map(Duration::from_millis),
+    )
+}
+
 // TODO use lazy static for most cmmon responses
-impl From<Error> for Response<Vec<u8>> {
-    fn from(val: Error) -> Self {
-        // response_no_body(self.into())
-        response_json(val.clone().into(), ErrorBody { error: val }).unwrap()
+fn error_response(error: Error, request_id: &str) -> Response<Vec<u8>> {
+    let retry_after = match error {
+        Error::QueueFull => Some(QUEUE_FULL_RETRY_AFTER),
+        Error::RateLimited => Some(RATE_LIMITED_RETRY_AFTER),
+        _ => None,
+    };
+    let mut response = response_json(
+        error.clone().into(),
+        ErrorBody {
+            error: error,
+            request_id: request_id,
+        },
+    )
+    .unwrap();
+    if let Some(retry_after) = retry_after {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
     }
+    response
 }
 
 impl HttpRunnerApp {
-    async fn apiv2_runi(&self, session: &mut ServerSession) -> Result<Response<Vec<u8>>, Error> {
+    // behind the lb every request comes from its address (or none over uds), so the real client
+    // is only known from the header it sets
+    fn client_ip(&self, session: &ServerSession) -> Option<IpAddr> {
+        if self.trust_forwarded_for {
+            session
+                .req_header()
+                .headers
+                .get(api::X_FORWARDED_FOR)?
+                .to_str()
+                .ok()?
+                .trim()
+                .parse()
+                .ok()
+        } else {
+            session
+                .client_addr()
+                .and_then(|x| x.as_inet())
+                .map(|x| x.ip())
+        }
+    }
+
+    fn rate_limited(&self, client_ip: Option<IpAddr>) -> bool {
+        match (&self.ip_rate_limiter, client_ip) {
+            (Some(limiter), Some(ip)) => !limiter.check(ip),
+            _ => false,
+        }
+    }
+
+    // queues the run and waits for its result, RUN_SECONDS covers both
+    async fn run_on_pool(
+        &self,
+        worker_input: worker::Input,
+    ) -> Result<worker::OutputResult, Error> {
+        let (resp_sender, resp_receiver) = tokio::sync::oneshot::channel();
+
+        let run_start = Instant::now();
+        () = self
+            .pool
+            .submit(worker_input, resp_sender)
+            .map_err(|e| match e {
+                worker::asynk::SubmitError::Full => Error::QueueFull,
+                worker::asynk::SubmitError::Closed => Error::ShuttingDown,
+            })?;
+
+        resp_receiver
+            .await
+            .map_err(|_| Error::WorkerRecv)
+            .inspect(|_| RUN_SECONDS.observe(run_start.elapsed().as_secs_f64()))
+    }
+
+    async fn apiv2_runi(
+        &self,
+        session: &mut ServerSession,
+        request_id: &str,
+    ) -> Result<Response<Vec<u8>>, Error> {
         REQ_RUN_COUNT.inc();
         let req_parts: &http::request::Parts = session.req_header();
 
+        check_content_length(&req_parts.headers, api::MAX_BODY_SIZE)?;
+
         let parsed_path = apiv2::runi::parse_path(req_parts.uri.path()).ok_or(Error::BadPath)?;
         trace!("parsed_path {:?}", parsed_path);
 
@@ -152,6 +400,9 @@ impl HttpRunnerApp {
                 Err(peimage_service::Error::ImageTooBig) => {
                     return Ok(response_string(StatusCode::BAD_REQUEST, "image too big"));
                 }
+                Err(peimage_service::Error::BadReference) => {
+                    return Err(Error::BadReference);
+                }
                 Err(peimage_service::Error::RatelimitExceeded) => {
                     return Ok(response_string(
                         StatusCode::INTERNAL_SERVER_ERROR,
@@ -172,11 +423,16 @@ impl HttpRunnerApp {
             .and_then(|x| x.try_into().ok())
             .ok_or(Error::BadContentType)?;
 
-        // not using accept header
-        let response_format = match content_type {
+        let accept = session
+            .req_header()
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|x| x.to_str().ok());
+        let response_format = match api::response_content_type(content_type, accept) {
             ContentType::ApplicationJson => peinit::ResponseFormat::JsonV1,
             ContentType::PeArchiveV1 => peinit::ResponseFormat::PeArchiveV1,
         };
+        let accept_gzip = accepts_gzip(&session.req_header().headers);
 
         // TODO this is a timeout on the reading the entire body, session.read_timeout
         let read_timeout = Duration::from_millis(2000);
@@ -192,91 +448,31 @@ impl HttpRunnerApp {
         let (body_offset, api_req) =
             apiv2::runi::parse_request(&body, &content_type).ok_or(Error::BadRequest)?;
 
-        let runtime_spec = create_runtime_spec(
-            &image_service_res.config,
-            api_req.entrypoint.as_deref(),
-            api_req.cmd.as_deref(),
-            api_req.env.as_deref(),
-        )
-        .map_err(|e| {
-            error!("got {e:?} when creating runtime_spec");
-            Error::OciSpec
-        })?;
-
-        let ch_config = CloudHypervisorConfig {
-            bin: self.cloud_hypervisor.clone(),
-            kernel: self.kernel.clone(),
-            initramfs: self.initramfs.clone(),
-            log_level: self.ch_log_level.clone(),
-            console: self.ch_console,
-            keep_args: true,
-            event_monitor: false,
-        };
-
-        let pe_config = peinit::Config {
-            timeout: RUN_TIMEOUT,
-            oci_runtime_config: serde_json::to_string(&runtime_spec).unwrap(),
-            stdin: api_req.stdin,
-            strace: self.strace,
-            crun_debug: false,
-            rootfs_dir: None,
-            rootfs_kind: peinit::RootfsKind::Erofs,
-            response_format: response_format,
-            kernel_inspect: false,
-            manifest_digest: image_service_res.manifest_digest,
-        };
-
-        let io_file = {
-            let mut builder = IoFileBuilder::new().map_err(|_| Error::IoFileCreate)?;
-            match content_type {
-                ContentType::ApplicationJson => {
-                    // this is blocking, but is going to memfd so I don't think its bad to do this?
-                    peinit::write_io_file_config(&mut builder, &pe_config, 0)
-                        .map_err(|_| Error::Internal)?;
-                }
-                ContentType::PeArchiveV1 => {
-                    // this is blocking (as above)
-                    let archive_size: u32 = (body.len() - body_offset)
-                        .try_into()
-                        .map_err(|_| Error::Internal)?;
-                    peinit::write_io_file_config(&mut builder, &pe_config, archive_size)
-                        .map_err(|_| Error::Internal)?;
-                    builder
-                        .write_all(&body[body_offset..])
-                        .map_err(|_| Error::Internal)?;
-                }
-            }
-            builder.finish().map_err(|_| Error::IoFileCreate)?
-        };
-
-        let worker_input = worker::Input {
-            id: 42, // id is useless because we are passing a return channel
-            ch_config: ch_config,
-            ch_timeout: RUN_TIMEOUT + CH_TIMEOUT_EXTRA,
-            io_file: io_file,
-            image: PathBufOrOwnedFd::Fd(image_service_res.fd),
+        let archive = match content_type {
+            ContentType::ApplicationJson => None,
+            ContentType::PeArchiveV1 => Some(&body[body_offset..]),
         };
-
-        let (resp_sender, resp_receiver) = tokio::sync::oneshot::channel();
-
-        () = self
-            .pool
-            .sender()
-            .try_send((worker_input, resp_sender))
-            .map_err(|_| Error::QueueFull)?;
-
-        let mut worker_output = resp_receiver
-            .await
-            .map_err(|_| Error::WorkerRecv)?
-            .map_err(|postmortem| {
+        let worker_input = self.run_input(
+            request_id,
+            image_service_res,
+            api_req,
+            response_format,
+            archive,
+        )?;
+
+        let mut worker_output = self.run_on_pool(worker_input).await?.map_err(
+            |worker::OutputError { postmortem, .. }| {
                 ERR_CH_COUNT.inc();
                 fn dump_file<F: Read>(name: &str, file: &mut F) {
                     eprintln!("=== {} ===", name);
                     let _ = std::io::copy(file, &mut std::io::stderr());
                 }
-                error!("worker error {:?}", postmortem.error);
+                error!(
+                    "request_id={request_id} worker error {:?}",
+                    postmortem.error
+                );
                 if let Some(args) = postmortem.args {
-                    error!("launched ch with {:?}", args);
+                    error!("request_id={request_id} launched ch with {:?}", args);
                 };
                 if let Some(mut err_file) = postmortem.logs.err_file {
                     dump_file("ch err", &mut err_file);
@@ -288,7 +484,8 @@ impl HttpRunnerApp {
                     dump_file("ch con", &mut con_file);
                 }
                 Error::Worker
-            })?;
+            },
+        )?;
 
         if log_enabled!(log::Level::Debug) {
             fn dump_file<F: Read>(name: &str, file: &mut F) {
@@ -311,17 +508,128 @@ impl HttpRunnerApp {
                 peinit::read_io_file_response_bytes(&mut worker_output.io_file)
                     .map_err(|_| Error::ResponseRead)
                     .map(|(_archive_size, json_bytes)| {
-                        response_json_vec(StatusCode::OK, json_bytes)
+                        observe_guest_timings(&json_bytes);
+                        let status = guest_response_status(&json_bytes);
+                        response_json_vec_gzip(status, json_bytes, accept_gzip)
                     })
             }
             peinit::ResponseFormat::PeArchiveV1 => {
                 peinit::read_io_file_response_archive_bytes(&mut worker_output.io_file)
                     .map_err(|_| Error::ResponseRead)
-                    .map(|response_bytes| response_pearchivev1(StatusCode::OK, response_bytes))
+                    .map(|response_bytes| {
+                        // <u32: response size> <response json> <archive>
+                        let json_size =
+                            u32::from_le_bytes(response_bytes[..4].try_into().unwrap()) as usize;
+                        let response_json = &response_bytes[4..4 + json_size];
+                        observe_guest_timings(response_json);
+                        let status = guest_response_status(response_json);
+                        response_pearchivev1(status, response_bytes)
+                    })
             }
         }
     }
 
+    // everything apiv2_runi hands to the pool: the guest's peinit::Config (and archive, if any)
+    // written to the io file, plus the timeouts ch is held to
+    fn run_input(
+        &self,
+        request_id: &str,
+        image: peimage_service::Response,
+        api_req: apiv2::runi::Request,
+        response_format: peinit::ResponseFormat,
+        archive: Option<&[u8]>,
+    ) -> Result<worker::Input, Error> {
+        let (resource_limits, image_run_timeout) =
+            image_limits(&self.images, &image.manifest_digest, self.max_image_pids);
+
+        let runtime_spec = create_runtime_spec(
+            &image.config,
+            api_req.entrypoint.as_deref(),
+            api_req.cmd.as_deref(),
+            api_req.env.as_deref(),
+            &resource_limits,
+        )
+        .map_err(|e| {
+            error!("request_id={request_id} got {e:?} when creating runtime_spec");
+            Error::OciSpec
+        })?;
+
+        let kernel = self
+            .kernels
+            .get(api_req.kernel.as_deref())
+            .ok_or(Error::UnknownKernel)?;
+
+        let (run_timeout, ch_timeout) = self
+            .timeouts
+            .for_request(api_req.timeout_ms, image_run_timeout)?;
+
+        let ch_config = CloudHypervisorConfig {
+            bin: self.cloud_hypervisor.clone(),
+            kernel: kernel.clone(),
+            initramfs: self.initramfs.clone(),
+            log_level: self.ch_log_level.clone(),
+            console: self.ch_console,
+            keep_args: true,
+            event_monitor: false,
+            vhost_user_image: None,
+            cmdline_extra: None,
+        };
+
+        let pe_config = peinit::Config {
+            timeout: run_timeout,
+            oci_runtime_config: serde_json::to_string(&runtime_spec).unwrap(),
+            stdin: api_req.stdin,
+            strace: self.strace,
+            crun_debug: false,
+            rootfs_dir: None,
+            rootfs_kind: peinit::RootfsKind::Erofs,
+            response_format: response_format,
+            kernel_inspect: false,
+            manifest_digest: image.manifest_digest,
+            binaries: peinit::Binaries::default(),
+            seccomp_log: false,
+            detach: true,
+            overlay_size_mb: None,
+            overlay: true,
+            sysctls: vec![],
+            capture_core: false,
+            post_run_cmd: None,
+        };
+
+        let io_file = {
+            let mut builder = IoFileBuilder::new().map_err(|_| Error::IoFileCreate)?;
+            match archive {
+                None => {
+                    // this is blocking, but is going to memfd so I don't think its bad to do this?
+                    peinit::write_io_file_config(&mut builder, &pe_config, 0)
+                        .map_err(|_| Error::Internal)?;
+                }
+                Some(archive) => {
+                    // this is blocking (as above)
+                    let archive_size: u32 =
+                        archive.len().try_into().map_err(|_| Error::Internal)?;
+                    peinit::write_io_file_config(&mut builder, &pe_config, archive_size)
+                        .map_err(|_| Error::Internal)?;
+                    builder.write_all(archive).map_err(|_| Error::Internal)?;
+                }
+            }
+            builder.finish().map_err(|_| Error::IoFileCreate)?
+        };
+
+        Ok(worker::Input {
+            id: 42, // id is useless because we are passing a return channel
+            ch_config: ch_config,
+            ch_timeout: ch_timeout,
+            boot_timeout: self.timeouts.boot,
+            io_file: io_file,
+            image: PathBufOrOwnedFd::Fd(image.fd),
+        })
+    }
+
+    async fn apiv1_image(&self, path: &str) -> Result<Response<Vec<u8>>, Error> {
+        image_response(&self.images, path)
+    }
+
     async fn api_internal_max_conn(
         &self,
         _session: &mut ServerSession,
@@ -333,19 +641,67 @@ impl HttpRunnerApp {
     }
 }
 
-#[async_trait]
-impl ServeHttp for HttpRunnerApp {
-    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+impl HttpRunnerApp {
+    // the response and the access log line for it
+    async fn respond(&self, session: &mut ServerSession) -> (Response<Vec<u8>>, String) {
+        let start = Instant::now();
+        let request_id = next_request_id();
         let req_parts: &http::request::Parts = session.req_header();
-        trace!("{} {}", req_parts.method, req_parts.uri.path());
-        let res = match (&req_parts.method, req_parts.uri.path()) {
-            (&Method::GET, "/api/internal/maxconn") => self.api_internal_max_conn(session).await,
+        let method = req_parts.method.clone();
+        let path = req_parts.uri.path().to_string();
+        let client_ip = self.client_ip(session);
+        trace!("{} {} {}", request_id, method, path);
+
+        let res = match (&method, path.as_str()) {
+            _ if self.rate_limited(client_ip) => Err(Error::RateLimited),
+            (&Method::OPTIONS, _) if self.cors_origin.is_some() => {
+                Ok(response_cors_preflight(self.cors_origin.as_ref().unwrap()))
+            }
+            (&Method::GET | &Method::HEAD, "/api/internal/maxconn") => {
+                self.api_internal_max_conn(session).await
+            }
+            (&Method::GET | &Method::HEAD, path) if path.starts_with(apiv1::image::PREFIX) => {
+                self.apiv1_image(path).await
+            }
             (&Method::POST, path) if path.starts_with(apiv2::runi::PREFIX) => {
-                self.apiv2_runi(session).await
+                self.apiv2_runi(session, &request_id).await
             }
-            _ => return response_no_body(StatusCode::NOT_FOUND),
+            _ => Ok(response_no_body(StatusCode::NOT_FOUND)),
+        };
+        let (mut response, error) = match res {
+            Ok(response) => (response, None),
+            Err(e) => (error_response(e.clone(), &request_id), Some(e)),
+        };
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert("x-request-id", value);
+        }
+        if let Some(origin) = &self.cors_origin {
+            add_cors_headers(&mut response, origin);
+        }
+        if method == Method::HEAD {
+            response = response_for_head(response);
+        }
+
+        let access_log = AccessLog {
+            request_id: &request_id,
+            method: method.as_str(),
+            path: &path,
+            image: apiv2::runi::parse_path(&path).map(|x| x.reference),
+            status: response.status().as_u16(),
+            duration_ms: start.elapsed().as_millis() as u64,
+            error: error,
         };
-        res.unwrap_or_else(|e| e.into())
+
+        (response, access_log.to_json())
+    }
+}
+
+#[async_trait]
+impl ServeHttp for HttpRunnerApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let (response, access_log) = self.respond(session).await;
+        info!("{}", access_log);
+        response
     }
 }
 
@@ -359,6 +715,10 @@ struct Args {
     #[arg(long, default_value = "../vmlinux")]
     kernel: OsString,
 
+    // name=path, selected by the kernel field of a request
+    #[arg(long)]
+    named_kernel: Vec<String>,
+
     #[arg(long, default_value = "../target/debug/initramfs")]
     initramfs: OsString,
 
@@ -369,14 +729,15 @@ struct Args {
     #[arg(long, default_value = "4:2:2")]
     worker_cpuset: String,
 
-    #[arg(long)]
+    // ex 0.0.0.0:1234
+    #[arg(long, visible_alias = "listen")]
     tcp: Option<String>,
 
     #[arg(long)]
     uds: Option<String>,
 
-    //#[arg(long, default_value="127.0.0.1:6193")]
-    #[arg(long)]
+    // ex 0.0.0.0:6193, tcp only
+    #[arg(long, visible_alias = "metrics-listen")]
     prom: Option<String>,
 
     #[arg(long)]
@@ -391,11 +752,70 @@ struct Args {
     #[arg(long)]
     image_service: String,
 
+    // .erofs/.sqfs files with an index whose images are served by /api/v1/image/, can be repeated
+    #[arg(long)]
+    index: Vec<PathBuf>,
+
     #[arg(long, default_value = "amd64")]
     arch: Arch,
 
     #[arg(long, default_value = "linux")]
     os: Os,
+
+    // allowed origin for browsers calling the api directly, ex https://programexplorer.org or *
+    #[arg(long)]
+    cors_origin: Option<String>,
+
+    // requests/sec refilled per client ip, unset to disable
+    #[arg(long)]
+    ip_rate: Option<f64>,
+
+    // use the lb's x-forwarded-for as the client ip for --ip-rate, without it every request
+    // through the lb counts against the lb's own ip. only set this if the worker can't be reached
+    // except through the lb since clients can send whatever they want in the header
+    #[arg(long)]
+    trust_forwarded_for: bool,
+
+    #[arg(long, default_value_t = 10)]
+    ip_burst: u32,
+
+    // most client ips we keep a bucket for
+    #[arg(long, default_value_t = 10_000)]
+    ip_max_clients: u64,
+
+    // most pids an image's dev.programexplorer.limits.pids label can ask for
+    #[arg(long, default_value_t = 4096)]
+    max_image_pids: i64,
+
+    // timeout on the user's process when a request doesn't give one
+    #[arg(long, default_value_t = 1000)]
+    run_timeout_ms: u64,
+
+    // added to the run timeout for kernel boot and crun start to get the timeout on ch
+    #[arg(long, default_value_t = 300)]
+    ch_timeout_extra_ms: u64,
+
+    // fail a run early if the guest hasn't booted in this long, instead of waiting out the whole
+    // ch timeout
+    #[arg(long)]
+    ch_boot_timeout_ms: Option<u64>,
+
+    // most a request can set timeout_ms to, defaults to --run-timeout-ms so requests can only
+    // lower it
+    #[arg(long)]
+    max_run_timeout_ms: Option<u64>,
+
+    // on shutdown, how long to wait for queued and running runs to finish before exiting anyways
+    #[arg(long, default_value_t = 10000)]
+    drain_timeout_ms: u64,
+}
+
+fn parse_named_kernel(x: &str) -> Option<(String, OsString)> {
+    let (name, path) = x.split_once('=')?;
+    if name.is_empty() || path.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), path.into()))
 }
 
 fn parse_cpuset_colon(x: &str) -> Option<(usize, usize, usize)> {
@@ -428,6 +848,13 @@ fn main() {
         std::process::exit(1);
     }
 
+    let timeouts = RunTimeouts::from_args(&args);
+    if timeouts.max_run < timeouts.run {
+        eprintln!("--max-run-timeout-ms must be at least --run-timeout-ms");
+        std::process::exit(1);
+    }
+    info!("timeouts {:?}", timeouts);
+
     let opt = Some(Opt {
         upgrade: false,
         daemon: false,
@@ -447,17 +874,34 @@ fn main() {
     };
     let worker_cpuset = {
         let (offset, workers, cores_per) = parse_cpuset_colon(&args.worker_cpuset).unwrap();
-        worker::cpuset(offset, workers, cores_per).unwrap()
+        worker::cpuset(offset, workers, cores_per)
+            .unwrap_or_else(|e| panic!("--worker-cpuset {}: {e}", args.worker_cpuset))
     };
+    for (id, c) in worker_cpuset.iter().enumerate() {
+        info!("worker {id} cpus {:?}", worker::cpuset_cpus(c));
+    }
 
-    let pool = worker::asynk::Pool::new(&worker_cpuset);
+    let pool = Arc::new(worker::asynk::Pool::new(&worker_cpuset));
     info!("using {} workers", pool.len());
 
     rustix::thread::sched_setaffinity(None, &server_cpuset).unwrap();
 
     let max_conn = pool.len() * 2; // TODO is this a good amount?
+
+    let kernels = {
+        let mut kernels = Kernels::new(cwd.join(args.kernel).into());
+        for x in args.named_kernel.iter() {
+            let (name, path) = parse_named_kernel(x).unwrap();
+            kernels.insert(name, cwd.join(path).into());
+        }
+        kernels
+    };
+    let images = PEImageMultiIndex::from_paths(PEImageMultiIndexKeyType::Name, &args.index)
+        .unwrap_or_else(|e| panic!("--index: {e}"));
+    info!("indexed {} images", images.map().len());
+
     let app = HttpRunnerApp {
-        pool: pool,
+        pool: pool.clone(),
         max_conn: max_conn,
         // NOTE: these files are opened/passed as paths into cloud hypervisor so changes will
         // get picked up, which may not be what we want. currently ch doesn't support passing
@@ -466,21 +910,40 @@ fn main() {
         // run
         // and really for these things, I am bundling them in a container so won't get switched
         // we join with cwd but if you provide an abspath it will be abs
-        kernel: cwd.join(args.kernel).into(),
+        kernels: kernels,
         initramfs: cwd.join(args.initramfs).into(),
         cloud_hypervisor: cwd.join(args.ch).into(),
 
         ch_console: args.ch_console,
         strace: args.strace,
-        ch_log_level: args.ch_log_level.map(|x| x.as_str().try_into().unwrap()),
+        ch_log_level: args.ch_log_level.map(|x| {
+            x.as_str()
+                .try_into()
+                .unwrap_or_else(|e| panic!("--ch-log-level: {e}"))
+        }),
 
         image_service: args.image_service,
 
         arch: args.arch,
         os: args.os,
+
+        cors_origin: args
+            .cors_origin
+            .map(|x| HeaderValue::from_str(&x).expect("bad --cors-origin")),
+
+        ip_rate_limiter: args
+            .ip_rate
+            .map(|rate| IpRateLimiter::new(rate, args.ip_burst, args.ip_max_clients)),
+        trust_forwarded_for: args.trust_forwarded_for,
+
+        timeouts: timeouts,
+        images: images,
+        max_image_pids: args.max_image_pids,
     };
 
-    assert_file_exists(&app.kernel);
+    for kernel in app.kernels.paths() {
+        assert_file_exists(kernel);
+    }
     assert_file_exists(&app.initramfs);
     assert_file_exists(&app.cloud_hypervisor);
 
@@ -503,7 +966,61 @@ fn main() {
 
     my_server.add_service(runner_service_http);
 
-    my_server.run_forever();
+    // run_forever would exit the process as soon as the server is done, leaving any ch still
+    // running orphaned. run returns after a SIGTERM once the listeners are closed, then we wait
+    // for the workers to finish what they were given
+    let drain_timeout = Duration::from_millis(args.drain_timeout_ms);
+    my_server.run(RunArgs::default());
+    info!("draining worker pool");
+    if !pool.drain(drain_timeout) {
+        error!("worker pool didn't drain within {:?}", drain_timeout);
+    }
+}
+
+// best effort since the response is passed through as is, a panic response has no timings
+fn observe_guest_timings(response_json: &[u8]) {
+    // just the part of peinit::Response we want
+    #[derive(Deserialize)]
+    struct ResponseTimings {
+        timings: Option<peinit::Timings>,
+    }
+
+    let timings = match serde_json::from_slice(response_json) {
+        Ok(ResponseTimings {
+            timings: Some(timings),
+        }) => timings,
+        _ => return,
+    };
+    let stages = [
+        // init starting is boot_at_ms after the kernel did
+        ("boot", Some(timings.boot_at_ms)),
+        ("unpack", Some(timings.unpack_ms)),
+        ("run", Some(timings.run_ms)),
+        ("pack", timings.pack_ms),
+    ];
+    for (stage, ms) in stages {
+        if let Some(ms) = ms {
+            GUEST_STAGE_SECONDS
+                .with_label_values(&[stage])
+                .observe(ms as f64 / 1000.0);
+        }
+    }
+}
+
+// the body is still the guest's response either way, the status just says whose fault it was: a
+// container that won't start is the user's command/image, a panic in peinit is ours
+fn guest_response_status(response_json: &[u8]) -> StatusCode {
+    // just the part of peinit::Response we want
+    #[derive(Deserialize)]
+    struct ResponseKind {
+        kind: String,
+    }
+
+    match serde_json::from_slice(response_json) {
+        Ok(ResponseKind { kind }) if kind == "ContainerStartError" => StatusCode::BAD_REQUEST,
+        Ok(ResponseKind { kind }) if kind == "Panic" => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::OK,
+    }
 }
 
 fn assert_file_exists<P: AsRef<Path>>(p: P) {
@@ -513,9 +1030,932 @@ fn assert_file_exists<P: AsRef<Path>>(p: P) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Seek, SeekFrom};
+    #[test]
+    fn listen_args() {
+        let args = Args::try_parse_from(["worker", "--image-service", "img.sock"]).unwrap();
+        assert_eq!(args.tcp, None);
+        assert_eq!(args.uds, None);
+        assert_eq!(args.prom, None);
+
+        let args = Args::try_parse_from([
+            "worker",
+            "--image-service",
+            "img.sock",
+            "--listen",
+            "0.0.0.0:1234",
+            "--metrics-listen",
+            "0.0.0.0:6193",
+        ])
+        .unwrap();
+        assert_eq!(args.tcp.as_deref(), Some("0.0.0.0:1234"));
+        assert_eq!(args.prom.as_deref(), Some("0.0.0.0:6193"));
+
+        let args = Args::try_parse_from([
+            "worker",
+            "--image-service",
+            "img.sock",
+            "--tcp",
+            "127.0.0.1:1234",
+            "--prom",
+            "127.0.0.1:6193",
+        ])
+        .unwrap();
+        assert_eq!(args.tcp.as_deref(), Some("127.0.0.1:1234"));
+        assert_eq!(args.prom.as_deref(), Some("127.0.0.1:6193"));
+
+        // they're the same arg so can only be given once
+        assert!(Args::try_parse_from([
+            "worker",
+            "--image-service",
+            "img.sock",
+            "--tcp",
+            "127.0.0.1:1234",
+            "--listen",
+            "0.0.0.0:1234",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn guest_response_status_by_kind() {
+        assert_eq!(
+            guest_response_status(br#"{"kind": "Ok", "manifest_digest": "sha256:abc"}"#),
+            StatusCode::OK
+        );
+        assert_eq!(
+            guest_response_status(br#"{"kind": "Overtime", "manifest_digest": "sha256:abc"}"#),
+            StatusCode::OK
+        );
+        assert_eq!(
+            guest_response_status(
+                br#"{"kind": "ContainerStartError", "message": "executable file not found"}"#
+            ),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            guest_response_status(br#"{"kind": "Panic", "message": "oh no"}"#),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(guest_response_status(b"not json"), StatusCode::OK);
+    }
+
+    #[test]
+    fn guest_timings_histogram() {
+        let stage = |x| GUEST_STAGE_SECONDS.with_label_values(&[x]);
+        let before: Vec<_> = ["boot", "unpack", "run", "pack"]
+            .into_iter()
+            .map(|x| (stage(x).get_sample_count(), stage(x).get_sample_sum()))
+            .collect();
+
+        observe_guest_timings(
+            br#"{"kind": "Ok", "timings": {"boot_at_ms": 100, "unpack_ms": 50, "run_ms": 250}}"#,
+        );
+        // no timings to observe
+        observe_guest_timings(br#"{"kind": "Panic", "message": "oh no"}"#);
+        observe_guest_timings(b"not json");
+
+        let expected = [
+            ("boot", 1, 0.1),
+            ("unpack", 1, 0.05),
+            ("run", 1, 0.25),
+            ("pack", 0, 0.0),
+        ];
+        for ((name, count, sum), (count_before, sum_before)) in expected.into_iter().zip(before) {
+            assert_eq!(
+                stage(name).get_sample_count() - count_before,
+                count,
+                "{name}"
+            );
+            assert!(
+                (stage(name).get_sample_sum() - sum_before - sum).abs() < 1e-9,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn run_timeouts() {
+        let args = Args::try_parse_from(["worker", "--image-service", "img.sock"]).unwrap();
+        let timeouts = RunTimeouts::from_args(&args);
+        let (run, ch) = timeouts.for_request(None, None).unwrap();
+        assert_eq!(run, Duration::from_millis(1000));
+        assert_eq!(ch, Duration::from_millis(1300));
+        assert_eq!(timeouts.boot, None);
+        // can go lower but not higher than the default when there is no max
+        assert!(timeouts.for_request(Some(500), None).is_ok());
+        assert!(matches!(
+            timeouts.for_request(Some(1001), None),
+            Err(Error::BadTimeout)
+        ));
+
+        let args = Args::try_parse_from([
+            "worker",
+            "--image-service",
+            "img.sock",
+            "--run-timeout-ms",
+            "5000",
+            "--ch-timeout-extra-ms",
+            "500",
+            "--max-run-timeout-ms",
+            "20000",
+            "--ch-boot-timeout-ms",
+            "2000",
+        ])
+        .unwrap();
+        let timeouts = RunTimeouts::from_args(&args);
+        let (run, ch_timeout) = timeouts.for_request(None, None).unwrap();
+        assert_eq!(run, Duration::from_secs(5));
+        assert_eq!(ch_timeout, Duration::from_millis(5500));
+
+        // what apiv2_runi hands to the pool
+        let mut app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+        app.timeouts = timeouts;
+        let run_input = |timeout_ms: Option<u64>| {
+            let api_req = serde_json::from_value(serde_json::json!({
+                "cmd": ["sh"],
+                "timeout_ms": timeout_ms,
+            }))
+            .unwrap();
+            app.run_input(
+                "0",
+                busybox_image_response(),
+                api_req,
+                peinit::ResponseFormat::JsonV1,
+                None,
+            )
+        };
+        let mut worker_input = run_input(None).unwrap();
+        assert_eq!(worker_input.ch_timeout, Duration::from_millis(5500));
+        assert_eq!(worker_input.boot_timeout, Some(Duration::from_secs(2)));
+        worker_input.io_file.seek(SeekFrom::Start(0)).unwrap();
+        let (archive_size, pe_config) =
+            peinit::read_io_file_config(&mut worker_input.io_file).unwrap();
+        assert_eq!(archive_size, 0);
+        assert_eq!(pe_config.timeout, Duration::from_secs(5));
+
+        let mut worker_input = run_input(Some(20000)).unwrap();
+        assert_eq!(worker_input.ch_timeout, Duration::from_millis(20500));
+        worker_input.io_file.seek(SeekFrom::Start(0)).unwrap();
+        let (_, pe_config) = peinit::read_io_file_config(&mut worker_input.io_file).unwrap();
+        assert_eq!(pe_config.timeout, Duration::from_secs(20));
+        assert!(matches!(run_input(Some(20001)), Err(Error::BadTimeout)));
+
+        let (run, ch) = timeouts.for_request(Some(20000), None).unwrap();
+        assert_eq!(run, Duration::from_secs(20));
+        assert_eq!(ch, Duration::from_millis(20500));
+        assert!(matches!(
+            timeouts.for_request(Some(20001), None),
+            Err(Error::BadTimeout)
+        ));
+        assert!(matches!(
+            timeouts.for_request(Some(0), None),
+            Err(Error::BadTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn run_seconds_observed() {
+        // stands in for ch and exits cleanly without looking at the io file
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("ch");
+        std::fs::write(&bin, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&bin, Permissions::from_mode(0o755)).unwrap();
+
+        let mut app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+        app.pool = Arc::new(worker::asynk::Pool::new(&[
+            rustix::thread::sched_getaffinity(None).unwrap(),
+        ]));
+        app.cloud_hypervisor = bin.into();
+        let api_req = serde_json::from_value(serde_json::json!({"cmd": ["sh"]})).unwrap();
+        let worker_input = app
+            .run_input(
+                "0",
+                busybox_image_response(),
+                api_req,
+                peinit::ResponseFormat::JsonV1,
+                None,
+            )
+            .unwrap();
+
+        let before = RUN_SECONDS.get_sample_count();
+        let output = app.run_on_pool(worker_input).await.unwrap();
+        assert!(output.is_ok());
+        assert_eq!(RUN_SECONDS.get_sample_count() - before, 1);
+        assert!(app.pool.drain(Duration::from_secs(1)));
+    }
+
     #[test]
     fn parse_cpuset_range_good() {
         assert_eq!(Some((4, Some(8))), parse_cpuset_range("4-8"));
         assert_eq!(Some((4, None)), parse_cpuset_range("4-"));
     }
+
+    #[test]
+    fn access_log_json() {
+        let path = "/api/v2/runi/amd64/linux/docker.io/library/busybox:1.37";
+        let log = AccessLog {
+            request_id: "00000000000000ff",
+            method: Method::POST.as_str(),
+            path: path,
+            image: apiv2::runi::parse_path(path).map(|x| x.reference),
+            status: 200,
+            duration_ms: 123,
+            error: None,
+        };
+        let got: serde_json::Value = serde_json::from_str(&log.to_json()).unwrap();
+        assert_eq!(
+            got,
+            serde_json::json!({
+                "request_id": "00000000000000ff",
+                "method": "POST",
+                "path": path,
+                "image": "docker.io/library/busybox:1.37",
+                "status": 200,
+                "duration_ms": 123,
+            })
+        );
+
+        let log = AccessLog {
+            request_id: "0000000000000100",
+            method: "POST",
+            path: path,
+            image: None,
+            status: 503,
+            duration_ms: 0,
+            error: Some(Error::QueueFull),
+        };
+        let got: serde_json::Value = serde_json::from_str(&log.to_json()).unwrap();
+        assert_eq!(got["error"], "QueueFull");
+        assert!(got.get("image").is_none());
+    }
+
+    // what the image service hands back for busybox, the fd is never looked at
+    fn busybox_image_response() -> peimage_service::Response {
+        peimage_service::Response {
+            manifest_digest: "sha256:1234".into(),
+            config: peoci::spec::ImageConfiguration {
+                architecture: peoci::spec::Arch::Amd64,
+                os: peoci::spec::Os::Linux,
+                config: None,
+            },
+            fd: std::fs::File::open("/dev/null").unwrap().into(),
+        }
+    }
+
+    // answers every request the way the image service would for busybox, returns its socket path
+    fn fake_image_service(dir: &Path) -> String {
+        use std::io::IoSlice;
+        use tokio_seqpacket::{ancillary::AncillaryMessageWriter, UnixSeqpacketListener};
+
+        let path = dir.join("img.sock");
+        let mut listener = UnixSeqpacketListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let conn = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                conn.recv(&mut buf).await.unwrap();
+                let image = busybox_image_response();
+                let wire_response = peimage_service::WireResponse::Ok {
+                    manifest_digest: image.manifest_digest,
+                    config: image.config,
+                };
+                let buf =
+                    bincode::encode_to_vec(&wire_response, bincode::config::standard()).unwrap();
+                let mut ancillary_buffer = [0; 128];
+                let mut ancillary = AncillaryMessageWriter::new(&mut ancillary_buffer);
+                ancillary.add_fds(&[&image.fd]).unwrap();
+                conn.send_vectored_with_ancillary(&[IoSlice::new(&buf)], &mut ancillary)
+                    .await
+                    .unwrap();
+            }
+        });
+        path.to_str().unwrap().to_string()
+    }
+
+    fn test_app(images: PEImageMultiIndex) -> HttpRunnerApp {
+        let args = Args::try_parse_from(["worker", "--image-service", "img.sock"]).unwrap();
+        HttpRunnerApp {
+            // no workers, nothing here should get as far as the pool
+            pool: Arc::new(worker::asynk::Pool::new(&[])),
+            max_conn: 4,
+            cloud_hypervisor: "ch".into(),
+            initramfs: "initramfs".into(),
+            kernels: Kernels::new("vmlinux".into()),
+            ch_console: false,
+            strace: false,
+            ch_log_level: None,
+            image_service: args.image_service.clone(),
+            arch: Arch::Amd64,
+            os: Os::Linux,
+            cors_origin: None,
+            ip_rate_limiter: None,
+            trust_forwarded_for: false,
+            timeouts: RunTimeouts::from_args(&args),
+            images: images,
+            max_image_pids: args.max_image_pids,
+        }
+    }
+
+    // runs a raw http/1.1 request through the handler, returns the response and its access log
+    async fn handle_request(
+        app: &HttpRunnerApp,
+        request: &[u8],
+    ) -> (Response<Vec<u8>>, serde_json::Value) {
+        let io = tokio_test::io::Builder::new().read(request).build();
+        let mut session = ServerSession::new_http1(Box::new(io));
+        assert!(session.read_request().await.unwrap());
+        let (response, access_log) = app.respond(&mut session).await;
+        (response, serde_json::from_str(&access_log).unwrap())
+    }
+
+    #[tokio::test]
+    async fn access_log_request() {
+        let app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+
+        let (response, log) =
+            handle_request(&app, b"GET /api/internal/maxconn HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), b"4");
+        assert_eq!(
+            log["request_id"],
+            response.headers()["x-request-id"].to_str().unwrap()
+        );
+        assert_eq!(log["method"], "GET");
+        assert_eq!(log["path"], "/api/internal/maxconn");
+        assert_eq!(log["status"], 200);
+        assert!(log["duration_ms"].is_u64());
+        assert!(log.get("image").is_none());
+        assert!(log.get("error").is_none());
+
+        // wrong arch fails before anything talks to the image service
+        let (response, log) = handle_request(
+            &app,
+            b"POST /api/v2/runi/arm64/linux/docker.io/library/busybox:1.37 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            log["request_id"],
+            response.headers()["x-request-id"].to_str().unwrap()
+        );
+        assert_eq!(log["method"], "POST");
+        assert_eq!(log["image"], "docker.io/library/busybox:1.37");
+        assert_eq!(log["status"], 400);
+        assert_eq!(log["error"], "ArchMismatch");
+
+        let (response, log) = handle_request(&app, b"DELETE /nope HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(log["method"], "DELETE");
+        assert_eq!(log["path"], "/nope");
+        assert_eq!(log["status"], 404);
+        assert!(log.get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn ip_rate_limited() {
+        let mut app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+        app.ip_rate_limiter = Some(IpRateLimiter::new(0.001, 1, 100));
+        let request = |ip: &str| {
+            format!("GET /api/internal/maxconn HTTP/1.1\r\nX-Forwarded-For: {ip}\r\n\r\n")
+                .into_bytes()
+        };
+
+        // the header is ignored unless we trust it, and these sessions have no address
+        for _ in 0..3 {
+            let (response, _) = handle_request(&app, &request("10.0.0.1")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        app.trust_forwarded_for = true;
+        let (response, _) = handle_request(&app, &request("10.0.0.1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (response, log) = handle_request(&app, &request("10.0.0.1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers()[header::RETRY_AFTER],
+            RATE_LIMITED_RETRY_AFTER.to_string()
+        );
+        assert_eq!(log["error"], "RateLimited");
+
+        // someone else behind the same lb isn't affected
+        let (response, _) = handle_request(&app, &request("10.0.0.2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn content_length_too_large() {
+        let mut headers = http::HeaderMap::new();
+        assert!(check_content_length(&headers, api::MAX_BODY_SIZE).is_ok());
+
+        headers.insert(header::CONTENT_LENGTH, api::MAX_BODY_SIZE.into());
+        assert!(check_content_length(&headers, api::MAX_BODY_SIZE).is_ok());
+
+        headers.insert(header::CONTENT_LENGTH, (api::MAX_BODY_SIZE + 1).into());
+        let error = check_content_length(&headers, api::MAX_BODY_SIZE).unwrap_err();
+        assert!(matches!(error, Error::PayloadTooLarge));
+        assert_eq!(
+            error_response(error, "0").status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn payload_too_large_request() {
+        let app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+        // turned away on the header alone, before the image service or reading the body
+        let request = format!(
+            "POST /api/v2/runi/amd64/linux/docker.io/library/busybox:1.37 HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            api::MAX_BODY_SIZE + 1
+        );
+        let (response, log) = handle_request(&app, request.as_bytes()).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(log["status"], 413);
+        assert_eq!(log["error"], "PayloadTooLarge");
+    }
+
+    #[test]
+    fn gzip_json_response() {
+        use flate2::read::GzDecoder;
+
+        let mut headers = http::HeaderMap::new();
+        assert!(!accepts_gzip(&headers));
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip;q=0"),
+        );
+        assert!(!accepts_gzip(&headers));
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("deflate, GZIP;q=0.8, br"),
+        );
+        assert!(accepts_gzip(&headers));
+
+        let body = serde_json::to_vec(&serde_json::json!({
+            "stdout": "hello world\n".repeat(1000),
+            "timings": {"total": 1234},
+        }))
+        .unwrap();
+
+        let response = response_json_vec_gzip(StatusCode::OK, body.clone(), true);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            api::APPLICATION_JSON
+        );
+        assert_eq!(
+            response.headers()[header::CONTENT_LENGTH],
+            response.body().len().to_string()
+        );
+        assert!(response.body().len() < body.len());
+        let mut decoded = vec![];
+        GzDecoder::new(response.body().as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        // vary survives the cors headers
+        let mut response = response;
+        add_cors_headers(&mut response, &HeaderValue::from_static("*"));
+        let vary: Vec<_> = response.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["Accept-Encoding", "Origin"]);
+
+        let response = response_json_vec_gzip(StatusCode::OK, body.clone(), false);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(response.body(), &body);
+
+        // too small to bother
+        let response = response_json_vec_gzip(StatusCode::OK, b"{}".to_vec(), true);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(response.body(), b"{}");
+    }
+
+    #[test]
+    fn queue_full_retry_after() {
+        let response = error_response(Error::QueueFull, "0");
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(header::RETRY_AFTER).unwrap(),
+            &QUEUE_FULL_RETRY_AFTER.to_string()
+        );
+
+        let response = error_response(Error::Internal, "0");
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+
+        let response = error_response(Error::RateLimited, "0");
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(header::RETRY_AFTER).unwrap(),
+            &RATE_LIMITED_RETRY_AFTER.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_full_request() {
+        let dir = tempfile::tempdir().unwrap();
+        // the test app's pool has no workers so nothing can be queued
+        let mut app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+        app.image_service = fake_image_service(dir.path());
+
+        let body = br#"{"cmd": ["sh"]}"#;
+        let mut request = format!(
+            "POST /api/v2/runi/amd64/linux/docker.io/library/busybox:1.37 HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        let (response, log) = handle_request(&app, &request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[header::RETRY_AFTER],
+            QUEUE_FULL_RETRY_AFTER.to_string()
+        );
+        assert_eq!(log["error"], "QueueFull");
+        let got: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(got["error"], "QueueFull");
+        assert_eq!(got["request_id"], log["request_id"]);
+    }
+
+    #[test]
+    fn cors_preflight() {
+        let origin = HeaderValue::from_static("https://programexplorer.org");
+        let response = response_cors_preflight(&origin);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        let methods = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("POST"));
+        assert!(methods.contains("OPTIONS"));
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type"
+        );
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cors_request() {
+        let mut app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+        let preflight =
+            b"OPTIONS /api/v2/runi/amd64/linux/docker.io/library/busybox:1.37 HTTP/1.1\r\nOrigin: https://programexplorer.org\r\nAccess-Control-Request-Method: POST\r\n\r\n";
+
+        // off by default so there is nothing routed for OPTIONS
+        let (response, _) = handle_request(&app, preflight).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let origin = HeaderValue::from_static("https://programexplorer.org");
+        app.cors_origin = Some(origin.clone());
+        let (response, log) = handle_request(&app, preflight).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(log["method"], "OPTIONS");
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type"
+        );
+        assert!(response.body().is_empty());
+
+        // and errors get it too so the browser can read the body
+        let (response, _) = handle_request(
+            &app,
+            b"POST /api/v2/runi/arm64/linux/docker.io/library/busybox:1.37 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            origin
+        );
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "x-request-id"
+        );
+    }
+
+    #[test]
+    fn cors_normal_response() {
+        let origin = HeaderValue::from_static("*");
+        let mut response = response_string(StatusCode::OK, "hi");
+        add_cors_headers(&mut response, &origin);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "x-request-id"
+        );
+        assert_eq!(response.body(), b"hi");
+
+        // errors get it too so the browser can read the error body
+        let mut response = error_response(Error::QueueFull, "0");
+        add_cors_headers(&mut response, &origin);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(response.headers().get(header::RETRY_AFTER).is_some());
+    }
+
+    #[test]
+    fn head_response() {
+        let body = serde_json::to_vec(&serde_json::json!({"max_conn": 4})).unwrap();
+        let etag = peserver::util::etag(&body);
+        let mut response = peserver::util::response_json_vec(StatusCode::OK, body.clone());
+        response
+            .headers_mut()
+            .insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+        let get_headers = response.headers().clone();
+
+        let response = response_for_head(response);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers(), &get_headers);
+        assert_eq!(
+            response.headers()[header::CONTENT_LENGTH],
+            body.len().to_string()
+        );
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn head_request() {
+        let app = test_app(PEImageMultiIndex::new(PEImageMultiIndexKeyType::Name));
+
+        let (get, _) = handle_request(&app, b"GET /api/internal/maxconn HTTP/1.1\r\n\r\n").await;
+        let (head, log) =
+            handle_request(&app, b"HEAD /api/internal/maxconn HTTP/1.1\r\n\r\n").await;
+        assert_eq!(head.status(), StatusCode::OK);
+        assert_eq!(log["method"], "HEAD");
+        assert_eq!(log["status"], 200);
+        assert!(head.body().is_empty());
+        assert_eq!(
+            head.headers()[header::CONTENT_LENGTH],
+            get.body().len().to_string()
+        );
+
+        // only GET and HEAD are routed here
+        let (response, _) =
+            handle_request(&app, b"PUT /api/internal/maxconn HTTP/1.1\r\n\r\n").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn request_ids_unique() {
+        let a = next_request_id();
+        let b = next_request_id();
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 16);
+    }
+
+    // index.docker.io/library/busybox:1.37 at sha256:1234 with the given image config json
+    fn busybox_index(dir: &Path, config: &str) -> PEImageMultiIndex {
+        use peimage::index::{PEImageId, PEImageIndex, PEImageIndexEntry};
+
+        let manifest = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "digest": "sha256:b5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7",
+                    "size": 7023
+                },
+                "layers": []
+            }"#,
+        )
+        .unwrap();
+        let index = PEImageIndex {
+            version: peimage::index::INDEX_VERSION,
+            images: vec![PEImageIndexEntry {
+                rootfs: "abcd".into(),
+                config: serde_json::from_str(config).unwrap(),
+                manifest: manifest,
+                id: PEImageId {
+                    digest: "sha256:1234".into(),
+                    repository: "library/busybox".into(),
+                    registry: "index.docker.io".into(),
+                    tag: "1.37".into(),
+                },
+            }],
+        };
+        let path = dir.join("index.erofs");
+        index
+            .write_to_file(&mut std::fs::File::create(&path).unwrap())
+            .unwrap();
+        PEImageMultiIndex::from_paths(PEImageMultiIndexKeyType::Name, &[&path]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn image_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app(busybox_index(
+            dir.path(),
+            r#"{
+                "architecture": "amd64",
+                "os": "linux",
+                "config": {"Cmd": ["sh"]},
+                "rootfs": {"type": "layers", "diff_ids": []},
+                "history": []
+            }"#,
+        ));
+        let digest = "sha256:1234";
+        let request = |method: &str, key: &str| {
+            format!("{method} {}{key} HTTP/1.1\r\n\r\n", apiv1::image::PREFIX).into_bytes()
+        };
+
+        for key in [
+            "index.docker.io/library/busybox:1.37",
+            "sha256:1234",
+            "sha256/1234",
+        ] {
+            let (response, log) = handle_request(&app, &request("GET", key)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{key}");
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                api::APPLICATION_JSON
+            );
+            let got: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(got["id"]["digest"], digest);
+            assert_eq!(got["id"]["tag"], "1.37");
+            assert_eq!(got["config"]["config"]["Cmd"], serde_json::json!(["sh"]));
+            assert_eq!(got["manifest"]["config"]["size"], 7023);
+            assert_eq!(
+                got["upstream_link"],
+                "https://hub.docker.com/layers/library/busybox/1.37/images/sha256-1234"
+            );
+            assert_eq!(log["status"], 200);
+
+            let (head, _) = handle_request(&app, &request("HEAD", key)).await;
+            assert_eq!(head.status(), StatusCode::OK);
+            assert_eq!(
+                head.headers()[header::CONTENT_LENGTH],
+                response.body().len().to_string()
+            );
+            assert!(head.body().is_empty());
+        }
+
+        for key in ["index.docker.io/library/busybox:1.36", "sha256:5678"] {
+            let (response, log) = handle_request(&app, &request("GET", key)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{key}");
+            assert_eq!(log["error"], "ImageNotFound");
+        }
+
+        let (response, log) = handle_request(&app, &request("GET", "")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(log["error"], "BadPath");
+
+        // only GET and HEAD are routed here
+        let (response, log) = handle_request(&app, &request("POST", "sha256:1234")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(log.get("error").is_none());
+    }
+
+    #[test]
+    fn image_limits_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let images = busybox_index(
+            dir.path(),
+            r#"{
+                "architecture": "amd64",
+                "os": "linux",
+                "config": {
+                    "Cmd": ["sh"],
+                    "Labels": {
+                        "dev.programexplorer.limits.memory-bytes": "2147483648",
+                        "dev.programexplorer.limits.pids": "512",
+                        "dev.programexplorer.limits.timeout-ms": "30000"
+                    }
+                },
+                "rootfs": {"type": "layers", "diff_ids": []},
+                "history": []
+            }"#,
+        );
+        let args = Args::try_parse_from(["worker", "--image-service", "img.sock"]).unwrap();
+        let timeouts = RunTimeouts::from_args(&args);
+
+        let (resource_limits, image_run) = image_limits(&images, "sha256:1234", 4096);
+        assert_eq!(
+            resource_limits,
+            ResourceLimits {
+                memory_bytes: Some(2 << 30),
+                pids: Some(512),
+            }
+        );
+        assert_eq!(image_run, Some(Duration::from_secs(30)));
+
+        // what the image service would hand back for it
+        let image_config = peoci::spec::ImageConfiguration {
+            architecture: peoci::spec::Arch::Amd64,
+            os: peoci::spec::Os::Linux,
+            config: None,
+        };
+        let spec = create_runtime_spec(
+            &image_config,
+            None,
+            Some(&["sh".into()]),
+            None,
+            &resource_limits,
+        )
+        .unwrap();
+        let resources = spec.linux().as_ref().unwrap().resources().as_ref().unwrap();
+        assert_eq!(resources.memory().as_ref().unwrap().limit(), Some(2 << 30));
+        assert_eq!(resources.pids().as_ref().unwrap().limit(), 512);
+
+        // the image default is past max_run so gets clamped to it, and can't raise what a request
+        // is allowed to ask for
+        let (run, ch) = timeouts.for_request(None, image_run).unwrap();
+        assert_eq!(run, Duration::from_millis(1000));
+        assert_eq!(ch, Duration::from_millis(1300));
+        assert!(matches!(
+            timeouts.for_request(Some(30000), image_run),
+            Err(Error::BadTimeout)
+        ));
+
+        let args = Args::try_parse_from([
+            "worker",
+            "--image-service",
+            "img.sock",
+            "--max-run-timeout-ms",
+            "60000",
+        ])
+        .unwrap();
+        let raised = RunTimeouts::from_args(&args);
+        let (run, ch) = raised.for_request(None, image_run).unwrap();
+        assert_eq!(run, Duration::from_secs(30));
+        assert_eq!(ch, Duration::from_millis(30300));
+        assert!(raised.for_request(Some(60000), image_run).is_ok());
+        assert!(matches!(
+            raised.for_request(Some(60001), image_run),
+            Err(Error::BadTimeout)
+        ));
+        let (run, _) = raised
+            .for_request(None, Some(Duration::from_secs(90)))
+            .unwrap();
+        assert_eq!(run, Duration::from_secs(60));
+
+        // an image we don't know about gets the server defaults and no cgroup limits
+        let (resource_limits, image_run) = image_limits(&images, "sha256:5678", 4096);
+        assert_eq!(resource_limits, ResourceLimits::default());
+        assert_eq!(image_run, None);
+        let spec = create_runtime_spec(
+            &image_config,
+            None,
+            Some(&["sh".into()]),
+            None,
+            &resource_limits,
+        )
+        .unwrap();
+        assert!(spec.linux().as_ref().unwrap().resources().is_none());
+        assert_eq!(
+            timeouts.for_request(None, image_run).unwrap().0,
+            Duration::from_millis(1000)
+        );
+
+        // labels asking for more than the guest has or past the pid cap get clamped
+        let dir = tempfile::tempdir().unwrap();
+        let images = busybox_index(
+            dir.path(),
+            r#"{
+                "architecture": "amd64",
+                "os": "linux",
+                "config": {
+                    "Cmd": ["sh"],
+                    "Labels": {
+                        "dev.programexplorer.limits.memory-bytes": "1099511627776",
+                        "dev.programexplorer.limits.pids": "1000000"
+                    }
+                },
+                "rootfs": {"type": "layers", "diff_ids": []},
+                "history": []
+            }"#,
+        );
+        let (resource_limits, _) = image_limits(&images, "sha256:1234", 4096);
+        assert_eq!(
+            resource_limits,
+            ResourceLimits {
+                memory_bytes: Some((GUEST_MEMORY_MB << 20) as i64),
+                pids: Some(4096),
+            }
+        );
+        let (resource_limits, _) = image_limits(&images, "sha256:1234", 100);
+        assert_eq!(resource_limits.pids, Some(100));
+    }
+
+    #[test]
+    fn parse_named_kernel_good() {
+        assert_eq!(
+            Some(("nfs".to_string(), OsString::from("../vmlinux-nfs"))),
+            parse_named_kernel("nfs=../vmlinux-nfs")
+        );
+        assert_eq!(None, parse_named_kernel("nfs"));
+        assert_eq!(None, parse_named_kernel("=../vmlinux"));
+        assert_eq!(None, parse_named_kernel("nfs="));
+    }
 }
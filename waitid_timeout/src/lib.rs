@@ -1,4 +1,4 @@
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, RawFd};
 use std::io;
 use std::time::Duration;
 use std::process::Child;
@@ -8,6 +8,7 @@ use libc::rusage as rusage_t;
 
 pub use mio_pidfd::PidFd;
 use mio::{Poll,Token,Events,Interest};
+use mio::unix::SourceFd;
 
 #[cfg(not(target_os = "linux"))]
 compile_error!("wait4 is a linux specific feature");
@@ -32,20 +33,36 @@ pub enum Error {
     FdConversion,
     PidConversion,
     Errno(i32),
+    // couldn't get a pidfd for the child, usually because it has already been reaped
+    PidFd(io::Error),
+    Wait(io::Error),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::PidFd(e) | Error::Wait(e) => e,
+            e => io::Error::other(format!("{e:?}")),
+        }
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
 pub enum WaitIdData {
     Exited{siginfo: siginfo_t, rusage: rusage_t},
     NotExited,
+    // the cancel fd given to PidFdWaiter::with_cancel became readable first
+    Cancelled,
 }
 
 pub enum WaitIdDataOvertime {
     Exited{siginfo: siginfo_t, rusage: rusage_t},
     ExitedOvertime{siginfo: siginfo_t, rusage: rusage_t},
     NotExited,
+    Cancelled,
 }
 
+// si_code interpreted with si_status which is the exit code for Exited and the signal otherwise
 #[derive(Debug,PartialEq)]
 pub enum Siginfo {
     Exited(i32),
@@ -54,7 +71,7 @@ pub enum Siginfo {
     Trapped(i32),
     Stopped(i32),
     Continued(i32),
-    Unk,
+    Unk{code: i32, status: i32},
 }
 
 impl From<&siginfo_t> for Siginfo {
@@ -67,7 +84,26 @@ impl From<&siginfo_t> for Siginfo {
             libc::CLD_TRAPPED => Self::Trapped(status),
             libc::CLD_STOPPED => Self::Stopped(status),
             libc::CLD_CONTINUED => Self::Continued(status),
-            _ => Self::Unk,
+            code => Self::Unk{code, status},
+        }
+    }
+}
+
+impl WaitIdData {
+    pub fn siginfo(&self) -> Option<Siginfo> {
+        match self {
+            WaitIdData::Exited{siginfo, ..} => Some(siginfo.into()),
+            WaitIdData::NotExited | WaitIdData::Cancelled => None,
+        }
+    }
+}
+
+impl WaitIdDataOvertime {
+    pub fn siginfo(&self) -> Option<Siginfo> {
+        match self {
+            WaitIdDataOvertime::Exited{siginfo, ..}
+            | WaitIdDataOvertime::ExitedOvertime{siginfo, ..} => Some(siginfo.into()),
+            WaitIdDataOvertime::NotExited | WaitIdDataOvertime::Cancelled => None,
         }
     }
 }
@@ -100,6 +136,9 @@ pub fn waitid_pid_exited_nohang(pid: u32) -> io::Result<WaitIdData> {
     waitid(libc::P_PID, pid, libc::WEXITED | libc::WNOHANG)
 }
 
+const PIDFD_TOKEN: Token = Token(0);
+const CANCEL_TOKEN: Token = Token(1);
+
 pub struct PidFdWaiter<'a> {
     poll: Poll,
     pidfd: &'a PidFd,
@@ -109,23 +148,49 @@ impl<'a> PidFdWaiter<'a> {
     pub fn new(pidfd: &'a mut PidFd) -> io::Result<Self> {
         let poll = Poll::new()?;
         poll.registry()
-            .register(pidfd, Token(0), Interest::READABLE)?;
+            .register(pidfd, PIDFD_TOKEN, Interest::READABLE)?;
         Ok(Self { poll, pidfd })
     }
 
+    /// wait_timeout also returns Cancelled once cancel becomes readable (like an eventfd being
+    /// written to). The fd is never read from and the registration is edge triggered, so each
+    /// signal cancels one wait. If the process has also exited, the exit wins
+    pub fn with_cancel<Fd: AsRawFd>(self, cancel: &Fd) -> io::Result<Self> {
+        let fd: RawFd = cancel.as_raw_fd();
+        self.poll.registry()
+            .register(&mut SourceFd(&fd), CANCEL_TOKEN, Interest::READABLE)?;
+        Ok(self)
+    }
+
     pub fn kill(&mut self, signal: c_int) -> io::Result<()> {
         self.pidfd.kill(signal)
     }
 
     pub fn wait_timeout(&mut self, duration: Duration) -> io::Result<WaitIdData> {
-        let mut events = Events::with_capacity(1);
+        let mut events = Events::with_capacity(2);
         self.poll.poll(&mut events, Some(duration))?;
         if events.is_empty() {
             return Ok(WaitIdData::NotExited);
         }
+        if events.iter().all(|x| x.token() == CANCEL_TOKEN) {
+            return Ok(WaitIdData::Cancelled);
+        }
+        waitid_pidfd_exited_nohang(self.pidfd)
+    }
+
+    /// blocks with no timeout until the process exits, or Cancelled if with_cancel fires first
+    pub fn wait(&mut self) -> io::Result<WaitIdData> {
+        let mut events = Events::with_capacity(2);
+        while events.is_empty() {
+            self.poll.poll(&mut events, None)?;
+        }
+        if events.iter().all(|x| x.token() == CANCEL_TOKEN) {
+            return Ok(WaitIdData::Cancelled);
+        }
         waitid_pidfd_exited_nohang(self.pidfd)
     }
 
+    /// a cancelled wait doesn't kill the process, that is up to the caller
     pub fn wait_timeout_or_kill(&mut self, duration: Duration) -> io::Result<WaitIdDataOvertime> {
         match self.wait_timeout(duration) {
             Ok(WaitIdData::NotExited) => {
@@ -133,33 +198,37 @@ impl<'a> PidFdWaiter<'a> {
                 match waitid_pidfd_exited_hang(self.pidfd) {
                     Ok(WaitIdData::Exited{siginfo, rusage}) => Ok(WaitIdDataOvertime::ExitedOvertime{siginfo, rusage}),
                     Ok(WaitIdData::NotExited)               => Ok(WaitIdDataOvertime::NotExited),
+                    Ok(WaitIdData::Cancelled)               => Ok(WaitIdDataOvertime::Cancelled),
                     Err(e) => Err(e),
                 }
             }
             Ok(WaitIdData::Exited{siginfo, rusage}) => Ok(WaitIdDataOvertime::Exited{siginfo, rusage}),
+            Ok(WaitIdData::Cancelled) => Ok(WaitIdDataOvertime::Cancelled),
             Err(e) => Err(e),
         }
     }
 }
 
+// Error::PidFd is kept apart from Error::Wait so that a child someone else already reaped can be
+// told from a wait that went wrong
 pub trait ChildWaitIdExt {
-    fn wait_timeout(&self, duration: Duration) -> io::Result<WaitIdData>;
-    fn wait_timeout_or_kill(&self, duration: Duration) -> io::Result<WaitIdDataOvertime>;
+    fn wait_timeout(&self, duration: Duration) -> Result<WaitIdData, Error>;
+    fn wait_timeout_or_kill(&self, duration: Duration) -> Result<WaitIdDataOvertime, Error>;
 }
 
 impl ChildWaitIdExt for Child {
-    fn wait_timeout(&self, duration: Duration) -> io::Result<WaitIdData> {
-        let mut pidfd = PidFd::new(self)?;
-        let mut waiter = PidFdWaiter::new(&mut pidfd)?;
-        waiter.wait_timeout(duration)
+    fn wait_timeout(&self, duration: Duration) -> Result<WaitIdData, Error> {
+        let mut pidfd = PidFd::new(self).map_err(Error::PidFd)?;
+        let mut waiter = PidFdWaiter::new(&mut pidfd).map_err(Error::Wait)?;
+        waiter.wait_timeout(duration).map_err(Error::Wait)
     }
 
     /// if you get Ok(WaitIdDataOvertime::NotExited) from this, something has gone pretty wrong and
     /// the child is probably not reaped, idk what else to do though
-    fn wait_timeout_or_kill(&self, duration: Duration) -> io::Result<WaitIdDataOvertime> {
-        let mut pidfd = PidFd::new(self)?;
-        let mut waiter = PidFdWaiter::new(&mut pidfd)?;
-        waiter.wait_timeout_or_kill(duration)
+    fn wait_timeout_or_kill(&self, duration: Duration) -> Result<WaitIdDataOvertime, Error> {
+        let mut pidfd = PidFd::new(self).map_err(Error::PidFd)?;
+        let mut waiter = PidFdWaiter::new(&mut pidfd).map_err(Error::Wait)?;
+        waiter.wait_timeout_or_kill(duration).map_err(Error::Wait)
     }
 }
 
@@ -169,7 +238,7 @@ mod tests {
     use std::time::Instant;
     use std::process::Command;
 
-    fn assert_exited(result: io::Result<WaitIdData>, pid: u32, status: i32) {
+    fn assert_exited<E: std::fmt::Debug>(result: Result<WaitIdData, E>, pid: u32, status: i32) {
         match result {
             Ok(WaitIdData::Exited{siginfo, ..}) => {
                 assert_eq!(pid, unsafe { siginfo.si_pid().try_into().unwrap() });
@@ -177,6 +246,7 @@ mod tests {
                 assert_eq!(info, Siginfo::Exited(status));
             },
             Ok(WaitIdData::NotExited) => { panic!("got NotExited and I shouldnt"); }
+            Ok(WaitIdData::Cancelled) => { panic!("got Cancelled and I shouldnt"); }
             Err(err)                  => { panic!("got err={err:?} and I shouldnt"); }
         }
     }
@@ -189,6 +259,7 @@ mod tests {
                 assert_eq!(info, Siginfo::Killed(signal));
             }
             Ok(WaitIdData::NotExited) => { panic!("expected an exit"); }
+            Ok(WaitIdData::Cancelled) => { panic!("expected an exit"); }
             Err(err)                  => { panic!("got err={err:?} and I shouldnt"); }
         }
     }
@@ -197,10 +268,42 @@ mod tests {
         match result {
             Ok(WaitIdData::NotExited) => {}
             Ok(WaitIdData::Exited{..}) => { panic!("got data and I shouldnt"); }
+            Ok(WaitIdData::Cancelled)  => { panic!("got Cancelled and I shouldnt"); }
             Err(err)                   => { panic!("got err={err:?} and I shouldnt"); }
         }
     }
 
+    #[test]
+    fn wait_pid_dumped() {
+        // nothing gets dumped if we can't raise the limit, and a piped core_pattern is up to
+        // whatever it pipes to, so skip rather than fail on hosts set up like that
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) }, 0);
+        let core_pattern =
+            std::fs::read_to_string("/proc/sys/kernel/core_pattern").unwrap_or_default();
+        if limit.rlim_max != libc::RLIM_INFINITY || core_pattern.starts_with('|') {
+            eprintln!(
+                "skipping, core hard limit {} core_pattern {:?}",
+                limit.rlim_max,
+                core_pattern.trim()
+            );
+            return;
+        }
+        // SIGQUIT dumps core by default, core_pattern is probably relative so do it somewhere we
+        // can clean up
+        let dir = std::env::temp_dir().join(format!("waitid-timeout-core-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let child = Command::new("sh").arg("-c").arg("ulimit -c unlimited; kill -QUIT $$")
+            .current_dir(&dir)
+            .spawn().unwrap();
+        let ret = child.wait_timeout(Duration::from_millis(1000)).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(ret.siginfo(), Some(Siginfo::Dumped(libc::SIGQUIT)));
+    }
+
     #[test]
     fn wait_pid_exit() {
         let child = Command::new("sh").arg("-c").arg("exit 11").spawn().unwrap();
@@ -291,6 +394,51 @@ mod tests {
         assert!(elapsed < Duration::from_millis(100));
     }
 
+    #[test]
+    fn wait_blocks_until_exited() {
+        let child = Command::new("sh").arg("-c").arg("sleep 0.050; exit 11").spawn().unwrap();
+        let mut pidfd = PidFd::new(&child).unwrap();
+        let mut waiter = PidFdWaiter::new(&mut pidfd).unwrap();
+        let start = Instant::now();
+        let ret = waiter.wait();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        if let Ok(WaitIdData::Exited{rusage, ..}) = &ret {
+            // sh at least touched some memory
+            assert!(rusage.ru_maxrss > 0);
+        }
+        assert_exited(ret, child.id(), 11);
+    }
+
+    #[test]
+    fn wait_timeout_cancelled() {
+        use std::io::Write;
+        use std::os::fd::FromRawFd;
+
+        let mut child = Command::new("sh").arg("-c").arg("sleep 1000").spawn().unwrap();
+        let mut pidfd = PidFd::new(&child).unwrap();
+        let efd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(efd >= 0);
+        let cancel = unsafe { std::fs::File::from_raw_fd(efd) };
+        let mut waiter = PidFdWaiter::new(&mut pidfd).unwrap().with_cancel(&cancel).unwrap();
+
+        let mut canceller = cancel.try_clone().unwrap();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            canceller.write_all(&1u64.to_ne_bytes()).unwrap();
+        });
+        let start = Instant::now();
+        let ret = waiter.wait_timeout(Duration::from_millis(1000)).unwrap();
+        assert!(matches!(ret, WaitIdData::Cancelled));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        t.join().unwrap();
+
+        // the process is left alone
+        assert_not_exited(waitid_pid_exited_nohang(child.id()));
+        child.kill().unwrap();
+        let ret = waiter.wait_timeout(Duration::from_millis(1000));
+        assert_signaled(ret, child.id(), libc::SIGKILL);
+    }
+
     #[test]
     fn child_wait_timeout() {
         let child = Command::new("sh").arg("-c").arg("sleep 0.050; exit 11").spawn().unwrap();
@@ -298,11 +446,24 @@ mod tests {
         assert_exited(ret, child.id(), 11);
     }
 
+    #[test]
+    fn child_already_reaped() {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        match child.wait_timeout(Duration::from_millis(10)) {
+            Err(Error::PidFd(e)) => { assert_eq!(e.raw_os_error(), Some(libc::ESRCH)); }
+            Err(e) => { panic!("expected a pidfd error, got {e:?}"); }
+            Ok(_)  => { panic!("expected a pidfd error"); }
+        }
+        assert!(matches!(child.wait_timeout_or_kill(Duration::from_millis(10)), Err(Error::PidFd(_))));
+    }
+
     #[test]
     fn child_wait_timeout_kill() {
         let child = Command::new("sh").arg("-c").arg("sleep 1000").spawn().unwrap();
         let start = Instant::now();
-        match child.wait_timeout_or_kill(Duration::from_millis(50)) {
+        let ret = child.wait_timeout_or_kill(Duration::from_millis(50));
+        match &ret {
             Ok(WaitIdDataOvertime::ExitedOvertime{siginfo, ..}) => {
                 assert_eq!(child.id(), unsafe { siginfo.si_pid().try_into().unwrap() });
                 assert_eq!(libc::CLD_KILLED, siginfo.si_code);
@@ -310,6 +471,7 @@ mod tests {
             }
             _ => { panic!("should have gotten exitedovertime"); }
         }
+        assert_eq!(ret.unwrap().siginfo(), Some(Siginfo::Killed(libc::SIGKILL)));
         let elapsed = start.elapsed();
         assert!(elapsed < Duration::from_millis(100));
     }